@@ -0,0 +1,176 @@
+//! Access control lists (RFC 4314). The list of a mailbox is kept in its
+//! directory in the .acl file, as one "<identifier> <rights>" line per
+//! entry. The owner of a maildir has every right on its mailboxes whatever
+//! the list says; "anyone" and "authenticated" give rights to every user.
+
+use std::collections::BTreeMap;
+use std::fs;
+use std::io;
+use std::path::{Path, PathBuf};
+
+use crate::message::Flag;
+
+pub static ACL_FILE: &str = ".acl";
+
+/// Every right, in the order they are given in responses: lookup, read,
+/// seen, write, insert, post, create, delete, delete messages, expunge and
+/// administer.
+pub static RIGHTS: &str = "lrswipkxtea";
+
+/// The rights in a SETACL rights string, in order. The obsolete "c" and "d"
+/// of RFC 2086 stand for the rights which replaced them. None if a right is
+/// unknown.
+pub fn parse_rights(rights: &str) -> Option<String> {
+    let mut given = String::new();
+    for right in rights.chars() {
+        match right {
+            'c' => given.push_str("kx"),
+            'd' => given.push_str("xte"),
+            right if RIGHTS.contains(right) => given.push(right),
+            _ => return None,
+        }
+    }
+    Some(RIGHTS.chars().filter(|&right| given.contains(right)).collect())
+}
+
+/// The right needed to set or clear a flag.
+pub fn flag_right(flag: &Flag) -> char {
+    match *flag {
+        Flag::Seen => 's',
+        Flag::Deleted => 't',
+        _ => 'w',
+    }
+}
+
+/// Remove the list of the mailbox in a directory, if it has one.
+pub fn remove(dir: &Path) -> io::Result<()> {
+    match fs::remove_file(dir.join(ACL_FILE)) {
+        Err(ref e) if e.kind() == io::ErrorKind::NotFound => Ok(()),
+        res => res,
+    }
+}
+
+#[derive(Debug, Default, PartialEq)]
+pub struct Acl {
+    // Where the list is saved
+    path: Option<PathBuf>,
+    // The rights given to each identifier
+    entries: BTreeMap<String, String>,
+}
+
+impl Acl {
+    /// Read the list of the mailbox in a directory. Having none is fine.
+    pub fn load(dir: &Path) -> Acl {
+        let contents = fs::read_to_string(dir.join(ACL_FILE)).unwrap_or_default();
+        let mut acl = Acl::parse(&contents);
+        acl.path = Some(dir.join(ACL_FILE));
+        acl
+    }
+
+    fn parse(contents: &str) -> Acl {
+        let mut entries = BTreeMap::new();
+        for line in contents.lines() {
+            let mut fields = line.splitn(2, ' ');
+            match (fields.next(), fields.next().and_then(parse_rights)) {
+                (Some(identifier), Some(rights)) if !identifier.is_empty() => {
+                    entries.insert(identifier.to_string(), rights);
+                }
+                _ => warn!("Invalid line in {}: {}", ACL_FILE, line),
+            }
+        }
+        Acl { path: None, entries }
+    }
+
+    fn save(&self) -> io::Result<()> {
+        let path = match self.path {
+            Some(ref path) => path,
+            None => return Ok(()),
+        };
+        let mut contents = String::new();
+        for (identifier, rights) in &self.entries {
+            contents.push_str(&format!("{} {}\n", identifier, rights));
+        }
+        let tmp_path = path.with_extension("tmp");
+        fs::write(&tmp_path, contents).and_then(|_| fs::rename(&tmp_path, path))
+    }
+
+    /// The rights of a user, in order. The owner of the mailbox has them
+    /// all.
+    pub fn rights(&self, identifier: &str, owner: bool) -> String {
+        if owner {
+            return RIGHTS.to_string();
+        }
+        let granted: String = [identifier, "anyone", "authenticated"]
+            .iter()
+            .filter_map(|identifier| self.entries.get(*identifier))
+            .map(|rights| &rights[..])
+            .collect();
+        RIGHTS.chars().filter(|&right| granted.contains(right)).collect()
+    }
+
+    /// Every entry of the list, by identifier.
+    pub fn entries(&self) -> impl Iterator<Item = (&str, &str)> {
+        self.entries
+            .iter()
+            .map(|(identifier, rights)| (&identifier[..], &rights[..]))
+    }
+
+    /// Change the rights of an identifier as SETACL does: a leading "+"
+    /// adds to them, a "-" takes away from them and otherwise they are
+    /// replaced. The rights must be valid.
+    pub fn set(&mut self, identifier: &str, modification: &str) -> io::Result<()> {
+        let current = self.entries.remove(identifier).unwrap_or_default();
+        let rights = match (modification.strip_prefix('+'), modification.strip_prefix('-')) {
+            (Some(rights), _) => format!("{}{}", current, rights),
+            (_, Some(rights)) => current.chars().filter(|&right| !rights.contains(right)).collect(),
+            _ => modification.to_string(),
+        };
+        let rights = parse_rights(&rights).unwrap_or_default();
+        if !rights.is_empty() {
+            self.entries.insert(identifier.to_string(), rights);
+        }
+        self.save()
+    }
+
+    /// Remove an identifier from the list, as DELETEACL does.
+    pub fn delete(&mut self, identifier: &str) -> io::Result<()> {
+        if self.entries.remove(identifier).is_some() {
+            self.save()?;
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testing::TempDir;
+
+    #[test]
+    fn test_acl() {
+        assert_eq!(parse_rights("rl"), Some("lr".to_string()));
+        assert_eq!(parse_rights("cd"), Some("kxte".to_string()));
+        assert_eq!(parse_rights("lz"), None);
+
+        let dir = TempDir::new("acl");
+        fs::create_dir_all(&dir).unwrap();
+        let mut acl = Acl::load(&dir);
+        assert_eq!(acl.rights("fred@example.com", false), "");
+        assert_eq!(acl.rights("will@xqz.ca", true), RIGHTS);
+
+        acl.set("fred@example.com", "lrs").unwrap();
+        acl.set("fred@example.com", "+wi").unwrap();
+        acl.set("fred@example.com", "-s").unwrap();
+        acl.set("anyone", "l").unwrap();
+        assert_eq!(
+            fs::read_to_string(dir.join(ACL_FILE)).unwrap(),
+            "anyone l\nfred@example.com lrwi\n"
+        );
+        let mut acl = Acl::load(&dir);
+        assert_eq!(acl.rights("fred@example.com", false), "lrwi");
+        assert_eq!(acl.rights("bob@example.com", false), "l");
+        acl.set("anyone", "").unwrap();
+        acl.delete("fred@example.com").unwrap();
+        assert_eq!(Acl::load(&dir).entries().count(), 0);
+    }
+}
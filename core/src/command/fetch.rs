@@ -1,6 +1,7 @@
 use std::collections::HashSet;
 
-use crate::command::Attribute::BodySection;
+use crate::command::sequence_set::{self, IntervalSet};
+use crate::command::Attribute::{BodySection, ModSeq};
 use crate::command::FetchCommand;
 use crate::folder::Folder;
 use crate::parser::{self, ParserResult};
@@ -17,20 +18,33 @@ pub fn fetch(args: Vec<&str>) -> ParserResult<FetchCommand> {
         cmd.push_str(arg);
     }
 
-    parser::fetch(cmd.as_bytes())
+    let mut parsed_cmd = parser::fetch(cmd.as_bytes())?;
+    // CHANGEDSINCE implies fetching the MODSEQ
+    if parsed_cmd.changed_since.is_some() && !parsed_cmd.attributes.contains(&ModSeq) {
+        parsed_cmd.attributes.push(ModSeq);
+    }
+    Ok(parsed_cmd)
 }
 
 /// Perform the fetch operation on each sequence number indicated and return
-/// the response to be sent back to the client.
+/// the response to be sent back to the client. `seen` is whether fetching
+/// a body section may set the \Seen flag.
 pub fn fetch_loop(
     parsed_cmd: &FetchCommand,
     folder: &mut Folder,
     sequence_iter: &[usize],
     tag: &str,
     uid: bool,
-) -> String {
+    seen: bool,
+) -> Vec<u8> {
+    // Fetching mod-sequences enables CONDSTORE
+    if parsed_cmd.attributes.contains(&ModSeq) {
+        folder.enable_condstore();
+    }
+
+    // Fetching a body section sets \Seen, if the user may set it
     for attr in &parsed_cmd.attributes {
-        if let BodySection(_, _) = *attr {
+        if let (BodySection(_, _), true) = (attr, seen) {
             let mut seen_flag_set = HashSet::new();
             seen_flag_set.insert(Seen);
             folder.store(
@@ -39,13 +53,25 @@ pub fn fetch_loop(
                 true,
                 seen_flag_set,
                 false,
-                tag,
+                None,
             );
             break;
         }
     }
 
-    let mut res = String::new();
+    let mut res = Vec::new();
+    let changed_since = parsed_cmd.changed_since.unwrap_or(0);
+    if parsed_cmd.vanished {
+        let set = IntervalSet::unbounded(&parsed_cmd.sequence_set, folder.uid_next() - 1);
+        let vanished: Vec<usize> = folder
+            .vanished_since(changed_since)
+            .into_iter()
+            .filter(|&uid| set.contains(uid))
+            .collect();
+        if !vanished.is_empty() {
+            res.extend_from_slice(format!("* VANISHED (EARLIER) {}\r\n", sequence_set::format(&vanished)).as_bytes());
+        }
+    }
     for i in sequence_iter {
         let index = if !uid {
             *i - 1
@@ -54,13 +80,12 @@ pub fn fetch_loop(
         } else {
             continue;
         };
-        res.push_str(&folder.fetch(index, &parsed_cmd.attributes)[..]);
-    }
-    res.push_str(tag);
-    res.push_str(" OK ");
-    if uid {
-        res.push_str("UID ");
+        if folder.message(index).is_none_or(|message| message.get_modseq() <= changed_since) {
+            continue;
+        }
+        res.extend_from_slice(&folder.fetch(index, &parsed_cmd.attributes));
     }
-    res.push_str("FETCH completed\r\n");
+    let uid = if uid { "UID " } else { "" };
+    res.extend_from_slice(format!("{} OK {}FETCH completed\r\n", tag, uid).as_bytes());
     res
 }
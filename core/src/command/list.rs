@@ -0,0 +1,704 @@
+//! LIST with the extensions of RFC 5258 (LIST-EXTENDED), RFC 5819
+//! (LIST-STATUS) and RFC 6154 (SPECIAL-USE), and LSUB.
+
+use std::collections::{BTreeMap, BTreeSet};
+use std::fs;
+use std::iter;
+use std::path::{Path, PathBuf};
+
+use crate::folder::{Folder, STATUS_ITEMS};
+use crate::layout::Layout;
+use crate::namespace::{Delimiter, Root};
+use crate::special_use::SpecialUse;
+use crate::subscriptions::Subscriptions;
+use crate::util::{mailbox_levels, wire_name};
+
+/// The parts of a LIST command line
+#[derive(Debug, Default, PartialEq)]
+pub struct ListCommand {
+    pub reference: String,
+    pub patterns: Vec<String>,
+    // Selection options
+    pub subscribed: bool,
+    pub recursive_match: bool,
+    pub special_use: bool,
+    // Return options. CHILDREN and SPECIAL-USE need none, as the attributes
+    // they ask for are always returned.
+    pub return_subscribed: bool,
+    pub status: Vec<String>,
+    // Whether this is LSUB rather than LIST
+    pub lsub: bool,
+    // Whether names are sent as UTF-8 rather than modified UTF-7
+    pub utf8: bool,
+    // The hierarchy delimiter clients see
+    pub delimiter: Delimiter,
+}
+
+#[derive(Debug, PartialEq)]
+enum Token {
+    Open,
+    Close,
+    // An atom, or the contents of a quoted string
+    String(String),
+}
+
+fn tokenize(args: &str) -> Option<Vec<Token>> {
+    let mut tokens = Vec::new();
+    let mut chars = args.chars().peekable();
+    while let Some(c) = chars.next() {
+        match c {
+            ' ' => {}
+            '(' => tokens.push(Token::Open),
+            ')' => tokens.push(Token::Close),
+            '"' => {
+                let mut string = String::new();
+                loop {
+                    match chars.next()? {
+                        '"' => break,
+                        '\\' => string.push(chars.next()?),
+                        c => string.push(c),
+                    }
+                }
+                tokens.push(Token::String(string));
+            }
+            c => {
+                let mut atom = c.to_string();
+                while let Some(&c) = chars.peek() {
+                    if c == ' ' || c == '(' || c == ')' || c == '"' {
+                        break;
+                    }
+                    atom.push(c);
+                    chars.next();
+                }
+                tokens.push(Token::String(atom));
+            }
+        }
+    }
+    Some(tokens)
+}
+
+/// The strings of a parenthesized list, whose opening parenthesis has
+/// been taken already.
+fn string_list<I: Iterator<Item = Token>>(tokens: &mut I) -> Option<Vec<String>> {
+    let mut strings = Vec::new();
+    loop {
+        match tokens.next()? {
+            Token::Close => return Some(strings),
+            Token::String(string) => strings.push(string),
+            Token::Open => return None,
+        }
+    }
+}
+
+/// Parse the arguments of LIST, or of LSUB if `lsub` is set. None if they
+/// are invalid.
+pub fn parse(args: &str, lsub: bool) -> Option<ListCommand> {
+    let mut tokens = tokenize(args)?.into_iter().peekable();
+    let mut cmd = ListCommand {
+        lsub,
+        ..ListCommand::default()
+    };
+
+    if !lsub && tokens.peek() == Some(&Token::Open) {
+        tokens.next();
+        for option in string_list(&mut tokens)? {
+            match &option.to_uppercase()[..] {
+                "SUBSCRIBED" => cmd.subscribed = true,
+                "RECURSIVEMATCH" => cmd.recursive_match = true,
+                "SPECIAL-USE" => cmd.special_use = true,
+                // There are no remote mailboxes to include
+                "REMOTE" => {}
+                _ => return None,
+            }
+        }
+        // RECURSIVEMATCH only modifies another selection option
+        if cmd.recursive_match && !cmd.subscribed {
+            return None;
+        }
+    }
+
+    cmd.reference = match tokens.next()? {
+        Token::String(reference) => reference,
+        _ => return None,
+    };
+    cmd.patterns = match tokens.next()? {
+        Token::String(pattern) => vec![pattern],
+        Token::Open if !lsub => string_list(&mut tokens)?,
+        _ => return None,
+    };
+    if cmd.patterns.is_empty() {
+        return None;
+    }
+
+    match tokens.next() {
+        None => return Some(cmd),
+        Some(Token::String(ref word)) if !lsub && word.eq_ignore_ascii_case("RETURN") => {}
+        _ => return None,
+    }
+    if tokens.next()? != Token::Open {
+        return None;
+    }
+    loop {
+        let option = match tokens.next()? {
+            Token::Close => break,
+            Token::String(option) => option.to_uppercase(),
+            Token::Open => return None,
+        };
+        match &option[..] {
+            "SUBSCRIBED" => cmd.return_subscribed = true,
+            "CHILDREN" | "SPECIAL-USE" => {}
+            "STATUS" => {
+                if tokens.next()? != Token::Open {
+                    return None;
+                }
+                for item in string_list(&mut tokens)? {
+                    let item = item.to_uppercase();
+                    if !STATUS_ITEMS.contains(&&item[..]) {
+                        return None;
+                    }
+                    cmd.status.push(item);
+                }
+            }
+            _ => return None,
+        }
+    }
+    if tokens.next().is_some() {
+        return None;
+    }
+    Some(cmd)
+}
+
+/// A LIST pattern (RFC 3501 section 6.3.8): "*" matches anything and "%"
+/// anything but the hierarchy delimiter. Matching simulates the pattern as
+/// an automaton, which takes time linear in the name for any pattern.
+#[derive(Debug, PartialEq)]
+struct Pattern {
+    chars: Vec<char>,
+}
+
+impl Pattern {
+    /// The pattern for a LIST pattern taken relative to the reference.
+    fn new(reference: &str, pattern: &str) -> Pattern {
+        let full = if reference.is_empty() || reference.ends_with('/') || pattern.starts_with('/') {
+            format!("{}{}", reference, pattern)
+        } else {
+            format!("{}/{}", reference, pattern)
+        };
+        Pattern {
+            chars: full.chars().collect(),
+        }
+    }
+
+    /// Add to the states those reached by letting wildcards match nothing.
+    fn close(&self, states: &mut [bool]) {
+        for i in 0..self.chars.len() {
+            if states[i] && (self.chars[i] == '*' || self.chars[i] == '%') {
+                states[i + 1] = true;
+            }
+        }
+    }
+
+    /// The positions in the pattern which a match of `name` may have
+    /// reached.
+    fn states(&self, name: &str) -> Vec<bool> {
+        let mut states = vec![false; self.chars.len() + 1];
+        states[0] = true;
+        self.close(&mut states);
+        for c in name.chars() {
+            let mut next = vec![false; states.len()];
+            for (i, &p) in self.chars.iter().enumerate() {
+                if !states[i] {
+                    continue;
+                }
+                match p {
+                    '*' => next[i] = true,
+                    '%' if c != '/' => next[i] = true,
+                    '%' => {}
+                    p if p == c => next[i + 1] = true,
+                    _ => {}
+                }
+            }
+            self.close(&mut next);
+            if !next.contains(&true) {
+                return next;
+            }
+            states = next;
+        }
+        states
+    }
+
+    /// Whether the pattern matches the name. INBOX is case-insensitive.
+    fn matches(&self, name: &str) -> bool {
+        if name == "INBOX" {
+            let upper = Pattern {
+                chars: self.chars.iter().map(|c| c.to_ascii_uppercase()).collect(),
+            };
+            return upper.states(name)[self.chars.len()];
+        }
+        self.states(name)[self.chars.len()]
+    }
+
+    /// Whether the pattern may match any mailbox below the named one, so
+    /// that it is worth looking through.
+    fn matches_within(&self, name: &str) -> bool {
+        self.states(&format!("{}/", name)).contains(&true)
+    }
+}
+
+/// A mail folder, or a directory on the way to one
+#[derive(Debug, PartialEq)]
+struct Mailbox {
+    // \Noselect, \Marked or \Unmarked
+    selectability: &'static str,
+    children: bool,
+    path: PathBuf,
+    // Whether the user may read the mailbox, and so have its STATUS
+    readable: bool,
+}
+
+/// Whether a directory in a maildir is a mailbox. Hidden directories, such
+/// as the one holding Sieve scripts, are not, and neither are those which
+/// hold the messages.
+fn is_mailbox_dir(entry: &fs::DirEntry) -> bool {
+    let name = entry.file_name();
+    let name = name.to_string_lossy();
+    entry.file_type().is_ok_and(|file_type| file_type.is_dir())
+        && !name.starts_with('.')
+        && name != "cur"
+        && name != "new"
+        && name != "tmp"
+}
+
+/// The mailboxes in a user's own maildir which some pattern matches, by
+/// name, INBOX being the maildir itself. Only directories which may hold
+/// such mailboxes are looked through.
+fn mailboxes(maildir: &Path, layout: Layout, patterns: &[Pattern]) -> BTreeMap<String, Mailbox> {
+    let mut mailboxes = BTreeMap::new();
+    // INBOX has no children: the other folders are beside it rather than
+    // in it
+    if patterns.iter().any(|pattern| pattern.matches("INBOX")) {
+        let mut inbox = mailbox(maildir);
+        inbox.children = false;
+        mailboxes.insert("INBOX".to_string(), inbox);
+    }
+    match layout {
+        Layout::Nested => find_mailboxes(maildir, "", patterns, &mut mailboxes),
+        // Maildir++ folders are all beside INBOX, and the parent of a folder
+        // need not exist
+        Layout::MaildirPlusPlus => {
+            let folders = layout.folders(maildir);
+            let names: BTreeSet<&str> = folders
+                .iter()
+                .flat_map(|folder| ancestors(folder).chain(iter::once(&folder[..])))
+                .collect();
+            for name in names {
+                if patterns.iter().any(|pattern| pattern.matches(name)) {
+                    let levels: Vec<&str> = name.split('/').collect();
+                    let mut mailbox = mailbox(&layout.folder_path(maildir, &levels));
+                    let prefix = format!("{}/", name);
+                    mailbox.children = folders.iter().any(|folder| folder.starts_with(&prefix));
+                    mailboxes.insert(name.to_string(), mailbox);
+                }
+            }
+        }
+    }
+    mailboxes
+}
+
+/// Add the mailboxes of another user's maildir or of the shared root which
+/// some pattern matches and the user may look up, along with the levels
+/// above them, which cannot be selected.
+fn shared_mailboxes(root: &Root, identifier: &str, patterns: &[Pattern], mailboxes: &mut BTreeMap<String, Mailbox>) {
+    let top = root.prefix.split('/').next().unwrap_or("");
+    if !patterns.iter().any(|pattern| pattern.matches(top) || pattern.matches_within(top)) {
+        return;
+    }
+    let mut folders = root.layout.folders(&root.path);
+    if root.owner.is_some() {
+        folders.insert("INBOX".to_string());
+    }
+    let mut visible = BTreeMap::new();
+    for folder in folders {
+        let path = root.layout.folder_path(&root.path, &mailbox_levels(&folder));
+        let rights = root.rights(identifier, &path);
+        if rights.contains('l') {
+            visible.insert(format!("{}/{}", root.prefix, folder), (path, rights.contains('r')));
+        }
+    }
+    let names: BTreeSet<&str> = visible
+        .keys()
+        .flat_map(|name| ancestors(name).chain(iter::once(&name[..])))
+        .collect();
+    for name in names {
+        if !patterns.iter().any(|pattern| pattern.matches(name)) {
+            continue;
+        }
+        let prefix = format!("{}/", name);
+        let children = visible.keys().any(|other| other.starts_with(&prefix));
+        let mailbox = match visible.get(name) {
+            Some((path, readable)) => Mailbox {
+                children,
+                readable: *readable,
+                ..mailbox(path)
+            },
+            None => Mailbox {
+                selectability: "\\Noselect",
+                children,
+                path: root.path.clone(),
+                readable: false,
+            },
+        };
+        mailboxes.insert(name.to_string(), mailbox);
+    }
+}
+
+fn find_mailboxes(dir: &Path, parent: &str, patterns: &[Pattern], mailboxes: &mut BTreeMap<String, Mailbox>) {
+    let entries = match fs::read_dir(dir) {
+        Ok(entries) => entries,
+        Err(_) => return,
+    };
+    for entry in entries.flatten().filter(is_mailbox_dir) {
+        let name = match entry.file_name().to_str() {
+            Some(name) if parent.is_empty() => name.to_string(),
+            Some(name) => format!("{}/{}", parent, name),
+            None => continue,
+        };
+        if patterns.iter().any(|pattern| pattern.matches(&name)) {
+            mailboxes.insert(name.clone(), mailbox(&entry.path()));
+        }
+        if patterns.iter().any(|pattern| pattern.matches_within(&name)) {
+            find_mailboxes(&entry.path(), &name, patterns, mailboxes);
+        }
+    }
+}
+
+fn mailbox(path: &Path) -> Mailbox {
+    // If it doesn't have any mail, then it isn't selectable as a mail
+    // folder but it may contain subfolders which hold mail. If there is
+    // new mail in the folder, we should inform the client.
+    let selectability = if !path.join("cur").is_dir() {
+        "\\Noselect"
+    } else {
+        match path.join("new").read_dir() {
+            Err(_) => "\\Noselect",
+            Ok(mut listing) => {
+                if listing.next().is_some() {
+                    "\\Marked"
+                } else {
+                    "\\Unmarked"
+                }
+            }
+        }
+    };
+    let children = match fs::read_dir(path) {
+        Ok(entries) => entries.flatten().any(|entry| is_mailbox_dir(&entry)),
+        Err(_) => false,
+    };
+    Mailbox {
+        selectability,
+        children,
+        path: path.to_path_buf(),
+        readable: true,
+    }
+}
+
+/// Every ancestor in the hierarchy of a mailbox name
+fn ancestors(name: &str) -> impl Iterator<Item = &str> {
+    name.match_indices('/').map(move |(i, _)| &name[..i])
+}
+
+/// Respond to a LIST or LSUB command for the user with an identifier, who
+/// sees the mailboxes in the roots, the first being their own maildir.
+/// `selected` is the folder selected in the session, whose STATUS comes
+/// from the session's view of it.
+pub fn list(
+    cmd: &ListCommand,
+    roots: &[Root],
+    identifier: &str,
+    special_use: &SpecialUse,
+    subscriptions: &Subscriptions,
+    selected: Option<&Folder>,
+) -> String {
+    let command = if cmd.lsub { "LSUB" } else { "LIST" };
+    // An empty pattern asks for the hierarchy delimiter
+    if cmd.patterns.len() == 1 && cmd.patterns[0].is_empty() && !cmd.subscribed && !cmd.special_use {
+        return format!("* {} (\\Noselect) \"{}\" \"\"\r\n", command, cmd.delimiter.as_char());
+    }
+    let patterns: Vec<Pattern> = cmd
+        .patterns
+        .iter()
+        .map(|pattern| Pattern::new(&cmd.reference, pattern))
+        .collect();
+    let matches = |name: &str| patterns.iter().any(|pattern| pattern.matches(name));
+
+    let mut mailboxes = mailboxes(&roots[0].path, roots[0].layout, &patterns);
+    for root in &roots[1..] {
+        shared_mailboxes(root, identifier, &patterns, &mut mailboxes);
+    }
+    // The names to list, each with whether it is listed for having a
+    // subscribed child only
+    let mut names: BTreeMap<&str, bool> = BTreeMap::new();
+    if cmd.subscribed || cmd.lsub {
+        for name in subscriptions.names() {
+            names.insert(name, false);
+            // LSUB lists the parents of subscribed mailboxes which the
+            // pattern doesn't match, as "%" may not, as \Noselect
+            if cmd.recursive_match || (cmd.lsub && !matches(name)) {
+                for ancestor in ancestors(name) {
+                    names.entry(ancestor).or_insert(true);
+                }
+            }
+        }
+    } else {
+        names.extend(mailboxes.keys().map(|name| (&name[..], false)));
+    }
+
+    let mut res = String::new();
+    for (name, child_only) in names {
+        if !matches(name) {
+            continue;
+        }
+        let uses = special_use.attributes(name);
+        if cmd.special_use && uses.is_empty() {
+            continue;
+        }
+        let mailbox = mailboxes.get(name);
+        let mut attributes = Vec::new();
+        match mailbox {
+            Some(mailbox) => {
+                attributes.push(mailbox.selectability);
+                attributes.push(if mailbox.children {
+                    "\\HasChildren"
+                } else {
+                    "\\HasNoChildren"
+                });
+            }
+            None if cmd.lsub => attributes.push("\\Noselect"),
+            None => attributes.push("\\NonExistent"),
+        }
+        if cmd.lsub && child_only && !attributes.contains(&"\\Noselect") {
+            attributes.push("\\Noselect");
+        }
+        if !cmd.lsub && (cmd.subscribed || cmd.return_subscribed) && subscriptions.contains(name) {
+            attributes.push("\\Subscribed");
+        }
+        attributes.extend(uses);
+        res.push_str(&format!(
+            "* {} ({}) \"{}\" {}",
+            command,
+            attributes.join(" "),
+            cmd.delimiter.as_char(),
+            wire_name(name, cmd.delimiter, cmd.utf8)
+        ));
+        // RECURSIVEMATCH tells why a mailbox which is not subscribed itself
+        // is listed
+        if cmd.recursive_match && child_only {
+            res.push_str(" (\"CHILDINFO\" (\"SUBSCRIBED\"))");
+        }
+        res.push_str("\r\n");
+
+        // LIST-STATUS gives the counts of each selectable mailbox listed
+        let readable = mailbox.filter(|mailbox| mailbox.selectability != "\\Noselect" && mailbox.readable);
+        if let (Some(mailbox), false) = (readable, cmd.status.is_empty()) {
+            let items: Vec<&str> = cmd.status.iter().map(|item| &item[..]).collect();
+            let status = match selected {
+                Some(folder) if folder.get_path() == mailbox.path => folder.status(&items),
+                _ => Folder::new(mailbox.path.clone(), true).and_then(|folder| folder.status(&items)),
+            };
+            if let Some(status) = status {
+                res.push_str(&format!("* STATUS {} ({})\r\n", wire_name(name, cmd.delimiter, cmd.utf8), status));
+            }
+        }
+    }
+    res
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::acl::Acl;
+    use crate::layout::convert;
+    use crate::testing::TempDir;
+    use std::fs;
+
+    #[test]
+    fn test_parse() {
+        let cmd = parse("\"\" *", false).unwrap();
+        assert_eq!(cmd.reference, "");
+        assert_eq!(cmd.patterns, vec!["*"]);
+        let cmd = parse(
+            "(SUBSCRIBED RECURSIVEMATCH remote) \"a\" (\"%\" Foo/*) RETURN (CHILDREN STATUS (MESSAGES unseen))",
+            false,
+        )
+        .unwrap();
+        assert!(cmd.subscribed && cmd.recursive_match && !cmd.special_use);
+        assert_eq!(cmd.patterns, vec!["%", "Foo/*"]);
+        assert_eq!(cmd.status, vec!["MESSAGES", "UNSEEN"]);
+        assert!(parse("(RECURSIVEMATCH) \"\" *", false).is_none());
+        assert!(parse("(BOGUS) \"\" *", false).is_none());
+        assert!(parse("\"\" * RETURN (STATUS (BOGUS))", false).is_none());
+        assert!(parse("\"\" \"*", false).is_none());
+        assert!(parse("\"\" (*)", true).is_none());
+        assert!(parse("\"\" * extra", false).is_none());
+    }
+
+    #[test]
+    fn test_pattern() {
+        let pattern = Pattern::new("", "%");
+        assert!(pattern.matches("Sent") && !pattern.matches("Lists/rust"));
+        assert!(!pattern.matches_within("Lists"));
+        let pattern = Pattern::new("Lists", "*");
+        assert!(pattern.matches("Lists/rust") && !pattern.matches("Lists"));
+        assert!(pattern.matches_within("Lists") && !pattern.matches_within("Sent"));
+        let pattern = Pattern::new("Lists/", "%/new");
+        assert!(pattern.matches("Lists/rust/new") && !pattern.matches("Lists/rust/a/new"));
+        assert!(pattern.matches_within("Lists/rust") && !pattern.matches_within("Lists/rust/a"));
+        assert!(Pattern::new("", "inB%").matches("INBOX"));
+        assert!(!Pattern::new("", "inB%").matches("inbound"));
+        let pattern = Pattern::new("", "a.b(*[");
+        assert!(pattern.matches("a.b(x/y[") && !pattern.matches("axb(["));
+
+        // Patterns which would make a backtracking matcher blow up
+        let pattern = Pattern::new("", &"*a".repeat(30));
+        assert!(!pattern.matches(&"a".repeat(29)));
+        assert!(pattern.matches(&"ba".repeat(30)));
+    }
+
+    #[test]
+    fn test_list() {
+        let maildir = TempDir::new("list");
+        for dir in &[
+            "cur",
+            "new",
+            "Sent/cur",
+            "Sent/new",
+            "Lists/rust/cur",
+            "Lists/rust/new",
+            ".sieve",
+        ] {
+            fs::create_dir_all(maildir.join(dir)).unwrap();
+        }
+        fs::write(maildir.join("new").join("1000"), "Subject: a\n\nbody\n").unwrap();
+        let mut defaults = BTreeMap::new();
+        defaults.insert("Sent".to_string(), "Sent".to_string());
+        let special_use = SpecialUse::load(&maildir, &defaults);
+        let mut subscriptions = Subscriptions::default();
+        subscriptions.subscribe("Lists/rust").unwrap();
+        subscriptions.subscribe("Gone").unwrap();
+
+        let root = |layout: Layout| Root {
+            prefix: String::new(),
+            path: maildir.to_path_buf(),
+            layout,
+            owner: Some("will@xqz.ca".to_string()),
+        };
+        let run_in = |layout: Layout, args: &str, lsub: bool| {
+            list(
+                &parse(args, lsub).unwrap(),
+                &[root(layout)],
+                "will@xqz.ca",
+                &special_use,
+                &subscriptions,
+                None,
+            )
+        };
+        let run = |args: &str, lsub: bool| run_in(Layout::Nested, args, lsub);
+        assert_eq!(
+            run("\"\" %", false),
+            "* LIST (\\Marked \\HasNoChildren) \"/\" \"INBOX\"\r\n\
+             * LIST (\\Noselect \\HasChildren) \"/\" \"Lists\"\r\n\
+             * LIST (\\Unmarked \\HasNoChildren \\Sent) \"/\" \"Sent\"\r\n"
+        );
+        assert_eq!(run("\"\" \"\"", false), "* LIST (\\Noselect) \"/\" \"\"\r\n");
+        assert_eq!(
+            run("(SUBSCRIBED RECURSIVEMATCH) \"\" %", false),
+            "* LIST (\\NonExistent \\Subscribed) \"/\" \"Gone\"\r\n\
+             * LIST (\\Noselect \\HasChildren) \"/\" \"Lists\" (\"CHILDINFO\" (\"SUBSCRIBED\"))\r\n"
+        );
+        assert_eq!(
+            run("(SPECIAL-USE) \"\" * RETURN (STATUS (MESSAGES RECENT))", false),
+            "* LIST (\\Unmarked \\HasNoChildren \\Sent) \"/\" \"Sent\"\r\n* STATUS \"Sent\" (MESSAGES 0 RECENT 0)\r\n"
+        );
+        assert_eq!(
+            run("\"\" Lists/* RETURN (SUBSCRIBED)", false),
+            "* LIST (\\Unmarked \\HasNoChildren \\Subscribed) \"/\" \"Lists/rust\"\r\n"
+        );
+        assert_eq!(
+            run("\"\" *", true),
+            "* LSUB (\\Noselect) \"/\" \"Gone\"\r\n* LSUB (\\Unmarked \\HasNoChildren) \"/\" \"Lists/rust\"\r\n"
+        );
+        assert_eq!(
+            run("\"\" %", true),
+            "* LSUB (\\Noselect) \"/\" \"Gone\"\r\n* LSUB (\\Noselect \\HasChildren) \"/\" \"Lists\"\r\n"
+        );
+
+        // Clients may see "." as the delimiter
+        let mut cmd = parse("\"\" Lists/*", false).unwrap();
+        cmd.delimiter = Delimiter::Dot;
+        assert_eq!(
+            list(&cmd, &[root(Layout::Nested)], "will@xqz.ca", &special_use, &subscriptions, None),
+            "* LIST (\\Unmarked \\HasNoChildren) \".\" \"Lists.rust\"\r\n"
+        );
+
+        // The same mailboxes are listed in Maildir++
+        let expected = vec![run("\"\" *", false), run("\"\" %", false), run("\"\" Lists/%", false)];
+        convert(&maildir, Layout::Nested, Layout::MaildirPlusPlus).unwrap();
+        assert!(maildir.join(".Lists.rust").is_dir());
+        let listed = vec![
+            run_in(Layout::MaildirPlusPlus, "\"\" *", false),
+            run_in(Layout::MaildirPlusPlus, "\"\" %", false),
+            run_in(Layout::MaildirPlusPlus, "\"\" Lists/%", false),
+        ];
+        assert_eq!(listed, expected);
+    }
+
+    #[test]
+    fn test_list_shared() {
+        let shared = TempDir::new("list-shared");
+        for dir in &["support/cur", "support/new", "support/2023/cur", "support/2023/new", "hr/cur", "hr/new"] {
+            fs::create_dir_all(shared.join(dir)).unwrap();
+        }
+        Acl::load(&shared.join("support")).set("anyone", "lr").unwrap();
+        Acl::load(&shared.join("support").join("2023")).set("fred@xqz.ca", "l").unwrap();
+        let roots = vec![
+            Root {
+                prefix: String::new(),
+                path: shared.join("none"),
+                layout: Layout::Nested,
+                owner: Some("will@xqz.ca".to_string()),
+            },
+            Root {
+                prefix: "Shared".to_string(),
+                path: shared.to_path_buf(),
+                layout: Layout::Nested,
+                owner: None,
+            },
+        ];
+        let run = |args: &str, identifier: &str| {
+            list(
+                &parse(args, false).unwrap(),
+                &roots,
+                identifier,
+                &SpecialUse::default(),
+                &Subscriptions::default(),
+                None,
+            )
+        };
+        assert_eq!(
+            run("\"\" S%", "will@xqz.ca"),
+            "* LIST (\\Noselect \\HasChildren) \"/\" \"Shared\"\r\n"
+        );
+        assert_eq!(
+            run("\"\" Shared/* RETURN (STATUS (MESSAGES))", "will@xqz.ca"),
+            "* LIST (\\Unmarked \\HasNoChildren) \"/\" \"Shared/support\"\r\n\
+             * STATUS \"Shared/support\" (MESSAGES 0)\r\n"
+        );
+        // Fred may look up the subfolder but not read it
+        assert_eq!(
+            run("\"\" Shared/* RETURN (STATUS (MESSAGES))", "fred@xqz.ca"),
+            "* LIST (\\Unmarked \\HasChildren) \"/\" \"Shared/support\"\r\n\
+             * STATUS \"Shared/support\" (MESSAGES 0)\r\n\
+             * LIST (\\Unmarked \\HasNoChildren) \"/\" \"Shared/support/2023\"\r\n"
+        );
+    }
+}
@@ -1,6 +1,10 @@
 pub mod fetch;
+pub mod list;
+pub mod search;
 pub mod sequence_set;
+pub mod sort;
 pub mod store;
+pub mod thread;
 
 use crate::command::sequence_set::SequenceItem;
 
@@ -16,6 +20,7 @@ pub enum Attribute {
     Envelope,
     Flags,
     InternalDate,
+    ModSeq,
     RFC822(RFC822Attribute),
     UID,
 }
@@ -33,10 +38,14 @@ pub enum RFC822Attribute {
 /// It has a list of message ids (either UIDs or indexes into the folder's list
 /// of messages)
 /// It has a list of message attributes which are being requested.
+/// With CHANGEDSINCE, only messages whose mod-sequence is greater are
+/// fetched, and VANISHED asks for the UIDs expunged since then as well.
 #[derive(PartialEq, Debug)]
 pub struct FetchCommand {
     pub sequence_set: Vec<SequenceItem>,
     pub attributes: Vec<Attribute>,
+    pub changed_since: Option<usize>,
+    pub vanished: bool,
 }
 
 impl FetchCommand {
@@ -44,6 +53,8 @@ impl FetchCommand {
         FetchCommand {
             sequence_set: sequence_set,
             attributes: attributes,
+            changed_since: None,
+            vanished: false,
         }
     }
 }
@@ -0,0 +1,656 @@
+//! SEARCH (RFC 3501 section 6.4.4): parsing search keys and finding the
+//! messages of the selected folder which match them. SORT and THREAD take
+//! the same keys.
+
+use std::collections::HashMap;
+use std::iter::Peekable;
+use std::slice::Iter;
+
+use chrono::NaiveDate;
+
+use self::SearchKey::{
+    And, Before, Body, Header, Ids, Larger, ModSeq, Not, On, Or, Recent, Sequence, Since, Smaller, Text, Uid,
+};
+use crate::command::sequence_set::{self, IntervalSet, SequenceItem};
+use crate::folder::Folder;
+use crate::fts::Lookup;
+use crate::message::{parse_flag, Flag, Message};
+
+/// A piece of the arguments of a command: a parenthesis, an atom or a
+/// quoted string.
+#[derive(Clone, Debug, PartialEq)]
+pub enum Token {
+    Open,
+    Close,
+    Atom(String),
+    Quoted(String),
+}
+
+/// Split the arguments of a command into tokens. None if a quoted string is
+/// left open.
+pub fn tokenize(args: &str) -> Option<Vec<Token>> {
+    let mut tokens = Vec::new();
+    let mut chars = args.chars().peekable();
+    while let Some(chr) = chars.next() {
+        match chr {
+            ' ' => {}
+            '(' => tokens.push(Token::Open),
+            ')' => tokens.push(Token::Close),
+            '"' => {
+                let mut string = String::new();
+                loop {
+                    match chars.next()? {
+                        '"' => break,
+                        '\\' => string.push(chars.next()?),
+                        chr => string.push(chr),
+                    }
+                }
+                tokens.push(Token::Quoted(string));
+            }
+            chr => {
+                let mut atom = chr.to_string();
+                while let Some(&chr) = chars.peek() {
+                    if chr == ' ' || chr == '(' || chr == ')' || chr == '"' {
+                        break;
+                    }
+                    atom.push(chr);
+                    chars.next();
+                }
+                tokens.push(Token::Atom(atom));
+            }
+        }
+    }
+    Some(tokens)
+}
+
+/// What a message has to be like to match a search.
+#[derive(Clone, Debug, PartialEq)]
+pub enum SearchKey {
+    /// Every one of the keys, so none is ALL
+    And(Vec<SearchKey>),
+    Or(Box<SearchKey>, Box<SearchKey>),
+    Not(Box<SearchKey>),
+    Sequence(Vec<SequenceItem>),
+    Uid(Vec<SequenceItem>),
+    /// The sequence numbers or, if true, the UIDs a sequence set stands
+    /// for, worked out for the folder searched
+    Ids(bool, IntervalSet),
+    Flag(Flag),
+    Recent,
+    /// A header field holding a string, case-insensitively. Any field with
+    /// the name matches the empty string.
+    Header(String, String),
+    /// Text in the decoded body
+    Body(String),
+    /// Text in the header or the decoded body
+    Text(String),
+    /// Whether the internal date or, when sent, the date in the Date header
+    /// field is before, on, or on or after a day
+    Before(bool, NaiveDate),
+    On(bool, NaiveDate),
+    Since(bool, NaiveDate),
+    Larger(usize),
+    Smaller(usize),
+    /// A mod-sequence of at least the one given (RFC 7162)
+    ModSeq(usize),
+}
+
+impl SearchKey {
+    /// Whether the key looks at mod-sequences, which makes the response
+    /// give the highest one matched.
+    pub fn has_modseq(&self) -> bool {
+        match *self {
+            And(ref keys) => keys.iter().any(|key| key.has_modseq()),
+            Or(ref a, ref b) => a.has_modseq() || b.has_modseq(),
+            Not(ref key) => key.has_modseq(),
+            ModSeq(_) => true,
+            _ => false,
+        }
+    }
+
+    /// The text looked for in message bodies, which the full-text index
+    /// may know about.
+    pub fn body_texts(&self) -> Vec<&str> {
+        match *self {
+            And(ref keys) => keys.iter().flat_map(|key| key.body_texts()).collect(),
+            Or(ref a, ref b) => a.body_texts().into_iter().chain(b.body_texts()).collect(),
+            Not(ref key) => key.body_texts(),
+            Body(ref text) | Text(ref text) => vec![&text[..]],
+            _ => Vec::new(),
+        }
+    }
+}
+
+impl SearchKey {
+    /// The key with its sequence sets worked out for a folder, which is
+    /// done once rather than for every message.
+    fn resolve(&self, folder: &Folder) -> SearchKey {
+        match *self {
+            And(ref keys) => And(keys.iter().map(|key| key.resolve(folder)).collect()),
+            Or(ref a, ref b) => Or(Box::new(a.resolve(folder)), Box::new(b.resolve(folder))),
+            Not(ref key) => not(key.resolve(folder)),
+            Sequence(ref set) | Uid(ref set) if sequence_set::is_saved(set) => {
+                let saved = sequence_set::resolve(set.clone(), &folder.saved(true));
+                Ids(true, IntervalSet::new(&saved, folder.uid_next() - 1))
+            }
+            Sequence(ref set) => Ids(false, IntervalSet::new(set, folder.message_count())),
+            Uid(ref set) => Ids(true, IntervalSet::new(set, folder.uid_next() - 1)),
+            ref key => key.clone(),
+        }
+    }
+}
+
+type Tokens<'a> = Peekable<Iter<'a, Token>>;
+
+fn not(key: SearchKey) -> SearchKey {
+    Not(Box::new(key))
+}
+
+fn all_of(mut keys: Vec<SearchKey>) -> SearchKey {
+    if keys.len() == 1 {
+        keys.remove(0)
+    } else {
+        And(keys)
+    }
+}
+
+fn string(tokens: &mut Tokens) -> Option<String> {
+    match tokens.next()? {
+        Token::Atom(string) | Token::Quoted(string) => Some(string.clone()),
+        _ => None,
+    }
+}
+
+// A date as in "1-Feb-1994"
+fn date(tokens: &mut Tokens) -> Option<NaiveDate> {
+    NaiveDate::parse_from_str(&string(tokens)?, "%d-%b-%Y").ok()
+}
+
+fn number(tokens: &mut Tokens) -> Option<usize> {
+    string(tokens)?.parse().ok()
+}
+
+fn parse_key(tokens: &mut Tokens) -> Option<SearchKey> {
+    let word = match tokens.next()? {
+        Token::Open => {
+            let mut keys = Vec::new();
+            while *tokens.peek()? != &Token::Close {
+                keys.push(parse_key(tokens)?);
+            }
+            tokens.next();
+            return if keys.is_empty() { None } else { Some(all_of(keys)) };
+        }
+        Token::Atom(word) => word,
+        _ => return None,
+    };
+    let key = match &word.to_ascii_uppercase()[..] {
+        "ALL" => And(Vec::new()),
+        "ANSWERED" => SearchKey::Flag(Flag::Answered),
+        "DELETED" => SearchKey::Flag(Flag::Deleted),
+        "DRAFT" => SearchKey::Flag(Flag::Draft),
+        "FLAGGED" => SearchKey::Flag(Flag::Flagged),
+        "SEEN" => SearchKey::Flag(Flag::Seen),
+        "KEYWORD" => SearchKey::Flag(parse_flag(&string(tokens)?)?),
+        "UNANSWERED" => not(SearchKey::Flag(Flag::Answered)),
+        "UNDELETED" => not(SearchKey::Flag(Flag::Deleted)),
+        "UNDRAFT" => not(SearchKey::Flag(Flag::Draft)),
+        "UNFLAGGED" => not(SearchKey::Flag(Flag::Flagged)),
+        "UNSEEN" => not(SearchKey::Flag(Flag::Seen)),
+        "UNKEYWORD" => not(SearchKey::Flag(parse_flag(&string(tokens)?)?)),
+        "RECENT" => Recent,
+        "NEW" => And(vec![Recent, not(SearchKey::Flag(Flag::Seen))]),
+        "OLD" => not(Recent),
+        field @ ("BCC" | "CC" | "FROM" | "SUBJECT" | "TO") => Header(field.to_string(), string(tokens)?),
+        "HEADER" => {
+            let field = string(tokens)?.to_ascii_uppercase();
+            Header(field, string(tokens)?)
+        }
+        "BODY" => Body(string(tokens)?),
+        "TEXT" => Text(string(tokens)?),
+        "BEFORE" => Before(false, date(tokens)?),
+        "ON" => On(false, date(tokens)?),
+        "SINCE" => Since(false, date(tokens)?),
+        "SENTBEFORE" => Before(true, date(tokens)?),
+        "SENTON" => On(true, date(tokens)?),
+        "SENTSINCE" => Since(true, date(tokens)?),
+        "LARGER" => Larger(number(tokens)?),
+        "SMALLER" => Smaller(number(tokens)?),
+        "UID" => Uid(sequence_set::parse(&string(tokens)?)?),
+        "NOT" => not(parse_key(tokens)?),
+        "OR" => {
+            let a = parse_key(tokens)?;
+            Or(Box::new(a), Box::new(parse_key(tokens)?))
+        }
+        "MODSEQ" => {
+            // The entry name and type of a flag's mod-sequence may come
+            // first. Only one mod-sequence is kept per message, so they
+            // make no difference.
+            if let Some(Token::Quoted(_)) = tokens.peek() {
+                tokens.next();
+                tokens.next();
+            }
+            ModSeq(number(tokens)?)
+        }
+        _ => Sequence(sequence_set::parse(word)?),
+    };
+    Some(key)
+}
+
+/// Parse the search keys which make up the rest of the tokens, all of which
+/// have to match. None if there are none or they are invalid.
+pub fn parse(tokens: &[Token]) -> Option<SearchKey> {
+    let mut tokens = tokens.iter().peekable();
+    let mut keys = Vec::new();
+    while tokens.peek().is_some() {
+        keys.push(parse_key(&mut tokens)?);
+    }
+    if keys.is_empty() {
+        None
+    } else {
+        Some(all_of(keys))
+    }
+}
+
+/// Whether strings in a charset given to SEARCH, SORT or THREAD can be
+/// understood.
+pub fn charset_supported(charset: &str) -> bool {
+    charset.eq_ignore_ascii_case("UTF-8") || charset.eq_ignore_ascii_case("US-ASCII")
+}
+
+/// The response to a command given a charset which is not supported.
+pub fn bad_charset(tag: &str) -> String {
+    format!("{} NO [BADCHARSET (US-ASCII UTF-8)] Unsupported charset\r\n", tag)
+}
+
+/// The result options of an extended SEARCH (RFC 4731), with SAVE (RFC
+/// 5182) to keep the messages found as "$".
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub struct ReturnOptions {
+    pub min: bool,
+    pub max: bool,
+    pub count: bool,
+    pub all: bool,
+    pub save: bool,
+}
+
+/// Parse the parenthesized list after RETURN at the start of the tokens.
+/// An empty list means ALL. Returns the options and the tokens left.
+pub fn parse_return(tokens: &[Token]) -> Option<(ReturnOptions, &[Token])> {
+    if tokens.first() != Some(&Token::Open) {
+        return None;
+    }
+    let mut options = ReturnOptions::default();
+    for (i, token) in tokens.iter().enumerate().skip(1) {
+        let word = match *token {
+            Token::Close if i == 1 => {
+                options.all = true;
+                return Some((options, &tokens[i + 1..]));
+            }
+            Token::Close => return Some((options, &tokens[i + 1..])),
+            Token::Atom(ref word) => word.to_ascii_uppercase(),
+            _ => return None,
+        };
+        match &word[..] {
+            "MIN" => options.min = true,
+            "MAX" => options.max = true,
+            "COUNT" => options.count = true,
+            "ALL" => options.all = true,
+            "SAVE" => options.save = true,
+            _ => return None,
+        }
+    }
+    None
+}
+
+/// The ESEARCH response to an extended SEARCH, given the sorted ids of the
+/// messages found. Nothing is given back for SAVE alone.
+fn esearch_response(options: ReturnOptions, ids: &[usize], uid: bool, tag: &str) -> String {
+    if !(options.min || options.max || options.count || options.all) {
+        return String::new();
+    }
+    let mut res = format!("* ESEARCH (TAG \"{}\")", tag);
+    if uid {
+        res.push_str(" UID");
+    }
+    if let (true, Some(min)) = (options.min, ids.first()) {
+        res.push_str(&format!(" MIN {}", min));
+    }
+    if let (true, Some(max)) = (options.max, ids.last()) {
+        res.push_str(&format!(" MAX {}", max));
+    }
+    if options.count {
+        res.push_str(&format!(" COUNT {}", ids.len()));
+    }
+    if options.all && !ids.is_empty() {
+        res.push_str(" ALL ");
+        res.push_str(&sequence_set::format(ids));
+    }
+    res
+}
+
+fn contains_ignore_case(haystack: &str, needle: &str) -> bool {
+    haystack.to_lowercase().contains(&needle.to_lowercase())
+}
+
+/// What the full-text index of a folder says about each text looked for
+/// in message bodies. Without an index, or for text it cannot tell about,
+/// the messages are read.
+pub type Lookups<'a> = HashMap<&'a str, Lookup<'a>>;
+
+fn body_contains(message: &Message, text: &str, lookups: &Lookups) -> bool {
+    let indexed = lookups.get(text).and_then(|lookup| lookup.contains(message.get_uid()));
+    indexed.unwrap_or_else(|| {
+        let mime_message = message.get_mime_message();
+        mime_message
+            .get_body_text(&["text".to_string()])
+            .iter()
+            .any(|body| contains_ignore_case(body, text))
+    })
+}
+
+// The day a message arrived or, when sent, the one in its Date header field
+fn day(message: &Message, sent: bool) -> Option<NaiveDate> {
+    if sent {
+        message.sent_date().map(|date| date.naive_local().date())
+    } else {
+        Some(message.internal_date().date())
+    }
+}
+
+/// Whether the message at an index of a folder matches a search key, the
+/// sequence sets of which have been worked out for the folder.
+fn matches(key: &SearchKey, folder: &Folder, index: usize, lookups: &Lookups) -> bool {
+    let message = match folder.message(index) {
+        Some(message) => message,
+        None => return false,
+    };
+    let mime_message = message.get_mime_message();
+    match *key {
+        And(ref keys) => keys.iter().all(|key| matches(key, folder, index, lookups)),
+        Or(ref a, ref b) => matches(a, folder, index, lookups) || matches(b, folder, index, lookups),
+        Not(ref key) => !matches(key, folder, index, lookups),
+        // Sequence sets are worked out before matching
+        Sequence(_) | Uid(_) => false,
+        Ids(false, ref set) => set.contains(index + 1),
+        Ids(true, ref set) => set.contains(message.get_uid()),
+        SearchKey::Flag(ref flag) => message.get_flags().contains(flag),
+        Recent => message.is_recent(),
+        Header(ref field, ref text) => mime_message
+            .get_header_values(field)
+            .iter()
+            .any(|value| contains_ignore_case(value, text)),
+        Body(ref text) => body_contains(message, text, lookups),
+        Text(ref text) => {
+            contains_ignore_case(mime_message.get_header(), text) || body_contains(message, text, lookups)
+        }
+        Before(sent, date) => day(message, sent).is_some_and(|day| day < date),
+        On(sent, date) => day(message, sent) == Some(date),
+        Since(sent, date) => day(message, sent).is_some_and(|day| day >= date),
+        Larger(size) => mime_message.get_size() > size,
+        Smaller(size) => mime_message.get_size() < size,
+        ModSeq(modseq) => message.get_modseq() >= modseq,
+    }
+}
+
+/// The indexes of the messages of a folder which match a search key, in
+/// order. The folder's full-text index, if it uses one, is brought up to
+/// date when the key looks at message bodies.
+pub fn matching(folder: &Folder, key: &SearchKey) -> Vec<usize> {
+    let texts = key.body_texts();
+    let fts = if texts.is_empty() { None } else { folder.fts_index() };
+    let mut lookups = Lookups::new();
+    if let Some(ref fts) = fts {
+        for text in texts {
+            if let Some(lookup) = fts.lookup(text) {
+                lookups.insert(text, lookup);
+            }
+        }
+    }
+    let key = key.resolve(folder);
+    (0..folder.message_count())
+        .filter(|&index| matches(&key, folder, index, &lookups))
+        .collect()
+}
+
+/// Perform SEARCH, or UID SEARCH, given the arguments after the command
+/// name. With RETURN options the response is an ESEARCH one. Returns the
+/// response to the client or None if a BAD response should be sent back.
+pub fn search(folder: &mut Folder, args: &str, uid: bool, tag: &str) -> Option<String> {
+    let tokens = tokenize(args)?;
+    let mut tokens = &tokens[..];
+    let mut options = None;
+    if let [Token::Atom(ref word), ..] = *tokens {
+        if word.eq_ignore_ascii_case("RETURN") {
+            let (return_options, rest) = parse_return(&tokens[1..])?;
+            options = Some(return_options);
+            tokens = rest;
+        }
+    }
+    // A SEARCH which fails leaves nothing saved
+    if options.is_some_and(|options| options.save) {
+        folder.save_result(&[]);
+    }
+    if let [Token::Atom(ref word), ref charset, ..] = *tokens {
+        if word.eq_ignore_ascii_case("CHARSET") {
+            match *charset {
+                Token::Atom(ref charset) | Token::Quoted(ref charset) if charset_supported(charset) => {}
+                Token::Atom(_) | Token::Quoted(_) => return Some(bad_charset(tag)),
+                _ => return None,
+            }
+            tokens = &tokens[2..];
+        }
+    }
+    let key = parse(tokens)?;
+    let indexes = matching(folder, &key);
+
+    let mut res = match options {
+        None => {
+            let mut res = "* SEARCH".to_string();
+            for &index in &indexes {
+                res.push(' ');
+                match folder.message(index) {
+                    Some(message) if uid => res.push_str(&message.get_uid().to_string()),
+                    _ => res.push_str(&(index + 1).to_string()),
+                }
+            }
+            res
+        }
+        Some(options) => {
+            let mut uids: Vec<usize> = indexes
+                .iter()
+                .filter_map(|&index| folder.message(index))
+                .map(|message| message.get_uid())
+                .collect();
+            uids.sort();
+            if options.save {
+                // With MIN or MAX but not ALL, only those messages are saved
+                if options.all || !(options.min || options.max) {
+                    folder.save_result(&uids);
+                } else {
+                    let mut saved = Vec::new();
+                    if options.min {
+                        saved.extend(uids.first());
+                    }
+                    if options.max {
+                        saved.extend(uids.last());
+                    }
+                    folder.save_result(&saved);
+                }
+            }
+            let ids: Vec<usize> = if uid {
+                uids
+            } else {
+                indexes.iter().map(|&index| index + 1).collect()
+            };
+            esearch_response(options, &ids, uid, tag)
+        }
+    };
+    // Searching by mod-sequence enables CONDSTORE and gives the highest
+    // mod-sequence of the messages found
+    if key.has_modseq() {
+        folder.enable_condstore();
+        let highest = indexes
+            .iter()
+            .filter_map(|&index| folder.message(index))
+            .map(|message| message.get_modseq())
+            .max();
+        if let (Some(highest), false) = (highest, res.is_empty()) {
+            if options.is_some() {
+                res.push_str(&format!(" MODSEQ {}", highest));
+            } else {
+                res.push_str(&format!(" (MODSEQ {})", highest));
+            }
+        }
+    }
+    if !res.is_empty() {
+        res.push_str("\r\n");
+    }
+    res.push_str(tag);
+    res.push_str(" OK ");
+    if uid {
+        res.push_str("UID ");
+    }
+    res.push_str("SEARCH completed\r\n");
+    Some(res)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::fs;
+    use crate::testing::TempDir;
+
+    // A folder holding three messages, the second of them seen and flagged
+    fn test_folder(name: &str) -> TempDir {
+        let path = TempDir::new(name);
+        fs::create_dir_all(path.join("cur")).unwrap();
+        fs::create_dir_all(path.join("new")).unwrap();
+        fs::write(
+            path.join("cur").join("1000000000"),
+            "From: Bob <bob@example.org>\nTo: zed@example.com\nSubject: Lunch\nDate: Mon, 3 Sep 2001 10:00:00 +0000\n\nShall we have lunch?\n",
+        )
+        .unwrap();
+        fs::write(
+            path.join("cur").join("1000000100:2,FS"),
+            "From: alice@example.com\nCc: carol@example.com\nSubject: Re: Lunch\nDate: Sun, 9 Sep 2001 12:00:00 +0200\nMessage-ID: <b@example.com>\n\nYes, at noon.\n",
+        )
+        .unwrap();
+        fs::write(
+            path.join("cur").join("1000100000"),
+            "From: =?UTF-8?Q?Z=C3=B6e?= <zoe@example.net>\nTo: alice@example.com\nSubject: Minutes\nContent-Type: text/plain; charset=utf-8\nContent-Transfer-Encoding: base64\n\nQ2Fmw6k=\n",
+        )
+        .unwrap();
+        path
+    }
+
+    #[test]
+    fn test_parse() {
+        let tokens = tokenize("OR (SEEN FLAGGED) HEADER X-Spam \"yes \\\"please\\\"\" 1:3,*").unwrap();
+        assert_eq!(tokens[0], Token::Atom("OR".to_string()));
+        assert_eq!(tokens[1], Token::Open);
+        assert_eq!(tokens[7], Token::Quoted("yes \"please\"".to_string()));
+        assert_eq!(
+            parse(&tokens),
+            Some(And(vec![
+                Or(
+                    Box::new(And(vec![SearchKey::Flag(Flag::Seen), SearchKey::Flag(Flag::Flagged)])),
+                    Box::new(Header("X-SPAM".to_string(), "yes \"please\"".to_string())),
+                ),
+                Sequence(sequence_set::parse("1:3,*").unwrap()),
+            ]))
+        );
+        let tokens = tokenize("UNSEEN SINCE 1-Feb-1994 MODSEQ \"/flags/\\\\draft\" all 620162338").unwrap();
+        assert_eq!(
+            parse(&tokens),
+            Some(And(vec![
+                not(SearchKey::Flag(Flag::Seen)),
+                Since(false, NaiveDate::from_ymd(1994, 2, 1)),
+                ModSeq(620162338),
+            ]))
+        );
+        assert_eq!(tokenize("\"open"), None);
+        assert_eq!(parse(&tokenize("").unwrap()), None);
+        assert_eq!(parse(&tokenize("(SEEN").unwrap()), None);
+        assert_eq!(parse(&tokenize("SINCE yesterday").unwrap()), None);
+        assert_eq!(parse(&tokenize("FROM").unwrap()), None);
+    }
+
+    #[test]
+    fn test_search() {
+        let path = test_folder("search");
+        let mut folder = Folder::new(path.to_path_buf(), true).unwrap();
+        let uids: Vec<usize> = (0..3).map(|index| folder.message(index).unwrap().get_uid()).collect();
+        let found = |folder: &Folder, args: &str| -> Vec<usize> {
+            let key = parse(&tokenize(args).unwrap()).unwrap();
+            let mut found: Vec<usize> = matching(folder, &key).into_iter().map(|index| uids[index]).collect();
+            found.sort();
+            found
+        };
+        assert_eq!(found(&folder, "ALL").len(), 3);
+        assert_eq!(found(&folder, "SEEN FLAGGED"), vec![1000000100]);
+        assert_eq!(found(&folder, "FROM \"zöe\""), vec![1000100000]);
+        assert_eq!(found(&folder, "BODY café"), vec![1000100000]);
+        assert_eq!(found(&folder, "TEXT carol"), vec![1000000100]);
+        assert_eq!(found(&folder, "HEADER Message-ID \"\""), vec![1000000100]);
+        assert_eq!(
+            found(&folder, "SENTON 9-Sep-2001 NOT SINCE 10-Sep-2001"),
+            vec![1000000100]
+        );
+        assert_eq!(found(&folder, "SENTBEFORE 1-Jan-2002 UNSEEN"), vec![1000000000]);
+        assert_eq!(
+            found(&folder, "UID 1000000000:1000000100 NOT SUBJECT re"),
+            vec![1000000000]
+        );
+        assert_eq!(
+            found(&folder, "OR LARGER 150 SMALLER 130"),
+            vec![1000000000, 1000100000]
+        );
+
+        let index = uids.iter().position(|&uid| uid == 1000000100).unwrap();
+        let res = search(&mut folder, "CHARSET UTF-8 FLAGGED", false, "a").unwrap();
+        assert_eq!(res, format!("* SEARCH {}\r\na OK SEARCH completed\r\n", index + 1));
+        let modseq = folder.message(index).unwrap().get_modseq();
+        let res = search(&mut folder, "FLAGGED MODSEQ 1", true, "a").unwrap();
+        assert_eq!(
+            res,
+            format!(
+                "* SEARCH 1000000100 (MODSEQ {})\r\na OK UID SEARCH completed\r\n",
+                modseq
+            )
+        );
+        assert!(folder.is_condstore());
+        assert_eq!(
+            search(&mut folder, "SEEN DRAFT", false, "a").unwrap(),
+            "* SEARCH\r\na OK SEARCH completed\r\n"
+        );
+        assert_eq!(
+            search(&mut folder, "CHARSET KOI8-R ALL", false, "a").unwrap(),
+            bad_charset("a")
+        );
+        assert_eq!(search(&mut folder, "NONSENSE", false, "a"), None);
+
+        let res = search(&mut folder, "RETURN (MIN COUNT) SEEN", true, "a").unwrap();
+        assert_eq!(res, "* ESEARCH (TAG \"a\") UID MIN 1000000100 COUNT 1\r\na OK UID SEARCH completed\r\n");
+        let res = search(&mut folder, "RETURN () UID 1:1000000000,1000100000", true, "a").unwrap();
+        assert_eq!(res, "* ESEARCH (TAG \"a\") UID ALL 1000000000,1000100000\r\na OK UID SEARCH completed\r\n");
+        let res = search(&mut folder, "RETURN (MAX ALL) DRAFT", false, "a").unwrap();
+        assert_eq!(res, "* ESEARCH (TAG \"a\")\r\na OK SEARCH completed\r\n");
+        // SAVE alone gives nothing back but "$" to search, fetch or store
+        let res = search(&mut folder, "RETURN (SAVE) NOT SEEN", true, "a").unwrap();
+        assert_eq!(res, "a OK UID SEARCH completed\r\n");
+        assert_eq!(folder.saved(true), vec![1000000000, 1000100000]);
+        assert_eq!(found(&folder, "$ NOT FROM bob"), vec![1000100000]);
+        search(&mut folder, "RETURN (MAX SAVE) NOT SEEN", false, "a").unwrap();
+        assert_eq!(folder.saved(true), vec![1000100000]);
+        assert_eq!(search(&mut folder, "RETURN (SAVE) NONSENSE", false, "a"), None);
+        assert!(folder.saved(true).is_empty());
+        assert_eq!(search(&mut folder, "RETURN (FIRST) ALL", false, "a"), None);
+
+        // The full-text index gives the same answers once it is built
+        folder.enable_fts();
+        assert_eq!(found(&folder, "BODY café"), vec![1000100000]);
+        assert!(path.join(".fts").is_file());
+        assert_eq!(found(&folder, "BODY CAF"), vec![1000100000]);
+        assert_eq!(found(&folder, "NOT TEXT carol"), vec![1000000000, 1000100000]);
+    }
+}
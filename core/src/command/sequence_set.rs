@@ -1,6 +1,4 @@
-use std::iter::Iterator;
-
-use self::SequenceItem::{Number, Range, Wildcard};
+use self::SequenceItem::{Number, Range, Saved, Wildcard};
 
 /// This represents an individual item in the list of requested message ids
 /// passed by the client
@@ -13,6 +11,9 @@ pub enum SequenceItem {
     Number(usize),
     Range(Box<SequenceItem>, Box<SequenceItem>),
     Wildcard,
+    /// The messages found by the last SEARCH with the SAVE option, written
+    /// as "$" (RFC 5182). It only ever makes up a whole sequence set.
+    Saved,
 }
 
 fn parse_item(item: &str) -> Option<SequenceItem> {
@@ -31,6 +32,9 @@ fn parse_item(item: &str) -> Option<SequenceItem> {
 /// Given a string as passed in from the client, create a list of sequence items
 /// If the string does not represent a valid list, return None
 pub fn parse(sequence_string: &str) -> Option<Vec<SequenceItem>> {
+    if sequence_string == "$" {
+        return Some(vec![Saved]);
+    }
     let sequences = sequence_string.split(',');
     let mut sequence_set = Vec::new();
     for sequence in sequences {
@@ -65,110 +69,114 @@ pub fn parse(sequence_string: &str) -> Option<Vec<SequenceItem>> {
     Some(sequence_set)
 }
 
-/// Create the list of unsigned integers representing valid ids from a list of
-/// sequence items. Ideally this would handle wildcards in O(1) rather than O(n)
-pub fn iterator(sequence_set: &[SequenceItem], max_id: usize) -> Vec<usize> {
-    // If the number of possible messages is 0, we return an empty vec.
-    if max_id == 0 {
-        return Vec::new();
+/// The ids a sequence set stands for, as sorted intervals which neither
+/// overlap nor touch. Ranges are kept as they are rather than expanded, so
+/// a set like 1:4294967295 costs no more than 1:2.
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct IntervalSet {
+    intervals: Vec<(usize, usize)>,
+}
+
+impl IntervalSet {
+    /// Resolve a sequence set against the ids in use, from 1 to `max_id`.
+    /// A wildcard stands for `max_id`, be the ids sequence numbers or UIDs,
+    /// and a range may run either way. Ids past `max_id` are left out.
+    pub fn new(sequence_set: &[SequenceItem], max_id: usize) -> IntervalSet {
+        IntervalSet::resolve(sequence_set, max_id, max_id)
     }
 
-    let stop = max_id + 1;
-
-    let mut items = Vec::new();
-    for item in sequence_set.iter() {
-        match *item {
-            // For a number we just need the value
-            Number(num) => items.push(num),
-            // For a range we need all the values inside it
-            Range(ref a, ref b) => {
-                // Grab the start of the range
-                let a = match **a {
-                    Number(num) => num,
-                    Wildcard => max_id,
-                    Range(_, _) => {
-                        error!("A range of ranges is invalid.");
-                        continue;
-                    }
-                };
-                // Grab the end of the range
-                let b = match **b {
-                    Number(num) => num,
-                    Wildcard => max_id,
-                    Range(_, _) => {
-                        error!("A range of ranges is invalid.");
-                        continue;
-                    }
-                };
-                // Figure out which way the range points
-                let (mut min, mut max) = if a <= b { (a, b) } else { (b, a) };
-
-                // Bounds checks
-                if min > stop {
-                    min = stop;
-                }
-                if max > stop {
-                    max = stop;
+    /// Resolve a set of UIDs, in which a wildcard stands for the largest
+    /// UID in use, keeping those past it, which may have been expunged.
+    pub fn unbounded(sequence_set: &[SequenceItem], max_uid: usize) -> IntervalSet {
+        IntervalSet::resolve(sequence_set, max_uid, usize::MAX)
+    }
+
+    fn resolve(sequence_set: &[SequenceItem], max_id: usize, limit: usize) -> IntervalSet {
+        let value = |item: &SequenceItem| match *item {
+            Number(num) => Some(num),
+            Wildcard => Some(max_id),
+            Range(_, _) | Saved => None,
+        };
+        let mut intervals: Vec<(usize, usize)> = sequence_set
+            .iter()
+            .filter_map(|item| match *item {
+                Range(ref a, ref b) => {
+                    let (a, b) = (value(a)?, value(b)?);
+                    Some((a.min(b), a.max(b)))
                 }
+                ref item => value(item).map(|id| (id, id)),
+            })
+            .map(|(start, end)| (start.max(1), end.min(limit)))
+            .filter(|&(start, end)| start <= end)
+            .collect();
+        intervals.sort();
 
-                // Generate the list of values between the min and max
-                let seq_range: Vec<usize> = (min..max + 1).collect();
-                items.extend(seq_range.iter());
-            }
-            Wildcard => {
-                // If the sequence set contains the wildcard operator, it spans
-                // the entire possible range of messages.
-                return (1..stop).collect();
+        let mut merged: Vec<(usize, usize)> = Vec::new();
+        for (start, end) in intervals {
+            match merged.last_mut() {
+                Some(last) if start <= last.1 + 1 => last.1 = last.1.max(end),
+                _ => merged.push((start, end)),
             }
         }
+        IntervalSet { intervals: merged }
     }
 
-    // Sort and remove duplicates.
-    items.sort();
-    items.dedup();
-    // Remove all elements that are greater than the maximum.
-    let items: Vec<usize> = items.into_iter().filter(|&x| x <= max_id).collect();
-    items
-}
-
-pub fn uid_iterator(sequence_set: &[SequenceItem]) -> Vec<usize> {
-    let mut items = Vec::new();
-    for item in sequence_set.iter() {
-        match *item {
-            Number(num) => items.push(num),
-            Range(ref a, ref b) => {
-                let a = match **a {
-                    Number(num) => num,
-                    Wildcard => return Vec::new(),
-                    Range(_, _) => {
-                        error!("A range of ranges is invalid.");
-                        continue;
-                    }
-                };
-                let b = match **b {
-                    Number(num) => num,
-                    Wildcard => return Vec::new(),
-                    Range(_, _) => {
-                        error!("A range of ranges is invalid.");
-                        continue;
-                    }
-                };
-                let (min, max) = if a <= b { (a, b) } else { (b, a) };
-                //if min > stop { min = stop; }
-                //if max > stop { max = stop; }
-                let seq_range: Vec<usize> = (min..max + 1).collect();
-                items.extend(seq_range.iter());
-            }
-            Wildcard => return Vec::new(),
+    pub fn is_empty(&self) -> bool {
+        self.intervals.is_empty()
+    }
+
+    pub fn contains(&self, id: usize) -> bool {
+        let i = self.intervals.partition_point(|&(_, end)| end < id);
+        self.intervals.get(i).is_some_and(|&(start, _)| start <= id)
+    }
+
+    /// The ids in order, one at a time.
+    pub fn iter(&self) -> impl Iterator<Item = usize> + '_ {
+        self.intervals.iter().flat_map(|&(start, end)| start..=end)
+    }
+}
+
+/// Whether a sequence set is "$", the saved search result.
+pub fn is_saved(sequence_set: &[SequenceItem]) -> bool {
+    sequence_set == [Saved]
+}
+
+/// The sequence set "$" stands for, given the ids of the saved messages, or
+/// else the set itself.
+pub fn resolve(sequence_set: Vec<SequenceItem>, saved: &[usize]) -> Vec<SequenceItem> {
+    if is_saved(&sequence_set) {
+        saved.iter().map(|&id| Number(id)).collect()
+    } else {
+        sequence_set
+    }
+}
+
+/// Write a sorted list of ids as a sequence set, joining consecutive ids
+/// into ranges.
+pub fn format(ids: &[usize]) -> String {
+    let mut res = String::new();
+    let mut i = 0;
+    while i < ids.len() {
+        let start = ids[i];
+        while i + 1 < ids.len() && ids[i + 1] == ids[i] + 1 {
+            i += 1;
+        }
+        if !res.is_empty() {
+            res.push(',');
         }
+        res.push_str(&start.to_string());
+        if ids[i] != start {
+            res.push(':');
+            res.push_str(&ids[i].to_string());
+        }
+        i += 1;
     }
+    res
+}
 
-    // Sort and remove duplicates.
-    items.sort();
-    items.dedup();
-    // Remove all elements that are greater than the maximum.
-    //let items: Vec<usize> = items.into_iter().filter(|&x| x <= max_id).collect();
-    items
+#[cfg(test)]
+fn iterator(sequence_set: &[SequenceItem], max_id: usize) -> Vec<usize> {
+    IntervalSet::new(sequence_set, max_id).iter().collect()
 }
 
 #[test]
@@ -217,14 +225,16 @@ fn test_sequence_range() {
 
 #[test]
 fn test_sequence_wildcard() {
+    // A wildcard is the last message, not every one
     assert_eq!(
         iterator(
             &[Range(Box::new(Number(10)), Box::new(Wildcard)), Wildcard],
             6
         ),
-        vec![1, 2, 3, 4, 5, 6]
+        vec![6]
     );
-    assert_eq!(iterator(&[Wildcard, Number(8)], 3), vec![1, 2, 3]);
+    assert_eq!(iterator(&[Wildcard, Number(8)], 3), vec![3]);
+    assert_eq!(iterator(&[Wildcard, Number(2)], 3), vec![2, 3]);
 }
 
 #[test]
@@ -244,3 +254,37 @@ fn test_sequence_complex() {
         vec![1, 3, 5, 6, 7, 9, 12, 13]
     );
 }
+
+#[test]
+fn test_interval_set() {
+    let set = IntervalSet::new(&parse("1:4294967295").unwrap(), 4294967295);
+    assert!(set.contains(1) && set.contains(4294967295) && !set.contains(0));
+    assert_eq!(set.iter().take(3).collect::<Vec<_>>(), vec![1, 2, 3]);
+    let set = IntervalSet::new(&parse("9,2:3,4,20:*,0").unwrap(), 12);
+    assert_eq!(set.intervals, vec![(2, 4), (9, 9), (12, 12)]);
+    assert!(!set.contains(5) && set.contains(12));
+    assert!(IntervalSet::new(&parse("*").unwrap(), 0).is_empty());
+}
+
+#[test]
+fn test_unbounded() {
+    let set = IntervalSet::unbounded(&parse("3,10:8,20:*,30").unwrap(), 25);
+    assert_eq!(set.intervals, vec![(3, 3), (8, 10), (20, 25), (30, 30)]);
+    assert!(set.contains(9) && set.contains(25) && set.contains(30));
+    assert!(!set.contains(4) && !set.contains(26));
+}
+
+#[test]
+fn test_saved() {
+    assert_eq!(parse("$"), Some(vec![Saved]));
+    assert_eq!(parse("$,1"), None);
+    assert_eq!(resolve(parse("$").unwrap(), &[2, 5]), vec![Number(2), Number(5)]);
+    assert_eq!(resolve(parse("3:*").unwrap(), &[2, 5]), parse("3:*").unwrap());
+}
+
+#[test]
+fn test_format() {
+    assert_eq!(format(&[]), "");
+    assert_eq!(format(&[4]), "4");
+    assert_eq!(format(&[1, 2, 3, 5, 7, 8]), "1:3,5,7:8");
+}
@@ -0,0 +1,232 @@
+//! SORT (RFC 5256), with the DISPLAYFROM and DISPLAYTO keys of RFC 5957.
+//! The messages sorted are those matching search keys, as for SEARCH.
+
+use std::cmp::Ordering;
+
+use mime::address::{parse_address_list, Address};
+use mime::Message as MIME_Message;
+
+use self::SortKey::{Arrival, Cc, Date, DisplayFrom, DisplayTo, From, Size, Subject, To};
+use super::search::{self, bad_charset, charset_supported, SearchKey, Token};
+use crate::folder::Folder;
+use crate::message::Message;
+
+/// What messages can be sorted by.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum SortKey {
+    /// The internal date
+    Arrival,
+    /// The mailbox of the first Cc address
+    Cc,
+    /// The date in the Date header field, or else the internal date
+    Date,
+    /// The display name of the first From address, or else the address
+    DisplayFrom,
+    DisplayTo,
+    From,
+    Size,
+    /// The base subject
+    Subject,
+    To,
+}
+
+/// A sort key and whether its order is reversed.
+pub type Criterion = (SortKey, bool);
+
+/// Parse a parenthesized list of sort criteria, such as "(REVERSE DATE
+/// SUBJECT)", at the start of the tokens. Returns them and the tokens left.
+pub fn parse_criteria(tokens: &[Token]) -> Option<(Vec<Criterion>, &[Token])> {
+    if tokens.first() != Some(&Token::Open) {
+        return None;
+    }
+    let mut criteria = Vec::new();
+    let mut reverse = false;
+    for (i, token) in tokens.iter().enumerate().skip(1) {
+        let word = match *token {
+            Token::Close if !criteria.is_empty() && !reverse => return Some((criteria, &tokens[i + 1..])),
+            Token::Atom(ref word) => word.to_ascii_uppercase(),
+            _ => return None,
+        };
+        let key = match &word[..] {
+            "REVERSE" if !reverse => {
+                reverse = true;
+                continue;
+            }
+            "ARRIVAL" => Arrival,
+            "CC" => Cc,
+            "DATE" => Date,
+            "DISPLAYFROM" => DisplayFrom,
+            "DISPLAYTO" => DisplayTo,
+            "FROM" => From,
+            "SIZE" => Size,
+            "SUBJECT" => Subject,
+            "TO" => To,
+            _ => return None,
+        };
+        criteria.push((key, reverse));
+        reverse = false;
+    }
+    None
+}
+
+/// The date a message was sent as a timestamp, falling back on when it
+/// arrived. THREAD orders messages by it too.
+pub fn sent_timestamp(message: &Message) -> i64 {
+    match message.sent_date() {
+        Some(date) => date.timestamp(),
+        None => message.internal_date().timestamp(),
+    }
+}
+
+fn first_address(mime_message: &MIME_Message, field: &str) -> Option<Address> {
+    let value = mime_message.get_body_part().get_header(field)?;
+    parse_address_list(value).into_iter().next()
+}
+
+// What a message is sorted by for a key, compared case-insensitively
+#[derive(PartialEq, Eq, PartialOrd, Ord)]
+enum Value {
+    Number(i64),
+    Text(String),
+}
+
+fn value(key: SortKey, message: &Message) -> Value {
+    let mime_message = message.get_mime_message();
+    let mailbox = |field| {
+        let address = first_address(mime_message, field).map(|address| address.email);
+        let mailbox = address.as_ref().and_then(|email| email.split('@').next()).unwrap_or("");
+        Value::Text(mailbox.to_lowercase())
+    };
+    let display = |field| {
+        let address = first_address(mime_message, field).map(|address| address.name.unwrap_or(address.email));
+        Value::Text(address.unwrap_or_default().to_lowercase())
+    };
+    match key {
+        Arrival => Value::Number(message.internal_date().timestamp()),
+        Cc => mailbox("CC"),
+        Date => Value::Number(sent_timestamp(message)),
+        DisplayFrom => display("FROM"),
+        DisplayTo => display("TO"),
+        From => mailbox("FROM"),
+        Size => Value::Number(mime_message.get_size() as i64),
+        Subject => Value::Text(mime_message.get_base_subject().0.to_lowercase()),
+        To => mailbox("TO"),
+    }
+}
+
+/// The indexes of the messages of a folder which match a search key, in
+/// the order the criteria give. Messages which compare equal stay in the
+/// order of the folder.
+pub fn sorted(folder: &Folder, criteria: &[Criterion], key: &SearchKey) -> Vec<usize> {
+    let mut values: Vec<(Vec<Value>, usize)> = search::matching(folder, key)
+        .into_iter()
+        .filter_map(|index| folder.message(index).map(|message| (message, index)))
+        .map(|(message, index)| (criteria.iter().map(|&(key, _)| value(key, message)).collect(), index))
+        .collect();
+    values.sort_by(|a, b| {
+        for (i, &(_, reverse)) in criteria.iter().enumerate() {
+            let ordering = a.0[i].cmp(&b.0[i]);
+            let ordering = if reverse { ordering.reverse() } else { ordering };
+            if ordering != Ordering::Equal {
+                return ordering;
+            }
+        }
+        a.1.cmp(&b.1)
+    });
+    values.into_iter().map(|(_, index)| index).collect()
+}
+
+/// Perform SORT, or UID SORT, given the arguments after the command name.
+/// Returns the response to the client or None if a BAD response should be
+/// sent back.
+pub fn sort(folder: &Folder, args: &str, uid: bool, tag: &str) -> Option<String> {
+    let tokens = search::tokenize(args)?;
+    let (criteria, tokens) = parse_criteria(&tokens)?;
+    match tokens.first()? {
+        Token::Atom(charset) | Token::Quoted(charset) if !charset_supported(charset) => return Some(bad_charset(tag)),
+        Token::Atom(_) | Token::Quoted(_) => {}
+        _ => return None,
+    }
+    let key = search::parse(&tokens[1..])?;
+
+    let mut res = "* SORT".to_string();
+    for index in sorted(folder, &criteria, &key) {
+        res.push(' ');
+        match folder.message(index) {
+            Some(message) if uid => res.push_str(&message.get_uid().to_string()),
+            _ => res.push_str(&(index + 1).to_string()),
+        }
+    }
+    res.push_str("\r\n");
+    res.push_str(tag);
+    res.push_str(" OK ");
+    if uid {
+        res.push_str("UID ");
+    }
+    res.push_str("SORT completed\r\n");
+    Some(res)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testing::TempDir;
+    use std::fs;
+
+    #[test]
+    fn test_sort() {
+        let tokens = search::tokenize("(REVERSE date Subject) UTF-8 ALL").unwrap();
+        let (criteria, rest) = parse_criteria(&tokens).unwrap();
+        assert_eq!(criteria, vec![(Date, true), (Subject, false)]);
+        assert_eq!(rest.len(), 2);
+        for args in &["()", "(REVERSE)", "(DATE", "(REVERSE REVERSE DATE)", "(COLOR)", "DATE"] {
+            assert_eq!(parse_criteria(&search::tokenize(args).unwrap()), None);
+        }
+
+        let path = TempDir::new("sort");
+        fs::create_dir_all(path.join("cur")).unwrap();
+        fs::create_dir_all(path.join("new")).unwrap();
+        let messages = [
+            (
+                1000,
+                "From: Zed <alice@example.com>\nSubject: Re: Lunch\nDate: Tue, 4 Sep 2001 10:00:00 +0000\n\n",
+            ),
+            (
+                1001,
+                "From: bob@example.com\nSubject: [list] apples\nDate: Tue, 4 Sep 2001 09:00:00 -0200\n\n",
+            ),
+            (
+                1002,
+                "From: \"Carol\" <zoe@example.com>\nSubject: lunch\n\nThe longest of them all, by quite a long way\n",
+            ),
+        ];
+        for &(uid, contents) in &messages {
+            fs::write(path.join("cur").join(uid.to_string()), contents).unwrap();
+        }
+        let folder = Folder::new(path.to_path_buf(), true).unwrap();
+        let uids = |args: &str| -> Vec<usize> {
+            let tokens = search::tokenize(args).unwrap();
+            let (criteria, tokens) = parse_criteria(&tokens).unwrap();
+            let key = search::parse(tokens).unwrap();
+            let indexes = sorted(&folder, &criteria, &key);
+            indexes
+                .into_iter()
+                .map(|index| folder.message(index).unwrap().get_uid())
+                .collect()
+        };
+        assert_eq!(uids("(ARRIVAL) ALL"), vec![1000, 1001, 1002]);
+        assert_eq!(uids("(DATE) ALL"), vec![1002, 1000, 1001]);
+        assert_eq!(uids("(SUBJECT REVERSE ARRIVAL) ALL"), vec![1001, 1002, 1000]);
+        assert_eq!(uids("(FROM) ALL"), vec![1000, 1001, 1002]);
+        assert_eq!(uids("(DISPLAYFROM) ALL"), vec![1001, 1002, 1000]);
+        assert_eq!(uids("(REVERSE SIZE) NOT FROM bob"), vec![1002, 1000]);
+
+        let res = sort(&folder, "(REVERSE ARRIVAL) US-ASCII UID 1000:1001", true, "a").unwrap();
+        assert_eq!(res, "* SORT 1001 1000\r\na OK UID SORT completed\r\n");
+        assert_eq!(
+            sort(&folder, "(SIZE) KOI8-R ALL", false, "a").unwrap(),
+            bad_charset("a")
+        );
+        assert_eq!(sort(&folder, "(SIZE)", false, "a"), None);
+    }
+}
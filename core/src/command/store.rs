@@ -1,11 +1,12 @@
 use std::collections::HashSet;
 
+use crate::acl::flag_right;
 use crate::folder::Folder;
 use crate::message::parse_flag;
 use crate::message::Flag;
 
 use self::StoreName::{Add, Replace, Sub};
-use super::sequence_set;
+use super::sequence_set::{self, IntervalSet};
 
 /// Representation of a STORE operation
 pub enum StoreName {
@@ -14,16 +15,31 @@ pub enum StoreName {
     Sub,     // remove new flags from current flags
 }
 
-/// Parse and perform the store operation specified by `store_args`. Returns the
-/// response to the client or None if a BAD response should be sent back to
-/// the client
-pub fn store(folder: &mut Folder, store_args: &[&str], seq_uid: bool, tag: &str) -> Option<String> {
+/// Parse and perform the store operation specified by `store_args`, given
+/// the user's rights on the folder. Returns the response to the client or
+/// None if a BAD response should be sent back to the client
+pub fn store(folder: &mut Folder, store_args: &[&str], seq_uid: bool, tag: &str, rights: &str) -> Option<String> {
     if store_args.len() < 3 {
         return None;
     }
 
     // Parse the sequence set argument
-    let sequence_set_opt = sequence_set::parse(store_args[0].trim_matches('"'));
+    let sequence_set_opt = sequence_set::parse(store_args[0].trim_matches('"'))
+        .map(|set| sequence_set::resolve(set, &folder.saved(seq_uid)));
+
+    // The UNCHANGEDSINCE modifier of RFC 7162 may come before the data name.
+    // It enables CONDSTORE. Skip over it, keeping the data name second.
+    let (unchanged_since, store_args) = if store_args[1].eq_ignore_ascii_case("(unchangedsince") {
+        let modseq = store_args.get(2)?.strip_suffix(')')?.parse().ok()?;
+        folder.enable_condstore();
+        (Some(modseq), &store_args[2..])
+    } else {
+        (None, store_args)
+    };
+    if store_args.len() < 3 {
+        return None;
+    }
+
     // Grab how to handle the flags. It should be case insensitive.
     let data_name = store_args[1].trim_matches('"').to_ascii_lowercase();
 
@@ -38,7 +54,8 @@ pub fn store(folder: &mut Folder, store_args: &[&str], seq_uid: bool, tag: &str)
     }
 
     // Grab the flags themselves.
-    let data_value = store_args[2].trim_matches('"');
+    let data_value = store_args[2..].join(" ");
+    let data_value = data_value.trim_matches('"');
 
     // Set the silent flag if it is present. If there is something else
     // instead of the word "silent", a BAD response should be sent to the
@@ -74,17 +91,36 @@ pub fn store(folder: &mut Folder, store_args: &[&str], seq_uid: bool, tag: &str)
         }
     }
 
+    // Each flag needs its own right (RFC 4314), and replacing the flags may
+    // change any of them
+    let needed: Vec<char> = match flag_name {
+        Replace => vec!['s', 't', 'w'],
+        _ => flags.iter().map(flag_right).collect(),
+    };
+    if needed.iter().any(|&right| !rights.contains(right)) {
+        return Some(format!("{} NO [NOPERM] Not allowed to set these flags\r\n", tag));
+    }
+
     // Perform the STORE operation on each message specified by the
     // sequence set.
     match sequence_set_opt {
         None => None,
         Some(sequence_set) => {
             let sequence_iter = if seq_uid {
-                sequence_set::uid_iterator(&sequence_set)
+                folder.uid_set(&sequence_set)
             } else {
-                sequence_set::iterator(&sequence_set, folder.message_count())
+                IntervalSet::new(&sequence_set, folder.message_count()).iter().collect()
             };
-            let res = folder.store(sequence_iter, &flag_name, silent, flags, seq_uid, tag);
+            let (mut res, modified) =
+                folder.store(sequence_iter, &flag_name, silent, flags, seq_uid, unchanged_since);
+            res.push_str(tag);
+            if modified.is_empty() {
+                res.push_str(" OK STORE complete\r\n");
+            } else {
+                res.push_str(" OK [MODIFIED ");
+                res.push_str(&sequence_set::format(&modified));
+                res.push_str("] Conditional STORE failed\r\n");
+            }
             Some(res)
         }
     }
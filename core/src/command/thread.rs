@@ -0,0 +1,428 @@
+//! THREAD (RFC 5256) by the ORDEREDSUBJECT and REFERENCES algorithms. The
+//! messages threaded are those matching search keys, as for SEARCH.
+
+use std::collections::HashMap;
+use std::mem;
+
+use super::search::{self, bad_charset, charset_supported, Token};
+use super::sort::sent_timestamp;
+use crate::folder::Folder;
+
+/// A message, or none where one the others refer to is missing, and the
+/// threads of replies to it.
+#[derive(Debug, PartialEq)]
+pub struct Thread {
+    pub number: Option<usize>,
+    pub children: Vec<Thread>,
+}
+
+/// What threading needs to know of a message.
+#[derive(Clone, Debug, Default)]
+pub struct Summary {
+    /// The sequence number or UID the response gives for the message
+    pub number: usize,
+    /// When the message was sent, as a timestamp
+    pub date: i64,
+    pub message_id: Option<String>,
+    pub references: Vec<String>,
+    /// The base subject, lower-cased
+    pub subject: String,
+    /// Whether the subject marks the message as a reply or forward
+    pub reply: bool,
+}
+
+fn summary(folder: &Folder, index: usize, uid: bool) -> Option<Summary> {
+    let message = folder.message(index)?;
+    let mime_message = message.get_mime_message();
+    let (subject, reply) = mime_message.get_base_subject();
+    Some(Summary {
+        number: if uid { message.get_uid() } else { index + 1 },
+        date: sent_timestamp(message),
+        message_id: mime_message.get_message_id(),
+        references: mime_message.get_references(),
+        subject: subject.to_lowercase(),
+        reply,
+    })
+}
+
+/// Thread messages, given in the order of the folder, by ORDEREDSUBJECT:
+/// the messages with the same base subject make up a thread, in which
+/// the first one sent is the parent of the others.
+pub fn ordered_subject(summaries: &[Summary]) -> Vec<Thread> {
+    let mut order: Vec<usize> = (0..summaries.len()).collect();
+    order.sort_by_key(|&i| (&summaries[i].subject, summaries[i].date, i));
+    let mut threads: Vec<((i64, usize), Thread)> = Vec::new();
+    let mut last_subject = None;
+    for i in order {
+        let summary = &summaries[i];
+        let thread = Thread {
+            number: Some(summary.number),
+            children: Vec::new(),
+        };
+        match threads.last_mut() {
+            Some((_, parent)) if last_subject == Some(&summary.subject) => parent.children.push(thread),
+            _ => threads.push(((summary.date, i), thread)),
+        }
+        last_subject = Some(&summary.subject);
+    }
+    threads.sort_by_key(|&(first, _)| first);
+    threads.into_iter().map(|(_, thread)| thread).collect()
+}
+
+// A message, or a message referred to but missing, with its place in a
+// thread
+#[derive(Default)]
+struct Container {
+    message: Option<usize>,
+    parent: Option<usize>,
+    children: Vec<usize>,
+}
+
+// The containers of the REFERENCES algorithm, by index
+struct Forest<'a> {
+    summaries: &'a [Summary],
+    containers: Vec<Container>,
+    ids: HashMap<String, usize>,
+}
+
+impl<'a> Forest<'a> {
+    fn add(&mut self) -> usize {
+        self.containers.push(Container::default());
+        self.containers.len() - 1
+    }
+
+    // The container of a message id, made if there is none yet
+    fn container(&mut self, id: &str) -> usize {
+        match self.ids.get(id) {
+            Some(&container) => container,
+            None => {
+                let container = self.add();
+                self.ids.insert(id.to_string(), container);
+                container
+            }
+        }
+    }
+
+    // Whether a container is another one or one of its ancestors
+    fn is_ancestor(&self, ancestor: usize, mut container: usize) -> bool {
+        loop {
+            if container == ancestor {
+                return true;
+            }
+            match self.containers[container].parent {
+                Some(parent) => container = parent,
+                None => return false,
+            }
+        }
+    }
+
+    fn link(&mut self, parent: usize, child: usize) {
+        self.containers[child].parent = Some(parent);
+        self.containers[parent].children.push(child);
+    }
+
+    fn unlink(&mut self, child: usize) {
+        if let Some(parent) = self.containers[child].parent.take() {
+            self.containers[parent].children.retain(|&other| other != child);
+        }
+    }
+
+    // The message of a container or, for a missing message, of its first
+    // child
+    fn first_message(&self, container: usize) -> Option<&'a Summary> {
+        let container = &self.containers[container];
+        let message = container.message.or_else(|| {
+            container
+                .children
+                .first()
+                .and_then(|&child| self.containers[child].message)
+        })?;
+        Some(&self.summaries[message])
+    }
+
+    // Drop the containers of missing messages, putting their children in
+    // their place. At the root, that is only done for a single child.
+    fn prune(&mut self, containers: Vec<usize>, root: bool) -> Vec<usize> {
+        let mut res = Vec::new();
+        for container in containers {
+            let children = mem::take(&mut self.containers[container].children);
+            let children = self.prune(children, false);
+            if self.containers[container].message.is_none() && (!root || children.len() <= 1) {
+                for &child in &children {
+                    self.containers[child].parent = self.containers[container].parent;
+                }
+                res.extend(children);
+            } else {
+                self.containers[container].children = children;
+                res.push(container);
+            }
+        }
+        res
+    }
+
+    // What siblings are ordered by: the sent date of the message, or of the
+    // first child for a missing one, then the order in the folder
+    fn sort_key(&self, container: usize) -> (i64, usize) {
+        let container = &self.containers[container];
+        match (container.message, container.children.first()) {
+            (Some(message), _) => (self.summaries[message].date, message),
+            (None, Some(&child)) => self.sort_key(child),
+            (None, None) => (i64::MAX, usize::MAX),
+        }
+    }
+
+    fn sort_children(&mut self, container: usize) {
+        let mut children = mem::take(&mut self.containers[container].children);
+        for &child in &children {
+            self.sort_children(child);
+        }
+        children.sort_by_key(|&child| self.sort_key(child));
+        self.containers[container].children = children;
+    }
+
+    // Put threads with the same base subject together
+    fn group_by_subject(&mut self, mut roots: Vec<usize>) -> Vec<usize> {
+        let mut table: HashMap<&'a str, usize> = HashMap::new();
+        for &root in &roots {
+            let summary = match self.first_message(root) {
+                Some(summary) if !summary.subject.is_empty() => summary,
+                _ => continue,
+            };
+            let missing = self.containers[root].message.is_none();
+            match table.get(&summary.subject[..]) {
+                // A missing message, or one which is not a reply, is
+                // preferred as the one others join
+                Some(&other) if self.containers[other].message.is_none() => {}
+                Some(&other) if !missing && (!self.first_message(other).is_some_and(|s| s.reply) || summary.reply) => {}
+                _ => {
+                    table.insert(&summary.subject, root);
+                }
+            }
+        }
+
+        let mut i = 0;
+        while i < roots.len() {
+            let root = roots[i];
+            let summary = match self.first_message(root) {
+                Some(summary) => summary,
+                None => {
+                    i += 1;
+                    continue;
+                }
+            };
+            let other = match table.get(&summary.subject[..]) {
+                Some(&other) if other != root => other,
+                _ => {
+                    i += 1;
+                    continue;
+                }
+            };
+            let missing = self.containers[root].message.is_none();
+            let other_missing = self.containers[other].message.is_none();
+            let other_reply = self.first_message(other).is_some_and(|s| s.reply);
+            if missing && other_missing {
+                for child in mem::take(&mut self.containers[root].children) {
+                    self.link(other, child);
+                }
+            } else if other_missing || (summary.reply && !other_reply) {
+                self.link(other, root);
+            } else {
+                // Both become replies to a message missing from the folder
+                let parent = self.add();
+                if let Some(position) = roots.iter().position(|&r| r == other) {
+                    roots[position] = parent;
+                }
+                self.link(parent, other);
+                self.link(parent, root);
+                table.insert(&summary.subject, parent);
+            }
+            roots.remove(i);
+        }
+        roots
+    }
+
+    fn thread(&self, container: usize) -> Thread {
+        let container = &self.containers[container];
+        Thread {
+            number: container.message.map(|message| self.summaries[message].number),
+            children: container.children.iter().map(|&child| self.thread(child)).collect(),
+        }
+    }
+}
+
+/// Thread messages, given in the order of the folder, by REFERENCES: the
+/// message ids in the References and In-Reply-To header fields make up the
+/// threads, and threads with the same base subject are put together.
+pub fn references(summaries: &[Summary]) -> Vec<Thread> {
+    let mut forest = Forest {
+        summaries,
+        containers: Vec::new(),
+        ids: HashMap::new(),
+    };
+    for (i, summary) in summaries.iter().enumerate() {
+        // A message without an id, or with one another message has, gets
+        // a container of its own
+        let current = match summary.message_id {
+            Some(ref id)
+                if forest
+                    .ids
+                    .get(id)
+                    .is_none_or(|&c| forest.containers[c].message.is_none()) =>
+            {
+                forest.container(id)
+            }
+            _ => forest.add(),
+        };
+        forest.containers[current].message = Some(i);
+
+        // Each reference is the parent of the next one, unless it has one
+        // already or that would make a loop
+        let references: Vec<usize> = summary.references.iter().map(|id| forest.container(id)).collect();
+        for pair in references.windows(2) {
+            if forest.containers[pair[1]].parent.is_none() && !forest.is_ancestor(pair[1], pair[0]) {
+                forest.link(pair[0], pair[1]);
+            }
+        }
+        // The last one is the parent of the message, whatever it was before
+        forest.unlink(current);
+        if let Some(&parent) = references.last() {
+            if !forest.is_ancestor(current, parent) {
+                forest.link(parent, current);
+            }
+        }
+    }
+
+    let roots: Vec<usize> = (0..forest.containers.len())
+        .filter(|&container| forest.containers[container].parent.is_none())
+        .collect();
+    let mut roots = forest.prune(roots, true);
+    for &root in &roots {
+        forest.sort_children(root);
+    }
+    roots.sort_by_key(|&root| forest.sort_key(root));
+    let roots = forest.group_by_subject(roots);
+    for &root in &roots {
+        forest.sort_children(root);
+    }
+    roots.iter().map(|&root| forest.thread(root)).collect()
+}
+
+fn format_members(thread: &Thread, res: &mut String) {
+    if let Some(number) = thread.number {
+        res.push_str(&number.to_string());
+    }
+    match thread.children.len() {
+        0 => {}
+        1 if thread.number.is_some() => {
+            res.push(' ');
+            format_members(&thread.children[0], res);
+        }
+        _ => {
+            if thread.number.is_some() {
+                res.push(' ');
+            }
+            for child in &thread.children {
+                res.push('(');
+                format_members(child, res);
+                res.push(')');
+            }
+        }
+    }
+}
+
+/// Write threads as the THREAD response does, as in "(1 2)(3 (4 5)(6))":
+/// a message followed by its only reply, or by each of its replies in
+/// parentheses.
+pub fn format(threads: &[Thread]) -> String {
+    let mut res = String::new();
+    for thread in threads {
+        res.push('(');
+        format_members(thread, &mut res);
+        res.push(')');
+    }
+    res
+}
+
+/// Perform THREAD, or UID THREAD, given the arguments after the command
+/// name. Returns the response to the client or None if a BAD response
+/// should be sent back.
+pub fn thread(folder: &Folder, args: &str, uid: bool, tag: &str) -> Option<String> {
+    let tokens = search::tokenize(args)?;
+    let algorithm: fn(&[Summary]) -> Vec<Thread> = match tokens.first()? {
+        Token::Atom(word) if word.eq_ignore_ascii_case("ORDEREDSUBJECT") => ordered_subject,
+        Token::Atom(word) if word.eq_ignore_ascii_case("REFERENCES") => references,
+        _ => return None,
+    };
+    match tokens.get(1)? {
+        Token::Atom(charset) | Token::Quoted(charset) if !charset_supported(charset) => return Some(bad_charset(tag)),
+        Token::Atom(_) | Token::Quoted(_) => {}
+        _ => return None,
+    }
+    let key = search::parse(&tokens[2..])?;
+    let summaries: Vec<Summary> = search::matching(folder, &key)
+        .into_iter()
+        .filter_map(|index| summary(folder, index, uid))
+        .collect();
+
+    let mut res = "* THREAD".to_string();
+    let threads = algorithm(&summaries);
+    if !threads.is_empty() {
+        res.push(' ');
+        res.push_str(&format(&threads));
+    }
+    res.push_str("\r\n");
+    res.push_str(tag);
+    res.push_str(" OK ");
+    if uid {
+        res.push_str("UID ");
+    }
+    res.push_str("THREAD completed\r\n");
+    Some(res)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn summary(number: usize, date: i64, id: &str, references: &[&str], subject: &str) -> Summary {
+        let (subject, reply) = mime::thread::base_subject(subject);
+        Summary {
+            number,
+            date,
+            message_id: Some(id.to_string()),
+            references: references.iter().map(|id| id.to_string()).collect(),
+            subject: subject.to_lowercase(),
+            reply,
+        }
+    }
+
+    fn summaries() -> Vec<Summary> {
+        vec![
+            summary(1, 1, "a", &[], "Lunch"),
+            summary(2, 2, "b", &["a"], "Re: Lunch"),
+            summary(3, 3, "c", &["a"], "Re: Lunch"),
+            // The first reference is missing, and c has a parent already
+            summary(4, 4, "d", &["x", "c"], "Re: Lunch"),
+            summary(5, 5, "e", &["m"], "Meeting"),
+            summary(6, 0, "f", &[], "Re: meeting"),
+            summary(7, 6, "g", &[], "Other"),
+            summary(8, 7, "h", &["z"], "other"),
+            // Another message with the id of the first
+            summary(9, 8, "a", &[], "Lone"),
+        ]
+    }
+
+    #[test]
+    fn test_references() {
+        assert_eq!(format(&references(&summaries())), "(1 (2)(3 4))(5 6)((7)(8))(9)");
+        // A reference loop is broken
+        let looped = vec![summary(1, 1, "a", &["b"], "x"), summary(2, 2, "b", &["a"], "y")];
+        assert_eq!(format(&references(&looped)), "(2 1)");
+        assert_eq!(format(&references(&[])), "");
+    }
+
+    #[test]
+    fn test_ordered_subject() {
+        assert_eq!(format(&ordered_subject(&summaries())), "(6 5)(1 (2)(3)(4))(7 8)(9)");
+    }
+}
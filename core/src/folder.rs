@@ -1,19 +1,40 @@
-use std::collections::{HashMap, HashSet};
-use std::fs;
-use std::io::Write;
+use std::collections::{BTreeSet, HashMap, HashSet};
+use std::fs::{self, File};
+use std::io::{self, Write};
 use std::path::Path;
 use std::path::PathBuf;
 
+use crate::command::sequence_set::{self, IntervalSet, SequenceItem};
 use crate::command::Attribute;
-use crate::message::Flag;
+use crate::fts::Index;
+use crate::keywords::Keywords;
 use crate::message::Message;
+use crate::message::Flag;
 
 use crate::command::store::StoreName;
 
+/// A message is given a UID above those of every message stored in or
+/// expunged from its folder before, so UIDs never get reused and the
+/// UIDVALIDITY of every folder stays the same.
+pub const UIDVALIDITY: usize = 1;
+
+/// The items STATUS may ask for
+pub static STATUS_ITEMS: &[&str] = &["MESSAGES", "RECENT", "UIDNEXT", "UIDVALIDITY", "UNSEEN", "HIGHESTMODSEQ"];
+
+// The file next to cur/ and new/ which the session with write access holds
+pub static LOCK_FILE: &str = ".lock";
+
+// The file next to cur/ and new/ holding the folder's mod-sequences
+pub static MODSEQ_FILE: &str = ".modseq";
+
+// The file next to cur/ and new/ which is locked while UIDs are handed out
+// or expunged
+pub static ALLOC_LOCK_FILE: &str = ".alloclock";
+
 /// Representation of a Folder
 #[derive(Clone, Debug)]
 pub struct Folder {
-    // How many messages are in folder/new/
+    // How many messages are recent in this session
     recent: usize,
     // How many messages are in the folder total
     exists: usize,
@@ -25,17 +46,135 @@ pub struct Folder {
     messages: Vec<Message>,
     // A mapping of message uids to indices in folder.messages
     uid_to_seqnum: HashMap<usize, usize>,
+    // The highest mod-sequence given to a change in the folder
+    highest_modseq: usize,
+    // The UIDs expunged from the folder with the mod-sequence of each expunge
+    expunged: Vec<(usize, usize)>,
+    // Whether the client has enabled CONDSTORE, so FETCH responses with FLAGS
+    // carry the MODSEQ too
+    condstore: bool,
+    // The keywords which the lowercase letters of filenames stand for
+    keywords: Keywords,
+    // Whether the folder's full-text index is used and kept up to date
+    fts: bool,
+    // The UIDs of the messages found by the last SEARCH with the SAVE option
+    saved: BTreeSet<usize>,
+}
+
+/// The mod-sequences of a folder as last written to disk: the highest one,
+/// each message's along with the filename it had then, and the ones at
+/// which UIDs were expunged.
+#[derive(Debug, PartialEq)]
+struct ModSeqFile {
+    highest: usize,
+    messages: HashMap<usize, (usize, String)>,
+    expunged: Vec<(usize, usize)>,
+}
+
+impl ModSeqFile {
+    fn load(path: &Path) -> ModSeqFile {
+        let contents = fs::read_to_string(path.join(MODSEQ_FILE)).unwrap_or_default();
+        ModSeqFile::parse(&contents)
+    }
+
+    /// Each line is either "highestmodseq <modseq>", "message <modseq>
+    /// <filename>" or "expunged <uid> <modseq>".
+    fn parse(contents: &str) -> ModSeqFile {
+        let mut file = ModSeqFile {
+            highest: 1,
+            messages: HashMap::new(),
+            expunged: Vec::new(),
+        };
+        for line in contents.lines() {
+            let fields: Vec<&str> = line.split(' ').collect();
+            match fields[..] {
+                ["highestmodseq", modseq] => {
+                    if let Ok(modseq) = modseq.parse() {
+                        file.highest = modseq;
+                    }
+                }
+                ["message", modseq, filename] => {
+                    let uid = filename.split(':').next().unwrap_or("").parse();
+                    if let (Ok(modseq), Ok(uid)) = (modseq.parse(), uid) {
+                        file.messages.insert(uid, (modseq, filename.to_string()));
+                    }
+                }
+                ["expunged", uid, modseq] => {
+                    if let (Ok(uid), Ok(modseq)) = (uid.parse(), modseq.parse()) {
+                        file.expunged.push((uid, modseq));
+                    }
+                }
+                _ => warn!("Invalid line in {}: {}", MODSEQ_FILE, line),
+            }
+        }
+        file
+    }
+
+    /// Give the messages their stored mod-sequences. Messages which are new
+    /// or were renamed by something else since, and so may have had their
+    /// flags changed, get new ones, and messages which have gone missing are
+    /// recorded as expunged. Returns whether anything changed.
+    fn apply(&mut self, messages: &mut [Message]) -> bool {
+        let mut changed = false;
+        for message in messages.iter_mut() {
+            let filename = message_filename(message);
+            match self.messages.remove(&message.get_uid()) {
+                Some((modseq, ref stored)) if *stored == filename => message.set_modseq(modseq),
+                _ => {
+                    self.highest += 1;
+                    message.set_modseq(self.highest);
+                    changed = true;
+                }
+            }
+        }
+        let mut missing: Vec<usize> = self.messages.drain().map(|(uid, _)| uid).collect();
+        missing.sort();
+        for uid in missing {
+            self.highest += 1;
+            self.expunged.push((uid, self.highest));
+            changed = true;
+        }
+        changed
+    }
+}
+
+/// Take the folder's allocation lock, which is held until the file returned
+/// is dropped.
+pub fn lock_allocations(path: &Path) -> io::Result<File> {
+    let file = fs::OpenOptions::new().write(true).create(true).truncate(false).open(path.join(ALLOC_LOCK_FILE))?;
+    file.lock()?;
+    Ok(file)
+}
+
+/// The highest UID ever given out in the folder: that of a message in cur/
+/// or new/, or one recorded as expunged. Only stable under the allocation
+/// lock.
+pub fn highest_uid(path: &Path) -> usize {
+    let mut highest = 0;
+    for dir in &["cur", "new"] {
+        if let Ok(entries) = fs::read_dir(path.join(dir)) {
+            for entry in entries.filter_map(|entry| entry.ok()) {
+                let uid = entry.file_name().to_str().and_then(|name| name.split(':').next()?.parse().ok());
+                highest = highest.max(uid.unwrap_or(0));
+            }
+        }
+    }
+    let modseqs = ModSeqFile::load(path);
+    let recorded = modseqs.messages.keys().chain(modseqs.expunged.iter().map(|(uid, _)| uid));
+    recorded.fold(highest, |highest, &uid| highest.max(uid))
+}
+
+// The name of the message's file on disk
+fn message_filename(message: &Message) -> String {
+    let name = message.get_path().file_name().and_then(|name| name.to_str());
+    name.unwrap_or("").to_string()
 }
 
 // Macro to handle each message in the folder
 macro_rules! handle_message(
-    ($msg_path_entry:ident, $uid_map:ident, $messages:ident, $i:ident, $unseen:ident) => ({
+    ($msg_path_entry:ident, $messages:ident, $i:ident, $keywords:ident) => ({
         if let Ok(msg_path) = $msg_path_entry {
-            if let Ok(message) = Message::new(msg_path.path().as_path()) {
-                if $unseen == !0usize && message.is_unseen() {
-                    $unseen = $i;
-                }
-                $uid_map.insert(message.get_uid(), $i);
+            if let Ok(message) = Message::new(msg_path.path().as_path(), &$keywords) {
                 $i += 1;
                 $messages.push(message);
             }
@@ -62,10 +201,13 @@ impl Folder {
     pub fn new(path: PathBuf, examine: bool) -> Option<Folder> {
         // the EXAMINE command is always read-only or we test SELECT for read-only status
         // We use a lock file to determine write access on a folder
-        let readonly = if examine || fs::File::open(&path.join(".lock")).is_ok() {
+        // Creating the lock is atomic, so only one session gets write access
+        // and, with it, the messages in folder/new/ as recent ones
+        let readonly = if examine {
             true
         } else {
-            if let Ok(mut file) = fs::File::create(&path.join(".lock")) {
+            let lock = fs::OpenOptions::new().write(true).create_new(true).open(path.join(LOCK_FILE));
+            if let Ok(mut file) = lock {
                 // Get the compiler to STFU with this match
                 let _ = file.write(b"selected");
                 false
@@ -77,38 +219,61 @@ impl Folder {
         if let Ok(cur) = fs::read_dir(&(path.join("cur"))) {
             if let Ok(new) = fs::read_dir(&(path.join("new"))) {
                 let mut messages = Vec::new();
-                let mut uid_to_seqnum: HashMap<usize, usize> = HashMap::new();
                 let mut i = 0usize;
-                let mut unseen = !0usize;
+                let mut keywords = Keywords::load(&path);
 
                 // populate messages
                 for msg_path in cur {
-                    handle_message!(msg_path, uid_to_seqnum, messages, i, unseen);
+                    handle_message!(msg_path, messages, i, keywords);
                 }
 
                 let old = i;
                 for msg_path in new {
-                    handle_message!(msg_path, uid_to_seqnum, messages, i, unseen);
+                    handle_message!(msg_path, messages, i, keywords);
                 }
 
-                // Move the messages from folder/new to folder/cur
-                messages = move_new(&messages, path.as_path(), unseen);
-                return Some(Folder {
+                // Move the messages from folder/new to folder/cur, which
+                // makes them recent in this session. A read-only session
+                // leaves them there for a session with the lock to claim,
+                // so that only that one session sees them as \Recent, but
+                // counts them as recent all the same.
+                let recent = if readonly {
+                    i - old
+                } else {
+                    messages = move_new(&messages, path.as_path(), old, &mut keywords);
+                    messages.iter().filter(|message| message.is_recent()).count()
+                };
+
+                let mut modseqs = ModSeqFile::load(&path);
+                let changed = modseqs.apply(&mut messages);
+                let mut folder = Folder {
                     path: path,
-                    recent: i - old,
-                    unseen: unseen,
+                    recent,
+                    unseen: !0usize,
                     exists: i,
                     messages: messages,
                     readonly: readonly,
-                    uid_to_seqnum: uid_to_seqnum,
-                });
+                    uid_to_seqnum: HashMap::new(),
+                    highest_modseq: modseqs.highest,
+                    expunged: modseqs.expunged,
+                    condstore: false,
+                    keywords,
+                    fts: false,
+                    saved: BTreeSet::new(),
+                };
+                folder.sort_messages();
+                if changed {
+                    folder.save_modseqs();
+                }
+                return Some(folder);
             }
         }
         None
     }
 
-    /// Generate the SELECT/EXAMINE response based on data in the folder
-    pub fn select_response(&self, tag: &str) -> String {
+    /// Generate the SELECT/EXAMINE response based on data in the folder.
+    /// `resync` holds any responses to QRESYNC, which go last.
+    pub fn select_response(&self, tag: &str, resync: &str) -> String {
         let unseen_res = if self.unseen <= self.exists {
             let unseen_str = self.unseen.to_string();
             let mut res = "* OK [UNSEEN ".to_string();
@@ -127,6 +292,14 @@ impl Folder {
             "[READ-WRITE]"
         };
 
+        // The system flags, then the keywords in the folder's index. Any
+        // new keyword may be stored too.
+        let mut flags = "\\Answered \\Deleted \\Draft \\Flagged \\Seen $Forwarded".to_string();
+        for keyword in self.keywords.names() {
+            flags.push(' ');
+            flags.push_str(keyword);
+        }
+
         // * <n> EXISTS
         // * <n> RECENT
         // * OK UNSEEN
@@ -134,34 +307,211 @@ impl Folder {
         // * OK PERMANENTFLAG - Should match values in enum Flag in message.rs
         // * OK UIDNEXT
         // * OK UIDVALIDITY
-        format!("* {} EXISTS\r\n* {} RECENT\r\n{}* FLAGS (\\Answered \\Deleted \\Draft \\Flagged \\Seen)\r\n* OK [PERMANENTFLAGS (\\Answered \\Deleted \\Draft \\Flagged \\Seen)] Permanent flags\r\n{} OK {} SELECT command was successful\r\n", 
-                 self.exists, self.recent, unseen_res, tag, read_status)
+        // * OK HIGHESTMODSEQ
+        format!("* {} EXISTS\r\n* {} RECENT\r\n{}* FLAGS ({})\r\n* OK [PERMANENTFLAGS ({} \\*)] Permanent flags\r\n* OK [UIDNEXT {}] Predicted next UID\r\n* OK [UIDVALIDITY {}] UIDs valid\r\n* OK [HIGHESTMODSEQ {}] Highest\r\n{}{} OK {} SELECT command was successful\r\n",
+                 self.exists, self.recent, unseen_res, flags, flags, self.uid_next(), UIDVALIDITY, self.highest_modseq, resync, tag, read_status)
+    }
+
+    /// The responses to SELECT with QRESYNC (RFC 7162) for a client which
+    /// last saw the folder at `modseq`: the UIDs expunged since, out of
+    /// `known_uids` if given, then the flags of the messages changed since.
+    pub fn qresync_response(&self, modseq: usize, known_uids: Option<&[SequenceItem]>) -> String {
+        let known_uids = known_uids.map(|set| IntervalSet::unbounded(set, self.uid_next() - 1));
+        let vanished: Vec<usize> = self
+            .vanished_since(modseq)
+            .into_iter()
+            .filter(|&uid| known_uids.as_ref().is_none_or(|set| set.contains(uid)))
+            .collect();
+        let mut res = String::new();
+        if !vanished.is_empty() {
+            res.push_str("* VANISHED (EARLIER) ");
+            res.push_str(&sequence_set::format(&vanished));
+            res.push_str("\r\n");
+        }
+        for (i, message) in self.messages.iter().enumerate() {
+            if message.get_modseq() > modseq {
+                let fetch = self.fetch(i, &[Attribute::UID, Attribute::Flags, Attribute::ModSeq]);
+                res.push_str(&String::from_utf8_lossy(&fetch));
+            }
+        }
+        res
     }
 
-    /// Delete on disk all the messages marked for deletion
+    /// The STATUS response items (RFC 3501) for the given item names, or
+    /// None if one of them is unknown. RECENT counts the messages recent in
+    /// this session, or in a read-only one those still in new/.
+    pub fn status(&self, items: &[&str]) -> Option<String> {
+        let mut res = Vec::new();
+        for item in items {
+            let value = match &item.to_uppercase()[..] {
+                "MESSAGES" => self.exists,
+                "RECENT" => self.recent,
+                "UIDNEXT" => self.uid_next(),
+                "UIDVALIDITY" => UIDVALIDITY,
+                "UNSEEN" => self.messages.iter().filter(|m| !m.get_flags().contains(&Flag::Seen)).count(),
+                "HIGHESTMODSEQ" => self.highest_modseq,
+                _ => return None,
+            };
+            res.push(format!("{} {}", item.to_uppercase(), value));
+        }
+        Some(res.join(" "))
+    }
+
+    pub fn get_path(&self) -> &Path {
+        self.path.as_path()
+    }
+
+    /// The UID the next message delivered is expected to get
+    pub fn uid_next(&self) -> usize {
+        let uids = self.messages.iter().map(|m| m.get_uid());
+        uids.chain(self.expunged.iter().map(|&(uid, _)| uid)).max().unwrap_or(0) + 1
+    }
+
+    pub fn highest_modseq(&self) -> usize {
+        self.highest_modseq
+    }
+
+    /// The UIDs expunged after the given mod-sequence, in order.
+    pub fn vanished_since(&self, modseq: usize) -> Vec<usize> {
+        let mut uids: Vec<usize> = self
+            .expunged
+            .iter()
+            .filter(|&&(_, expunged)| expunged > modseq)
+            .map(|&(uid, _)| uid)
+            .collect();
+        uids.sort();
+        uids.dedup();
+        uids
+    }
+
+    /// Include the MODSEQ in every FETCH response with FLAGS from now on.
+    pub fn enable_condstore(&mut self) {
+        self.condstore = true;
+    }
+
+    pub fn is_condstore(&self) -> bool {
+        self.condstore
+    }
+
+    /// Keep the UIDs of messages as the saved search result, "$".
+    pub fn save_result(&mut self, uids: &[usize]) {
+        self.saved = uids.iter().cloned().collect();
+    }
+
+    /// The saved messages still in the folder: their UIDs, or else their
+    /// sequence numbers, in order.
+    pub fn saved(&self, uid: bool) -> Vec<usize> {
+        let mut ids: Vec<usize> = self
+            .saved
+            .iter()
+            .filter_map(|saved| self.uid_to_seqnum.get(saved).map(|&index| if uid { *saved } else { index + 1 }))
+            .collect();
+        ids.sort();
+        ids
+    }
+
+    /// Use the folder's full-text index for searches and keep it up to date
+    /// when expunging.
+    pub fn enable_fts(&mut self) {
+        self.fts = true;
+    }
+
+    /// The folder's full-text index, if it is used, after indexing the
+    /// messages it does not cover yet. Messages gone from the folder are
+    /// dropped from it, except those newer than any in the folder, which
+    /// may have been delivered since it was selected.
+    pub fn fts_index(&self) -> Option<Index> {
+        if !self.fts {
+            return None;
+        }
+        let mut index = Index::load(&self.path);
+        let mut changed = false;
+        for message in &self.messages {
+            if !index.covers(message.get_uid()) {
+                index.add(message.get_uid(), message.get_mime_message());
+                changed = true;
+            }
+        }
+        let newest = self.messages.iter().map(|message| message.get_uid()).max().unwrap_or(0);
+        let gone: Vec<usize> = index
+            .uids()
+            .filter(|&uid| uid <= newest && !self.uid_to_seqnum.contains_key(&uid))
+            .collect();
+        if !gone.is_empty() {
+            index.remove(&gone);
+            changed = true;
+        }
+        if changed {
+            if let Err(e) = index.save() {
+                warn!("Error saving the full-text index of {}: {}", self.path.display(), e);
+            }
+        }
+        Some(index)
+    }
+
+    /// Write the mod-sequences out next to the messages. A read-only folder
+    /// leaves that to whoever holds its lock.
+    fn save_modseqs(&self) {
+        if self.readonly {
+            return;
+        }
+        let mut contents = format!("highestmodseq {}\n", self.highest_modseq);
+        for message in &self.messages {
+            contents.push_str(&format!("message {} {}\n", message.get_modseq(), message_filename(message)));
+        }
+        for &(uid, modseq) in &self.expunged {
+            contents.push_str(&format!("expunged {} {}\n", uid, modseq));
+        }
+        let tmp_path = self.path.join(format!("{}.tmp", MODSEQ_FILE));
+        let res = fs::write(&tmp_path, contents).and_then(|_| fs::rename(&tmp_path, self.path.join(MODSEQ_FILE)));
+        if let Err(e) = res {
+            warn!("Error saving mod-sequences in {}: {}", self.path.display(), e);
+        }
+    }
+
+    /// Delete on disk all the messages marked for deletion, or with UID
+    /// EXPUNGE only those of them with the given UIDs
     /// Returns the list of sequence numbers which have been deleted on disk
     /// Per RFC 3501, the later sequence numbers are calculated based on the
     /// sequence numbers at the time of the deletion not at the start of the function
-    pub fn expunge(&self) -> Vec<usize> {
+    pub fn expunge(&mut self, uids: Option<&[usize]>) -> Vec<usize> {
         let mut result = Vec::new();
         // We can't perform the deletion if the folder has been opened as
         // read-only
         if !self.readonly {
+            // Hold off deliveries until the expunged UIDs are recorded, so
+            // that none of them is handed out again
+            let _lock = lock_allocations(&self.path);
+
             // Vectors are 0-indexed
             let mut index = 0usize;
 
             // self.messages will get smaller as we go through it
             while index < self.messages.len() {
-                if self.messages[index].remove_if_deleted() {
+                let uid = self.messages[index].get_uid();
+                let selected = uids.is_none_or(|uids| uids.contains(&uid));
+                if selected && self.messages[index].remove_if_deleted() {
                     // Sequence numbers are 1-indexed
                     result.push(index + 1);
+                    self.highest_modseq += 1;
+                    self.expunged.push((uid, self.highest_modseq));
+                    self.messages.remove(index);
                 } else {
                     index += 1;
                 }
             }
-            // Get the compiler to STFU with empty match block
-            match fs::remove_file(&self.path.join(".lock")) {
-                _ => {}
+            self.exists = self.messages.len();
+            self.recent = self.messages.iter().filter(|message| message.is_recent()).count();
+            self.sort_messages();
+            self.save_modseqs();
+            if self.fts && !result.is_empty() {
+                let mut index = Index::load(&self.path);
+                let start = self.expunged.len() - result.len();
+                let uids: Vec<usize> = self.expunged[start..].iter().map(|&(uid, _)| uid).collect();
+                index.remove(&uids);
+                if let Err(e) = index.save() {
+                    warn!("Error saving the full-text index of {}: {}", self.path.display(), e);
+                }
             }
         }
         result
@@ -171,25 +521,78 @@ impl Folder {
         self.messages.len()
     }
 
+    /// Whether the folder was opened read-only, either by EXAMINE or because
+    /// another session holds its lock.
+    pub fn is_readonly(&self) -> bool {
+        self.readonly
+    }
+
+    /// The message at the given index.
+    pub fn message(&self, index: usize) -> Option<&Message> {
+        self.messages.get(index)
+    }
+
+    /// Add or remove a single flag on the message at the given index. Like
+    /// STORE, the change reaches the disk on the next check.
+    pub fn set_flag(&mut self, index: usize, flag: Flag, set: bool) {
+        if let Some(message) = self.messages.get_mut(index) {
+            let flag_name = if set { StoreName::Add } else { StoreName::Sub };
+            let mut flags = HashSet::new();
+            flags.insert(flag);
+            let modseq = self.highest_modseq + 1;
+            message.store(&flag_name, flags, modseq);
+            if message.get_modseq() == modseq {
+                self.highest_modseq = modseq;
+            }
+        }
+    }
+
+    /// Give up the lock on the folder without expunging anything. The
+    /// session is done with the folder then; expunging never does this.
+    pub fn unlock(&self) {
+        if !self.readonly {
+            let _ = fs::remove_file(self.path.join(LOCK_FILE));
+        }
+    }
+
     /// Perform a fetch of the specified attributes on self.messsages[index]
-    /// Return the FETCH response string to be sent back to the client
-    pub fn fetch(&self, index: usize, attributes: &[Attribute]) -> String {
-        let mut res = "* ".to_string();
-        res.push_str(&(index + 1).to_string()[..]);
-        res.push_str(" FETCH (");
-        res.push_str(&self.messages[index].fetch(attributes)[..]);
-        res.push_str(")\r\n");
+    /// Return the FETCH response to be sent back to the client
+    pub fn fetch(&self, index: usize, attributes: &[Attribute]) -> Vec<u8> {
+        let mut res = format!("* {} FETCH (", index + 1).into_bytes();
+        res.extend_from_slice(&self.messages[index].fetch(attributes));
+        if self.condstore && attributes.contains(&Attribute::Flags) && !attributes.contains(&Attribute::ModSeq) {
+            res.extend_from_slice(format!(" MODSEQ ({})", self.messages[index].get_modseq()).as_bytes());
+        }
+        res.extend_from_slice(b")\r\n");
         res
     }
 
+    /// The UIDs of the messages in the folder which fall within a UID
+    /// sequence set, in order. Only the UIDs in use are looked at, however
+    /// large the ranges of the set.
+    pub fn uid_set(&self, set: &[SequenceItem]) -> Vec<usize> {
+        let set = IntervalSet::new(set, self.uid_next() - 1);
+        let mut uids: Vec<usize> = self
+            .messages
+            .iter()
+            .map(|message| message.get_uid())
+            .filter(|&uid| set.contains(uid))
+            .collect();
+        uids.sort();
+        uids
+    }
+
     /// Turn a UID into a sequence number
     pub fn get_index_from_uid(&self, uid: &usize) -> Option<&usize> {
         self.uid_to_seqnum.get(uid)
     }
 
     /// Perform a STORE on the specified set of sequence numbers
-    /// This modifies the flags of the specified messages
-    /// Returns the String response to be sent back to the client.
+    /// This modifies the flags of the specified messages, each change taking
+    /// the next mod-sequence. With UNCHANGEDSINCE, messages changed since the
+    /// given mod-sequence are left alone.
+    /// Returns the untagged responses to be sent back to the client and the
+    /// ids of the messages which failed the UNCHANGEDSINCE test.
     pub fn store(
         &mut self,
         sequence_set: Vec<usize>,
@@ -197,9 +600,12 @@ impl Folder {
         silent: bool,
         flags: HashSet<Flag>,
         seq_uid: bool,
-        tag: &str,
-    ) -> String {
+        unchanged_since: Option<usize>,
+    ) -> (String, Vec<usize>) {
+        let condstore = self.condstore || unchanged_since.is_some();
+        let flags: HashSet<Flag> = flags.into_iter().map(|flag| self.keywords.canonical(flag)).collect();
         let mut responses = String::new();
+        let mut modified = Vec::new();
         for num in &sequence_set {
             let (uid, i) = if seq_uid {
                 match self.get_index_from_uid(num) {
@@ -217,29 +623,40 @@ impl Folder {
                 continue;
             }
 
-            // Create the FETCH response for this STORE operation.
+            let modseq = self.highest_modseq + 1;
             if let Some(message) = self.messages.get_mut(i - 1) {
-                responses.push_str("* ");
-                responses.push_str(&i.to_string()[..]);
-                responses.push_str(" FETCH (FLAGS ");
-                responses.push_str(&message.store(flag_name, flags.clone())[..]);
+                if unchanged_since.is_some_and(|since| message.get_modseq() > since) {
+                    modified.push(*num);
+                    continue;
+                }
+                let flags_res = message.store(flag_name, flags.clone(), modseq);
+                let changed = message.get_modseq() == modseq;
+                if changed {
+                    self.highest_modseq = modseq;
+                }
+
+                // The client wanted the STORE to be SILENT, but it still
+                // needs to learn the new mod-sequence under CONDSTORE
+                if silent && !(condstore && changed) {
+                    continue;
+                }
 
+                // Create the FETCH response for this STORE operation.
+                let mut items = Vec::new();
+                if !silent {
+                    items.push(format!("FLAGS {}", flags_res));
+                }
                 // UID STORE needs to respond with the UID for each FETCH response
                 if seq_uid {
-                    let uid_res = format!(" UID {}", uid);
-                    responses.push_str(&uid_res[..]);
+                    items.push(format!("UID {}", uid));
+                }
+                if condstore {
+                    items.push(format!("MODSEQ ({})", message.get_modseq()));
                 }
-                responses.push_str(" )\r\n");
+                responses.push_str(&format!("* {} FETCH ({})\r\n", i, items.join(" ")));
             }
         }
-
-        // Return an empty string if the client wanted the STORE to be SILENT
-        if silent {
-            responses = String::new();
-        }
-        responses.push_str(tag);
-        responses.push_str(" OK STORE complete\r\n");
-        responses
+        (responses, modified)
     }
 
     /// Reconcile the internal state of the folder with the disk.
@@ -255,7 +672,7 @@ impl Folder {
         let mut new_messages = Vec::new();
         for msg in &self.messages {
             // Grab the new filename composed of this message's UID and its current flags.
-            let filename = msg.get_new_filename();
+            let filename = msg.get_new_filename(&mut self.keywords);
             let curpath = self.path.join("cur").join(filename);
 
             // If the new filename is the same as the current filename, add the
@@ -270,25 +687,202 @@ impl Folder {
         // Set the current list of messages to the new list of messages
         // The compiler *should* make this discard the old list...
         self.messages = new_messages;
+        self.sort_messages();
+        self.save_modseqs();
+    }
+
+    /// Put the messages in UID order, which sequence numbers follow (RFC
+    /// 3501 section 2.3.1.2), and index them again.
+    fn sort_messages(&mut self) {
+        self.messages.sort_by_key(|message| message.get_uid());
+        self.uid_to_seqnum = self
+            .messages
+            .iter()
+            .enumerate()
+            .map(|(i, message)| (message.get_uid(), i))
+            .collect();
+        self.unseen = match self.messages.iter().position(|message| message.is_unseen()) {
+            Some(index) => index + 1,
+            None => !0usize,
+        };
     }
 }
 
 /// This moves a list of messages from folder/new/ to folder/cur/ and returns a
-/// new list of messages
-fn move_new(messages: &[Message], path: &Path, start_index: usize) -> Vec<Message> {
+/// new list of messages. The messages from start_index on are the ones in
+/// folder/new/; they keep any flags in their filenames. The messages this
+/// session moves are recent in it; those another session moved first are
+/// picked up from folder/cur/ without being recent.
+fn move_new(messages: &[Message], path: &Path, start_index: usize, keywords: &mut Keywords) -> Vec<Message> {
     let mut new_messages = Vec::new();
 
     // Go over the messages by index
     for (i, msg) in messages.iter().enumerate() {
         // messages before start_index are already in folder/cur/
-        if i + 1 < start_index {
+        if i < start_index {
             new_messages.push(msg.clone());
             continue;
         }
-        let curpath = path.join("cur").join(msg.get_uid().to_string());
-        rename_message!(msg, curpath, new_messages);
+        let curpath = path.join("cur").join(msg.get_new_filename(keywords));
+        if fs::rename(msg.get_path(), &curpath).is_ok() {
+            let mut msg = msg.rename(curpath);
+            msg.set_recent(true);
+            new_messages.push(msg);
+        } else if curpath.is_file() {
+            new_messages.push(msg.rename(curpath));
+        } else {
+            new_messages.push(msg.clone());
+        }
     }
 
     // Return the new list of messages
     new_messages
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testing::TempDir;
+
+    // A folder holding two messages, the first of them seen
+    fn test_folder(name: &str) -> TempDir {
+        let path = TempDir::new(&format!("folder-{}", name));
+        fs::create_dir_all(path.join("cur")).unwrap();
+        fs::create_dir_all(path.join("new")).unwrap();
+        fs::write(path.join("cur").join("1000:2,S"), "Subject: a\n\nfirst\n").unwrap();
+        fs::write(path.join("new").join("1001"), "Subject: b\n\nsecond\n").unwrap();
+        path
+    }
+
+    fn flags(flag: Flag) -> HashSet<Flag> {
+        let mut flags = HashSet::new();
+        flags.insert(flag);
+        flags
+    }
+
+    #[test]
+    fn test_keywords() {
+        let path = test_folder("keywords");
+        fs::write(path.join("dovecot-keywords"), "0 $Junk\n").unwrap();
+        fs::rename(path.join("cur").join("1000:2,S"), path.join("cur").join("1000:2,SXaz")).unwrap();
+        let mut folder = Folder::new(path.to_path_buf(), false).unwrap();
+        assert!(folder.select_response("a", "").contains("* FLAGS (\\Answered \\Deleted \\Draft \\Flagged \\Seen $Forwarded $Junk)\r\n"));
+        assert!(folder.select_response("a", "").contains("$Junk \\*)]"));
+        let message = folder.message(0).unwrap();
+        assert!(message.get_flags().contains(&Flag::Keyword("$Junk".to_string())));
+
+        // Unknown letters survive, and new keywords get a letter of their own
+        let mut new_flags = flags(Flag::Deleted);
+        new_flags.insert(Flag::Keyword("NonJunk".to_string()));
+        new_flags.insert(Flag::Keyword("$junk".to_string()));
+        folder.store(vec![1], &StoreName::Add, true, new_flags, false, None);
+        folder.check();
+        assert!(path.join("cur").join("1000:2,STXabz").is_file());
+        assert_eq!(fs::read_to_string(path.join("dovecot-keywords")).unwrap(), "0 $Junk\n1 NonJunk\n");
+        let folder = Folder::new(path.to_path_buf(), true).unwrap();
+        assert_eq!(folder.message(0).unwrap().get_flags().len(), 4);
+    }
+
+    #[test]
+    fn test_recent() {
+        let path = test_folder("recent");
+
+        // EXAMINE leaves the new message for a session to claim, counting
+        // it as recent without flagging it \Recent
+        let folder = Folder::new(path.to_path_buf(), true).unwrap();
+        assert!(folder.select_response("a", "").contains("* 1 RECENT\r\n"));
+        assert_eq!(folder.fetch(1, &[Attribute::Flags]), b"* 2 FETCH (FLAGS ())\r\n");
+        assert!(path.join("new").join("1001").is_file());
+
+        // The session with the lock claims it, another one can't
+        let folder = Folder::new(path.to_path_buf(), false).unwrap();
+        assert_eq!(folder.status(&["messages", "RECENT"]), Some("MESSAGES 2 RECENT 1".to_string()));
+        assert_eq!(folder.fetch(1, &[Attribute::Flags]), b"* 2 FETCH (FLAGS (\\Recent))\r\n");
+        assert_eq!(folder.fetch(0, &[Attribute::Flags]), b"* 1 FETCH (FLAGS (\\Seen))\r\n");
+        assert!(Folder::new(path.to_path_buf(), false).unwrap().is_readonly());
+
+        // A session which finds the folder locked counts only the message
+        // still in new/ as recent, and doesn't flag it \Recent
+        fs::write(path.join("new").join("1002"), "Subject: c\n\nthird\n").unwrap();
+        let locked_out = Folder::new(path.to_path_buf(), false).unwrap();
+        assert!(locked_out.is_readonly());
+        assert_eq!(locked_out.status(&["MESSAGES", "RECENT"]), Some("MESSAGES 3 RECENT 1".to_string()));
+        assert!(!locked_out.message(2).unwrap().is_recent());
+        fs::remove_file(path.join("new").join("1002")).unwrap();
+        assert_eq!(folder.status(&["BOGUS"]), None);
+        folder.unlock();
+
+        // and it is not recent in later sessions
+        let folder = Folder::new(path.to_path_buf(), false).unwrap();
+        assert_eq!(folder.status(&["RECENT", "UNSEEN"]), Some("RECENT 0 UNSEEN 1".to_string()));
+        folder.unlock();
+
+        // A session which finds a message already claimed doesn't get it
+        let claimed = folder.message(1).unwrap().get_path().to_path_buf();
+        fs::copy(&claimed, path.join("new").join("1001")).unwrap();
+        let message = Message::new(&path.join("new").join("1001"), &Keywords::default()).unwrap();
+        fs::remove_file(path.join("new").join("1001")).unwrap();
+        let moved = move_new(&[message], &path, 0, &mut Keywords::default());
+        assert!(!moved[0].is_recent());
+        assert_eq!(moved[0].get_path(), claimed.as_path());
+    }
+
+    #[test]
+    fn test_uid_order() {
+        // The message in cur/, read first, was delivered after the new one
+        let path = test_folder("order");
+        fs::rename(path.join("cur").join("1000:2,S"), path.join("cur").join("1002:2,S")).unwrap();
+        let folder = Folder::new(path.to_path_buf(), true).unwrap();
+        let uids: Vec<usize> = (0..2).map(|i| folder.message(i).unwrap().get_uid()).collect();
+        assert_eq!(uids, vec![1001, 1002]);
+        assert_eq!(folder.get_index_from_uid(&1002), Some(&1));
+        assert!(folder.select_response("a", "").contains("[UNSEEN 1]"));
+    }
+
+    #[test]
+    fn test_modseq_file() {
+        let mut file = ModSeqFile::parse("highestmodseq 7\nmessage 5 1000:2,S\nmessage 6 1001\nexpunged 999 4\n");
+        assert_eq!(file.highest, 7);
+        assert_eq!(file.messages[&1001], (6, "1001".to_string()));
+        assert_eq!(file.expunged, vec![(999, 4)]);
+        assert_eq!(ModSeqFile::parse("").highest, 1);
+        assert!(file.apply(&mut []));
+        assert_eq!(file.expunged, vec![(999, 4), (1000, 8), (1001, 9)]);
+    }
+
+    #[test]
+    fn test_modseqs() {
+        let path = test_folder("modseq");
+        let mut folder = Folder::new(path.to_path_buf(), false).unwrap();
+        assert_eq!(folder.highest_modseq(), 3);
+        assert_eq!(folder.message(0).unwrap().get_modseq(), 2);
+
+        // Only a change to the flags takes a mod-sequence
+        let (res, modified) = folder.store(vec![1, 2], &StoreName::Add, false, flags(Flag::Seen), false, None);
+        assert_eq!(res, "* 1 FETCH (FLAGS (\\Seen))\r\n* 2 FETCH (FLAGS (\\Seen \\Recent))\r\n");
+        assert!(modified.is_empty());
+        assert_eq!(folder.highest_modseq(), 4);
+        assert_eq!(folder.message(1).unwrap().get_modseq(), 4);
+
+        // Messages changed since UNCHANGEDSINCE are left alone
+        let (res, modified) =
+            folder.store(vec![1001, 1000], &StoreName::Add, true, flags(Flag::Deleted), true, Some(3));
+        assert_eq!(res, "* 1 FETCH (UID 1000 MODSEQ (5))\r\n");
+        assert_eq!(modified, vec![1001]);
+        folder.check();
+        assert_eq!(folder.expunge(Some(&[1001])), Vec::<usize>::new());
+        assert_eq!(folder.uid_set(&sequence_set::parse("1000:*").unwrap()), vec![1000, 1001]);
+        assert_eq!(folder.expunge(Some(&[1000])), vec![1]);
+        assert_eq!(folder.vanished_since(5), vec![1000]);
+        assert_eq!(folder.vanished_since(6), Vec::<usize>::new());
+
+        // The mod-sequences survive reopening the folder
+        let folder = Folder::new(path.to_path_buf(), true).unwrap();
+        assert_eq!(folder.highest_modseq(), 6);
+        assert_eq!(folder.message(0).unwrap().get_modseq(), 4);
+        assert_eq!(
+            folder.qresync_response(3, None),
+            "* VANISHED (EARLIER) 1000\r\n* 1 FETCH (UID 1001 FLAGS (\\Seen) MODSEQ (4))\r\n"
+        );
+    }
+}
@@ -0,0 +1,221 @@
+//! The full-text index of a folder, which lets SEARCH BODY and TEXT skip
+//! reading every message. It maps each word of the decoded text parts of the
+//! messages to the UIDs of those holding it, and is kept in the folder next
+//! to cur/ and new/ so it moves along when the folder is renamed.
+//!
+//! The index is only ever a shortcut: messages it does not cover, such as
+//! those an update was lost for when two sessions saved it at once, are
+//! indexed on the next search or else scanned.
+
+use std::collections::{BTreeMap, BTreeSet, HashSet};
+use std::fs;
+use std::io;
+use std::path::{Path, PathBuf};
+
+use mime::Message as MIME_Message;
+
+pub static INDEX_FILE: &str = ".fts";
+
+#[derive(Debug, Default, PartialEq)]
+pub struct Index {
+    // Where the index is saved; None to keep it in memory only
+    path: Option<PathBuf>,
+    // The UIDs of the messages indexed
+    indexed: BTreeSet<usize>,
+    // The UIDs of the messages holding each word
+    terms: BTreeMap<String, BTreeSet<usize>>,
+}
+
+/// The words of some text: its runs of alphanumeric characters, lowercased.
+pub fn words(text: &str) -> Vec<String> {
+    text.to_lowercase()
+        .split(|c: char| !c.is_alphanumeric())
+        .filter(|word| !word.is_empty())
+        .map(|word| word.to_string())
+        .collect()
+}
+
+/// What the index knows about the messages holding some text.
+pub struct Lookup<'a> {
+    index: &'a Index,
+    // The UIDs of the indexed messages which may hold the text
+    uids: HashSet<usize>,
+    // Whether all of those certainly hold it
+    exact: bool,
+}
+
+impl<'a> Lookup<'a> {
+    /// Whether the message with a UID holds the text, or None if it has to
+    /// be read to tell.
+    pub fn contains(&self, uid: usize) -> Option<bool> {
+        if !self.index.indexed.contains(&uid) {
+            None
+        } else if !self.uids.contains(&uid) {
+            Some(false)
+        } else if self.exact {
+            Some(true)
+        } else {
+            None
+        }
+    }
+}
+
+impl Index {
+    /// Read the index of a folder. Having none is fine.
+    pub fn load(folder: &Path) -> Index {
+        let contents = fs::read_to_string(folder.join(INDEX_FILE)).unwrap_or_default();
+        let mut index = Index::parse(&contents);
+        index.path = Some(folder.join(INDEX_FILE));
+        index
+    }
+
+    // The first line lists the messages indexed and each other one a word
+    // followed by the messages holding it
+    fn parse(contents: &str) -> Index {
+        let uids = |rest: &mut std::str::SplitWhitespace| -> BTreeSet<usize> {
+            rest.filter_map(|uid| uid.parse().ok()).collect()
+        };
+        let mut index = Index::default();
+        let mut lines = contents.lines();
+        if let Some(line) = lines.next() {
+            let mut fields = line.split_whitespace();
+            if fields.next() != Some("indexed") {
+                return index;
+            }
+            index.indexed = uids(&mut fields);
+        }
+        for line in lines {
+            let mut fields = line.split_whitespace();
+            if let Some(term) = fields.next() {
+                index.terms.insert(term.to_string(), uids(&mut fields));
+            }
+        }
+        index
+    }
+
+    pub fn save(&self) -> io::Result<()> {
+        let path = match self.path {
+            Some(ref path) => path,
+            None => return Ok(()),
+        };
+        let mut contents = "indexed".to_string();
+        for uid in &self.indexed {
+            contents.push_str(&format!(" {}", uid));
+        }
+        contents.push('\n');
+        for (term, uids) in &self.terms {
+            contents.push_str(term);
+            for uid in uids {
+                contents.push_str(&format!(" {}", uid));
+            }
+            contents.push('\n');
+        }
+        let tmp_path = path.with_extension("tmp");
+        fs::write(&tmp_path, contents).and_then(|_| fs::rename(&tmp_path, path))
+    }
+
+    pub fn covers(&self, uid: usize) -> bool {
+        self.indexed.contains(&uid)
+    }
+
+    /// The UIDs of the messages indexed.
+    pub fn uids(&self) -> impl Iterator<Item = usize> + '_ {
+        self.indexed.iter().cloned()
+    }
+
+    /// Index the decoded text parts of a message.
+    pub fn add(&mut self, uid: usize, message: &MIME_Message) {
+        for text in message.get_body_text(&["text".to_string()]) {
+            for word in words(&text) {
+                self.terms.entry(word).or_default().insert(uid);
+            }
+        }
+        self.indexed.insert(uid);
+    }
+
+    pub fn remove(&mut self, uids: &[usize]) {
+        for uid in uids {
+            self.indexed.remove(uid);
+        }
+        for messages in self.terms.values_mut() {
+            for uid in uids {
+                messages.remove(uid);
+            }
+        }
+        self.terms.retain(|_, messages| !messages.is_empty());
+    }
+
+    /// Look up the messages whose text holds some text, ignoring case.
+    /// None if the text has no words for the index to go by.
+    pub fn lookup(&self, text: &str) -> Option<Lookup<'_>> {
+        let words = words(text);
+        // Every word of the text is part of a word of a message holding it,
+        // and a single word is all there is to it
+        let exact = words.len() == 1 && words[0] == text.to_lowercase();
+        let mut uids: Option<HashSet<usize>> = None;
+        for word in &words {
+            let holding: HashSet<usize> = self
+                .terms
+                .iter()
+                .filter(|(term, _)| term.contains(&word[..]))
+                .flat_map(|(_, messages)| messages.iter().cloned())
+                .collect();
+            uids = Some(match uids {
+                Some(uids) => uids.intersection(&holding).cloned().collect(),
+                None => holding,
+            });
+        }
+        Some(Lookup {
+            index: self,
+            uids: uids?,
+            exact,
+        })
+    }
+}
+
+/// Add a message just stored in a folder to the folder's index.
+pub fn add_message(folder: &Path, uid: usize, data: &[u8]) {
+    let message = match MIME_Message::from_bytes(data) {
+        Ok(message) => message,
+        Err(_) => return,
+    };
+    let mut index = Index::load(folder);
+    index.add(uid, &message);
+    if let Err(e) = index.save() {
+        warn!("Error saving the full-text index of {}: {}", folder.display(), e);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_index() {
+        assert_eq!(words("Café, au-lait!  x2"), vec!["café", "au", "lait", "x2"]);
+
+        let mut index = Index::default();
+        let first = MIME_Message::from_bytes(b"Subject: a\n\nLunch at the CAFE\n").unwrap();
+        let second = MIME_Message::from_bytes(
+            b"Content-Type: text/plain; charset=utf-8\nContent-Transfer-Encoding: base64\n\nQnJ1bmNoIGF0IFrDtmUncw==\n",
+        )
+        .unwrap();
+        index.add(1, &first);
+        index.add(2, &second);
+
+        let lookup = index.lookup("unch").unwrap();
+        assert_eq!((lookup.contains(1), lookup.contains(2)), (Some(true), Some(true)));
+        let lookup = index.lookup("zöe").unwrap();
+        assert_eq!((lookup.contains(1), lookup.contains(2)), (Some(false), Some(true)));
+        // Text spanning words narrows the messages down without settling it
+        let lookup = index.lookup("lunch at").unwrap();
+        assert_eq!((lookup.contains(1), lookup.contains(2)), (None, Some(false)));
+        assert_eq!(lookup.contains(3), None);
+        assert!(index.lookup(" ,").is_none());
+
+        index.remove(&[1]);
+        assert!(!index.covers(1));
+        let contents = "indexed 2\nat 2\nbrunch 2\ns 2\nzöe 2\n";
+        assert_eq!(Index::parse(contents), index);
+    }
+}
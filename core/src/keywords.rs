@@ -0,0 +1,206 @@
+//! The keyword index of a maildir folder, which maps keyword flags onto the
+//! lowercase letters of maildir filenames. Like Dovecot, it is kept in the
+//! folder's dovecot-keywords file as one "<index> <keyword>" line per
+//! keyword, index 0 standing for 'a'. Letters are given out under an
+//! exclusive lock on the dovecot-keywords.lock file, as other sessions and
+//! deliveries add keywords to the same index.
+
+use std::fs::{self, File, OpenOptions};
+use std::io;
+use std::path::{Path, PathBuf};
+
+use crate::message::Flag;
+
+static KEYWORDS_FILE: &str = "dovecot-keywords";
+static LOCK_FILE: &str = "dovecot-keywords.lock";
+// There is one lowercase letter per keyword
+const MAX_KEYWORDS: usize = 26;
+
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct Keywords {
+    // Where the index is saved when a keyword is added; None to keep it in
+    // memory only
+    path: Option<PathBuf>,
+    // The keyword each letter stands for, by index
+    names: Vec<Option<String>>,
+}
+
+impl Keywords {
+    /// Read the keyword index of a folder. A missing index is empty.
+    pub fn load(folder: &Path) -> Keywords {
+        let contents = fs::read_to_string(folder.join(KEYWORDS_FILE)).unwrap_or_default();
+        let mut keywords = Keywords::parse(&contents);
+        keywords.path = Some(folder.join(KEYWORDS_FILE));
+        keywords
+    }
+
+    fn parse(contents: &str) -> Keywords {
+        let mut names = vec![None; MAX_KEYWORDS];
+        for line in contents.lines() {
+            let mut fields = line.splitn(2, ' ');
+            let index = fields.next().and_then(|index| index.parse::<usize>().ok());
+            match (index, fields.next()) {
+                (Some(index), Some(name)) if index < MAX_KEYWORDS && !name.is_empty() => {
+                    names[index] = Some(name.to_string());
+                }
+                _ => warn!("Invalid line in {}: {}", KEYWORDS_FILE, line),
+            }
+        }
+        Keywords { path: None, names }
+    }
+
+    /// Lock the index on disk and pick up the keywords added to it since it
+    /// was read. Keywords only this copy has keep their letters where those
+    /// are still free. The index is locked until the file returned is
+    /// dropped.
+    fn reload(&mut self) -> io::Result<Option<File>> {
+        let path = match self.path {
+            Some(ref path) => path.clone(),
+            None => return Ok(None),
+        };
+        let lock = OpenOptions::new()
+            .write(true)
+            .create(true)
+            .truncate(false)
+            .open(path.with_file_name(LOCK_FILE))?;
+        lock.lock()?;
+        let contents = match fs::read_to_string(&path) {
+            Ok(contents) => contents,
+            Err(ref e) if e.kind() == io::ErrorKind::NotFound => String::new(),
+            Err(e) => return Err(e),
+        };
+        let mut names = Keywords::parse(&contents).names;
+        for (index, name) in self.names.iter().enumerate() {
+            if let Some(name) = name {
+                let known = names.iter().flatten().any(|n| n.eq_ignore_ascii_case(name));
+                if names[index].is_none() && !known {
+                    names[index] = Some(name.clone());
+                }
+            }
+        }
+        self.names = names;
+        Ok(Some(lock))
+    }
+
+    fn save(&self) {
+        let path = match self.path {
+            Some(ref path) => path,
+            None => return,
+        };
+        let mut contents = String::new();
+        for (index, name) in self.names.iter().enumerate() {
+            if let Some(name) = name {
+                contents.push_str(&format!("{} {}\n", index, name));
+            }
+        }
+        let tmp_path = path.with_extension("tmp");
+        let res = fs::write(&tmp_path, contents).and_then(|_| fs::rename(&tmp_path, path));
+        if let Err(e) = res {
+            warn!("Error saving keywords in {}: {}", path.display(), e);
+        }
+    }
+
+    /// The keyword a lowercase letter stands for.
+    pub fn name(&self, letter: char) -> Option<&str> {
+        if !letter.is_ascii_lowercase() {
+            return None;
+        }
+        let index = (letter as u8 - b'a') as usize;
+        self.names.get(index).and_then(|name| name.as_deref())
+    }
+
+    fn index(&self, name: &str) -> Option<usize> {
+        self.names
+            .iter()
+            .position(|n| n.as_ref().is_some_and(|n| n.eq_ignore_ascii_case(name)))
+    }
+
+    /// The letter of a keyword, which gets the first free one if it has none
+    /// yet. Returns None once every letter is taken, or if the index can't
+    /// be locked.
+    pub fn letter(&mut self, name: &str) -> Option<char> {
+        if let Some(index) = self.index(name) {
+            return Some((b'a' + index as u8) as char);
+        }
+        let _lock = match self.reload() {
+            Ok(lock) => lock,
+            Err(e) => {
+                warn!("Error locking {}: {}", LOCK_FILE, e);
+                return None;
+            }
+        };
+        let index = match self.index(name) {
+            Some(index) => index,
+            None => {
+                let index = self.names.iter().position(|n| n.is_none())?;
+                self.names[index] = Some(name.to_string());
+                self.save();
+                index
+            }
+        };
+        Some((b'a' + index as u8) as char)
+    }
+
+    /// Keywords are case-insensitive: give a keyword the spelling it has in
+    /// the index, adding it there if it is new and there is room.
+    pub fn canonical(&mut self, flag: Flag) -> Flag {
+        match flag {
+            Flag::Keyword(name) => match self.letter(&name).and_then(|_| self.index(&name)) {
+                Some(index) => Flag::Keyword(self.names[index].clone().unwrap_or(name)),
+                None => Flag::Keyword(name),
+            },
+            flag => flag,
+        }
+    }
+
+    /// Every keyword in the index.
+    pub fn names(&self) -> Vec<&str> {
+        self.names.iter().filter_map(|name| name.as_deref()).collect()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testing::TempDir;
+
+    #[test]
+    fn test_keywords() {
+        let mut keywords = Keywords::parse("0 $Junk\n2 NonJunk\nbogus\n");
+        assert_eq!(keywords.name('a'), Some("$Junk"));
+        assert_eq!(keywords.name('b'), None);
+        assert_eq!(keywords.name('c'), Some("NonJunk"));
+        assert_eq!(keywords.name('D'), None);
+        assert_eq!(keywords.letter("$junk"), Some('a'));
+        assert_eq!(keywords.letter("$Phishing"), Some('b'));
+        assert_eq!(keywords.names(), vec!["$Junk", "$Phishing", "NonJunk"]);
+        assert_eq!(keywords.canonical(Flag::Keyword("nonjunk".to_string())), Flag::Keyword("NonJunk".to_string()));
+        assert_eq!(keywords.canonical(Flag::Keyword("Later".to_string())), Flag::Keyword("Later".to_string()));
+        assert_eq!(keywords.letter("later"), Some('d'));
+        for i in 4..MAX_KEYWORDS {
+            assert!(keywords.letter(&format!("k{}", i)).is_some());
+        }
+        assert_eq!(keywords.letter("one-too-many"), None);
+    }
+
+    #[test]
+    fn test_keywords_file() {
+        let folder = TempDir::new("keywords");
+        fs::create_dir_all(&folder).unwrap();
+        let mut keywords = Keywords::load(&folder);
+        assert_eq!(keywords.letter("$Junk"), Some('a'));
+        assert_eq!(fs::read_to_string(folder.join(KEYWORDS_FILE)).unwrap(), "0 $Junk\n");
+        assert_eq!(Keywords::load(&folder), keywords);
+
+        // A keyword added by someone else since keeps its letter
+        let mut other = Keywords::load(&folder);
+        assert_eq!(other.letter("NonJunk"), Some('b'));
+        assert_eq!(keywords.letter("$Phishing"), Some('c'));
+        assert_eq!(keywords.name('b'), Some("NonJunk"));
+        assert_eq!(
+            fs::read_to_string(folder.join(KEYWORDS_FILE)).unwrap(),
+            "0 $Junk\n1 NonJunk\n2 $Phishing\n"
+        );
+        assert_eq!(other.letter("$phishing"), Some('c'));
+    }
+}
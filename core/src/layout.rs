@@ -0,0 +1,314 @@
+//! How the folders of a maildir are laid out on disk. By default a folder's
+//! subfolders are directories inside it, so "Archive/2023" is kept in
+//! Archive/2023/. Maildir++, as Courier and Dovecot use it, instead keeps
+//! every folder beside INBOX in a directory named after the whole mailbox
+//! name with "." between the levels, such as .Archive.2023/, holding a
+//! maildirfolder file.
+
+use std::collections::BTreeSet;
+use std::fs::{self, File};
+use std::io;
+use std::path::{Path, PathBuf};
+use std::str::FromStr;
+
+use crate::server::lmtp::create_folder;
+use crate::server::JMAP_DIR;
+use crate::sieve::SCRIPT_DIR;
+use crate::util::valid_folder_name;
+use crate::{acl, folder, fts, special_use};
+
+static MAILDIRFOLDER_FILE: &str = "maildirfolder";
+
+/// The dot-prefixed files and directories kept in the root of a maildir,
+/// beside the Maildir++ folders: Sieve scripts, JMAP state and the metadata
+/// of INBOX. The "<file>.tmp" files written while saving them hold a "."
+/// past the first, which no Maildir++ folder name does.
+static METADATA_FILES: &[&str] = &[
+    folder::LOCK_FILE,
+    folder::ALLOC_LOCK_FILE,
+    folder::MODSEQ_FILE,
+    fts::INDEX_FILE,
+    acl::ACL_FILE,
+    special_use::SPECIAL_USE_FILE,
+    JMAP_DIR,
+    SCRIPT_DIR,
+];
+
+#[derive(Clone, Copy, Debug, Default, PartialEq, Serialize, Deserialize)]
+pub enum Layout {
+    /// Subfolders are directories inside their parent folder
+    #[default]
+    #[serde(rename = "nested")]
+    Nested,
+    /// Every folder is a dot-prefixed directory in the maildir itself
+    #[serde(rename = "maildir++")]
+    MaildirPlusPlus,
+}
+
+impl FromStr for Layout {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Layout, String> {
+        match s {
+            "nested" => Ok(Layout::Nested),
+            "maildir++" => Ok(Layout::MaildirPlusPlus),
+            _ => Err(format!("Unknown maildir layout {}", s)),
+        }
+    }
+}
+
+impl Layout {
+    /// Whether a level of a mailbox name can be kept on disk. In Maildir++
+    /// a "." would start another level, and some names are taken by
+    /// metadata.
+    pub fn valid_level(self, level: &str) -> bool {
+        valid_folder_name(level)
+            && (self == Layout::Nested
+                || (!level.contains('.') && !METADATA_FILES.iter().any(|file| file[1..] == *level)))
+    }
+
+    /// The directory of a folder, given the levels of its name. No levels
+    /// is INBOX, which is the maildir itself.
+    pub fn folder_path(self, maildir: &Path, levels: &[&str]) -> PathBuf {
+        match self {
+            _ if levels.is_empty() => maildir.to_path_buf(),
+            Layout::Nested => levels
+                .iter()
+                .fold(maildir.to_path_buf(), |path, level| path.join(level)),
+            Layout::MaildirPlusPlus => maildir.join(format!(".{}", levels.join("."))),
+        }
+    }
+
+    /// Every folder in a maildir other than INBOX, by name with "/" between
+    /// the levels.
+    pub fn folders(self, maildir: &Path) -> BTreeSet<String> {
+        let mut folders = BTreeSet::new();
+        match self {
+            Layout::Nested => find_nested(maildir, "", &mut folders),
+            Layout::MaildirPlusPlus => {
+                let entries = match fs::read_dir(maildir) {
+                    Ok(entries) => entries,
+                    Err(_) => return folders,
+                };
+                for entry in entries.flatten() {
+                    let name = entry.file_name();
+                    let levels = match name.to_str().and_then(|name| name.strip_prefix('.')) {
+                        Some(levels) => levels.split('.').collect::<Vec<&str>>(),
+                        None => continue,
+                    };
+                    if levels.iter().all(|level| self.valid_level(level)) && is_folder(&entry.path()) {
+                        folders.insert(levels.join("/"));
+                    }
+                }
+            }
+        }
+        folders
+    }
+
+    /// Create a folder other than INBOX.
+    pub fn create(self, path: &Path) -> io::Result<()> {
+        create_folder(path)?;
+        if self == Layout::MaildirPlusPlus {
+            File::create(path.join(MAILDIRFOLDER_FILE))?;
+        }
+        Ok(())
+    }
+
+    /// Rename a folder, along with its subfolders, given the levels of the
+    /// names, which must be valid in this layout.
+    pub fn rename(self, maildir: &Path, from: &[&str], to: &[&str]) -> io::Result<()> {
+        let from_path = self.folder_path(maildir, from);
+        let to_path = self.folder_path(maildir, to);
+        if !is_folder(&from_path) {
+            return Err(io::Error::new(
+                io::ErrorKind::NotFound,
+                format!("No folder {}", from.join("/")),
+            ));
+        }
+        match self {
+            Layout::Nested => {
+                if let Some(parent) = to_path.parent() {
+                    fs::create_dir_all(parent)?;
+                }
+                fs::rename(from_path, to_path)
+            }
+            Layout::MaildirPlusPlus => {
+                for folder in self.folders(maildir) {
+                    let levels: Vec<&str> = folder.split('/').collect();
+                    if levels.len() > from.len() && levels.starts_with(from) {
+                        let renamed: Vec<&str> = to.iter().chain(&levels[from.len()..]).cloned().collect();
+                        fs::rename(self.folder_path(maildir, &levels), self.folder_path(maildir, &renamed))?;
+                    }
+                }
+                fs::rename(from_path, to_path)
+            }
+        }
+    }
+}
+
+/// Whether a directory is a maildir folder.
+fn is_folder(path: &Path) -> bool {
+    path.join("cur").is_dir() && path.join("new").is_dir()
+}
+
+fn find_nested(dir: &Path, parent: &str, folders: &mut BTreeSet<String>) {
+    let entries = match fs::read_dir(dir) {
+        Ok(entries) => entries,
+        Err(_) => return,
+    };
+    for entry in entries.flatten() {
+        let name = match entry.file_name().to_str() {
+            Some(name) if valid_folder_name(name) => name.to_string(),
+            _ => continue,
+        };
+        if !entry.file_type().is_ok_and(|file_type| file_type.is_dir()) {
+            continue;
+        }
+        let name = if parent.is_empty() {
+            name
+        } else {
+            format!("{}/{}", parent, name)
+        };
+        if is_folder(&entry.path()) {
+            folders.insert(name.clone());
+        }
+        find_nested(&entry.path(), &name, folders);
+    }
+}
+
+/// Lay out the folders of a maildir anew, offline. Nothing is moved if a
+/// folder's name cannot be kept in the new layout or its new directory is
+/// taken. Returns how many folders were moved.
+pub fn convert(maildir: &Path, from: Layout, to: Layout) -> io::Result<usize> {
+    if from == to {
+        return Ok(0);
+    }
+    let folders = from.folders(maildir);
+    let mut moves = Vec::new();
+    for folder in &folders {
+        let levels: Vec<&str> = folder.split('/').collect();
+        if !levels.iter().all(|level| to.valid_level(level)) {
+            return Err(io::Error::other(format!(
+                "{} cannot be kept in the {:?} layout",
+                folder, to
+            )));
+        }
+        let target = to.folder_path(maildir, &levels);
+        // A nested folder's directory may already be there as the parent of
+        // a Maildir++ folder which is moved first
+        let taken = match to {
+            Layout::Nested => is_folder(&target),
+            Layout::MaildirPlusPlus => target.exists(),
+        };
+        if taken {
+            return Err(io::Error::other(format!("{} already exists", target.display())));
+        }
+        moves.push((from.folder_path(maildir, &levels), target, levels.len()));
+    }
+    // Nested subfolders move out of their parents before the parents move;
+    // Maildir++ parents move into place before their subfolders move into
+    // them
+    match to {
+        Layout::MaildirPlusPlus => moves.sort_by_key(|&(_, _, depth)| std::cmp::Reverse(depth)),
+        Layout::Nested => moves.sort_by_key(|&(_, _, depth)| depth),
+    }
+    for (from_path, to_path, _) in &moves {
+        if let Some(parent) = to_path.parent() {
+            fs::create_dir_all(parent)?;
+        }
+        fs::rename(from_path, to_path)?;
+        match to {
+            Layout::MaildirPlusPlus => {
+                File::create(to_path.join(MAILDIRFOLDER_FILE))?;
+            }
+            Layout::Nested => {
+                let _ = fs::remove_file(to_path.join(MAILDIRFOLDER_FILE));
+            }
+        }
+    }
+    // Directories which only held subfolders are left empty
+    if to == Layout::MaildirPlusPlus {
+        for folder in &folders {
+            let levels: Vec<&str> = folder.split('/').collect();
+            for depth in (1..levels.len()).rev() {
+                let _ = fs::remove_dir(from.folder_path(maildir, &levels[..depth]));
+            }
+        }
+    }
+    Ok(moves.len())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testing::TempDir;
+
+    #[test]
+    fn test_layout() {
+        let maildir = Path::new("/var/mail/will");
+        assert_eq!(Layout::Nested.folder_path(maildir, &[]), maildir);
+        assert_eq!(
+            Layout::Nested.folder_path(maildir, &["Archive", "2023"]),
+            maildir.join("Archive").join("2023")
+        );
+        assert_eq!(
+            Layout::MaildirPlusPlus.folder_path(maildir, &["Archive", "2023"]),
+            maildir.join(".Archive.2023")
+        );
+        assert!(Layout::Nested.valid_level("v1.2"));
+        assert!(!Layout::MaildirPlusPlus.valid_level("v1.2"));
+        assert!(Layout::Nested.valid_level("sieve"));
+        assert!(!Layout::MaildirPlusPlus.valid_level("sieve"));
+        assert!(!Layout::MaildirPlusPlus.valid_level("jmap"));
+        assert!(!Layout::MaildirPlusPlus.valid_level("lock"));
+        assert!(!Layout::MaildirPlusPlus.valid_level("alloclock"));
+        assert!(!Layout::MaildirPlusPlus.valid_level("modseq.tmp"));
+        assert!(Layout::MaildirPlusPlus.valid_level("locks"));
+        assert_eq!("maildir++".parse(), Ok(Layout::MaildirPlusPlus));
+    }
+
+    #[test]
+    fn test_convert() {
+        let maildir = TempDir::new("layout");
+        for folder in &["", "Sent", "Archive/2023", "Archive/2023/Q1", "Lists"] {
+            let levels: Vec<&str> = folder.split('/').filter(|level| !level.is_empty()).collect();
+            create_folder(&Layout::Nested.folder_path(&maildir, &levels)).unwrap();
+        }
+        fs::write(maildir.join("Sent").join("cur").join("1"), "Subject: a\n\n").unwrap();
+        let names = vec!["Archive/2023", "Archive/2023/Q1", "Lists", "Sent"];
+        assert_eq!(Layout::Nested.folders(&maildir).iter().collect::<Vec<_>>(), names);
+
+        assert_eq!(convert(&maildir, Layout::Nested, Layout::MaildirPlusPlus).unwrap(), 4);
+        assert_eq!(
+            Layout::MaildirPlusPlus.folders(&maildir).iter().collect::<Vec<_>>(),
+            names
+        );
+        assert!(maildir.join(".Archive.2023.Q1").join(MAILDIRFOLDER_FILE).is_file());
+        assert!(maildir.join(".Sent").join("cur").join("1").is_file());
+        assert!(!maildir.join("Archive").exists());
+        assert!(Layout::Nested.folders(&maildir).is_empty());
+
+        Layout::MaildirPlusPlus
+            .rename(&maildir, &["Archive"], &["Old"])
+            .unwrap_err();
+        Layout::MaildirPlusPlus.create(&maildir.join(".Archive")).unwrap();
+        Layout::MaildirPlusPlus
+            .rename(&maildir, &["Archive"], &["Old"])
+            .unwrap();
+        let renamed = vec!["Lists", "Old", "Old/2023", "Old/2023/Q1", "Sent"];
+        assert_eq!(
+            Layout::MaildirPlusPlus.folders(&maildir).iter().collect::<Vec<_>>(),
+            renamed
+        );
+
+        assert_eq!(convert(&maildir, Layout::MaildirPlusPlus, Layout::Nested).unwrap(), 5);
+        assert_eq!(Layout::Nested.folders(&maildir).iter().collect::<Vec<_>>(), renamed);
+        assert!(!maildir.join("Old").join("2023").join(MAILDIRFOLDER_FILE).exists());
+        assert!(maildir.join("Sent").join("cur").join("1").is_file());
+
+        // Names with a "." cannot become Maildir++ folders
+        create_folder(&maildir.join("v1.2")).unwrap();
+        assert!(convert(&maildir, Layout::Nested, Layout::MaildirPlusPlus).is_err());
+        assert!(maildir.join("Sent").join("cur").is_dir());
+    }
+}
@@ -11,21 +11,41 @@ extern crate nom;
 #[macro_use]
 extern crate serde_derive;
 
-use crate::server::{imap_serve, lmtp_serve, Server};
+use crate::server::{
+    imap_serve, jmap_serve, lmtp_serve, managesieve_serve, pop3_serve, relay_spool, submission_serve, Server,
+};
 
+use crate::layout::Layout;
+
+use std::env;
 use std::net::{TcpListener, TcpStream};
+use std::path::Path;
+use std::process::exit;
 use std::sync::Arc;
 use std::thread::spawn;
 
+mod acl;
 mod command;
 mod error;
 mod folder;
+mod fts;
+mod keywords;
+mod layout;
+mod namespace;
 mod parser;
+mod quota;
 #[macro_use]
 mod util;
 #[macro_use]
 mod server;
+mod special_use;
+mod subscriptions;
+#[cfg(test)]
+mod testing;
+mod utf7;
 mod message;
+mod sieve;
+mod spool;
 
 fn listen_generic(
     v: TcpListener,
@@ -54,8 +74,58 @@ fn listen_imap(v: TcpListener, serv: Arc<Server>) {
     listen_generic(v, serv, "IMAP", imap_serve);
 }
 
+fn listen_pop3(v: TcpListener, serv: Arc<Server>) {
+    listen_generic(v, serv, "POP3", pop3_serve);
+}
+
+fn listen_managesieve(v: TcpListener, serv: Arc<Server>) {
+    listen_generic(v, serv, "ManageSieve", managesieve_serve);
+}
+
+fn listen_submission(v: TcpListener, serv: Arc<Server>) {
+    listen_generic(v, serv, "submission", submission_serve);
+}
+
+fn listen_jmap(v: TcpListener, serv: Arc<Server>) {
+    listen_generic(v, serv, "JMAP", jmap_serve);
+}
+
+/// Lay out the folders of a maildir anew, as in
+/// `segimap convert <maildir> <from layout> <to layout>`. This is for when
+/// the server is stopped.
+fn convert_maildir(args: &[String]) {
+    let (maildir, from, to) = match args {
+        [maildir, from, to] => (maildir, from.parse::<Layout>(), to.parse::<Layout>()),
+        _ => {
+            eprintln!("Usage: segimap convert <maildir> <nested|maildir++> <nested|maildir++>");
+            exit(2);
+        }
+    };
+    let (from, to) = match (from, to) {
+        (Ok(from), Ok(to)) => (from, to),
+        (Err(e), _) | (_, Err(e)) => {
+            eprintln!("{}", e);
+            exit(2);
+        }
+    };
+    match layout::convert(Path::new(maildir), from, to) {
+        Ok(moved) => println!("Moved {} folders in '{}'", moved, maildir),
+        Err(e) => {
+            eprintln!("Error converting '{}': {}", maildir, e);
+            exit(1);
+        }
+    }
+}
+
 fn main() {
     env_logger::init();
+
+    let args: Vec<String> = env::args().collect();
+    if args.get(1).map(|arg| &arg[..]) == Some("convert") {
+        convert_maildir(&args[2..]);
+        return;
+    }
+
     info!("Application started");
 
     // Create the server. We wrap it so that it is atomically reference
@@ -100,6 +170,89 @@ fn main() {
         None
     };
 
+    let pop3_h = if let Some(pop3_listener) = serv.pop3_listener() {
+        match pop3_listener {
+            Err(e) => {
+                error!("Error listening on POP3 port: {}", e);
+                None
+            }
+            Ok(v) => {
+                let pop3_serv = serv.clone();
+                Some(spawn(move || listen_pop3(v, pop3_serv)))
+            }
+        }
+    } else {
+        None
+    };
+
+    let pop3_ssl_h = if let Some(pop3_listener) = serv.pop3_ssl_listener() {
+        match pop3_listener {
+            Err(e) => {
+                error!("Error listening on POP3 SSL port: {}", e);
+                None
+            }
+            Ok(v) => {
+                let pop3_serv = serv.clone();
+                Some(spawn(move || listen_pop3(v, pop3_serv)))
+            }
+        }
+    } else {
+        None
+    };
+
+    let managesieve_h = if let Some(managesieve_listener) = serv.managesieve_listener() {
+        match managesieve_listener {
+            Err(e) => {
+                error!("Error listening on ManageSieve port: {}", e);
+                None
+            }
+            Ok(v) => {
+                let managesieve_serv = serv.clone();
+                Some(spawn(move || listen_managesieve(v, managesieve_serv)))
+            }
+        }
+    } else {
+        None
+    };
+
+    let submission_h = if let Some(submission_listener) = serv.submission_listener() {
+        match submission_listener {
+            Err(e) => {
+                error!("Error listening on submission port: {}", e);
+                None
+            }
+            Ok(v) => {
+                let submission_serv = serv.clone();
+                Some(spawn(move || listen_submission(v, submission_serv)))
+            }
+        }
+    } else {
+        None
+    };
+
+    // Outgoing mail waits in the spool until it is relayed
+    let relay_h = if serv.can_relay() {
+        let relay_serv = serv.clone();
+        Some(spawn(move || relay_spool(relay_serv)))
+    } else {
+        None
+    };
+
+    let jmap_h = if let Some(jmap_listener) = serv.jmap_listener() {
+        match jmap_listener {
+            Err(e) => {
+                error!("Error listening on JMAP port: {}", e);
+                None
+            }
+            Ok(v) => {
+                let jmap_serv = serv.clone();
+                Some(spawn(move || listen_jmap(v, jmap_serv)))
+            }
+        }
+    } else {
+        None
+    };
+
     // The main thread handles listening for IMAP connections
     let imap_h = if let Some(imap_listener) = serv.imap_listener() {
         match imap_listener {
@@ -136,6 +289,30 @@ fn main() {
         return_on_err!(lsh.join());
     }
 
+    if let Some(ph) = pop3_h {
+        return_on_err!(ph.join());
+    }
+
+    if let Some(psh) = pop3_ssl_h {
+        return_on_err!(psh.join());
+    }
+
+    if let Some(mh) = managesieve_h {
+        return_on_err!(mh.join());
+    }
+
+    if let Some(sh) = submission_h {
+        return_on_err!(sh.join());
+    }
+
+    if let Some(rh) = relay_h {
+        return_on_err!(rh.join());
+    }
+
+    if let Some(jh) = jmap_h {
+        return_on_err!(jh.join());
+    }
+
     if let Some(ih) = imap_h {
         return_on_err!(ih.join());
     }
@@ -1,4 +1,4 @@
-use chrono::{Datelike, NaiveDateTime, Timelike};
+use chrono::{DateTime, Datelike, FixedOffset, NaiveDateTime, Timelike};
 use std::collections::HashSet;
 use std::fs;
 use std::path::Path;
@@ -8,11 +8,13 @@ use std::str;
 use crate::command::store::StoreName;
 use crate::command::Attribute;
 use crate::command::Attribute::{
-    Body, BodyPeek, BodySection, BodyStructure, Envelope, Flags, InternalDate, RFC822, UID,
+    Body, BodyPeek, BodySection, BodyStructure, Envelope, Flags, InternalDate, ModSeq, RFC822,
+    UID,
 };
 use crate::command::RFC822Attribute::{AllRFC822, HeaderRFC822, SizeRFC822, TextRFC822};
 
 use crate::error::{Error, ImapResult};
+use crate::keywords::Keywords;
 
 use mime::Message as MIME_Message;
 
@@ -24,20 +26,92 @@ pub enum Flag {
     Flagged,
     Seen,
     Deleted,
+    // The $Forwarded keyword, which maildir calls Passed
+    Forwarded,
+    // Any other keyword, kept in the folder's keyword index
+    Keyword(String),
 }
 
 /// Takes a flag argument and returns the corresponding enum.
+/// System flags are case-insensitive. Anything else which is a valid atom
+/// is a keyword.
 pub fn parse_flag(flag: &str) -> Option<Flag> {
-    match flag {
-        "\\Deleted" => Some(Flag::Deleted),
-        "\\Seen" => Some(Flag::Seen),
-        "\\Draft" => Some(Flag::Draft),
-        "\\Answered" => Some(Flag::Answered),
-        "\\Flagged" => Some(Flag::Flagged),
+    match &flag.to_ascii_lowercase()[..] {
+        "\\deleted" => Some(Flag::Deleted),
+        "\\seen" => Some(Flag::Seen),
+        "\\draft" => Some(Flag::Draft),
+        "\\answered" => Some(Flag::Answered),
+        "\\flagged" => Some(Flag::Flagged),
+        "$forwarded" => Some(Flag::Forwarded),
+        _ if is_keyword(flag) => Some(Flag::Keyword(flag.to_string())),
         _ => None,
     }
 }
 
+// Keywords are atoms; the ones starting with a backslash are reserved for
+// system flags.
+fn is_keyword(flag: &str) -> bool {
+    !flag.is_empty()
+        && !flag.starts_with('\\')
+        && flag.bytes().all(|c| c > b' ' && c < 0x7f && !b"(){%*\"\\]".contains(&c))
+}
+
+/// The name of a flag as IMAP writes it.
+pub fn flag_name(flag: &Flag) -> &str {
+    match *flag {
+        Flag::Answered => "\\Answered",
+        Flag::Draft => "\\Draft",
+        Flag::Flagged => "\\Flagged",
+        Flag::Seen => "\\Seen",
+        Flag::Deleted => "\\Deleted",
+        Flag::Forwarded => "$Forwarded",
+        Flag::Keyword(ref name) => name,
+    }
+}
+
+/// The maildir letter of a flag. Keywords get a lowercase letter from the
+/// folder's keyword index, or none if it is full.
+fn flag_letter(flag: &Flag, keywords: &mut Keywords) -> Option<char> {
+    match *flag {
+        Flag::Draft => Some('D'),
+        Flag::Flagged => Some('F'),
+        Flag::Forwarded => Some('P'),
+        Flag::Answered => Some('R'),
+        Flag::Seen => Some('S'),
+        Flag::Deleted => Some('T'),
+        Flag::Keyword(ref name) => {
+            let letter = keywords.letter(name);
+            if letter.is_none() {
+                warn!("No maildir letter is left for keyword {}", name);
+            }
+            letter
+        }
+    }
+}
+
+/// The info part of a maildir filename for a set of flags: a colon, then
+/// 2, then the single character per flag representation of the flags,
+/// along with any `other_letters`. It is empty if there are no letters.
+pub fn maildir_info(flags: &HashSet<Flag>, other_letters: &str, keywords: &mut Keywords) -> String {
+    let mut letters: Vec<char> = flags.iter().filter_map(|flag| flag_letter(flag, keywords)).collect();
+    letters.extend(other_letters.chars());
+
+    // There is no info part if no flags are set.
+    if letters.is_empty() {
+        return String::new();
+    }
+
+    // As per the Maildir standard, the flags are to be written in
+    // ASCII order
+    letters.sort();
+    letters.dedup();
+
+    // Add the prelud which separates the flags
+    let mut res = ":2,".to_string();
+    res.extend(letters);
+    res
+}
+
 /// Representation of a Message
 #[derive(Debug, Clone)]
 pub struct Message {
@@ -52,12 +126,25 @@ pub struct Message {
     // contains the message's flags
     flags: HashSet<Flag>,
 
+    // the letters of the filename which stand for no flag we know of, kept
+    // so that renaming the message does not lose them
+    other_letters: String,
+
     // marks the message for deletion
     deleted: bool,
+
+    // the mod-sequence of the last change to the message's flags (RFC 7162)
+    modseq: usize,
+
+    // whether this session is the first to see the message, which only the
+    // session that moves it out of new/ is
+    recent: bool,
 }
 
 impl Message {
-    pub fn new(arg_path: &Path) -> ImapResult<Message> {
+    /// Read a message. The folder's keyword index gives the keywords which
+    /// lowercase letters in the filename stand for.
+    pub fn new(arg_path: &Path, keywords: &Keywords) -> ImapResult<Message> {
         let mime_message = MIME_Message::new(arg_path)?;
 
         // Grab the string in the filename representing the flags
@@ -74,42 +161,40 @@ impl Message {
         let uid = filename.parse().map_err(|_| Error::MessageUidDecode)?;
 
         // Parse the flags from the filename.
-        let flags = match path_flags {
-            // if there are no flags, create an empty set
-            None => HashSet::new(),
-            Some(flags) =>
-            // The uid is separated from the flag part of the filename by a
-            // colon. The flag part consists of a 2 followed by a comma and
-            // then some letters. Those letters represent the message flags
-            {
-                match flags.splitn(2, ',').nth(1) {
-                    None => HashSet::new(),
-                    Some(unparsed_flags) => {
-                        let mut set_flags: HashSet<Flag> = HashSet::new();
-                        for flag in unparsed_flags.chars() {
-                            let parsed_flag = match flag {
-                                'D' => Some(Flag::Draft),
-                                'F' => Some(Flag::Flagged),
-                                'R' => Some(Flag::Answered),
-                                'S' => Some(Flag::Seen),
-                                _ => None,
-                            };
-                            if let Some(enum_flag) = parsed_flag {
-                                set_flags.insert(enum_flag);
-                            }
-                        }
-                        set_flags
+        let mut flags = HashSet::new();
+        let mut other_letters = String::new();
+        // The uid is separated from the flag part of the filename by a
+        // colon. The flag part consists of a 2 followed by a comma and
+        // then some letters. Those letters represent the message flags
+        if let Some(unparsed_flags) = path_flags.and_then(|flags| flags.split_once(',').map(|(_, rest)| rest)) {
+            for letter in unparsed_flags.chars() {
+                let parsed_flag = match letter {
+                    'D' => Some(Flag::Draft),
+                    'F' => Some(Flag::Flagged),
+                    'P' => Some(Flag::Forwarded),
+                    'R' => Some(Flag::Answered),
+                    'S' => Some(Flag::Seen),
+                    'T' => Some(Flag::Deleted),
+                    _ => keywords.name(letter).map(|name| Flag::Keyword(name.to_string())),
+                };
+                match parsed_flag {
+                    Some(flag) => {
+                        flags.insert(flag);
                     }
+                    None => other_letters.push(letter),
                 }
             }
-        };
+        }
 
         let message = Message {
             uid: uid,
             path: arg_path.to_path_buf(),
             mime_message: mime_message,
+            deleted: flags.contains(&Flag::Deleted),
             flags: flags,
-            deleted: false,
+            other_letters,
+            modseq: 0,
+            recent: false,
         };
 
         Ok(message)
@@ -117,7 +202,7 @@ impl Message {
 
     /// convenience method for determining if Seen is in this message's flags
     pub fn is_unseen(&self) -> bool {
-        self.flags.contains(&Flag::Seen)
+        !self.flags.contains(&Flag::Seen)
     }
 
     pub fn rename(&self, pb: PathBuf) -> Message {
@@ -126,7 +211,10 @@ impl Message {
             path: pb,
             mime_message: self.mime_message.clone(),
             flags: self.flags.clone(),
+            other_letters: self.other_letters.clone(),
             deleted: self.deleted,
+            modseq: self.modseq,
+            recent: self.recent,
         }
     }
 
@@ -148,7 +236,35 @@ impl Message {
         self.uid
     }
 
-    pub fn store(&mut self, flag_name: &StoreName, new_flags: HashSet<Flag>) -> String {
+    pub fn get_flags(&self) -> &HashSet<Flag> {
+        &self.flags
+    }
+
+    pub fn get_mime_message(&self) -> &MIME_Message {
+        &self.mime_message
+    }
+
+    pub fn get_modseq(&self) -> usize {
+        self.modseq
+    }
+
+    pub fn set_modseq(&mut self, modseq: usize) {
+        self.modseq = modseq;
+    }
+
+    /// Whether the message has the \Recent flag in this session
+    pub fn is_recent(&self) -> bool {
+        self.recent
+    }
+
+    pub fn set_recent(&mut self, recent: bool) {
+        self.recent = recent;
+    }
+
+    /// Change the message's flags. If they end up different, the message
+    /// takes on the given mod-sequence.
+    pub fn store(&mut self, flag_name: &StoreName, new_flags: HashSet<Flag>, modseq: usize) -> String {
+        let old_flags = self.flags.clone();
         match *flag_name {
             StoreName::Sub => {
                 for flag in &new_flags {
@@ -166,56 +282,60 @@ impl Message {
         }
 
         self.deleted = self.flags.contains(&Flag::Deleted);
+        if self.flags != old_flags {
+            self.modseq = modseq;
+        }
         self.print_flags()
     }
 
     /// Goes through the list of attributes, constructing a FETCH response for
-    /// this message containing the values of the requested attributes
-    pub fn fetch(&self, attributes: &[Attribute]) -> String {
-        let mut res = String::new();
+    /// this message containing the values of the requested attributes. Message
+    /// contents are sent as stored, so the response need not be UTF-8.
+    pub fn fetch(&self, attributes: &[Attribute]) -> Vec<u8> {
+        let mut res = Vec::new();
         let mut first = true;
         for attr in attributes.iter() {
             // We need to space separate the attribute values
             if first {
                 first = false;
             } else {
-                res.push(' ');
+                res.push(b' ');
             }
 
             // Provide the attribute name followed by the attribute value
             match *attr {
                 Envelope => {
-                    res.push_str("ENVELOPE ");
-                    res.push_str(&self.mime_message.get_envelope()[..]);
+                    res.extend_from_slice(b"ENVELOPE ");
+                    res.extend_from_slice(self.mime_message.get_envelope().as_bytes());
                 }
                 Flags => {
-                    res.push_str("FLAGS ");
-                    res.push_str(&self.print_flags()[..]);
+                    res.extend_from_slice(b"FLAGS ");
+                    res.extend_from_slice(self.print_flags().as_bytes());
                 }
                 InternalDate => {
-                    res.push_str("INTERNALDATE \"");
-                    res.push_str(&self.date_received()[..]);
-                    res.push('"');
+                    res.extend_from_slice(b"INTERNALDATE \"");
+                    res.extend_from_slice(self.date_received().as_bytes());
+                    res.push(b'"');
                 }
                 RFC822(ref attr) => {
-                    res.push_str("RFC822");
+                    res.extend_from_slice(b"RFC822");
                     match *attr {
                         AllRFC822 | TextRFC822 => {}
                         HeaderRFC822 => {
-                            res.push_str(".HEADER {");
-                            res.push_str(&self.mime_message.get_header_boundary()[..]);
-                            res.push_str("}\r\n");
-                            res.push_str(self.mime_message.get_header());
+                            res.extend_from_slice(b".HEADER {");
+                            res.extend_from_slice(self.mime_message.get_header_boundary().as_bytes());
+                            res.extend_from_slice(b"}\r\n");
+                            res.extend_from_slice(self.mime_message.get_raw_header());
                         }
                         SizeRFC822 => {
-                            res.push_str(".SIZE ");
-                            res.push_str(&self.mime_message.get_size()[..])
+                            res.extend_from_slice(b".SIZE ");
+                            res.extend_from_slice(self.mime_message.get_size().to_string().as_bytes())
                         }
                     };
                 }
                 Body | BodyStructure => {}
                 BodySection(ref section, ref octets) | BodyPeek(ref section, ref octets) => {
-                    res.push_str(&self.mime_message.get_body(section, octets)[..])
+                    res.extend_from_slice(&self.mime_message.get_body(section, octets))
                 }
                 /*
                 BodyStructure => {
@@ -252,8 +372,13 @@ impl Message {
                 },
                 */
                 UID => {
-                    res.push_str("UID ");
-                    res.push_str(&self.uid.to_string()[..])
+                    res.extend_from_slice(b"UID ");
+                    res.extend_from_slice(self.uid.to_string().as_bytes())
+                }
+                ModSeq => {
+                    res.extend_from_slice(b"MODSEQ (");
+                    res.extend_from_slice(self.modseq.to_string().as_bytes());
+                    res.push(b')');
                 }
             }
         }
@@ -272,14 +397,15 @@ impl Message {
             } else {
                 res.push(' ');
             }
-            let flag_str = match *flag {
-                Flag::Answered => "\\Answered",
-                Flag::Draft => "\\Draft",
-                Flag::Flagged => "\\Flagged",
-                Flag::Seen => "\\Seen",
-                Flag::Deleted => "\\Deleted",
-            };
-            res.push_str(flag_str);
+            res.push_str(flag_name(flag));
+        }
+        // \Recent belongs to the session rather than the message, so it is
+        // never stored and clients can't set it
+        if self.recent {
+            if !first {
+                res.push(' ');
+            }
+            res.push_str("\\Recent");
         }
         res.push(')');
         res
@@ -288,37 +414,29 @@ impl Message {
     /// Creates a new filename using the convention that we use while parsing
     /// the message's filename. UID followed by a colon, then 2, then the
     /// single character per flag representation of the current set of flags.
-    pub fn get_new_filename(&self) -> String {
-        let mut res = self.uid.to_string();
+    pub fn get_new_filename(&self, keywords: &mut Keywords) -> String {
+        self.filename_with_flags(&self.flags, keywords)
+    }
 
-        // it is just the UID if no flags are set.
-        if self.flags.is_empty() {
-            return res;
-        }
+    /// The filename the message would have with the given flags instead.
+    pub fn filename_with_flags(&self, flags: &HashSet<Flag>, keywords: &mut Keywords) -> String {
+        format!("{}{}", self.uid, maildir_info(flags, &self.other_letters, keywords))
+    }
 
-        // Add the prelud which separates the flags
-        res.push_str(":2,");
+    /// When the message arrived, which its UID records.
+    pub fn internal_date(&self) -> NaiveDateTime {
+        NaiveDateTime::from_timestamp(self.uid as i64, 0)
+    }
 
-        // As per the Maildir standard, the flags are to be written in
-        // alphabetical order
-        if self.flags.contains(&Flag::Draft) {
-            res.push('D');
-        }
-        if self.flags.contains(&Flag::Flagged) {
-            res.push('F');
-        }
-        if self.flags.contains(&Flag::Answered) {
-            res.push('R');
-        }
-        if self.flags.contains(&Flag::Seen) {
-            res.push('S');
-        }
-        res
+    /// The date in the message's Date header field, if it is valid.
+    pub fn sent_date(&self) -> Option<DateTime<FixedOffset>> {
+        let date = self.mime_message.get_header_values("Date").into_iter().next()?;
+        DateTime::parse_from_rfc2822(date.trim()).ok()
     }
 
     fn date_received(&self) -> String {
         // Retrieve the date received from the UID.
-        let date_received = NaiveDateTime::from_timestamp(self.uid as i64, 0);
+        let date_received = self.internal_date();
 
         let month = match date_received.month() {
             0 => "Jan",
@@ -0,0 +1,212 @@
+//! Mailbox names as clients see them. Within the server the levels of a
+//! name are always separated by "/", as in the subscriptions and special-use
+//! files; the configuration may have clients see "." instead. NAMESPACE
+//! (RFC 2342) tells clients which delimiter and prefixes are used.
+//!
+//! Besides a user's own mailboxes, which have no prefix, the configuration
+//! may show each user the maildirs of the others, below a prefix and their
+//! address, and a root of shared mailboxes below another prefix. Which of
+//! those mailboxes a user may see and use is up to their ACLs.
+
+use std::path::{Path, PathBuf};
+
+use crate::acl::Acl;
+use crate::layout::Layout;
+use crate::util::{mailbox_levels, mailbox_path, wire_name};
+
+#[derive(Clone, Copy, Debug, Default, PartialEq, Serialize, Deserialize)]
+pub enum Delimiter {
+    #[default]
+    #[serde(rename = "/")]
+    Slash,
+    #[serde(rename = ".")]
+    Dot,
+}
+
+impl Delimiter {
+    pub fn as_char(self) -> char {
+        match self {
+            Delimiter::Slash => '/',
+            Delimiter::Dot => '.',
+        }
+    }
+
+    /// The name within the server of a mailbox name or LIST pattern from
+    /// the wire. With "." as the delimiter, a "/" is part of a level, which
+    /// no mailbox can have, so it becomes a NUL which no name holds.
+    pub fn stored_name(self, name: &str) -> String {
+        match self {
+            Delimiter::Slash => name.to_string(),
+            Delimiter::Dot => name
+                .chars()
+                .map(|c| match c {
+                    '/' => '\0',
+                    '.' => '/',
+                    c => c,
+                })
+                .collect(),
+        }
+    }
+
+    /// The name of a mailbox on the wire. A level holding the delimiter,
+    /// which nested folders may have, cannot be told from two levels.
+    pub fn wire_name(self, name: &str) -> String {
+        name.replace('/', &self.as_char().to_string())
+    }
+}
+
+/// A directory of mailboxes which users see below a prefix: a user's
+/// maildir, with INBOX, or the root of the shared mailboxes, without.
+#[derive(Clone, Debug, PartialEq)]
+pub struct Root {
+    // The names of the mailboxes in the root start with this and "/"; the
+    // user's own maildir has none
+    pub prefix: String,
+    pub path: PathBuf,
+    pub layout: Layout,
+    // The address of the user whose maildir this is; None for the shared
+    // root
+    pub owner: Option<String>,
+}
+
+impl Root {
+    /// The mailbox in this root with a name without the prefix. None if
+    /// the name cannot be kept in the root.
+    fn locate(&self, name: &str) -> Option<Location> {
+        // The shared root is not a mailbox of its own
+        if self.owner.is_none() && name.split('/').next()?.eq_ignore_ascii_case("INBOX") {
+            return None;
+        }
+        let path = mailbox_path(&self.path, name, self.layout)?;
+        Some(Location {
+            root: self.clone(),
+            levels: mailbox_levels(name).iter().map(|level| level.to_string()).collect(),
+            path,
+        })
+    }
+
+    /// The rights of a user on a mailbox in this root, given its directory.
+    pub fn rights(&self, identifier: &str, path: &Path) -> String {
+        Acl::load(path).rights(identifier, self.owner.as_deref() == Some(identifier))
+    }
+}
+
+/// Where a mailbox named by a user is kept
+#[derive(Debug, PartialEq)]
+pub struct Location {
+    pub root: Root,
+    // The levels of the name within the root, without INBOX
+    pub levels: Vec<String>,
+    pub path: PathBuf,
+}
+
+impl Location {
+    pub fn levels(&self) -> Vec<&str> {
+        self.levels.iter().map(|level| &level[..]).collect()
+    }
+
+    /// Whether this is the INBOX of a maildir, which cannot be created,
+    /// deleted or renamed.
+    pub fn is_inbox(&self) -> bool {
+        self.levels.is_empty()
+    }
+
+    /// The rights of a user on the mailbox.
+    pub fn rights(&self, identifier: &str) -> String {
+        self.root.rights(identifier, &self.path)
+    }
+
+    /// The rights of a user on the mailbox's parent, which decide whether
+    /// it may be created. A top-level mailbox's parent is the root.
+    pub fn parent_rights(&self, identifier: &str) -> String {
+        let levels = self.levels();
+        let parent = self.root.layout.folder_path(&self.root.path, &levels[..levels.len().saturating_sub(1)]);
+        self.root.rights(identifier, &parent)
+    }
+}
+
+/// The mailbox a name stands for among the roots a user sees, the first of
+/// which is the user's own maildir. The name of a root itself, or of the
+/// levels above it, is none.
+pub fn locate(roots: &[Root], name: &str) -> Option<Location> {
+    let mut located = None;
+    for root in roots {
+        if root.prefix.is_empty() {
+            located = located.or(Some((root, name)));
+        } else if let Some(rest) = name.strip_prefix(&root.prefix).and_then(|rest| rest.strip_prefix('/')) {
+            return root.locate(rest);
+        } else if name == root.prefix || root.prefix.starts_with(&format!("{}/", name)) {
+            return None;
+        }
+    }
+    located.and_then(|(root, name)| root.locate(name))
+}
+
+/// The response to NAMESPACE: the user's own mailboxes have no prefix,
+/// and the other users' and shared ones have theirs if configured.
+pub fn response(delimiter: Delimiter, other_users: Option<&str>, shared: Option<&str>, utf8: bool) -> String {
+    let namespace = |prefix: Option<&str>| match prefix {
+        Some(prefix) => format!(
+            "((\"{}\" \"{}\"))",
+            wire_name(&format!("{}/", prefix), delimiter, utf8).trim_matches('"'),
+            delimiter.as_char()
+        ),
+        None => "NIL".to_string(),
+    };
+    format!(
+        "* NAMESPACE ((\"\" \"{}\")) {} {}\r\n",
+        delimiter.as_char(),
+        namespace(other_users),
+        namespace(shared)
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_delimiter() {
+        assert_eq!(Delimiter::Slash.stored_name("Lists/rust.lang"), "Lists/rust.lang");
+        assert_eq!(Delimiter::Dot.stored_name("Lists.rust"), "Lists/rust");
+        assert_eq!(Delimiter::Dot.stored_name("a/b"), "a\0b");
+        assert_eq!(Delimiter::Dot.wire_name("Lists/rust"), "Lists.rust");
+        assert_eq!(Delimiter::Slash.wire_name("Lists/rust"), "Lists/rust");
+        assert_eq!(
+            response(Delimiter::Dot, None, None, false),
+            "* NAMESPACE ((\"\" \".\")) NIL NIL\r\n"
+        );
+        assert_eq!(
+            response(Delimiter::Slash, Some("Other Users"), Some("Shared"), false),
+            "* NAMESPACE ((\"\" \"/\")) ((\"Other Users/\" \"/\")) ((\"Shared/\" \"/\"))\r\n"
+        );
+    }
+
+    #[test]
+    fn test_locate() {
+        let root = |prefix: &str, path: &str, owner: Option<&str>| Root {
+            prefix: prefix.to_string(),
+            path: PathBuf::from(path),
+            layout: Layout::Nested,
+            owner: owner.map(|owner| owner.to_string()),
+        };
+        let roots = vec![
+            root("", "/mail/will", Some("will@xqz.ca")),
+            root("Other Users/fred@xqz/ca", "/mail/fred", Some("fred@xqz.ca")),
+            root("Shared", "/mail/shared", None),
+        ];
+        let path = |name: &str| locate(&roots, name).map(|location| location.path);
+        assert_eq!(path("INBOX"), Some(PathBuf::from("/mail/will")));
+        assert_eq!(path("Sent"), Some(PathBuf::from("/mail/will/Sent")));
+        assert_eq!(path("Other Users/fred@xqz/ca/INBOX"), Some(PathBuf::from("/mail/fred")));
+        assert_eq!(path("Other Users/fred@xqz/ca/Sent"), Some(PathBuf::from("/mail/fred/Sent")));
+        assert_eq!(path("Shared/support"), Some(PathBuf::from("/mail/shared/support")));
+        assert_eq!(path("Other Users/fred@xqz"), None);
+        assert_eq!(path("Shared"), None);
+        assert_eq!(path("Shared/INBOX"), None);
+        assert_eq!(path("Shared/../will"), None);
+        let location = locate(&roots, "Shared/support/2023").unwrap();
+        assert_eq!(location.levels(), vec!["support", "2023"]);
+        assert!(!location.is_inbox());
+    }
+}
@@ -1,5 +1,6 @@
 use crate::command::Attribute::{
-    self, Body, BodyPeek, BodySection, BodyStructure, Envelope, Flags, InternalDate, RFC822, UID,
+    self, Body, BodyPeek, BodySection, BodyStructure, Envelope, Flags, InternalDate, ModSeq, RFC822,
+    UID,
 };
 use crate::command::FetchCommand;
 use crate::command::RFC822Attribute::{AllRFC822, HeaderRFC822, SizeRFC822, TextRFC822};
@@ -39,8 +40,34 @@ named!(pub fetch<FetchCommand>,
             map!(tag_no_case!("FULL"), |_| { vec![Flags, InternalDate, RFC822(SizeRFC822), Envelope, Body] }) |
             map!(tag_no_case!("FAST"), |_| { vec![Flags, InternalDate, RFC822(SizeRFC822)] })
         ) >>
+        modifiers: opt!(complete!(preceded!(whitespace, fetch_modifiers))) >>
+
+        ({
+            let mut cmd = FetchCommand::new(set, attrs);
+            if let Some((changed_since, vanished)) = modifiers {
+                cmd.changed_since = Some(changed_since);
+                cmd.vanished = vanished;
+            }
+            cmd
+        })
+    )
+);
+
+// The CHANGEDSINCE modifier of RFC 7162, with the VANISHED modifier which
+// may only follow it.
+named!(
+    fetch_modifiers<(usize, bool)>,
+    delimited!(
+        tag!("("),
+        do_parse!(
+            tag_no_case!("CHANGEDSINCE") >>
+            whitespace >>
+            modseq: number >>
+            vanished: opt!(complete!(preceded!(whitespace, tag_no_case!("VANISHED")))) >>
 
-        ({ FetchCommand::new(set, attrs) })
+            ((modseq, vanished.is_some()))
+        ),
+        tag!(")")
     )
 );
 
@@ -50,6 +77,7 @@ named!(
         complete!(tag_no_case!("ENVELOPE")) => { |_| { Envelope } } |
         complete!(tag_no_case!("FLAGS")) => { |_| { Flags } } |
         complete!(tag_no_case!("INTERNALDATE")) => { |_| { InternalDate } } |
+        complete!(tag_no_case!("MODSEQ")) => { |_| { ModSeq } } |
         do_parse!(
             tag_no_case!("RFC822")                            >>
             sub_attr: opt!(alt!(
@@ -193,7 +221,8 @@ mod tests {
     };
     use crate::command::sequence_set::SequenceItem::{Number, Range, Wildcard};
     use crate::command::Attribute::{
-        Body, BodyPeek, BodySection, BodyStructure, Envelope, Flags, InternalDate, RFC822,
+        Body, BodyPeek, BodySection, BodyStructure, Envelope, Flags, InternalDate, ModSeq, RFC822,
+        UID,
     };
     use crate::command::FetchCommand;
     use crate::command::RFC822Attribute::{AllRFC822, HeaderRFC822, SizeRFC822};
@@ -277,6 +306,24 @@ mod tests {
         );
     }
 
+    #[test]
+    fn test_fetch_modifiers() {
+        let mut cmd = FetchCommand::new(vec![Number(1)], vec![Flags]);
+        cmd.changed_since = Some(12);
+        assert_eq!(fetch(b"FETCH 1 FLAGS (CHANGEDSINCE 12)"), Done(&b""[..], cmd));
+        let mut cmd = FetchCommand::new(vec![Number(1)], vec![UID, ModSeq]);
+        cmd.changed_since = Some(3);
+        cmd.vanished = true;
+        assert_eq!(
+            fetch(b"FETCH 1 (UID MODSEQ) (changedsince 3 vanished)"),
+            Done(&b""[..], cmd)
+        );
+        assert_eq!(
+            fetch(b"FETCH 1 FLAGS (VANISHED)"),
+            Done(&b" (VANISHED)"[..], FetchCommand::new(vec![Number(1)], vec![Flags]))
+        );
+    }
+
     #[test]
     fn test_fetch_case_insensitivity() {
         assert_eq!(
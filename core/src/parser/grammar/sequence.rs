@@ -3,13 +3,21 @@ use crate::parser::grammar::nz_number;
 
 /* Sequence item and set rules */
 
+// "$" stands alone for the saved search result (RFC 5182)
 named!(pub sequence_set<Vec<SequenceItem>>,
+    alt!(
+        tag!("$") => { |_| vec![SequenceItem::Saved] } |
+        seq_list
+    )
+);
+
+named!(seq_list<Vec<SequenceItem>>,
     do_parse!(
         a: alt!(
             complete!(seq_range) |
             seq_number
         )                                             >>
-        b: many0!(preceded!(tag!(","), sequence_set)) >>
+        b: many0!(preceded!(tag!(","), seq_list))     >>
 
         ({
             let mut seq: Vec<SequenceItem> = b.into_iter()
@@ -43,7 +51,7 @@ named!(
 #[cfg(test)]
 mod tests {
     use super::{seq_number, seq_range, sequence_set};
-    use crate::command::sequence_set::SequenceItem::{Number, Range, Wildcard};
+    use crate::command::sequence_set::SequenceItem::{Number, Range, Saved, Wildcard};
     use nom::ErrorKind::Alt;
     use nom::IResult::{Done, Error, Incomplete};
     use nom::Needed::Size;
@@ -56,6 +64,8 @@ mod tests {
         assert_eq!(sequence_set(b"a:*"), Error(Alt));
         assert_eq!(sequence_set(b":*"), Error(Alt));
         assert_eq!(sequence_set(b"*"), Done(&b""[..], vec![Wildcard]));
+        assert_eq!(sequence_set(b"$"), Done(&b""[..], vec![Saved]));
+        assert_eq!(sequence_set(b"1,$"), Done(&b",$"[..], vec![Number(1)]));
         assert_eq!(sequence_set(b"1"), Done(&b""[..], vec![Number(1)]));
         assert_eq!(sequence_set(b"1:"), Done(&b":"[..], vec![Number(1)]));
         assert_eq!(sequence_set(b"4,5,6,"), Incomplete(Size(7)));
@@ -0,0 +1,292 @@
+//! Quotas (RFC 9208) on the storage and number of messages in a user's
+//! maildir. Usage is cached in the maildir's maildirsize file the way
+//! Maildir++ quotas keep it: a first line with the limits, such as
+//! "1048576S,1000C", followed by lines each adding a number of bytes and
+//! messages. The first line is empty unless SETQUOTA has set limits other
+//! than the user's own. Removals are not logged; the file is rewritten
+//! from a scan of the maildir when it is missing or has grown long, and
+//! whenever the cached usage would refuse a message.
+
+use std::fs::{self, OpenOptions};
+use std::io::{self, Write};
+use std::path::{Path, PathBuf};
+
+use crate::layout::Layout;
+
+static MAILDIRSIZE_FILE: &str = "maildirsize";
+// Past this size the file is rewritten rather than appended to
+const MAX_FILE_SIZE: u64 = 5120;
+
+/// The most storage, in bytes, and messages a maildir may hold. None is
+/// unlimited.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Serialize, Deserialize)]
+pub struct Limits {
+    #[serde(default)]
+    pub storage: Option<u64>,
+    #[serde(default)]
+    pub messages: Option<u64>,
+}
+
+impl Limits {
+    /// Parse the first line of maildirsize.
+    fn parse(line: &str) -> Option<Limits> {
+        let mut limits = Limits::default();
+        for limit in line.split(',').filter(|limit| !limit.is_empty()) {
+            let (value, kind) = limit.split_at(limit.len() - 1);
+            match kind {
+                "S" => limits.storage = Some(value.parse().ok()?),
+                "C" => limits.messages = Some(value.parse().ok()?),
+                _ => return None,
+            }
+        }
+        Some(limits)
+    }
+
+    fn format(&self) -> String {
+        let storage = self.storage.map(|storage| format!("{}S", storage));
+        let messages = self.messages.map(|messages| format!("{}C", messages));
+        storage.into_iter().chain(messages).collect::<Vec<_>>().join(",")
+    }
+}
+
+/// Why a message cannot be added to a maildir
+#[derive(Debug, PartialEq)]
+pub enum Exceeded {
+    /// The maildir is too full to take it
+    Full,
+    /// It would not fit even in an empty maildir
+    TooBig,
+}
+
+#[derive(Debug)]
+pub struct Quota {
+    maildir: PathBuf,
+    layout: Layout,
+    // The limits set with SETQUOTA, which override the user's own
+    overrides: Option<Limits>,
+    default_limits: Limits,
+    // Bytes and messages in the maildir
+    pub storage: u64,
+    pub messages: u64,
+}
+
+impl Quota {
+    /// Read the usage of a maildir from its cache, scanning the maildir if
+    /// the cache is missing or long.
+    pub fn load(maildir: &Path, layout: Layout, default_limits: Limits) -> Quota {
+        let mut quota = Quota {
+            maildir: maildir.to_path_buf(),
+            layout,
+            overrides: None,
+            default_limits,
+            storage: 0,
+            messages: 0,
+        };
+        let path = maildir.join(MAILDIRSIZE_FILE);
+        let long = fs::metadata(&path).map_or(true, |metadata| metadata.len() > MAX_FILE_SIZE);
+        let contents = fs::read_to_string(&path).unwrap_or_default();
+        let mut lines = contents.lines();
+        quota.overrides = lines.next().filter(|line| !line.is_empty()).and_then(Limits::parse);
+        let mut valid = true;
+        for line in lines {
+            let mut fields = line.split_whitespace().map(|field| field.parse::<i64>());
+            match (fields.next(), fields.next()) {
+                (Some(Ok(storage)), Some(Ok(messages))) => {
+                    quota.storage = quota.storage.saturating_add_signed(storage);
+                    quota.messages = quota.messages.saturating_add_signed(messages);
+                }
+                _ => valid = false,
+            }
+        }
+        if long || !valid {
+            if let Err(e) = quota.recalculate() {
+                warn!("Error recalculating the quota of '{}': {}", maildir.display(), e);
+            }
+        }
+        quota
+    }
+
+    /// The limits on the maildir: those set with SETQUOTA, or the user's.
+    pub fn limits(&self) -> Limits {
+        self.overrides.unwrap_or(self.default_limits)
+    }
+
+    /// Count the messages in every folder of the maildir and rewrite the
+    /// cache.
+    pub fn recalculate(&mut self) -> io::Result<()> {
+        let (mut storage, mut messages) = (0, 0);
+        let folders = self.layout.folders(&self.maildir);
+        let paths = folders
+            .iter()
+            .map(|folder| self.layout.folder_path(&self.maildir, &folder.split('/').collect::<Vec<_>>()))
+            .chain(Some(self.maildir.clone()));
+        for path in paths {
+            for dir in &["cur", "new"] {
+                let entries = match fs::read_dir(path.join(dir)) {
+                    Ok(entries) => entries,
+                    Err(_) => continue,
+                };
+                for metadata in entries.flatten().filter_map(|entry| entry.metadata().ok()) {
+                    if metadata.is_file() {
+                        storage += metadata.len();
+                        messages += 1;
+                    }
+                }
+            }
+        }
+        self.storage = storage;
+        self.messages = messages;
+        self.save()
+    }
+
+    fn save(&self) -> io::Result<()> {
+        let path = self.maildir.join(MAILDIRSIZE_FILE);
+        let definition = self.overrides.map(|limits| limits.format()).unwrap_or_default();
+        let contents = format!("{}\n{} {}\n", definition, self.storage, self.messages);
+        let tmp_path = path.with_extension("tmp");
+        fs::write(&tmp_path, contents).and_then(|_| fs::rename(&tmp_path, &path))
+    }
+
+    /// Whether messages of a total size can be added, allowing the usage to
+    /// go past the limits by a percentage of them. The maildir is scanned
+    /// before a message is refused, as the cache does not know of removals.
+    pub fn check(&mut self, storage: u64, messages: u64, grace: u64) -> Result<(), Exceeded> {
+        let limits = self.limits();
+        let with_grace = |limit: u64| limit.saturating_add(limit.saturating_mul(grace) / 100);
+        if limits.storage.is_some_and(|limit| storage > with_grace(limit))
+            || limits.messages.is_some_and(|limit| messages > with_grace(limit))
+        {
+            return Err(Exceeded::TooBig);
+        }
+        let fits = |quota: &Quota| {
+            limits.storage.is_none_or(|limit| quota.storage + storage <= with_grace(limit))
+                && limits.messages.is_none_or(|limit| quota.messages + messages <= with_grace(limit))
+        };
+        if fits(self) {
+            return Ok(());
+        }
+        if let Err(e) = self.recalculate() {
+            warn!("Error recalculating the quota of '{}': {}", self.maildir.display(), e);
+        }
+        if fits(self) {
+            Ok(())
+        } else {
+            Err(Exceeded::Full)
+        }
+    }
+
+    /// Record messages added to the maildir.
+    pub fn add(&mut self, storage: u64, messages: u64) -> io::Result<()> {
+        self.storage += storage;
+        self.messages += messages;
+        let path = self.maildir.join(MAILDIRSIZE_FILE);
+        if !path.exists() {
+            return self.save();
+        }
+        let mut file = OpenOptions::new().append(true).open(path)?;
+        file.write_all(format!("{} {}\n", storage, messages).as_bytes())
+    }
+
+    /// Set the limits, as SETQUOTA does. Setting none leaves the user's
+    /// own.
+    pub fn set_limits(&mut self, limits: Limits) -> io::Result<()> {
+        self.overrides = Some(limits).filter(|limits| *limits != Limits::default());
+        self.save()
+    }
+
+    /// The untagged QUOTA response for the quota root with a name. Storage
+    /// is counted in units of 1024 bytes.
+    pub fn response(&self, root: &str) -> String {
+        let limits = self.limits();
+        let mut resources = Vec::new();
+        if let Some(limit) = limits.storage {
+            resources.push(format!("STORAGE {} {}", self.storage.div_ceil(1024), limit / 1024));
+        }
+        if let Some(limit) = limits.messages {
+            resources.push(format!("MESSAGE {} {}", self.messages, limit));
+        }
+        format!("* QUOTA {} ({})\r\n", root, resources.join(" "))
+    }
+}
+
+/// Parse the resource limits of SETQUOTA, such as "(STORAGE 512 MESSAGE
+/// 1000)", with storage in units of 1024 bytes. Resources not given are
+/// unlimited. None if a resource is unknown.
+pub fn parse_setquota(list: &str) -> Option<Limits> {
+    let list = list.strip_prefix('(')?.strip_suffix(')')?;
+    let mut limits = Limits::default();
+    let mut words = list.split_whitespace();
+    while let Some(resource) = words.next() {
+        let limit: u64 = words.next()?.parse().ok()?;
+        match &resource.to_ascii_uppercase()[..] {
+            "STORAGE" => limits.storage = Some(limit.checked_mul(1024)?),
+            "MESSAGE" => limits.messages = Some(limit),
+            _ => return None,
+        }
+    }
+    Some(limits)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::server::lmtp::create_folder;
+    use crate::testing::TempDir;
+
+    #[test]
+    fn test_limits() {
+        let limits = Limits {
+            storage: Some(1048576),
+            messages: Some(1000),
+        };
+        assert_eq!(limits.format(), "1048576S,1000C");
+        assert_eq!(Limits::parse("1048576S,1000C"), Some(limits));
+        assert_eq!(Limits::parse("1000C").unwrap().storage, None);
+        assert_eq!(Limits::parse("10X"), None);
+        assert_eq!(parse_setquota("(STORAGE 1024 message 1000)"), Some(limits));
+        assert_eq!(parse_setquota("()"), Some(Limits::default()));
+        assert_eq!(parse_setquota("(FOO 1)"), None);
+    }
+
+    #[test]
+    fn test_quota() {
+        let maildir = TempDir::new("quota");
+        create_folder(&maildir).unwrap();
+        create_folder(&maildir.join("Sent")).unwrap();
+        fs::write(maildir.join("new").join("1"), vec![b'a'; 600]).unwrap();
+        fs::write(maildir.join("Sent").join("cur").join("2:2,S"), vec![b'a'; 400]).unwrap();
+
+        let limits = Limits {
+            storage: Some(2048),
+            messages: Some(3),
+        };
+        let mut quota = Quota::load(&maildir, Layout::Nested, limits);
+        assert_eq!((quota.storage, quota.messages), (1000, 2));
+        assert_eq!(quota.response("\"will@xqz.ca\""), "* QUOTA \"will@xqz.ca\" (STORAGE 1 2 MESSAGE 2 3)\r\n");
+        assert_eq!(quota.check(1000, 1, 0), Ok(()));
+        fs::write(maildir.join("new").join("3"), vec![b'a'; 1000]).unwrap();
+        quota.add(1000, 1).unwrap();
+        assert_eq!(quota.check(4000, 1, 50), Err(Exceeded::TooBig));
+        assert_eq!(quota.check(100, 1, 0), Err(Exceeded::Full));
+        // The grace lets one more message in
+        assert_eq!(quota.check(100, 1, 50), Ok(()));
+
+        // The cache is read back, and a removal is noticed when it matters
+        let mut quota = Quota::load(&maildir, Layout::Nested, limits);
+        assert_eq!((quota.storage, quota.messages), (2000, 3));
+        fs::remove_file(maildir.join("new").join("1")).unwrap();
+        assert_eq!(quota.check(100, 1, 0), Ok(()));
+        assert_eq!((quota.storage, quota.messages), (1400, 2));
+
+        let unlimited = Limits {
+            storage: None,
+            messages: Some(10),
+        };
+        quota.set_limits(unlimited).unwrap();
+        let mut quota = Quota::load(&maildir, Layout::Nested, limits);
+        assert_eq!(quota.limits(), unlimited);
+        assert_eq!(quota.response("\"\""), "* QUOTA \"\" (MESSAGE 2 10)\r\n");
+        quota.set_limits(Limits::default()).unwrap();
+        assert_eq!(Quota::load(&maildir, Layout::Nested, limits).limits(), limits);
+    }
+}
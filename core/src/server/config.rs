@@ -1,8 +1,11 @@
 use crate::error::ImapResult;
+use crate::layout::Layout;
+use crate::namespace::Delimiter;
 use openssl::error::ErrorStack;
 use openssl::pkcs12::Pkcs12;
 use openssl::ssl::{SslAcceptor, SslMethod};
 use openssl::x509::X509;
+use std::collections::BTreeMap;
 use std::env;
 use std::fs::File;
 use std::io::{Error as IoError, Read, Write};
@@ -28,6 +31,20 @@ impl From<ErrorStack> for PkcsError {
     }
 }
 
+/// Where mail for a recipient address with a detail part, such as
+/// `user+lists@domain`, is delivered
+#[derive(Clone, Copy, Debug, Default, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum DetailMailbox {
+    /// Always deliver into INBOX
+    #[default]
+    Inbox,
+    /// Deliver into the folder named by the detail if it already exists
+    Existing,
+    /// Deliver into the folder named by the detail, creating it if needed
+    Create,
+}
+
 /// Representation of configuration data for the server
 #[derive(Debug, Serialize, Deserialize)]
 pub struct Config {
@@ -41,12 +58,91 @@ pub struct Config {
     pub lmtp_ssl_port: Option<u16>,
     // SSL port on which to listen for IMAP
     pub imap_ssl_port: Option<u16>,
+    // Plaintext port on which to listen for POP3
+    #[serde(default)]
+    pub pop3_port: Option<u16>,
+    // SSL port on which to listen for POP3
+    #[serde(default)]
+    pub pop3_ssl_port: Option<u16>,
+    // Port on which to listen for ManageSieve
+    #[serde(default)]
+    pub managesieve_port: Option<u16>,
+    // Port on which to listen for JMAP over HTTP
+    #[serde(default)]
+    pub jmap_port: Option<u16>,
+    // Port on which to listen for mail submission (RFC 6409)
+    #[serde(default)]
+    pub submission_port: Option<u16>,
+    // host:port of the SMTP server through which outgoing mail is relayed;
+    // mail stays in the spool if unset
+    #[serde(default)]
+    pub smarthost: Option<String>,
+    // Largest message in bytes accepted over LMTP and submission; unlimited
+    // if unset
+    #[serde(default)]
+    pub lmtp_max_size: Option<usize>,
+    // Largest message in bytes accepted by IMAP APPEND
+    #[serde(default = "default_imap_max_size")]
+    pub imap_max_size: usize,
+    // Characters separating the user from the detail in recipient
+    // addresses, "+" for user+detail@domain by default; "" turns this off
+    #[serde(default = "default_recipient_delimiter")]
+    pub recipient_delimiter: Option<String>,
+    // Which folder mail to a recipient address with a detail is delivered to
+    #[serde(default)]
+    pub detail_mailbox: DetailMailbox,
+    // Directory in which outgoing mail, such as Sieve redirects and vacation
+    // replies, waits to be relayed; such mail is dropped if unset
+    #[serde(default)]
+    pub spool_dir: Option<String>,
     // file in which user data is stored
     pub users: String,
     // Filename of PKCS #12 archive
     pub pkcs_file: String,
     // Password for PKCS #12 archive
     pub pkcs_pass: String,
+    // Whether the mailboxes in special_use are created on a user's first
+    // login
+    #[serde(default)]
+    pub create_special_use: bool,
+    // The mailbox with each special use (RFC 6154), such as Sent = "Sent";
+    // users may override these
+    #[serde(default)]
+    pub special_use: BTreeMap<String, String>,
+    // How the folders of each maildir are laid out on disk, "nested" or
+    // "maildir++"; users may override this
+    #[serde(default)]
+    pub layout: Layout,
+    // The hierarchy delimiter clients see, "/" or "."
+    #[serde(default)]
+    pub delimiter: Delimiter,
+    // The prefix below which users see each other's maildirs, e.g. "Users";
+    // a user sees only the mailboxes whose ACL lets them
+    #[serde(default)]
+    pub other_users_namespace: Option<String>,
+    // The prefix below which users see the mailboxes in shared_root, e.g.
+    // "Shared"
+    #[serde(default)]
+    pub shared_namespace: Option<String>,
+    // Directory holding the shared mailboxes, laid out as `layout` says
+    #[serde(default)]
+    pub shared_root: Option<String>,
+    // How far past their quota, as a percentage of it, delivered mail may
+    // take a user; APPEND and COPY keep within the quota
+    #[serde(default)]
+    pub quota_grace: u64,
+    // Whether each folder keeps a full-text index for SEARCH BODY and TEXT,
+    // updated as mail arrives; users may override this
+    #[serde(default)]
+    pub full_text_index: bool,
+}
+
+fn default_recipient_delimiter() -> Option<String> {
+    Some("+".to_string())
+}
+
+fn default_imap_max_size() -> usize {
+    64 * 1024 * 1024
 }
 
 impl Config {
@@ -93,7 +189,12 @@ impl Config {
     }
 
     pub fn get_ssl_acceptor(&self) -> Result<SslAcceptor, PkcsError> {
-        if self.imap_ssl_port == None && self.lmtp_ssl_port == None {
+        // Submission needs TLS for STARTTLS even without an SSL port
+        if self.imap_ssl_port.is_none()
+            && self.lmtp_ssl_port.is_none()
+            && self.pop3_ssl_port.is_none()
+            && self.submission_port.is_none()
+        {
             return Err(PkcsError::PortsDisabled);
         }
         let mut buf = vec![];
@@ -113,6 +214,11 @@ impl Config {
 
     fn default(directory: &String) -> Self {
         let users_file = format!("{}/users.json", directory);
+        let spool_dir = format!("{}/spool", directory);
+        let special_use = ["Archive", "Drafts", "Junk", "Sent", "Trash"]
+            .iter()
+            .map(|name| (name.to_string(), name.to_string()))
+            .collect();
 
         Config {
             host: "127.0.0.1".to_string(),
@@ -120,9 +226,42 @@ impl Config {
             imap_port: Some(10000),
             lmtp_ssl_port: None,
             imap_ssl_port: Some(10001),
+            pop3_port: Some(11000),
+            pop3_ssl_port: None,
+            managesieve_port: Some(4190),
+            jmap_port: Some(8080),
+            submission_port: None,
+            smarthost: None,
+            lmtp_max_size: None,
+            imap_max_size: default_imap_max_size(),
+            recipient_delimiter: default_recipient_delimiter(),
+            detail_mailbox: DetailMailbox::Inbox,
+            spool_dir: Some(spool_dir),
             users: users_file,
             pkcs_file: String::new(),
             pkcs_pass: String::new(),
+            create_special_use: true,
+            special_use,
+            layout: Layout::Nested,
+            delimiter: Delimiter::Slash,
+            other_users_namespace: None,
+            shared_namespace: None,
+            shared_root: None,
+            quota_grace: 0,
+            full_text_index: false,
         }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_defaults() {
+        let parsed: Config = toml::from_str("host = \"127.0.0.1\"\nusers = \"\"\npkcs_file = \"\"\npkcs_pass = \"\"\n").unwrap();
+        let default = Config::default(&".".to_string());
+        assert_eq!(parsed.recipient_delimiter, default.recipient_delimiter);
+        assert_eq!(parsed.imap_max_size, default.imap_max_size);
+    }
+}
@@ -1,25 +1,37 @@
 use bufstream::BufStream;
-use regex::Regex;
+use std::collections::BTreeMap;
 use std::fs;
-use std::io::{BufRead, Write};
+use std::io::{self, BufRead, Read, Write};
 use std::net::TcpStream;
-use std::os::unix::fs::PermissionsExt;
-use std::path::Path;
-use std::path::MAIN_SEPARATOR;
+use std::path::{Path, PathBuf};
 use std::str::Split;
 use std::sync::Arc;
 
-use crate::folder::Folder;
+use crate::acl::{self, Acl, RIGHTS};
+use crate::folder::{Folder, UIDVALIDITY};
+use crate::fts;
+use crate::layout::Layout;
+use crate::message::{flag_name, parse_flag, Flag};
+use crate::namespace::{self, Location, Root};
+use crate::quota::{self, Quota};
+use crate::server::lmtp;
 use crate::server::Server;
 use crate::server::Stream;
+use crate::special_use::{self, SpecialUse};
+use crate::subscriptions::Subscriptions;
+use crate::utf7;
 
 use crate::command::fetch;
+use crate::command::list;
+use crate::command::search;
 use crate::command::sequence_set;
-use crate::command::sequence_set::SequenceItem::{Number, Range, Wildcard};
+use crate::command::sequence_set::IntervalSet;
+use crate::command::sort;
 use crate::command::store;
+use crate::command::thread;
 use crate::command::Attribute::UID;
 use crate::error::Error;
-use crate::util;
+use crate::util::{self, SelectParam};
 
 // Used to grab every file for removal while performing DELETE on a folder.
 macro_rules! opendirlisting(
@@ -49,6 +61,26 @@ pub struct ImapSession {
     /// If None, no folder selected. Otherwise, contains the currently selected
     /// folder.
     folder: Option<Folder>,
+    /// Whether the client has enabled CONDSTORE, explicitly or by using it
+    condstore: bool,
+    /// Whether the client has enabled QRESYNC, which implies CONDSTORE
+    qresync: bool,
+    /// The mailbox with each special use by default for the logged in user
+    special_use: BTreeMap<String, String>,
+    /// How the folders of the logged in user's maildir are laid out
+    layout: Layout,
+    /// Whether the client has enabled UTF8=ACCEPT (RFC 6855), so mailbox
+    /// names go over the wire as UTF-8 rather than modified UTF-7
+    utf8: bool,
+    /// The address the user logged in with, which names them in ACLs
+    identifier: String,
+    /// The roots of the mailboxes the logged in user sees, their own
+    /// maildir first
+    roots: Vec<Root>,
+    /// The user's rights on the selected folder
+    rights: String,
+    /// Whether the user may see and set everyone's quotas
+    admin: bool,
 }
 
 impl ImapSession {
@@ -58,6 +90,15 @@ impl ImapSession {
             logout: false,
             maildir: None,
             folder: None,
+            condstore: false,
+            qresync: false,
+            special_use: BTreeMap::new(),
+            layout: Layout::Nested,
+            utf8: false,
+            identifier: String::new(),
+            roots: Vec::new(),
+            rights: String::new(),
+            admin: false,
         }
     }
 
@@ -88,14 +129,14 @@ impl ImapSession {
                     // have to wait on our response in order to issue new commands.
                     let mut starttls = false;
                     let res = match args.next() {
-                        None => inv_str.to_string(),
+                        None => inv_str.as_bytes().to_vec(),
                         Some(tag) => {
                             let mut bad_res = tag.to_string();
                             bad_res.push_str(inv_str);
 
                             // Interpret the command and generate a response
                             match args.next() {
-                                None => bad_res,
+                                None => bad_res.into_bytes(),
                                 Some(c) => {
                                     warn!("Cmd: {}", command.trim());
                                     match &c.to_ascii_lowercase()[..] {
@@ -108,13 +149,18 @@ impl ImapSession {
                                                     ok_res.push_str(
                                                         " OK Begin TLS negotiation now\r\n",
                                                     );
-                                                    ok_res
+                                                    ok_res.into_bytes()
                                                 } else {
-                                                    bad_res
+                                                    bad_res.into_bytes()
                                                 }
                                             }
-                                            _ => bad_res,
+                                            _ => bad_res.into_bytes(),
                                         },
+                                        // APPEND is handled here because it reads
+                                        // the message from the stream
+                                        "append" => {
+                                            self.append(&mut stream, &mut args, tag, bad_res).into_bytes()
+                                        }
                                         cmd => self.interpret(cmd, &mut args, tag, bad_res),
                                     }
                                 }
@@ -123,9 +169,9 @@ impl ImapSession {
                     };
 
                     // Log the response
-                    warn!("Response:\n{}", res);
+                    warn!("Response:\n{}", String::from_utf8_lossy(&res));
 
-                    return_on_err!(stream.write(res.as_bytes()));
+                    return_on_err!(stream.write_all(&res));
                     return_on_err!(stream.flush());
 
                     if starttls {
@@ -150,8 +196,21 @@ impl ImapSession {
         }
     }
 
-    /// Interprets a client command and generates a String response
-    fn interpret(
+    /// Interprets a client command and generates the response. FETCH sends
+    /// messages as they are stored, which need not be UTF-8, so the response
+    /// is bytes.
+    fn interpret(&mut self, cmd: &str, args: &mut Split<char>, tag: &str, bad_res: String) -> Vec<u8> {
+        let mut uid_args = args.clone();
+        match (cmd, uid_args.next().map(|uidcmd| uidcmd.to_ascii_lowercase())) {
+            ("fetch", _) => self.fetch(args, false, tag, bad_res),
+            ("uid", Some(ref uidcmd)) if uidcmd == "fetch" => self.fetch(&mut uid_args, true, tag, bad_res),
+            _ => self.interpret_text(cmd, args, tag, bad_res).into_bytes(),
+        }
+    }
+
+    /// Interprets a client command other than FETCH and generates a String
+    /// response
+    fn interpret_text(
         &mut self,
         cmd: &str,
         args: &mut Split<char>,
@@ -161,6 +220,11 @@ impl ImapSession {
         // The argument after the tag specified the command issued.
         // Additional arguments are arguments for that specific command.
         match cmd {
+            "namespace" => {
+                let (other_users, shared) = self.serv.namespaces();
+                let res = namespace::response(self.serv.conf.delimiter, other_users, shared, self.utf8);
+                format!("{}{} OK NAMESPACE completed\r\n", res, tag)
+            }
             "noop" => {
                 let mut res = tag.to_string();
                 res += " OK NOOP\r\n";
@@ -170,7 +234,9 @@ impl ImapSession {
             // Inform the client of the supported IMAP version and
             // extension(s)
             "capability" => {
-                let mut res = "* CAPABILITY IMAP4rev1 CHILDREN\r\n".to_string();
+                let mut res =
+                    "* CAPABILITY IMAP4rev1 ACL CHILDREN ENABLE CONDSTORE CREATE-SPECIAL-USE ESEARCH LIST-EXTENDED LIST-STATUS MOVE NAMESPACE QRESYNC QUOTA QUOTA=RES-MESSAGE QUOTA=RES-STORAGE QUOTASET RIGHTS=texk SEARCHRES SORT SORT=DISPLAY SPECIAL-USE THREAD=ORDEREDSUBJECT THREAD=REFERENCES UIDPLUS UNSELECT UTF8=ACCEPT\r\n"
+                        .to_string();
                 res.push_str(tag);
                 res.push_str(" OK Capability successful\r\n");
                 res
@@ -186,6 +252,11 @@ impl ImapSession {
                 no_res.push_str(" NO invalid username or password\r\n");
                 if let Some(user) = self.serv.login(email.to_string(), password.to_string()) {
                     self.maildir = Some(user.maildir.clone());
+                    self.special_use = self.serv.special_use(user);
+                    self.layout = self.serv.layout(user);
+                    self.identifier = user.email.to_string();
+                    self.roots = self.serv.roots(user);
+                    self.admin = user.admin;
                 } else {
                     return no_res;
                 }
@@ -206,9 +277,13 @@ impl ImapSession {
 
                 // Write out current state of selected folder (if any)
                 // to disk
-                if let Some(ref folder) = self.folder {
-                    folder.expunge();
+                if let Some(ref mut folder) = self.folder {
+                    if self.rights.contains('e') {
+                        folder.expunge(None);
+                    }
+                    folder.check();
                 }
+                self.deselect();
 
                 let mut res = "* BYE Server logging out\r\n".to_string();
                 res.push_str(tag);
@@ -216,71 +291,129 @@ impl ImapSession {
                 res
             }
             // Examine and Select should be nearly identical...
-            "select" => {
-                let maildir = match self.maildir {
-                    None => {
-                        return bad_res;
+            "select" => self.select(args, false, tag, bad_res),
+            "examine" => self.select(args, true, tag, bad_res),
+            // Turn on extensions which change how the server responds
+            // (RFC 5161)
+            "enable" => {
+                if self.maildir.is_none() {
+                    return bad_res;
+                }
+                // Report what this command turned on, including CONDSTORE
+                // when it is asked for along with QRESYNC
+                let (condstore, qresync) = (self.condstore, self.qresync);
+                let mut enabled = Vec::new();
+                for capability in args {
+                    match &capability.to_ascii_uppercase()[..] {
+                        "CONDSTORE" if !condstore && !enabled.contains(&"CONDSTORE") => {
+                            self.condstore = true;
+                            enabled.push("CONDSTORE");
+                        }
+                        "QRESYNC" if !qresync && !enabled.contains(&"QRESYNC") => {
+                            self.condstore = true;
+                            self.qresync = true;
+                            enabled.push("QRESYNC");
+                        }
+                        "UTF8=ACCEPT" if !self.utf8 => {
+                            self.utf8 = true;
+                            enabled.push("UTF8=ACCEPT");
+                        }
+                        _ => {}
                     }
-                    Some(ref maildir) => maildir,
-                };
-                let (folder, res) =
-                    util::perform_select(&maildir[..], &args.collect::<Vec<&str>>(), false, tag);
-                self.folder = folder;
-                match self.folder {
-                    None => bad_res,
-                    _ => res,
                 }
-            }
-            "examine" => {
-                let maildir = match self.maildir {
-                    None => {
-                        return bad_res;
+                if self.condstore {
+                    if let Some(ref mut folder) = self.folder {
+                        folder.enable_condstore();
                     }
-                    Some(ref maildir) => maildir,
-                };
-                let (folder, res) =
-                    util::perform_select(&maildir[..], &args.collect::<Vec<&str>>(), true, tag);
-                self.folder = folder;
-                match self.folder {
-                    None => bad_res,
-                    _ => res,
                 }
+                let mut res = "* ENABLED".to_string();
+                for capability in enabled {
+                    res.push(' ');
+                    res.push_str(capability);
+                }
+                res.push_str("\r\n");
+                res.push_str(tag);
+                res.push_str(" OK Enable completed\r\n");
+                res
             }
             "create" => {
                 let create_args: Vec<&str> = args.collect();
                 if create_args.len() < 1 {
                     return bad_res;
                 }
-                let mbox_name = create_args[0].trim_matches('"').replace("INBOX", "");
+                // The folder may be made the one with some special uses
+                // (RFC 6154)
+                let uses = if create_args.len() > 1 {
+                    let params = create_args[1..].join(" ");
+                    let names = match special_use::parse_create_params(&params) {
+                        Some(names) => names,
+                        None => return bad_res,
+                    };
+                    let uses: Option<Vec<&'static str>> = names
+                        .iter()
+                        .map(|name| name.strip_prefix('\\').and(special_use::attribute(name)))
+                        .collect();
+                    match uses {
+                        Some(uses) => uses,
+                        None => return format!("{} NO [USEATTR] Unsupported special use\r\n", tag),
+                    }
+                } else {
+                    Vec::new()
+                };
+                let name = match self.mailbox_name(create_args[0]) {
+                    Some(name) => name,
+                    None => return bad_res,
+                };
                 match self.maildir {
                     None => bad_res,
                     Some(ref maildir) => {
                         let mut no_res = tag.to_string();
                         no_res.push_str(" NO Could not create folder.\r\n");
-                        let maildir_path = Path::new(&maildir[..]).join(mbox_name);
-
-                        // Create directory for new mail
-                        let newmaildir_path = maildir_path.join("new");
-                        if fs::create_dir_all(&newmaildir_path).is_err() {
-                            return no_res;
+                        let location = match namespace::locate(&self.roots, &name) {
+                            Some(location) => location,
+                            None => return format!("{} NO [CANNOT] Invalid mailbox name\r\n", tag),
+                        };
+                        if location.is_inbox() {
+                            return format!("{} NO [ALREADYEXISTS] INBOX always exists\r\n", tag);
                         }
-                        if fs::set_permissions(&newmaildir_path, fs::Permissions::from_mode(0o755))
-                            .is_err()
-                        {
-                            return no_res;
+                        let parent_rights = location.parent_rights(&self.identifier);
+                        if !parent_rights.contains('k') {
+                            return denied(tag, &parent_rights);
+                        }
+                        // Special uses are kept for the user's own mailboxes
+                        let own = location.root.owner.as_ref() == Some(&self.identifier);
+                        if !uses.is_empty() && !own {
+                            return format!("{} NO [USEATTR] Only your own mailboxes have special uses\r\n", tag);
                         }
 
-                        // Create directory for current mail
-                        let curmaildir_path = maildir_path.join("cur");
-                        if fs::create_dir_all(&curmaildir_path).is_err() {
+                        // A Maildir++ directory which is there already may
+                        // hold something other than a folder
+                        let exists = match location.root.layout {
+                            Layout::Nested => location.path.join("cur").is_dir(),
+                            Layout::MaildirPlusPlus => location.path.exists(),
+                        };
+                        if exists {
+                            return format!("{} NO [ALREADYEXISTS] Mailbox already exists\r\n", tag);
+                        }
+
+                        // Create the directories for new and current mail
+                        if location.root.layout.create(&location.path).is_err() {
                             return no_res;
                         }
-                        if fs::set_permissions(&curmaildir_path, fs::Permissions::from_mode(0o755))
-                            .is_err()
-                        {
+                        // A mailbox which someone else owns, or which is
+                        // shared, would otherwise be out of its creator's
+                        // reach
+                        if !own && Acl::load(&location.path).set(&self.identifier, RIGHTS).is_err() {
                             return no_res;
                         }
 
+                        if !uses.is_empty() {
+                            let mut special_use = SpecialUse::load(Path::new(&maildir[..]), &self.special_use);
+                            if special_use.set(&name, &uses).is_err() {
+                                return no_res;
+                            }
+                        }
+
                         let mut ok_res = tag.to_string();
                         ok_res.push_str(" OK CREATE successful.\r\n");
                         ok_res
@@ -292,13 +425,27 @@ impl ImapSession {
                 if delete_args.len() < 1 {
                     return bad_res;
                 }
-                let mbox_name = delete_args[0].trim_matches('"').replace("INBOX", "");
+                let mbox_name = match self.mailbox_name(delete_args[0]) {
+                    Some(name) => name,
+                    None => return bad_res,
+                };
                 match self.maildir {
                     None => bad_res,
-                    Some(ref maildir) => {
+                    Some(_) => {
                         let mut no_res = tag.to_string();
                         no_res.push_str(" NO Invalid folder.\r\n");
-                        let maildir_path = Path::new(&maildir[..]).join(mbox_name);
+                        let location = match namespace::locate(&self.roots, &mbox_name) {
+                            Some(location) => location,
+                            None => return format!("{} NO [CANNOT] Invalid mailbox name\r\n", tag),
+                        };
+                        if location.is_inbox() {
+                            return format!("{} NO [CANNOT] INBOX cannot be deleted\r\n", tag);
+                        }
+                        let rights = location.rights(&self.identifier);
+                        if !rights.contains('x') {
+                            return denied(tag, &rights);
+                        }
+                        let maildir_path = location.path;
                         let newmaildir_path = maildir_path.join("new");
                         let curmaildir_path = maildir_path.join("cur");
                         opendirlisting!(
@@ -336,10 +483,20 @@ impl ImapSession {
                                     return no_res;
                                 }
 
-                                // This folder might contain subfolders
-                                // holding mail. For this reason, we
-                                // leave the other files, and the
-                                // folder itself, in tact.
+                                // In the nested layout this folder might
+                                // contain subfolders holding mail. For
+                                // this reason, we leave the other files,
+                                // and the folder itself, in tact, but not its
+                                // ACL, which a new folder of the same name
+                                // must not get. Maildir++ subfolders are
+                                // beside it instead.
+                                let removed = match location.root.layout {
+                                    Layout::Nested => acl::remove(&maildir_path),
+                                    Layout::MaildirPlusPlus => fs::remove_dir_all(&maildir_path),
+                                };
+                                if removed.is_err() {
+                                    return no_res;
+                                }
                                 let mut ok_res = tag.to_string();
                                 ok_res.push_str(" OK DELETE successsful.\r\n");
                                 ok_res
@@ -348,58 +505,89 @@ impl ImapSession {
                     }
                 }
             }
-            // List folders which match the specified regular expression.
-            "list" => {
-                let list_args: Vec<&str> = args.collect();
-                if list_args.len() < 2 {
+            // List folders which match the specified patterns.
+            "list" => self.list(args, false, tag, bad_res),
+            "lsub" => self.list(args, true, tag, bad_res),
+            "rename" => {
+                if self.maildir.is_none() {
                     return bad_res;
                 }
-                let reference = list_args[0].trim_matches('"');
-                let mailbox_name = list_args[1].trim_matches('"');
-                match self.maildir {
-                    None => bad_res,
-                    Some(ref maildir) => {
-                        if mailbox_name.is_empty() {
-                            return format!(
-                                "* LIST (\\Noselect) \"/\" \"{}\"\r\n{} OK List successful\r\n",
-                                reference, tag
-                            );
-                        }
-                        let mailbox_name = mailbox_name.replace("*", ".*").replace("%", "[^/]*");
-                        let maildir_path = Path::new(&maildir[..]);
-                        let re_opt = Regex::new(
-                            &format!(
-                                "{}{}?{}{}?{}$",
-                                path_filename_to_str!(maildir_path),
-                                MAIN_SEPARATOR,
-                                reference,
-                                MAIN_SEPARATOR,
-                                mailbox_name.replace("INBOX", "")
-                            )[..],
-                        );
-                        match re_opt {
-                            Err(_) => bad_res,
-                            Ok(re) => {
-                                let list_responses = util::list(&maildir[..], &re);
-                                let mut ok_res = String::new();
-                                for list_response in &list_responses {
-                                    ok_res.push_str(&list_response[..]);
-                                    ok_res.push_str("\r\n");
-                                }
-                                ok_res.push_str(tag);
-                                ok_res.push_str(" OK list successful\r\n");
-                                ok_res
-                            }
-                        }
+                let rename_args: Vec<&str> = args.collect();
+                if rename_args.len() < 2 {
+                    return bad_res;
+                }
+                let (from, to) = match (self.mailbox_name(rename_args[0]), self.mailbox_name(rename_args[1])) {
+                    (Some(from), Some(to)) => (from, to),
+                    _ => return bad_res,
+                };
+                let (from, to) = match (namespace::locate(&self.roots, &from), namespace::locate(&self.roots, &to)) {
+                    (Some(from), Some(to)) => (from, to),
+                    _ => return format!("{} NO [CANNOT] Invalid mailbox name\r\n", tag),
+                };
+                let (from_levels, to_levels) = (from.levels(), to.levels());
+                // Renaming INBOX moves its mail to a new folder, which is
+                // not supported
+                if from.is_inbox() || to.is_inbox() {
+                    return format!("{} NO [CANNOT] INBOX cannot be renamed\r\n", tag);
+                }
+                if from.root != to.root {
+                    return format!("{} NO [CANNOT] Mailboxes cannot move between namespaces\r\n", tag);
+                }
+                if to_levels.starts_with(&from_levels) {
+                    return format!("{} NO [CANNOT] A mailbox cannot be moved into itself\r\n", tag);
+                }
+                let rights = from.rights(&self.identifier);
+                if !from.path.join("cur").is_dir() || !rights.contains('x') {
+                    return denied(tag, &rights);
+                }
+                let parent_rights = to.parent_rights(&self.identifier);
+                if !parent_rights.contains('k') {
+                    return denied(tag, &parent_rights);
+                }
+                if to.path.join("cur").is_dir() {
+                    return format!("{} NO [ALREADYEXISTS] Mailbox already exists\r\n", tag);
+                }
+                match from.root.layout.rename(&from.root.path, &from_levels, &to_levels) {
+                    Ok(()) => format!("{} OK RENAME completed\r\n", tag),
+                    Err(e) => {
+                        warn!("Error renaming '{}': {}", from.path.display(), e);
+                        format!("{} NO RENAME failed\r\n", tag)
                     }
                 }
             }
+            "subscribe" | "unsubscribe" => {
+                let maildir = match self.maildir {
+                    Some(ref maildir) => maildir.clone(),
+                    None => return bad_res,
+                };
+                let name = match args.next().and_then(|name| self.mailbox_name(name)) {
+                    Some(name) => name,
+                    None => return bad_res,
+                };
+                if namespace::locate(&self.roots, &name).is_none() {
+                    return format!("{} NO [CANNOT] Invalid mailbox name\r\n", tag);
+                }
+                let mut subscriptions = Subscriptions::load(Path::new(&maildir));
+                let res = if cmd == "subscribe" {
+                    subscriptions.subscribe(&name).map(|_| true)
+                } else {
+                    subscriptions.unsubscribe(&name)
+                };
+                match res {
+                    Ok(true) => format!("{} OK {} completed\r\n", tag, cmd.to_uppercase()),
+                    Ok(false) => format!("{} NO Not subscribed to {}\r\n", tag, name),
+                    Err(_) => format!("{} NO {} failed\r\n", tag, cmd.to_uppercase()),
+                }
+            }
+            "status" => self.status(args, tag, bad_res),
+            // Access control lists (RFC 4314)
+            "getacl" | "setacl" | "deleteacl" | "listrights" | "myrights" => self.acl(cmd, args, tag, bad_res),
+            // Quotas (RFC 9208)
+            "getquota" | "getquotaroot" | "setquota" => self.quota(cmd, args, tag, bad_res),
             // Resolve state of folder in memory with state of mail on
             // disk
             "check" => {
-                match self.expunge() {
-                    _ => {}
-                }
+                let _ = self.expunge(None);
                 match self.folder {
                     None => bad_res,
                     Some(ref mut folder) => {
@@ -412,62 +600,31 @@ impl ImapSession {
             }
             // Close the currently selected folder. Perform all
             // required cleanup.
-            "close" => match self.expunge() {
+            "close" => match self.expunge(None) {
                 Err(_) => bad_res,
                 Ok(_) => {
                     if let Some(ref mut folder) = self.folder {
                         folder.check();
                     }
-                    self.folder = None;
+                    self.deselect();
                     format!("{} OK close completed\r\n", tag)
                 }
             },
-            // Delete the messages currently marked for deletion.
-            "expunge" => match self.expunge() {
-                Err(_) => bad_res,
-                Ok(v) => {
-                    let mut ok_res = String::new();
-                    for i in &v {
-                        ok_res.push_str("* ");
-                        ok_res.push_str(&i.to_string()[..]);
-                        ok_res.push_str(" EXPUNGE\r\n");
-                    }
-                    ok_res.push_str(tag);
-                    ok_res.push_str(" OK expunge completed\r\n");
-                    ok_res
+            // Close the selected folder without expunging anything (RFC
+            // 3691)
+            "unselect" => match self.folder {
+                None => bad_res,
+                Some(ref mut folder) => {
+                    folder.check();
+                    self.deselect();
+                    format!("{} OK unselect completed\r\n", tag)
                 }
             },
-            "fetch" => {
-                // Retrieve the current folder, if it exists.
-                // If it doesn't, the command is invalid.
-                let folder = match self.folder {
-                    Some(ref mut folder) => folder,
-                    None => return bad_res,
-                };
-
-                // Parse command, make sure it is validly formed.
-                let parsed_cmd = match fetch::fetch(args.collect()) {
-                    Ok(cmd) => cmd,
-                    _ => return bad_res,
-                };
-
-                /*
-                 * Verify that the requested sequence set is valid.
-                 *
-                 * Per RFC 3501 seq-number definition:
-                 * "The server should respond with a tagged BAD
-                 * response to a command that uses a message
-                 * sequence number greater than the number of
-                 * messages in the selected mailbox. This
-                 * includes "*" if the selected mailbox is empty."
-                 */
-                let sequence_iter =
-                    sequence_set::iterator(&parsed_cmd.sequence_set, folder.message_count());
-                if sequence_iter.is_empty() {
-                    return bad_res;
-                }
-                fetch::fetch_loop(&parsed_cmd, folder, &sequence_iter, tag, false)
-            }
+            // Delete the messages currently marked for deletion.
+            "expunge" => self.expunge_response(None, tag, bad_res),
+            "copy" => self.copy(args, false, tag, bad_res),
+            "move" => self.move_messages(args, false, tag, bad_res),
+            "search" | "sort" | "thread" => self.search(cmd, args, false, tag, bad_res),
             // These commands use UIDs instead of sequence numbers.
             // Sequence numbers map onto the list of messages in the
             // folder directly and change whenever messages are added
@@ -476,66 +633,24 @@ impl ImapSession {
                 match args.next() {
                     Some(uidcmd) => {
                         match &uidcmd.to_ascii_lowercase()[..] {
-                            "fetch" => {
-                                // Retrieve the current folder, if it
-                                // exists.
-                                let folder = match self.folder {
-                                    Some(ref mut folder) => folder,
+                            // Only expunge the messages with the given
+                            // UIDs (RFC 4315)
+                            "expunge" => {
+                                let set = match args.next().and_then(sequence_set::parse) {
+                                    Some(set) => set,
                                     None => return bad_res,
                                 };
-                                // Parse the command with the PEG
-                                // parser.
-                                let mut parsed_cmd = match fetch::fetch(args.collect()) {
-                                    Ok(cmd) => cmd,
-                                    _ => return bad_res,
-                                };
-                                parsed_cmd.attributes.push(UID);
-
-                                // SPECIAL CASE FOR RANGES WITH WILDCARDS
-                                if let Range(ref a, ref b) = parsed_cmd.sequence_set[0] {
-                                    if let Number(n) = **a {
-                                        if let Wildcard = **b {
-                                            if folder.message_count() == 0 {
-                                                return bad_res;
-                                            }
-                                            let start = match folder.get_index_from_uid(&n) {
-                                                Some(start) => *start,
-                                                None => {
-                                                    if n == 1 {
-                                                        0usize
-                                                    } else {
-                                                        return bad_res;
-                                                    }
-                                                }
-                                            };
-                                            let mut res = String::new();
-                                            for index in start..folder.message_count() {
-                                                res.push_str(&folder.fetch(index+1, &parsed_cmd.attributes)[..]);
-                                            }
-                                            res.push_str(tag);
-                                            res.push_str(" OK UID FETCH completed\r\n");
-                                            return res;
-                                        }
+                                let uids = match self.folder {
+                                    Some(ref folder) => {
+                                        folder.uid_set(&sequence_set::resolve(set, &folder.saved(true)))
                                     }
+                                    None => return bad_res,
                                 };
-
-                                /*
-                                 * Verify that the requested sequence set is valid.
-                                 *
-                                 * Per RFC 3501 seq-number definition:
-                                 * "The server should respond with a tagged BAD
-                                 * response to a command that uses a message
-                                 * sequence number greater than the number of
-                                 * messages in the selected mailbox. This
-                                 * includes "*" if the selected mailbox is empty."
-                                 */
-                                let sequence_iter =
-                                    sequence_set::uid_iterator(&parsed_cmd.sequence_set);
-                                if sequence_iter.is_empty() {
-                                    return bad_res;
-                                }
-                                fetch::fetch_loop(&parsed_cmd, folder, &sequence_iter, tag, true)
+                                self.expunge_response(Some(&uids), tag, bad_res)
                             }
+                            "copy" => self.copy(args, true, tag, bad_res),
+                            "move" => self.move_messages(args, true, tag, bad_res),
+                            cmd @ ("search" | "sort" | "thread") => self.search(cmd, args, true, tag, bad_res),
                             "store" => {
                                 // There should be a folder selected.
                                 let folder = match self.folder {
@@ -543,8 +658,7 @@ impl ImapSession {
                                     Some(ref mut folder) => folder,
                                 };
 
-                                match store::store(folder, &args.collect::<Vec<&str>>(), true, tag)
-                                {
+                                match store::store(folder, &args.collect::<Vec<&str>>(), true, tag, &self.rights) {
                                     Some(res) => res,
                                     _ => bad_res,
                                 }
@@ -564,7 +678,7 @@ impl ImapSession {
                     Some(ref mut folder) => folder,
                 };
 
-                match store::store(folder, &args.collect::<Vec<&str>>(), false, tag) {
+                match store::store(folder, &args.collect::<Vec<&str>>(), false, tag, &self.rights) {
                     Some(res) => res,
                     _ => bad_res,
                 }
@@ -573,11 +687,1031 @@ impl ImapSession {
         }
     }
 
-    // should generate list of sequence numbers that were deleted
-    fn expunge(&self) -> Result<Vec<usize>, Error> {
+    /// FETCH or UID FETCH. VANISHED is only for UID FETCH, once QRESYNC is
+    /// enabled.
+    fn fetch(&mut self, args: &mut Split<char>, uid: bool, tag: &str, bad_res: String) -> Vec<u8> {
+        // Retrieve the current folder, if it exists.
+        // If it doesn't, the command is invalid.
+        let folder = match self.folder {
+            Some(ref mut folder) => folder,
+            None => return bad_res.into_bytes(),
+        };
+
+        // Parse command, make sure it is validly formed.
+        let mut parsed_cmd = match fetch::fetch(args.collect()) {
+            Ok(ref cmd) if cmd.vanished && !(uid && self.qresync) => return bad_res.into_bytes(),
+            Ok(cmd) => cmd,
+            _ => return bad_res.into_bytes(),
+        };
+        if uid {
+            parsed_cmd.attributes.push(UID);
+        }
+        // An empty saved result is fine
+        let saved = sequence_set::is_saved(&parsed_cmd.sequence_set);
+        parsed_cmd.sequence_set = sequence_set::resolve(parsed_cmd.sequence_set, &folder.saved(uid));
+
+        // UIDs which are not in use are not an error
+        if uid {
+            let uids = folder.uid_set(&parsed_cmd.sequence_set);
+            return fetch::fetch_loop(&parsed_cmd, folder, &uids, tag, true, self.rights.contains('s'));
+        }
+
+        /*
+         * Verify that the requested sequence set is valid.
+         *
+         * Per RFC 3501 seq-number definition:
+         * "The server should respond with a tagged BAD
+         * response to a command that uses a message
+         * sequence number greater than the number of
+         * messages in the selected mailbox. This
+         * includes "*" if the selected mailbox is empty."
+         */
+        let sequence_iter: Vec<usize> =
+            IntervalSet::new(&parsed_cmd.sequence_set, folder.message_count()).iter().collect();
+        if sequence_iter.is_empty() && !saved {
+            return bad_res.into_bytes();
+        }
+        fetch::fetch_loop(&parsed_cmd, folder, &sequence_iter, tag, false, self.rights.contains('s'))
+    }
+
+    /// SELECT or EXAMINE a folder, taking the CONDSTORE or QRESYNC
+    /// parameter of RFC 7162 after the folder name.
+    fn select(&mut self, args: &mut Split<char>, examine: bool, tag: &str, bad_res: String) -> String {
+        if self.maildir.is_none() {
+            return bad_res;
+        }
+        let select_args: Vec<&str> = args.collect();
+        let name = match select_args.first().and_then(|name| self.mailbox_name(name)) {
+            Some(name) => name,
+            None => return bad_res,
+        };
+        let param = match util::parse_select_param(&select_args.get(1..).unwrap_or(&[]).join(" ")) {
+            Some(param) => param,
+            None => return bad_res,
+        };
+        let resync = match param {
+            SelectParam::QResync { .. } if !self.qresync => return bad_res,
+            SelectParam::CondStore | SelectParam::QResync { .. } => {
+                self.condstore = true;
+                Some(param)
+            }
+            SelectParam::None => None,
+        };
+        // Using CONDSTORE on the previous folder enabled it for the session
+        if let Some(ref folder) = self.folder {
+            self.condstore |= folder.is_condstore();
+        }
+        let location = match namespace::locate(&self.roots, &name) {
+            Some(location) => location,
+            None => return bad_res,
+        };
+        // Without the right to change anything the folder is read-only
+        let rights = location.rights(&self.identifier);
+        if !rights.contains('r') {
+            return denied(tag, &rights);
+        }
+        let read_only = examine || !"stwe".chars().any(|right| rights.contains(right));
+        let fts = self.owner_indexes(&location.root);
+        // The previous folder is closed even if this one can't be selected
+        self.deselect();
+        let (folder, res) = util::perform_select(location.path, read_only, tag, resync);
+        self.folder = folder;
+        self.rights = rights;
+        match self.folder {
+            None => bad_res,
+            Some(ref mut folder) => {
+                if self.condstore {
+                    folder.enable_condstore();
+                }
+                if fts {
+                    folder.enable_fts();
+                }
+                res
+            }
+        }
+    }
+
+    /// The name of a mailbox, or a LIST pattern, as it is stored, from its
+    /// name on the wire. None if it is not valid modified UTF-7.
+    fn mailbox_name(&self, arg: &str) -> Option<String> {
+        let name = arg.trim_matches('"');
+        let name = if self.utf8 {
+            name.to_string()
+        } else {
+            utf7::decode(name)?
+        };
+        Some(self.serv.conf.delimiter.stored_name(&name))
+    }
+
+    /// LIST or LSUB the folders matching the patterns given.
+    fn list(&mut self, args: &mut Split<char>, lsub: bool, tag: &str, bad_res: String) -> String {
+        let maildir = match self.maildir {
+            Some(ref maildir) => PathBuf::from(maildir),
+            None => return bad_res,
+        };
+        let mut cmd = match list::parse(&args.collect::<Vec<&str>>().join(" "), lsub) {
+            Some(cmd) => cmd,
+            None => return bad_res,
+        };
+        let patterns: Option<Vec<String>> = cmd.patterns.iter().map(|pattern| self.mailbox_name(pattern)).collect();
+        match (self.mailbox_name(&cmd.reference), patterns) {
+            (Some(reference), Some(patterns)) => {
+                cmd.reference = reference;
+                cmd.patterns = patterns;
+            }
+            _ => return bad_res,
+        }
+        cmd.utf8 = self.utf8;
+        cmd.delimiter = self.serv.conf.delimiter;
+        let special_use = SpecialUse::load(&maildir, &self.special_use);
+        let subscriptions = Subscriptions::load(&maildir);
+        let selected = self.folder.as_ref();
+        let res = list::list(&cmd, &self.roots, &self.identifier, &special_use, &subscriptions, selected);
+        format!("{}{} OK {} completed\r\n", res, tag, if lsub { "LSUB" } else { "LIST" })
+    }
+
+    /// STATUS of a folder. The selected folder answers from this session's
+    /// view of it; any other is opened read-only, so its messages in new/
+    /// stay unclaimed and count as recent.
+    fn status(&mut self, args: &mut Split<char>, tag: &str, bad_res: String) -> String {
+        if self.maildir.is_none() {
+            return bad_res;
+        }
+        let status_args: Vec<&str> = args.collect();
+        if status_args.len() < 2 {
+            return bad_res;
+        }
+        let name = match self.mailbox_name(status_args[0]) {
+            Some(name) => name,
+            None => return bad_res,
+        };
+        let items = status_args[1..].join(" ");
+        let items: Vec<&str> = items.trim_start_matches('(').trim_end_matches(')').split_whitespace().collect();
+        let location = match namespace::locate(&self.roots, &name) {
+            Some(location) => location,
+            None => return format!("{} NO [CANNOT] Invalid mailbox name\r\n", tag),
+        };
+        let rights = location.rights(&self.identifier);
+        if !rights.contains('r') {
+            return denied(tag, &rights);
+        }
+        let path = location.path;
+        let status = match self.folder {
+            Some(ref folder) if folder.get_path() == path => folder.status(&items),
+            _ => match Folder::new(path, true) {
+                Some(folder) => folder.status(&items),
+                None => return format!("{} NO STATUS failed: no such folder\r\n", tag),
+            },
+        };
+        match status {
+            Some(status) => format!("* STATUS {} ({})\r\n{} OK STATUS completed\r\n", util::wire_name(&name, self.serv.conf.delimiter, self.utf8), status, tag),
+            None => bad_res,
+        }
+    }
+
+    /// Expunge the messages marked for deletion, out of `uids` if given,
+    /// and respond with the sequence numbers or, under QRESYNC, the UIDs of
+    /// the messages expunged.
+    fn expunge_response(&mut self, uids: Option<&[usize]>, tag: &str, bad_res: String) -> String {
+        if self.folder.is_some() && !self.rights.contains('e') {
+            return format!("{} NO [NOPERM] Not allowed to expunge this folder\r\n", tag);
+        }
+        match self.expunge(uids) {
+            Err(_) => bad_res,
+            Ok(v) => {
+                let mut ok_res = self.expunged(&v);
+                ok_res.push_str(tag);
+                ok_res.push_str(" OK expunge completed\r\n");
+                ok_res
+            }
+        }
+    }
+
+    /// The untagged responses telling of the messages just expunged, given
+    /// their sequence numbers.
+    fn expunged(&self, v: &[usize]) -> String {
+        let mut res = String::new();
+        match self.folder {
+            // Under QRESYNC, the UIDs of expunged messages are
+            // sent instead of their sequence numbers
+            Some(ref folder) if self.qresync => {
+                if !v.is_empty() {
+                    let uids = folder.vanished_since(folder.highest_modseq() - v.len());
+                    res.push_str("* VANISHED ");
+                    res.push_str(&sequence_set::format(&uids));
+                    res.push_str("\r\n");
+                }
+            }
+            _ => {
+                for i in v {
+                    res.push_str("* ");
+                    res.push_str(&i.to_string()[..]);
+                    res.push_str(" EXPUNGE\r\n");
+                }
+            }
+        }
+        res
+    }
+
+    /// APPEND a message, sent as a literal at the end of the command, to a
+    /// folder. The optional flags are kept; the date-time is not, as the
+    /// internal date of a message is its UID.
+    fn append<S: BufRead + Write>(
+        &mut self,
+        stream: &mut S,
+        args: &mut Split<char>,
+        tag: &str,
+        bad_res: String,
+    ) -> String {
+        if self.maildir.is_none() {
+            return bad_res;
+        }
+        let append_args: Vec<&str> = args.collect();
+        if append_args.len() < 2 {
+            return bad_res;
+        }
+        let mut literal = append_args[append_args.len() - 1];
+        let mut middle_args = &append_args[1..append_args.len() - 1];
+        // Under UTF8=ACCEPT the message may come as "UTF8 (~{n})", which
+        // makes no difference to us
+        if let Some(utf8_literal) = literal.strip_prefix("(~").filter(|_| self.utf8) {
+            match middle_args.split_last() {
+                Some((utf8, rest)) if utf8.eq_ignore_ascii_case("UTF8") => middle_args = rest,
+                _ => return bad_res,
+            }
+            literal = utf8_literal;
+        }
+        let size = match literal.strip_prefix('{').and_then(|l| l.strip_suffix('}')) {
+            Some(size) => size,
+            None => return bad_res,
+        };
+        // A non-synchronizing literal is sent without waiting for us
+        let (size, sync) = match size.strip_suffix('+') {
+            Some(size) => (size, false),
+            None => (size, true),
+        };
+        let size: usize = match size.parse() {
+            Ok(size) => size,
+            Err(_) => return bad_res,
+        };
+        if size > self.serv.conf.imap_max_size {
+            if sync {
+                return format!("{} NO [TOOBIG] Message too large\r\n", tag);
+            }
+            // The literal is on its way and too large to read past, so the
+            // connection cannot go on
+            self.logout = true;
+            return format!("* BYE Message too large\r\n{} BAD [TOOBIG] Message too large\r\n", tag);
+        }
+
+        // Everything but the message is checked before it is read. A
+        // synchronizing literal is never sent if it is refused, while a
+        // non-synchronizing one has to be read past.
+        let checked = match self.append_location(append_args[0], size, tag, &bad_res) {
+            Ok(location) => {
+                if sync && stream.write_all(b"+ Ready for literal data\r\n").and_then(|_| stream.flush()).is_err() {
+                    return bad_res;
+                }
+                Ok(location)
+            }
+            Err(res) if sync => return res,
+            Err(res) => Err(res),
+        };
+        let mut data = Vec::new();
+        let mut rest = String::new();
+        let read = match checked {
+            Ok(_) => Read::take(&mut *stream, size as u64).read_to_end(&mut data),
+            Err(_) => io::copy(&mut Read::take(&mut *stream, size as u64), &mut io::sink()).map(|n| n as usize),
+        };
+        if read.ok() != Some(size) || stream.read_line(&mut rest).is_err() {
+            return bad_res;
+        }
+        let (location, rights) = match checked {
+            Ok(location) => location,
+            Err(res) => return res,
+        };
+
+        // The flags come in parentheses between the folder name and the
+        // date-time or literal
+        let middle = middle_args.join(" ");
+        let mut flags: Vec<String> = match middle.strip_prefix('(') {
+            Some(flags) => match flags.split(')').next() {
+                Some(flags) => flags.split_whitespace().map(|f| f.to_string()).collect(),
+                None => return bad_res,
+            },
+            None => Vec::new(),
+        };
+        let path = location.path;
+        flags.retain(|flag| may_set(&rights, flag));
+        let data = unix_line_endings(&data);
+        let mut quota = self.owner_quota(&location.root);
+        if quota.as_mut().is_some_and(|quota| quota.check(data.len() as u64, 1, 0).is_err()) {
+            return format!("{} NO [OVERQUOTA] Quota exceeded\r\n", tag);
+        }
+        match lmtp::store_with_uid(&path, &data, &flags) {
+            Ok((uid, _)) => {
+                if self.owner_indexes(&location.root) {
+                    fts::add_message(&path, uid, &data);
+                }
+                if let Some(Err(e)) = quota.map(|mut quota| quota.add(data.len() as u64, 1)) {
+                    warn!("Error updating the quota of '{}': {}", location.root.path.display(), e);
+                }
+                format!("{} OK [APPENDUID {} {}] APPEND completed\r\n", tag, UIDVALIDITY, uid)
+            }
+            Err(e) => {
+                warn!("Error appending to '{}': {}", path.display(), e);
+                format!("{} NO Could not append message\r\n", tag)
+            }
+        }
+    }
+
+    /// The mailbox APPEND puts a message of a size into, along with the
+    /// user's rights on it, or else the response refusing it.
+    fn append_location(&self, name: &str, size: usize, tag: &str, bad_res: &str) -> Result<(Location, String), String> {
+        let name = self.mailbox_name(name).ok_or_else(|| bad_res.to_string())?;
+        let location = namespace::locate(&self.roots, &name)
+            .ok_or_else(|| format!("{} NO [CANNOT] Invalid mailbox name\r\n", tag))?;
+        let rights = location.rights(&self.identifier);
+        if !rights.contains('i') {
+            return Err(denied(tag, &rights));
+        }
+        if !location.path.join("cur").is_dir() {
+            return Err(format!("{} NO [TRYCREATE] No such folder\r\n", tag));
+        }
+        // The literal may shrink a little once its line endings are
+        // converted, which is checked again once it is in
+        if let Some(mut quota) = self.owner_quota(&location.root) {
+            if quota.check(size as u64, 1, 0).is_err() {
+                return Err(format!("{} NO [OVERQUOTA] Quota exceeded\r\n", tag));
+            }
+        }
+        Ok((location, rights))
+    }
+
+    /// COPY messages of the selected folder, by sequence number or by UID,
+    /// to another folder. The response maps their UIDs onto those of the
+    /// copies (RFC 4315).
+    fn copy(&mut self, args: &mut Split<char>, uid: bool, tag: &str, bad_res: String) -> String {
+        match self.copy_messages(args, uid, tag, bad_res) {
+            Ok((source_uids, _)) if source_uids.is_empty() => format!("{} OK COPY completed\r\n", tag),
+            Ok((source_uids, copy_uids)) => {
+                format!("{} OK [{}] COPY completed\r\n", tag, copyuid(&source_uids, &copy_uids))
+            }
+            Err(res) => res,
+        }
+    }
+
+    /// MOVE messages to another folder (RFC 6851): COPY them, then expunge
+    /// them from the selected folder, which takes the rights to.
+    fn move_messages(&mut self, args: &mut Split<char>, uid: bool, tag: &str, bad_res: String) -> String {
+        match self.folder {
+            None => return bad_res,
+            Some(ref folder) if folder.is_readonly() => {
+                return format!("{} NO [READ-ONLY] Folder is read-only\r\n", tag);
+            }
+            Some(_) if !self.rights.contains('t') || !self.rights.contains('e') => {
+                return format!("{} NO [NOPERM] Not allowed to expunge this folder\r\n", tag);
+            }
+            Some(_) => {}
+        }
+        let (source_uids, copy_uids) = match self.copy_messages(args, uid, tag, bad_res) {
+            Ok(uids) => uids,
+            Err(res) => return res,
+        };
+        if source_uids.is_empty() {
+            return format!("{} OK MOVE completed\r\n", tag);
+        }
+        let mut res = format!("* OK [{}] Moved\r\n", copyuid(&source_uids, &copy_uids));
+        if let Some(ref mut folder) = self.folder {
+            for uid in &source_uids {
+                if let Some(&index) = folder.get_index_from_uid(uid) {
+                    folder.set_flag(index, Flag::Deleted, true);
+                }
+            }
+            let expunged = folder.expunge(Some(&source_uids));
+            res.push_str(&self.expunged(&expunged));
+        }
+        res.push_str(tag);
+        res.push_str(" OK MOVE completed\r\n");
+        res
+    }
+
+    /// Copy the messages named by a COPY or MOVE command, all or nothing.
+    /// Gives the UIDs of the messages copied and of their copies, in order,
+    /// or else the response to send.
+    fn copy_messages(
+        &mut self,
+        args: &mut Split<char>,
+        uid: bool,
+        tag: &str,
+        bad_res: String,
+    ) -> Result<(Vec<usize>, Vec<usize>), String> {
+        let folder = match self.folder {
+            Some(ref folder) => folder,
+            None => return Err(bad_res),
+        };
+        let copy_args: Vec<&str> = args.collect();
+        if copy_args.len() < 2 {
+            return Err(bad_res);
+        }
+        let set = match sequence_set::parse(copy_args[0]) {
+            Some(set) => set,
+            None => return Err(bad_res),
+        };
+        let saved = sequence_set::is_saved(&set);
+        let set = sequence_set::resolve(set, &folder.saved(uid));
+        let mut indices: Vec<usize> = if uid {
+            folder
+                .uid_set(&set)
+                .iter()
+                .filter_map(|uid| folder.get_index_from_uid(uid).cloned())
+                .collect()
+        } else {
+            let indices = IntervalSet::new(&set, folder.message_count());
+            if indices.is_empty() && !saved {
+                return Err(bad_res);
+            }
+            indices.iter().map(|i| i - 1).collect()
+        };
+        indices.sort_by_key(|&i| folder.message(i).map(|message| message.get_uid()));
+
+        let name = match self.mailbox_name(&copy_args[1..].join(" ")) {
+            Some(name) => name,
+            None => return Err(bad_res),
+        };
+        let location = match namespace::locate(&self.roots, &name) {
+            Some(location) => location,
+            None => return Err(format!("{} NO [CANNOT] Invalid mailbox name\r\n", tag)),
+        };
+        let rights = location.rights(&self.identifier);
+        if !rights.contains('i') {
+            return Err(denied(tag, &rights));
+        }
+        let path = location.path;
+        if !path.join("cur").is_dir() {
+            return Err(format!("{} NO [TRYCREATE] No such folder\r\n", tag));
+        }
+        let mut quota = self.owner_quota(&location.root);
+        let fts = self.owner_indexes(&location.root);
+        if let Some(ref mut quota) = quota {
+            let messages: Vec<_> = indices.iter().filter_map(|&i| folder.message(i)).collect();
+            let size = messages
+                .iter()
+                .map(|message| fs::metadata(message.get_path()).map_or(0, |metadata| metadata.len()))
+                .sum();
+            if quota.check(size, messages.len() as u64, 0).is_err() {
+                return Err(format!("{} NO [OVERQUOTA] Quota exceeded\r\n", tag));
+            }
+        }
+        // COPY is all or nothing (RFC 3501 section 6.4.7), so the copies
+        // made are removed again if one fails
+        let mut source_uids = Vec::new();
+        let mut copies = Vec::new();
+        let mut copied_size = 0;
+        for message in indices.iter().filter_map(|&i| folder.message(i)) {
+            let flags: Vec<String> = message
+                .get_flags()
+                .iter()
+                .map(|f| flag_name(f).to_string())
+                .filter(|flag| may_set(&rights, flag))
+                .collect();
+            let copied = fs::read(message.get_path()).and_then(|data| {
+                let (uid, copy_path) = lmtp::store_with_uid(&path, &data, &flags)?;
+                if fts {
+                    fts::add_message(&path, uid, &data);
+                }
+                Ok((uid, copy_path, data.len()))
+            });
+            match copied {
+                Ok((copy_uid, copy_path, size)) => {
+                    copied_size += size as u64;
+                    source_uids.push(message.get_uid());
+                    copies.push((copy_uid, copy_path));
+                }
+                Err(e) => {
+                    warn!("Error copying to '{}': {}", path.display(), e);
+                    remove_copies(&path, &copies, fts);
+                    return Err(format!("{} NO Could not copy messages\r\n", tag));
+                }
+            }
+        }
+        if source_uids.is_empty() {
+            return Ok((source_uids, Vec::new()));
+        }
+        if let Some(Err(e)) = quota.map(|mut quota| quota.add(copied_size, source_uids.len() as u64)) {
+            warn!("Error updating the quota of '{}': {}", location.root.path.display(), e);
+        }
+        Ok((source_uids, copies.into_iter().map(|(uid, _)| uid).collect()))
+    }
+
+    /// SEARCH, SORT or THREAD (RFC 5256) on the selected folder, giving
+    /// UIDs rather than sequence numbers with `uid` set.
+    fn search(&mut self, cmd: &str, args: &mut Split<char>, uid: bool, tag: &str, bad_res: String) -> String {
+        let folder = match self.folder {
+            Some(ref mut folder) => folder,
+            None => return bad_res,
+        };
+        let args = args.collect::<Vec<&str>>().join(" ");
+        let res = match cmd {
+            "search" => search::search(folder, &args, uid, tag),
+            "sort" => sort::sort(folder, &args, uid, tag),
+            _ => thread::thread(folder, &args, uid, tag),
+        };
+        res.unwrap_or(bad_res)
+    }
+
+    /// GETACL, SETACL, DELETEACL, LISTRIGHTS and MYRIGHTS (RFC 4314). All
+    /// but MYRIGHTS need the administer right on the mailbox.
+    fn acl(&mut self, cmd: &str, args: &mut Split<char>, tag: &str, bad_res: String) -> String {
+        if self.maildir.is_none() {
+            return bad_res;
+        }
+        let acl_args: Vec<&str> = args.collect();
+        let arg_count = match cmd {
+            "getacl" | "myrights" => 1,
+            "deleteacl" | "listrights" => 2,
+            _ => 3,
+        };
+        if acl_args.len() != arg_count {
+            return bad_res;
+        }
+        let name = match self.mailbox_name(acl_args[0]) {
+            Some(name) => name,
+            None => return bad_res,
+        };
+        let location = match namespace::locate(&self.roots, &name) {
+            Some(location) if location.path.join("cur").is_dir() => location,
+            _ => return format!("{} NO [NONEXISTENT] No such mailbox\r\n", tag),
+        };
+        let rights = location.rights(&self.identifier);
+        let wire_name = util::wire_name(&name, self.serv.conf.delimiter, self.utf8);
+        let command = cmd.to_uppercase();
+        if cmd == "myrights" {
+            if !"lrikxa".chars().any(|right| rights.contains(right)) {
+                return format!("{} NO [NONEXISTENT] No such mailbox\r\n", tag);
+            }
+            return format!("* MYRIGHTS {} {}\r\n{} OK {} completed\r\n", wire_name, astring(&rights), tag, command);
+        }
+        if !rights.contains('a') {
+            return denied(tag, &rights);
+        }
+        let owner = location.root.owner.as_deref();
+        let identifier = acl_args.get(1).map_or("", |identifier| identifier.trim_matches('"'));
+        let res = match cmd {
+            // The owner is listed with every right, which they always have
+            "getacl" => {
+                let acl = Acl::load(&location.path);
+                let mut res = format!("* ACL {}", wire_name);
+                let entries = owner.map(|owner| (owner, RIGHTS)).into_iter();
+                for (identifier, rights) in entries.chain(acl.entries().filter(|&(identifier, _)| Some(identifier) != owner)) {
+                    res.push_str(&format!(" {} {}", astring(identifier), astring(rights)));
+                }
+                res + "\r\n"
+            }
+            // Anyone but the owner may be given any of the rights alone
+            "listrights" => {
+                let rights = if Some(identifier) == owner {
+                    RIGHTS.to_string()
+                } else {
+                    let optional: Vec<String> = RIGHTS.chars().map(|right| right.to_string()).collect();
+                    format!("\"\" {}", optional.join(" "))
+                };
+                format!("* LISTRIGHTS {} {} {}\r\n", wire_name, astring(identifier), rights)
+            }
+            _ => {
+                if identifier.starts_with('-') {
+                    return format!("{} NO [CANNOT] Negative rights are not supported\r\n", tag);
+                }
+                if Some(identifier) == owner {
+                    return format!("{} NO [CANNOT] The owner always has every right\r\n", tag);
+                }
+                let mut acl = Acl::load(&location.path);
+                let changed = match acl_args.get(2) {
+                    Some(modification) => {
+                        let modification = modification.trim_matches('"');
+                        if acl::parse_rights(modification.trim_start_matches(['+', '-'])).is_none() {
+                            return bad_res;
+                        }
+                        acl.set(identifier, modification)
+                    }
+                    None => acl.delete(identifier),
+                };
+                if let Err(e) = changed {
+                    warn!("Error changing the ACL of '{}': {}", location.path.display(), e);
+                    return format!("{} NO {} failed\r\n", tag, command);
+                }
+                String::new()
+            }
+        };
+        format!("{}{} OK {} completed\r\n", res, tag, command)
+    }
+
+    /// GETQUOTA, GETQUOTAROOT and SETQUOTA (RFC 9208). Each user's maildir
+    /// is a quota root named by their address. Users see their own quota;
+    /// admins see and set everyone's. The shared root has no quota.
+    fn quota(&mut self, cmd: &str, args: &mut Split<char>, tag: &str, bad_res: String) -> String {
+        if self.maildir.is_none() {
+            return bad_res;
+        }
+        let quota_args: Vec<&str> = args.collect();
+        let first = match quota_args.first() {
+            Some(arg) => arg.trim_matches('"'),
+            None => return bad_res,
+        };
+        let command = cmd.to_uppercase();
+        if cmd == "getquotaroot" {
+            if quota_args.len() != 1 {
+                return bad_res;
+            }
+            let name = match self.mailbox_name(first) {
+                Some(name) => name,
+                None => return bad_res,
+            };
+            let location = match namespace::locate(&self.roots, &name) {
+                Some(location) => location,
+                None => return format!("{} NO [CANNOT] Invalid mailbox name\r\n", tag),
+            };
+            if !location.path.join("cur").is_dir() || !location.rights(&self.identifier).contains('l') {
+                return format!("{} NO [NONEXISTENT] No such mailbox\r\n", tag);
+            }
+            let mut res = format!("* QUOTAROOT {}", util::wire_name(&name, self.serv.conf.delimiter, self.utf8));
+            let owner = location.root.owner.as_deref().filter(|&owner| self.admin || owner == self.identifier);
+            match owner.and_then(|owner| Some((owner, self.serv.user_by_address(owner)?))) {
+                Some((owner, user)) => {
+                    let mut quota = self.serv.quota(user);
+                    if let Err(e) = quota.recalculate() {
+                        warn!("Error recalculating the quota of {}: {}", owner, e);
+                    }
+                    res.push_str(&format!(" {}\r\n{}", astring(owner), quota.response(&astring(owner))));
+                }
+                None => res.push_str("\r\n"),
+            }
+            return format!("{}{} OK {} completed\r\n", res, tag, command);
+        }
+
+        let user = match self.serv.user_by_address(first) {
+            Some(user) if self.admin || first == self.identifier => user,
+            _ => return format!("{} NO [NONEXISTENT] No such quota root\r\n", tag),
+        };
+        let mut quota = self.serv.quota(user);
+        if cmd == "setquota" {
+            if !self.admin {
+                return format!("{} NO [NOPERM] Only administrators may set quotas\r\n", tag);
+            }
+            let limits = match quota::parse_setquota(&quota_args[1..].join(" ")) {
+                Some(limits) => limits,
+                None => return bad_res,
+            };
+            if let Err(e) = quota.set_limits(limits) {
+                warn!("Error setting the quota of {}: {}", first, e);
+                return format!("{} NO SETQUOTA failed\r\n", tag);
+            }
+        } else if quota_args.len() != 1 {
+            return bad_res;
+        }
+        if let Err(e) = quota.recalculate() {
+            warn!("Error recalculating the quota of {}: {}", first, e);
+        }
+        format!("{}{} OK {} completed\r\n", quota.response(&astring(first)), tag, command)
+    }
+
+    /// Whether the folders of a root of mailboxes keep a full-text index:
+    /// as their owner has it or, for shared mailboxes, as configured.
+    fn owner_indexes(&self, root: &Root) -> bool {
+        match root.owner.as_deref() {
+            Some(owner) => self.serv.user_by_address(owner).is_some_and(|owner| self.serv.full_text_index(owner)),
+            None => self.serv.conf.full_text_index,
+        }
+    }
+
+    /// The quota on the maildir a root of mailboxes is, if it is a user's.
+    fn owner_quota(&self, root: &Root) -> Option<Quota> {
+        let owner = self.serv.user_by_address(root.owner.as_deref()?)?;
+        Some(self.serv.quota(owner))
+    }
+
+    // should generate list of sequence numbers that were deleted. Nothing
+    // is expunged without the right to.
+    fn expunge(&mut self, uids: Option<&[usize]>) -> Result<Vec<usize>, Error> {
         match self.folder {
             None => Err(Error::InvalidImapState),
-            Some(ref folder) => Ok(folder.expunge()),
+            Some(_) if !self.rights.contains('e') => Ok(Vec::new()),
+            Some(ref mut folder) => Ok(folder.expunge(uids)),
+        }
+    }
+
+    /// Close the selected folder, if any, giving up its lock.
+    fn deselect(&mut self) {
+        if let Some(folder) = self.folder.take() {
+            folder.unlock();
+        }
+    }
+}
+
+impl Drop for ImapSession {
+    // A session whose connection drops lets go of its folder too
+    fn drop(&mut self) {
+        self.deselect();
+    }
+}
+
+/// The COPYUID response code (RFC 4315) mapping the UIDs of messages onto
+/// those of their copies.
+fn copyuid(source_uids: &[usize], copy_uids: &[usize]) -> String {
+    format!(
+        "COPYUID {} {} {}",
+        UIDVALIDITY,
+        sequence_set::format(source_uids),
+        sequence_set::format(copy_uids)
+    )
+}
+
+/// Remove the messages a failed COPY made in a folder, which may have been
+/// moved from new/ to cur/ since, and drop them from its full-text index.
+fn remove_copies(folder: &Path, copies: &[(usize, PathBuf)], fts: bool) {
+    for (uid, path) in copies {
+        if fs::remove_file(path).is_ok() {
+            continue;
+        }
+        let uid = uid.to_string();
+        let claimed = fs::read_dir(folder.join("cur"))
+            .into_iter()
+            .flatten()
+            .flatten()
+            .find(|entry| entry.file_name().to_str().and_then(|name| name.split(':').next()) == Some(&uid[..]));
+        if claimed.is_none_or(|entry| fs::remove_file(entry.path()).is_err()) {
+            warn!("Error removing the copy {} from '{}'", uid, folder.display());
+        }
+    }
+    if fts {
+        let uids: Vec<usize> = copies.iter().map(|&(uid, _)| uid).collect();
+        let mut index = fts::Index::load(folder);
+        index.remove(&uids);
+        if let Err(e) = index.save() {
+            warn!("Error saving the full-text index of {}: {}", folder.display(), e);
+        }
+    }
+}
+
+/// The response when a user lacks a right on a mailbox, which does not give
+/// away that the mailbox exists unless they may look it up (RFC 4314
+/// section 6).
+fn denied(tag: &str, rights: &str) -> String {
+    if rights.contains('l') {
+        format!("{} NO [NOPERM] Permission denied\r\n", tag)
+    } else {
+        format!("{} NO [NONEXISTENT] No such mailbox\r\n", tag)
+    }
+}
+
+/// Whether a flag may be set on messages put into a mailbox, given the
+/// user's rights on it. Flags they may not set are left off.
+fn may_set(rights: &str, flag: &str) -> bool {
+    parse_flag(flag).is_none_or(|flag| rights.contains(acl::flag_right(&flag)))
+}
+
+/// An identifier or rights as an IMAP astring.
+fn astring(string: &str) -> String {
+    if string.is_empty() || string.contains(|c: char| " (){%*\"\\]".contains(c) || c.is_control()) {
+        util::quote(string)
+    } else {
+        string.to_string()
+    }
+}
+
+/// Messages are stored with the line endings LMTP delivery leaves them with.
+fn unix_line_endings(data: &[u8]) -> Vec<u8> {
+    let mut res = Vec::with_capacity(data.len());
+    for (i, &byte) in data.iter().enumerate() {
+        if byte != b'\r' || data.get(i + 1) != Some(&b'\n') {
+            res.push(byte);
+        }
+    }
+    res
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testing::TempDir;
+    use std::collections::HashMap;
+    use std::sync::Mutex;
+
+    #[test]
+    fn test_unix_line_endings() {
+        assert_eq!(unix_line_endings(b"a\r\nb\r\n"), b"a\nb\n".to_vec());
+        assert_eq!(unix_line_endings(b"a\rb\n\r"), b"a\rb\n\r".to_vec());
+    }
+
+    fn session(maildir: &Path, layout: Layout) -> ImapSession {
+        let conf = toml::from_str("host = \"127.0.0.1\"\nusers = \"\"\npkcs_file = \"\"\npkcs_pass = \"\"\n").unwrap();
+        let serv = Server {
+            conf,
+            users: HashMap::new(),
+            ssl_acceptor: None,
+            jmap_caches: Mutex::new(HashMap::new()),
+        };
+        let mut session = ImapSession::new(Arc::new(serv));
+        session.maildir = Some(maildir.to_str().unwrap().to_string());
+        session.layout = layout;
+        session.identifier = "will@xqz.ca".to_string();
+        session.roots = vec![Root {
+            prefix: String::new(),
+            path: maildir.to_path_buf(),
+            layout,
+            owner: Some("will@xqz.ca".to_string()),
+        }];
+        session
+    }
+
+    fn run(session: &mut ImapSession, command: &str) -> String {
+        let mut args = command.split(' ');
+        let cmd = args.next().unwrap().to_ascii_lowercase();
+        let res = session.interpret(&cmd, &mut args, "a1", "a1 BAD Invalid command\r\n".to_string());
+        String::from_utf8_lossy(&res).into_owned()
+    }
+
+    #[test]
+    fn test_create_metadata_name() {
+        let maildir = TempDir::new("imap-create");
+        lmtp::create_folder(&maildir).unwrap();
+        let script = crate::sieve::script_path(&maildir, "main");
+        fs::create_dir_all(script.parent().unwrap()).unwrap();
+        fs::write(&script, "keep;\n").unwrap();
+        let mut session = session(&maildir, Layout::MaildirPlusPlus);
+
+        assert!(run(&mut session, "CREATE sieve").starts_with("a1 NO"));
+        assert!(run(&mut session, "DELETE sieve").starts_with("a1 NO"));
+        assert!(script.is_file());
+
+        assert_eq!(run(&mut session, "CREATE Lists"), "a1 OK CREATE successful.\r\n");
+        assert_eq!(
+            run(&mut session, "CREATE Lists"),
+            "a1 NO [ALREADYEXISTS] Mailbox already exists\r\n"
+        );
+    }
+
+    #[test]
+    fn test_folder_lock() {
+        let maildir = TempDir::new("imap-lock");
+        lmtp::create_folder(&maildir).unwrap();
+        let lock = maildir.join(".lock");
+        let mut first = session(&maildir, Layout::Nested);
+        let mut second = session(&maildir, Layout::Nested);
+
+        // Expunging keeps the lock, so another session can't take it
+        assert!(run(&mut first, "SELECT INBOX").ends_with("a1 OK [READ-WRITE] SELECT command was successful\r\n"));
+        for command in &["EXPUNGE", "UID EXPUNGE 1:*", "CHECK"] {
+            run(&mut first, command);
+            assert!(lock.is_file(), "{}", command);
+        }
+        assert!(run(&mut second, "SELECT INBOX").contains("[READ-ONLY]"));
+
+        // Closing the folder one way or another gives it up
+        for command in &["CLOSE", "UNSELECT", "SELECT Trash"] {
+            assert!(run(&mut first, "SELECT INBOX").contains("[READ-WRITE]"), "{}", command);
+            run(&mut first, command);
+            assert!(!lock.exists(), "{}", command);
+        }
+        run(&mut first, "SELECT INBOX");
+        drop(first);
+        assert!(!lock.exists());
+    }
+
+    #[test]
+    fn test_copy_all_or_nothing() {
+        let maildir = TempDir::new("imap-copy");
+        lmtp::create_folder(&maildir).unwrap();
+        lmtp::create_folder(&maildir.join("Trash")).unwrap();
+        fs::write(maildir.join("cur").join("1000:2,S"), "Subject: a\n\nfirst\n").unwrap();
+        fs::write(maildir.join("cur").join("1001:2,"), "Subject: b\n\nsecond\n").unwrap();
+        let mut session = session(&maildir, Layout::Nested);
+        run(&mut session, "SELECT INBOX");
+        let copies = || {
+            let trash = maildir.join("Trash");
+            fs::read_dir(trash.join("cur")).unwrap().count() + fs::read_dir(trash.join("new")).unwrap().count()
+        };
+
+        // The second message can't be read, so the first isn't kept either
+        fs::remove_file(maildir.join("cur").join("1001:2,")).unwrap();
+        assert_eq!(run(&mut session, "COPY 1:2 Trash"), "a1 NO Could not copy messages\r\n");
+        assert_eq!(copies(), 0);
+        assert!(run(&mut session, "COPY 1 Trash").starts_with("a1 OK [COPYUID 1 1000 "));
+        assert_eq!(copies(), 1);
+    }
+
+    #[test]
+    fn test_move() {
+        let maildir = TempDir::new("imap-move");
+        lmtp::create_folder(&maildir).unwrap();
+        lmtp::create_folder(&maildir.join("Trash")).unwrap();
+        fs::write(maildir.join("cur").join("1000:2,"), "Subject: a\n\nfirst\n").unwrap();
+        fs::write(maildir.join("cur").join("1001:2,S"), "Subject: b\n\nsecond\n").unwrap();
+        fs::write(maildir.join("cur").join("1002:2,"), "Subject: c\n\nthird\n").unwrap();
+        let mut session = session(&maildir, Layout::Nested);
+        assert!(run(&mut session, "CAPABILITY").contains(" MOVE "));
+        run(&mut session, "SELECT INBOX");
+
+        // The saved search result names the messages to move
+        assert_eq!(run(&mut session, "SEARCH RETURN (SAVE) SEEN"), "a1 OK SEARCH completed\r\n");
+        let res = run(&mut session, "MOVE $ Trash");
+        assert!(res.starts_with("* OK [COPYUID 1 1001 "), "{}", res);
+        assert!(res.ends_with("] Moved\r\n* 2 EXPUNGE\r\na1 OK MOVE completed\r\n"), "{}", res);
+        assert_eq!(fs::read_dir(maildir.join("Trash").join("cur")).unwrap().count(), 1);
+        assert!(!maildir.join("cur").join("1001:2,S").exists());
+
+        let res = run(&mut session, "UID MOVE 1002 Trash");
+        assert!(res.ends_with("] Moved\r\n* 2 EXPUNGE\r\na1 OK MOVE completed\r\n"), "{}", res);
+        assert_eq!(run(&mut session, "MOVE 2 Trash"), "a1 BAD Invalid command\r\n");
+        assert_eq!(fs::read_dir(maildir.join("cur")).unwrap().count(), 1);
+    }
+
+    #[test]
+    fn test_fetch_eight_bit() {
+        let maildir = TempDir::new("imap-fetch");
+        lmtp::create_folder(&maildir).unwrap();
+        let raw = b"Subject: caf\xe9\n\nLunch at the caf\xe9\n";
+        fs::write(maildir.join("cur").join("1000:2,"), raw).unwrap();
+        let mut session = session(&maildir, Layout::Nested);
+        run(&mut session, "SELECT INBOX");
+
+        // The message goes out byte for byte, with its stored size
+        let mut args = "1 (RFC822.SIZE BODY.PEEK[])".split(' ');
+        let res = session.interpret("fetch", &mut args, "a1", "a1 BAD Invalid command\r\n".to_string());
+        let mut expected = format!("* 1 FETCH (RFC822.SIZE {} BODY[] {{{}}}\r\n", raw.len(), raw.len()).into_bytes();
+        expected.extend_from_slice(raw);
+        assert_eq!(res[..expected.len()], expected[..]);
+        assert!(res.ends_with(b")\r\na1 OK FETCH completed\r\n"));
+    }
+
+    /// A client connection with its input ready, which keeps what is
+    /// written to it.
+    struct Client {
+        input: io::Cursor<Vec<u8>>,
+        output: Vec<u8>,
+    }
+
+    impl Read for Client {
+        fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+            self.input.read(buf)
+        }
+    }
+
+    impl BufRead for Client {
+        fn fill_buf(&mut self) -> io::Result<&[u8]> {
+            self.input.fill_buf()
+        }
+
+        fn consume(&mut self, amount: usize) {
+            self.input.consume(amount)
+        }
+    }
+
+    impl Write for Client {
+        fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+            self.output.write(buf)
         }
+
+        fn flush(&mut self) -> io::Result<()> {
+            Ok(())
+        }
+    }
+
+    fn append(session: &mut ImapSession, args: &str, input: &[u8]) -> (String, Client) {
+        let mut client = Client {
+            input: io::Cursor::new(input.to_vec()),
+            output: Vec::new(),
+        };
+        let res = session.append(&mut client, &mut args.split(' '), "a1", "a1 BAD Invalid command\r\n".to_string());
+        (res, client)
+    }
+
+    #[test]
+    fn test_append_size() {
+        let maildir = TempDir::new("imap-append");
+        lmtp::create_folder(&maildir).unwrap();
+        let mut session = session(&maildir, Layout::Nested);
+        Arc::get_mut(&mut session.serv).unwrap().conf.imap_max_size = 16;
+
+        let (res, client) = append(&mut session, "INBOX {12}", b"Subject: a\r\n\r\n");
+        assert!(res.starts_with("a1 OK [APPENDUID "));
+        assert_eq!(client.output, b"+ Ready for literal data\r\n".to_vec());
+
+        // A synchronizing literal which is too large is never asked for
+        let (res, client) = append(&mut session, "INBOX {17}", b"");
+        assert_eq!(res, "a1 NO [TOOBIG] Message too large\r\n");
+        assert!(client.output.is_empty());
+
+        // A refused non-synchronizing literal is read past
+        let (res, client) = append(&mut session, "Missing {12+}", b"Subject: a\r\n\r\nnext");
+        assert_eq!(res, "a1 NO [TRYCREATE] No such folder\r\n");
+        assert_eq!(client.input.position(), 14);
+        assert!(!session.logout);
+
+        // but one which is too large ends the connection unread
+        let (res, _) = append(&mut session, "INBOX {18446744073709551615+}", b"");
+        assert!(res.ends_with("a1 BAD [TOOBIG] Message too large\r\n"));
+        assert!(session.logout);
+        assert_eq!(fs::read_dir(maildir.join("new")).unwrap().count(), 1);
     }
 }
@@ -0,0 +1,1207 @@
+//! The Email and Thread methods of RFC 8621 sections 3 and 4. Emails are
+//! read with the mime crate; the keywords are the maildir flags.
+
+use std::cmp::Ordering;
+use std::collections::{BTreeSet, HashSet};
+use std::fs::{self, File};
+use std::io::Read;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use chrono::{DateTime, NaiveDateTime, Utc};
+use mime::address::parse_address_list;
+use mime::encoding::{base64_encode, decode_header, encode_header};
+use mime::thread::message_ids;
+use mime::{get_param, Message as MIME_Message, Part};
+use serde_json::{json, Map, Value};
+
+use super::store::{Email, Store};
+use super::{
+    bool_argument, check_state, ids_argument, properties_argument, query_window, select_properties, set_error,
+    uint_argument, MethodError, MethodResult, SetResults, MAX_OBJECTS_IN_SET,
+};
+use crate::keywords::Keywords;
+use crate::message::{flag_name, parse_flag, Flag};
+use crate::server::lmtp::store as store_message;
+
+/// The properties Email/query can sort by.
+pub static SORT_PROPERTIES: &[&str] = &["receivedAt", "sentAt", "size", "from", "to", "subject"];
+
+static PROPERTIES: &[&str] = &[
+    "id",
+    "blobId",
+    "threadId",
+    "mailboxIds",
+    "keywords",
+    "size",
+    "receivedAt",
+    "messageId",
+    "inReplyTo",
+    "references",
+    "sender",
+    "from",
+    "to",
+    "cc",
+    "bcc",
+    "replyTo",
+    "subject",
+    "sentAt",
+    "hasAttachment",
+    "preview",
+    "bodyValues",
+    "textBody",
+    "htmlBody",
+    "attachments",
+];
+
+// The properties an email can be created with.
+static CREATE_PROPERTIES: &[&str] = &[
+    "mailboxIds",
+    "keywords",
+    "messageId",
+    "inReplyTo",
+    "references",
+    "sender",
+    "from",
+    "to",
+    "cc",
+    "bcc",
+    "replyTo",
+    "subject",
+    "sentAt",
+    "bodyValues",
+    "textBody",
+    "htmlBody",
+    "attachments",
+];
+
+// The header fields holding addresses, by the properties they map onto.
+static ADDRESS_FIELDS: &[(&str, &str)] = &[
+    ("sender", "Sender"),
+    ("from", "From"),
+    ("to", "To"),
+    ("cc", "Cc"),
+    ("bcc", "Bcc"),
+    ("replyTo", "Reply-To"),
+];
+
+// JMAP keywords and the IMAP flags they stand for. Other keywords are
+// IMAP keywords of the same name.
+static KEYWORDS: &[(&str, &str)] = &[
+    ("$seen", "\\Seen"),
+    ("$flagged", "\\Flagged"),
+    ("$answered", "\\Answered"),
+    ("$draft", "\\Draft"),
+    ("$forwarded", "$Forwarded"),
+];
+
+const PREVIEW_LENGTH: usize = 256;
+
+fn keyword_flag(keyword: &str) -> Option<Flag> {
+    match KEYWORDS.iter().find(|(k, _)| k.eq_ignore_ascii_case(keyword)) {
+        Some((_, flag)) => parse_flag(flag),
+        None => parse_flag(keyword).filter(|flag| matches!(flag, Flag::Keyword(_))),
+    }
+}
+
+fn keywords_json(flags: &HashSet<Flag>) -> Value {
+    let mut keywords = Map::new();
+    for (keyword, flag) in KEYWORDS {
+        if parse_flag(flag).map(|f| flags.contains(&f)) == Some(true) {
+            keywords.insert(keyword.to_string(), Value::Bool(true));
+        }
+    }
+    // Keywords are case-insensitive, and JMAP writes them in lowercase
+    for flag in flags {
+        if let Flag::Keyword(ref name) = *flag {
+            keywords.insert(name.to_ascii_lowercase(), Value::Bool(true));
+        }
+    }
+    Value::Object(keywords)
+}
+
+/// The IMAP names of a set of flags, as taken by the LMTP store. JMAP has
+/// no \Deleted.
+fn flag_names(flags: &HashSet<Flag>) -> Vec<String> {
+    let mut names: Vec<String> = flags
+        .iter()
+        .filter(|flag| **flag != Flag::Deleted)
+        .map(|flag| flag_name(flag).to_string())
+        .collect();
+    names.sort();
+    names
+}
+
+fn utc_date(timestamp: i64) -> String {
+    NaiveDateTime::from_timestamp(timestamp, 0).format("%Y-%m-%dT%H:%M:%SZ").to_string()
+}
+
+fn parse_utc_date(value: &Value) -> Result<i64, MethodError> {
+    value
+        .as_str()
+        .and_then(|v| DateTime::parse_from_rfc3339(v).ok())
+        .map(|d| d.timestamp())
+        .ok_or_else(|| MethodError::invalid_arguments("Invalid date"))
+}
+
+/// The date in the Date header field, as a timestamp.
+fn sent_at(message: &MIME_Message) -> Option<DateTime<chrono::FixedOffset>> {
+    let date = message.get_header_values("Date").into_iter().next()?;
+    DateTime::parse_from_rfc2822(date.trim()).ok()
+}
+
+/// The addresses in a header field, or null if the message has none. The
+/// raw value is parsed, so that encoded words in names cannot add commas.
+fn addresses_json(message: &MIME_Message, field: &str) -> Value {
+    match message.get_body_part().get_header(&field.to_ascii_uppercase()) {
+        Some(value) => Value::Array(
+            parse_address_list(value)
+                .into_iter()
+                .map(|a| json!({"name": a.name, "email": a.email}))
+                .collect(),
+        ),
+        None => Value::Null,
+    }
+}
+
+fn message_ids_json(message: &MIME_Message, field: &str) -> Value {
+    match message.get_header_values(field).first() {
+        Some(value) => json!(message_ids(value)),
+        None => Value::Null,
+    }
+}
+
+/// A header field asked for as a `header:{name}[:{form}][:all]` property.
+fn header_property(message: &MIME_Message, property: &str) -> Value {
+    let mut split = property["header:".len()..].split(':');
+    let name = split.next().unwrap_or("");
+    let mut form = "asRaw";
+    let mut all = false;
+    for option in split {
+        match option {
+            "all" => all = true,
+            form_option => form = form_option,
+        }
+    }
+    let convert = |value: &str| -> Value {
+        match form {
+            "asAddresses" => Value::Array(
+                parse_address_list(value)
+                    .into_iter()
+                    .map(|a| json!({"name": a.name, "email": a.email}))
+                    .collect(),
+            ),
+            "asMessageIds" => json!(message_ids(value)),
+            "asDate" => match DateTime::parse_from_rfc2822(value.trim()) {
+                Ok(date) => json!(date.to_rfc3339()),
+                Err(_) => Value::Null,
+            },
+            "asURLs" => json!(message_ids(value)),
+            "asText" => json!(decode_header(value).trim()),
+            _ => json!(value),
+        }
+    };
+    let values: Vec<Value> = message
+        .get_header_values(name)
+        .iter()
+        .map(|v| convert(v))
+        .collect();
+    if all {
+        Value::Array(values)
+    } else {
+        values.into_iter().last().unwrap_or(Value::Null)
+    }
+}
+
+/// A body part and its part id: the positions of the part and its parents
+/// in their multiparts, separated by dots.
+type PartRef<'p> = (String, &'p Part);
+
+/// The body part with the given part id.
+pub fn find_part<'p>(root: &'p Part, part_id: &str) -> Option<&'p Part> {
+    if root.content_type().0 != "multipart" {
+        return if part_id == "1" { Some(root) } else { None };
+    }
+    let mut part = root;
+    for index in part_id.split('.') {
+        let index: usize = index.parse().ok()?;
+        part = part.parts().get(index.checked_sub(1)?)?;
+    }
+    Some(part)
+}
+
+fn children<'p>(part: &'p Part, part_id: &str) -> Vec<PartRef<'p>> {
+    part.parts()
+        .iter()
+        .enumerate()
+        .map(|(i, child)| {
+            let child_id = if part_id.is_empty() {
+                (i + 1).to_string()
+            } else {
+                format!("{}.{}", part_id, i + 1)
+            };
+            (child_id, child)
+        })
+        .collect()
+}
+
+fn full_type(part: &Part) -> String {
+    let (media_type, subtype) = part.content_type();
+    format!("{}/{}", media_type, subtype)
+}
+
+fn is_inline_media(full_type: &str) -> bool {
+    full_type.starts_with("image/") || full_type.starts_with("audio/") || full_type.starts_with("video/")
+}
+
+fn disposition(part: &Part) -> Option<String> {
+    let value = part.get_header("CONTENT-DISPOSITION")?;
+    Some(value.split(';').next().unwrap_or("").trim().to_ascii_lowercase())
+}
+
+fn part_name(part: &Part) -> Option<String> {
+    let name = part
+        .get_header("CONTENT-DISPOSITION")
+        .and_then(|v| get_param(v, "filename"))
+        .or_else(|| part.get_header("CONTENT-TYPE").and_then(|v| get_param(v, "name")))?;
+    Some(decode_header(&name))
+}
+
+/// The parts of a message making up its text and HTML bodies and its
+/// attachments.
+struct Bodies<'p> {
+    text: Vec<PartRef<'p>>,
+    html: Vec<PartRef<'p>>,
+    attachments: Vec<PartRef<'p>>,
+}
+
+/// Sort body parts into the bodies, following the algorithm of RFC 8621
+/// section 4.1.4.
+fn parse_structure<'p>(
+    parts: Vec<PartRef<'p>>,
+    multipart_type: &str,
+    in_alternative: bool,
+    mut html: Option<&mut Vec<PartRef<'p>>>,
+    mut text: Option<&mut Vec<PartRef<'p>>>,
+    attachments: &mut Vec<PartRef<'p>>,
+) {
+    let text_length = text.as_ref().map(|t| t.len());
+    let html_length = html.as_ref().map(|h| h.len());
+    for (i, (part_id, part)) in parts.into_iter().enumerate() {
+        let (media_type, subtype) = part.content_type();
+        let full_type = full_type(part);
+        let is_inline = disposition(part).as_deref() != Some("attachment")
+            && (full_type == "text/plain" || full_type == "text/html" || is_inline_media(&full_type))
+            && (i == 0 || (multipart_type != "related" && (is_inline_media(&full_type) || part_name(part).is_none())));
+        if media_type == "multipart" {
+            parse_structure(
+                children(part, &part_id),
+                &subtype,
+                in_alternative || subtype == "alternative",
+                html.as_deref_mut(),
+                text.as_deref_mut(),
+                attachments,
+            );
+        } else if is_inline {
+            if multipart_type == "alternative" {
+                match &full_type[..] {
+                    "text/plain" => {
+                        if let Some(text) = text.as_deref_mut() {
+                            text.push((part_id, part));
+                        }
+                    }
+                    "text/html" => {
+                        if let Some(html) = html.as_deref_mut() {
+                            html.push((part_id, part));
+                        }
+                    }
+                    _ => attachments.push((part_id, part)),
+                }
+                continue;
+            } else if in_alternative {
+                if full_type == "text/plain" {
+                    html = None;
+                }
+                if full_type == "text/html" {
+                    text = None;
+                }
+            }
+            if let Some(text) = text.as_deref_mut() {
+                text.push((part_id.clone(), part));
+            }
+            if let Some(html) = html.as_deref_mut() {
+                html.push((part_id.clone(), part));
+            }
+            if (text.is_none() || html.is_none()) && is_inline_media(&full_type) {
+                attachments.push((part_id, part));
+            }
+        } else {
+            attachments.push((part_id, part));
+        }
+    }
+    if multipart_type == "alternative" {
+        if let (Some(text), Some(html)) = (text, html) {
+            // Parts found in only one of the alternatives belong to both
+            if text_length == Some(text.len()) && html_length != Some(html.len()) {
+                let extra: Vec<PartRef> = html[html_length.unwrap_or(0)..].to_vec();
+                text.extend(extra);
+            }
+            if html_length == Some(html.len()) && text_length != Some(text.len()) {
+                let extra: Vec<PartRef> = text[text_length.unwrap_or(0)..].to_vec();
+                html.extend(extra);
+            }
+        }
+    }
+}
+
+fn bodies(message: &MIME_Message) -> Bodies<'_> {
+    let root = message.get_body_part();
+    let mut bodies = Bodies {
+        text: Vec::new(),
+        html: Vec::new(),
+        attachments: Vec::new(),
+    };
+    let (media_type, subtype) = root.content_type();
+    let (parts, multipart_type) = if media_type == "multipart" {
+        (children(root, ""), subtype)
+    } else {
+        (vec![("1".to_string(), root)], "mixed".to_string())
+    };
+    parse_structure(
+        parts,
+        &multipart_type,
+        multipart_type == "alternative",
+        Some(&mut bodies.html),
+        Some(&mut bodies.text),
+        &mut bodies.attachments,
+    );
+    bodies
+}
+
+fn part_json(email: &Email, (part_id, part): &PartRef) -> Value {
+    let full_type = full_type(part);
+    let charset = part
+        .get_header("CONTENT-TYPE")
+        .and_then(|v| get_param(v, "charset"))
+        .or_else(|| {
+            if full_type.starts_with("text/") {
+                Some("us-ascii".to_string())
+            } else {
+                None
+            }
+        });
+    let cid = part
+        .get_header("CONTENT-ID")
+        .map(|v| v.trim().trim_start_matches('<').trim_end_matches('>').to_string());
+    json!({
+        "partId": part_id,
+        "blobId": format!("{}-{}", email.blob_id(), part_id.replace('.', "_")),
+        "size": part.decoded_bytes().len(),
+        "name": part_name(part),
+        "type": full_type,
+        "charset": charset,
+        "disposition": disposition(part),
+        "cid": cid,
+        "language": null,
+        "location": null,
+    })
+}
+
+/// Remove the tags from HTML, roughly, for previews.
+fn strip_tags(html: &str) -> String {
+    let mut res = String::new();
+    let mut in_tag = false;
+    for chr in html.chars() {
+        match chr {
+            '<' => in_tag = true,
+            '>' if in_tag => {
+                in_tag = false;
+                res.push(' ');
+            }
+            _ if !in_tag => res.push(chr),
+            _ => {}
+        }
+    }
+    res.replace("&nbsp;", " ")
+        .replace("&lt;", "<")
+        .replace("&gt;", ">")
+        .replace("&amp;", "&")
+}
+
+fn preview(bodies: &Bodies) -> String {
+    let text = match (bodies.text.first(), bodies.html.first()) {
+        (Some((_, part)), _) if full_type(part) == "text/plain" => part.decoded_body(),
+        (Some((_, part)), _) | (None, Some((_, part))) => strip_tags(&part.decoded_body()),
+        (None, None) => String::new(),
+    };
+    let words: Vec<&str> = text.split_whitespace().collect();
+    words.join(" ").chars().take(PREVIEW_LENGTH).collect()
+}
+
+/// The values of text body parts, truncated to a number of bytes.
+fn body_value(part: &Part, max_bytes: Option<u64>) -> Value {
+    let mut value = part.decoded_body();
+    let mut truncated = false;
+    if let Some(max) = max_bytes {
+        let mut end = (max as usize).min(value.len());
+        while !value.is_char_boundary(end) {
+            end -= 1;
+        }
+        truncated = end < value.len();
+        value.truncate(end);
+    }
+    json!({
+        "value": value,
+        "isEncodingProblem": false,
+        "isTruncated": truncated,
+    })
+}
+
+/// Options of Email/get controlling which body values are returned.
+struct BodyValueOptions {
+    text: bool,
+    html: bool,
+    all: bool,
+    max_bytes: Option<u64>,
+}
+
+fn to_json(email: &Email, properties: &[String], options: &BodyValueOptions) -> Value {
+    let message = email.mime();
+    let bodies = bodies(message);
+    let mut object = Map::new();
+    for property in properties {
+        let value = match &property[..] {
+            "id" => json!(email.id),
+            "blobId" => json!(email.blob_id()),
+            "threadId" => json!(email.thread_id),
+            "mailboxIds" => {
+                let ids: Map<String, Value> = email.mailbox_ids().into_iter().map(|id| (id, Value::Bool(true))).collect();
+                Value::Object(ids)
+            }
+            "keywords" => keywords_json(&email.flags()),
+            "size" => json!(email.size),
+            "receivedAt" => json!(utc_date(email.received_at() as i64)),
+            "messageId" => message_ids_json(message, "Message-ID"),
+            "inReplyTo" => message_ids_json(message, "In-Reply-To"),
+            "references" => message_ids_json(message, "References"),
+            "subject" => match message.get_header_values("Subject").first() {
+                Some(subject) => json!(subject.trim()),
+                None => Value::Null,
+            },
+            "sentAt" => match sent_at(message) {
+                Some(date) => json!(date.to_rfc3339()),
+                None => Value::Null,
+            },
+            "hasAttachment" => json!(!bodies.attachments.is_empty()),
+            "preview" => json!(preview(&bodies)),
+            "textBody" => Value::Array(bodies.text.iter().map(|p| part_json(email, p)).collect()),
+            "htmlBody" => Value::Array(bodies.html.iter().map(|p| part_json(email, p)).collect()),
+            "attachments" => Value::Array(bodies.attachments.iter().map(|p| part_json(email, p)).collect()),
+            "bodyValues" => {
+                let mut values = Map::new();
+                let text_parts = bodies.text.iter().chain(bodies.html.iter());
+                for (part_id, part) in text_parts {
+                    let is_text = full_type(part).starts_with("text/");
+                    let wanted = options.all
+                        || (options.text && bodies.text.iter().any(|(id, _)| id == part_id))
+                        || (options.html && bodies.html.iter().any(|(id, _)| id == part_id));
+                    if is_text && wanted && !values.contains_key(part_id) {
+                        values.insert(part_id.clone(), body_value(part, options.max_bytes));
+                    }
+                }
+                Value::Object(values)
+            }
+            property if property.starts_with("header:") => header_property(message, property),
+            property => match ADDRESS_FIELDS.iter().find(|(p, _)| *p == property) {
+                Some((_, field)) => addresses_json(message, field),
+                None => continue,
+            },
+        };
+        object.insert(property.clone(), value);
+    }
+    Value::Object(object)
+}
+
+pub fn get(store: &mut Store, args: &Value) -> MethodResult {
+    let properties = properties_argument(args, PROPERTIES, PROPERTIES)?;
+    let options = BodyValueOptions {
+        text: bool_argument(args, "fetchTextBodyValues")?,
+        html: bool_argument(args, "fetchHTMLBodyValues")?,
+        all: bool_argument(args, "fetchAllBodyValues")?,
+        max_bytes: uint_argument(args, "maxBodyValueBytes")?,
+    };
+    let ids = match ids_argument(store, args)? {
+        Some(ids) => ids,
+        None => store.emails.iter().map(|e| e.id.clone()).collect(),
+    };
+    let mut list = Vec::new();
+    let mut not_found = Vec::new();
+    for id in ids {
+        match store.email(&id) {
+            Some(email) => list.push(select_properties(to_json(email, &properties, &options), &properties)),
+            None => not_found.push(id),
+        }
+    }
+    Ok(json!({
+        "accountId": store.account_id(),
+        "state": store.email_state(),
+        "list": list,
+        "notFound": not_found,
+    }))
+}
+
+pub fn get_threads(store: &mut Store, args: &Value) -> MethodResult {
+    let ids = match ids_argument(store, args)? {
+        Some(ids) => ids,
+        None => {
+            let ids: BTreeSet<String> = store.emails.iter().map(|e| e.thread_id.clone()).collect();
+            ids.into_iter().collect()
+        }
+    };
+    let mut list = Vec::new();
+    let mut not_found = Vec::new();
+    for id in ids {
+        let mut emails: Vec<&Email> = store.emails.iter().filter(|e| e.thread_id == id).collect();
+        if emails.is_empty() {
+            not_found.push(id);
+            continue;
+        }
+        emails.sort_by(|a, b| a.received_at().cmp(&b.received_at()).then_with(|| a.id.cmp(&b.id)));
+        let email_ids: Vec<&str> = emails.iter().map(|e| &e.id[..]).collect();
+        list.push(json!({"id": id, "emailIds": email_ids}));
+    }
+    Ok(json!({
+        "accountId": store.account_id(),
+        "state": store.email_state(),
+        "list": list,
+        "notFound": not_found,
+    }))
+}
+
+fn contains_ignore_case(haystack: &str, needle: &str) -> bool {
+    haystack.to_lowercase().contains(&needle.to_lowercase())
+}
+
+fn header_text(message: &MIME_Message, field: &str) -> String {
+    message.get_header_values(field).join(" ")
+}
+
+fn body_text(message: &MIME_Message) -> String {
+    message.get_body_text(&["text".to_string()]).join("\n")
+}
+
+/// Whether an email matches a filter: a FilterCondition or a
+/// FilterOperator combining other filters.
+fn matches(store: &Store, email: &Email, filter: &Value) -> Result<bool, MethodError> {
+    let conditions = filter;
+    let filter = match filter.as_object() {
+        Some(filter) => filter,
+        None => return Err(MethodError::invalid_arguments("filter must be an object")),
+    };
+    if let Some(operator) = filter.get("operator") {
+        let conditions = filter.get("conditions").and_then(|c| c.as_array());
+        let conditions = conditions.ok_or_else(|| MethodError::invalid_arguments("conditions must be an array"))?;
+        let mut results = Vec::new();
+        for condition in conditions {
+            results.push(matches(store, email, condition)?);
+        }
+        return match operator.as_str() {
+            Some("AND") => Ok(results.iter().all(|r| *r)),
+            Some("OR") => Ok(results.iter().any(|r| *r)),
+            Some("NOT") => Ok(!results.iter().any(|r| *r)),
+            _ => Err(MethodError::invalid_arguments("Unknown filter operator")),
+        };
+    }
+    let message = email.mime();
+    let string = |value: &Value| -> Result<String, MethodError> {
+        match value.as_str() {
+            Some(s) => Ok(s.to_string()),
+            None => Err(MethodError::invalid_arguments("Filter value must be a string")),
+        }
+    };
+    for (name, value) in filter {
+        let matched = match &name[..] {
+            "inMailbox" => email.mailbox_ids().contains(&store.resolve_id(&string(value)?)),
+            "inMailboxOtherThan" => {
+                let ids: Vec<String> = match value.as_array() {
+                    Some(ids) => ids.iter().filter_map(|id| id.as_str()).map(|id| store.resolve_id(id)).collect(),
+                    None => return Err(MethodError::invalid_arguments("inMailboxOtherThan must be an array")),
+                };
+                email.mailbox_ids().iter().any(|id| !ids.contains(id))
+            }
+            "before" => (email.received_at() as i64) < parse_utc_date(value)?,
+            "after" => (email.received_at() as i64) >= parse_utc_date(value)?,
+            "minSize" => email.size >= uint_argument(conditions, name)?.unwrap_or(0),
+            "maxSize" => email.size < uint_argument(conditions, name)?.unwrap_or(u64::MAX),
+            "hasKeyword" => match keyword_flag(&string(value)?) {
+                Some(flag) => email.flags().contains(&flag),
+                None => false,
+            },
+            "notKeyword" => match keyword_flag(&string(value)?) {
+                Some(flag) => !email.flags().contains(&flag),
+                None => true,
+            },
+            "hasAttachment" => Some(!bodies(message).attachments.is_empty()) == value.as_bool(),
+            "text" => {
+                let text = string(value)?;
+                ["From", "To", "Cc", "Bcc", "Subject"]
+                    .iter()
+                    .any(|field| contains_ignore_case(&header_text(message, field), &text))
+                    || contains_ignore_case(&body_text(message), &text)
+            }
+            "from" | "to" | "cc" | "bcc" | "subject" => {
+                let field = match &name[..] {
+                    "from" => "From",
+                    "to" => "To",
+                    "cc" => "Cc",
+                    "bcc" => "Bcc",
+                    _ => "Subject",
+                };
+                contains_ignore_case(&header_text(message, field), &string(value)?)
+            }
+            "body" => contains_ignore_case(&body_text(message), &string(value)?),
+            "header" => match value.as_array().map(|v| &v[..]) {
+                Some([Value::String(field)]) => !message.get_header_values(field).is_empty(),
+                Some([Value::String(field), Value::String(text)]) => message
+                    .get_header_values(field)
+                    .iter()
+                    .any(|v| contains_ignore_case(v, text)),
+                _ => return Err(MethodError::invalid_arguments("header must be an array of one or two strings")),
+            },
+            _ => return Err(MethodError::new("unsupportedFilter")),
+        };
+        if !matched {
+            return Ok(false);
+        }
+    }
+    Ok(true)
+}
+
+fn first_address(message: &MIME_Message, field: &str) -> String {
+    match message.get_body_part().get_header(&field.to_ascii_uppercase()) {
+        Some(value) => parse_address_list(value)
+            .into_iter()
+            .next()
+            .map(|a| a.name.unwrap_or(a.email).to_lowercase())
+            .unwrap_or_default(),
+        None => String::new(),
+    }
+}
+
+fn compare(a: &Email, b: &Email, property: &str) -> Ordering {
+    match property {
+        "receivedAt" => a.received_at().cmp(&b.received_at()),
+        "sentAt" => sent_at(a.mime()).map(|d| d.timestamp()).cmp(&sent_at(b.mime()).map(|d| d.timestamp())),
+        "size" => a.size.cmp(&b.size),
+        "from" | "to" => {
+            let field = if property == "from" { "From" } else { "To" };
+            first_address(a.mime(), field).cmp(&first_address(b.mime(), field))
+        }
+        _ => header_text(a.mime(), "Subject")
+            .trim()
+            .to_lowercase()
+            .cmp(&header_text(b.mime(), "Subject").trim().to_lowercase()),
+    }
+}
+
+pub fn query(store: &mut Store, args: &Value) -> MethodResult {
+    let mut emails: Vec<&Email> = Vec::new();
+    for email in &store.emails {
+        match args.get("filter") {
+            None | Some(Value::Null) => emails.push(email),
+            Some(filter) => {
+                if matches(store, email, filter)? {
+                    emails.push(email);
+                }
+            }
+        }
+    }
+
+    // The newest emails come first unless asked otherwise
+    let mut comparators: Vec<(String, bool)> = vec![("receivedAt".to_string(), false)];
+    if let Some(sort) = args.get("sort").and_then(|s| s.as_array()) {
+        comparators.clear();
+        for comparator in sort {
+            let property = comparator.get("property").and_then(|p| p.as_str()).unwrap_or("");
+            if !SORT_PROPERTIES.contains(&property) {
+                return Err(MethodError::new("unsupportedSort"));
+            }
+            let ascending = comparator.get("isAscending").and_then(|a| a.as_bool()).unwrap_or(true);
+            comparators.push((property.to_string(), ascending));
+        }
+    }
+    emails.sort_by(|a, b| {
+        for (property, ascending) in &comparators {
+            let ordering = compare(a, b, property);
+            let ordering = if *ascending { ordering } else { ordering.reverse() };
+            if ordering != Ordering::Equal {
+                return ordering;
+            }
+        }
+        a.id.cmp(&b.id)
+    });
+
+    if bool_argument(args, "collapseThreads")? {
+        let mut seen = HashSet::new();
+        emails.retain(|e| seen.insert(e.thread_id.clone()));
+    }
+
+    let ids: Vec<String> = emails.iter().map(|e| e.id.clone()).collect();
+    let total = ids.len();
+    let (position, ids) = query_window(ids, args)?;
+    let mut response = json!({
+        "accountId": store.account_id(),
+        "queryState": store.email_state(),
+        "canCalculateChanges": false,
+        "position": position,
+        "ids": ids,
+    });
+    if bool_argument(args, "calculateTotal")? {
+        response["total"] = json!(total);
+    }
+    Ok(response)
+}
+
+/// Format a list of EmailAddress objects for a header field.
+fn format_addresses(value: &Value) -> Result<String, Value> {
+    let invalid = || set_error("invalidProperties", "Invalid address list");
+    let mut res = Vec::new();
+    for address in value.as_array().ok_or_else(invalid)? {
+        let email = address.get("email").and_then(|e| e.as_str()).ok_or_else(invalid)?;
+        if email.contains(['<', '>', ',', '\r', '\n']) {
+            return Err(invalid());
+        }
+        match address.get("name").and_then(|n| n.as_str()) {
+            Some(name) if !name.is_empty() => {
+                let name = if name.is_ascii() {
+                    format!("\"{}\"", name.replace('\\', "\\\\").replace('"', "\\\""))
+                } else {
+                    encode_header(name)
+                };
+                res.push(format!("{} <{}>", name, email));
+            }
+            _ => res.push(format!("<{}>", email)),
+        }
+    }
+    Ok(res.join(", "))
+}
+
+fn format_message_ids(value: &Value) -> Result<String, Value> {
+    let invalid = || set_error("invalidProperties", "Invalid message ids");
+    let mut res = Vec::new();
+    for id in value.as_array().ok_or_else(invalid)? {
+        let id = id.as_str().ok_or_else(invalid)?;
+        if id.contains(['<', '>', ' ', '\r', '\n']) {
+            return Err(invalid());
+        }
+        res.push(format!("<{}>", id));
+    }
+    Ok(res.join(" "))
+}
+
+/// A MIME entity holding text.
+fn text_entity(content_type: &str, text: &str) -> String {
+    let mut text = text.replace("\r\n", "\n");
+    if !text.ends_with('\n') {
+        text.push('\n');
+    }
+    let encoding = if text.is_ascii() { "7bit" } else { "8bit" };
+    format!(
+        "Content-Type: {}; charset=utf-8\nContent-Transfer-Encoding: {}\n\n{}",
+        content_type, encoding, text
+    )
+}
+
+/// A multipart MIME entity made of other entities.
+fn multipart_entity(subtype: &str, entities: &[String]) -> String {
+    let nanos = SystemTime::now().duration_since(UNIX_EPOCH).map(|d| d.as_nanos()).unwrap_or(0);
+    let boundary = format!("=_{}_{}", subtype, nanos);
+    let mut res = format!("Content-Type: multipart/{}; boundary=\"{}\"\n\n", subtype, boundary);
+    for entity in entities {
+        res.push_str(&format!("--{}\n{}\n", boundary, entity));
+    }
+    res.push_str(&format!("--{}--\n", boundary));
+    res
+}
+
+fn attachment_entity(store: &Store, attachment: &Value) -> Result<String, Value> {
+    let blob_id = match attachment.get("blobId").and_then(|b| b.as_str()) {
+        Some(blob_id) => store.resolve_id(blob_id),
+        None => return Err(set_error("invalidProperties", "Attachments need a blobId")),
+    };
+    let data = match store.blob(&blob_id) {
+        Some(data) => data,
+        None => {
+            let mut error = set_error("blobNotFound", "No such blob");
+            error["notFound"] = json!([blob_id]);
+            return Err(error);
+        }
+    };
+    let content_type = attachment
+        .get("type")
+        .and_then(|t| t.as_str())
+        .filter(|t| !t.contains(['\r', '\n', ';']))
+        .unwrap_or("application/octet-stream");
+    let disposition = match attachment.get("disposition").and_then(|d| d.as_str()) {
+        Some("inline") => "inline",
+        _ => "attachment",
+    };
+    let mut entity = format!("Content-Type: {}", content_type);
+    let mut disposition = disposition.to_string();
+    if let Some(name) = attachment.get("name").and_then(|n| n.as_str()) {
+        let name = encode_header(&name.replace(['"', '\\', '\r', '\n'], ""));
+        entity.push_str(&format!("; name=\"{}\"", name));
+        disposition.push_str(&format!("; filename=\"{}\"", name));
+    }
+    entity.push_str(&format!("\nContent-Disposition: {}\n", disposition));
+    if let Some(cid) = attachment.get("cid").and_then(|c| c.as_str()) {
+        entity.push_str(&format!("Content-ID: <{}>\n", cid.replace(['<', '>', '\r', '\n'], "")));
+    }
+    entity.push_str("Content-Transfer-Encoding: base64\n\n");
+    let encoded = base64_encode(&data);
+    for line in encoded.as_bytes().chunks(76) {
+        entity.push_str(&String::from_utf8_lossy(line));
+        entity.push('\n');
+    }
+    Ok(entity)
+}
+
+/// Put together the message for a new email.
+fn build_message(store: &Store, object: &Map<String, Value>) -> Result<Vec<u8>, Value> {
+    let mut header = String::new();
+    let now = Utc::now();
+    let date = match object.get("sentAt") {
+        None | Some(Value::Null) => now.to_rfc2822(),
+        Some(value) => match value.as_str().map(DateTime::parse_from_rfc3339) {
+            Some(Ok(date)) => date.to_rfc2822(),
+            _ => return Err(set_error("invalidProperties", "Invalid sentAt")),
+        },
+    };
+    header.push_str(&format!("Date: {}\n", date));
+    for (property, field) in ADDRESS_FIELDS {
+        if let Some(value) = object.get(*property).filter(|v| !v.is_null()) {
+            header.push_str(&format!("{}: {}\n", field, format_addresses(value)?));
+        }
+    }
+    if let Some(subject) = object.get("subject").and_then(|s| s.as_str()) {
+        header.push_str(&format!("Subject: {}\n", encode_header(&subject.replace(['\r', '\n'], " "))));
+    }
+    match object.get("messageId").filter(|v| !v.is_null()) {
+        Some(value) => header.push_str(&format!("Message-ID: {}\n", format_message_ids(value)?)),
+        None => header.push_str(&format!("Message-ID: <{}.jmap@{}>\n", now.timestamp_nanos(), store.host)),
+    }
+    for (property, field) in &[("inReplyTo", "In-Reply-To"), ("references", "References")] {
+        if let Some(value) = object.get(*property).filter(|v| !v.is_null()) {
+            header.push_str(&format!("{}: {}\n", field, format_message_ids(value)?));
+        }
+    }
+    header.push_str("MIME-Version: 1.0\n");
+
+    let empty = Map::new();
+    let body_values = object.get("bodyValues").and_then(|b| b.as_object()).unwrap_or(&empty);
+    let body_entities = |property: &str, default_type: &str| -> Result<Vec<String>, Value> {
+        let mut entities = Vec::new();
+        if let Some(parts) = object.get(property).and_then(|p| p.as_array()) {
+            for part in parts {
+                let part_id = part.get("partId").and_then(|p| p.as_str()).unwrap_or("");
+                let value = body_values.get(part_id).and_then(|v| v.get("value")).and_then(|v| v.as_str());
+                let value = value.ok_or_else(|| set_error("invalidProperties", "Body parts need a value"))?;
+                let content_type = part.get("type").and_then(|t| t.as_str()).unwrap_or(default_type);
+                if content_type != default_type {
+                    return Err(set_error("invalidProperties", "Invalid body part type"));
+                }
+                entities.push(text_entity(content_type, value));
+            }
+        }
+        Ok(entities)
+    };
+    let mut entities = body_entities("textBody", "text/plain")?;
+    let html = body_entities("htmlBody", "text/html")?;
+    let mut body = match (entities.len(), html.len()) {
+        (0, 0) => text_entity("text/plain", ""),
+        (1, 0) => entities.remove(0),
+        (0, 1) => html[0].clone(),
+        (1, 1) => multipart_entity("alternative", &[entities.remove(0), html[0].clone()]),
+        _ => {
+            entities.extend(html);
+            multipart_entity("mixed", &entities)
+        }
+    };
+    if let Some(attachments) = object.get("attachments").and_then(|a| a.as_array()) {
+        if !attachments.is_empty() {
+            let mut parts = vec![body];
+            for attachment in attachments {
+                parts.push(attachment_entity(store, attachment)?);
+            }
+            body = multipart_entity("mixed", &parts);
+        }
+    }
+    header.push_str(&body);
+    Ok(header.into_bytes())
+}
+
+/// The mailboxes in a mailboxIds value, which must name at least one.
+fn mailbox_ids(store: &Store, value: Option<&Value>) -> Result<BTreeSet<String>, Value> {
+    let invalid = |description: &str| set_error("invalidProperties", description);
+    let object = value.and_then(|v| v.as_object()).ok_or_else(|| invalid("mailboxIds is required"))?;
+    let mut ids = BTreeSet::new();
+    for (id, included) in object {
+        let id = store.resolve_id(id);
+        if included != &Value::Bool(true) || store.mailbox(&id).is_none() {
+            return Err(invalid("Invalid mailboxIds"));
+        }
+        ids.insert(id);
+    }
+    if ids.is_empty() {
+        return Err(invalid("An email must be in a mailbox"));
+    }
+    Ok(ids)
+}
+
+fn keyword_flags(value: &Value) -> Result<HashSet<Flag>, Value> {
+    let object = value
+        .as_object()
+        .ok_or_else(|| set_error("invalidProperties", "keywords must be an object"))?;
+    let mut flags = HashSet::new();
+    for (keyword, set) in object {
+        match keyword_flag(keyword) {
+            Some(flag) if set == &Value::Bool(true) => {
+                flags.insert(flag);
+            }
+            _ => return Err(set_error("invalidProperties", &format!("Unsupported keyword {}", keyword))),
+        }
+    }
+    Ok(flags)
+}
+
+fn create(store: &mut Store, object: &Value) -> Result<Value, Value> {
+    let object = object
+        .as_object()
+        .ok_or_else(|| set_error("invalidProperties", "Not an object"))?;
+    if let Some(property) = object.keys().find(|p| !CREATE_PROPERTIES.contains(&&p[..])) {
+        return Err(set_error("invalidProperties", &format!("Cannot set {}", property)));
+    }
+    let mailboxes = mailbox_ids(store, object.get("mailboxIds"))?;
+    let flags = match object.get("keywords") {
+        Some(keywords) => keyword_flags(keywords)?,
+        None => HashSet::new(),
+    };
+    let data = build_message(store, object)?;
+    let id = format!("e{}", super::store::digest(&data));
+    for mailbox_id in &mailboxes {
+        if let Some(mailbox) = store.mailbox(mailbox_id) {
+            if let Err(e) = store_message(&mailbox.path, &data, &flag_names(&flags)) {
+                warn!("Error storing email in '{}': {}", mailbox.path.display(), e);
+                return Err(set_error("serverFail", "Could not store the email"));
+            }
+        }
+    }
+    store.scan();
+    match store.email(&id) {
+        Some(email) => Ok(json!({
+            "id": email.id,
+            "blobId": email.blob_id(),
+            "threadId": email.thread_id,
+            "size": email.size,
+        })),
+        None => Err(set_error("serverFail", "Could not store the email")),
+    }
+}
+
+fn update(store: &mut Store, id: &str, patch: &Value) -> Result<(), Value> {
+    let patch = patch.as_object().ok_or_else(|| set_error("invalidPatch", "Not an object"))?;
+    let email = store.email(id).ok_or_else(|| set_error("notFound", "No such email"))?;
+    let mut mailboxes = email.mailbox_ids();
+    let mut flags = email.flags();
+    for (path, value) in patch {
+        if path == "keywords" {
+            flags = keyword_flags(value)?;
+        } else if let Some(keyword) = path.strip_prefix("keywords/") {
+            let flag = keyword_flag(keyword)
+                .ok_or_else(|| set_error("invalidProperties", &format!("Unsupported keyword {}", keyword)))?;
+            match value {
+                Value::Bool(true) => flags.insert(flag),
+                Value::Null => flags.remove(&flag),
+                _ => return Err(set_error("invalidPatch", "Keywords are set to true or null")),
+            };
+        } else if path == "mailboxIds" {
+            mailboxes = mailbox_ids(store, Some(value))?;
+        } else if let Some(mailbox_id) = path.strip_prefix("mailboxIds/") {
+            let mailbox_id = store.resolve_id(mailbox_id);
+            match value {
+                Value::Bool(true) if store.mailbox(&mailbox_id).is_some() => mailboxes.insert(mailbox_id),
+                Value::Null => mailboxes.remove(&mailbox_id),
+                _ => return Err(set_error("invalidProperties", "Invalid mailboxIds")),
+            };
+        } else {
+            return Err(set_error("invalidProperties", &format!("Cannot update {}", path)));
+        }
+    }
+    if mailboxes.is_empty() {
+        return Err(set_error("invalidProperties", "An email must be in a mailbox"));
+    }
+
+    // Copy the email into the mailboxes it is added to
+    let current = email.mailbox_ids();
+    let added: Vec<&String> = mailboxes.difference(&current).collect();
+    if !added.is_empty() {
+        let mut data = Vec::new();
+        if let Err(e) = File::open(email.path()).and_then(|mut f| f.read_to_end(&mut data)) {
+            warn!("Error reading '{}': {}", email.path().display(), e);
+            return Err(set_error("serverFail", "Could not read the email"));
+        }
+        for mailbox_id in added {
+            if let Some(mailbox) = store.mailbox(mailbox_id) {
+                if let Err(e) = store_message(&mailbox.path, &data, &flag_names(&flags)) {
+                    warn!("Error storing email in '{}': {}", mailbox.path.display(), e);
+                    return Err(set_error("serverFail", "Could not store the email"));
+                }
+            }
+        }
+    }
+    // Then remove it from those it leaves, and update the flags of the rest
+    for file in &email.files {
+        let path = file.message.get_path();
+        let result = if !mailboxes.contains(&file.mailbox_id) {
+            fs::remove_file(path)
+        } else if *file.message.get_flags() != flags {
+            let mailbox = match store.mailbox(&file.mailbox_id) {
+                Some(mailbox) => mailbox,
+                None => continue,
+            };
+            let filename = file.message.filename_with_flags(&flags, &mut Keywords::load(&mailbox.path));
+            fs::rename(path, mailbox.path.join("cur").join(filename))
+        } else {
+            Ok(())
+        };
+        if let Err(e) = result {
+            warn!("Error updating '{}': {}", path.display(), e);
+            return Err(set_error("serverFail", "Could not update the email"));
+        }
+    }
+    store.scan();
+    Ok(())
+}
+
+fn destroy(store: &mut Store, id: &str) -> Result<(), Value> {
+    let email = store.email(id).ok_or_else(|| set_error("notFound", "No such email"))?;
+    for file in &email.files {
+        if let Err(e) = fs::remove_file(file.message.get_path()) {
+            warn!("Error removing '{}': {}", file.message.get_path().display(), e);
+            return Err(set_error("serverFail", "Could not destroy the email"));
+        }
+    }
+    store.scan();
+    Ok(())
+}
+
+pub fn set(store: &mut Store, args: &Value) -> MethodResult {
+    let old_state = store.email_state();
+    check_state(args, &old_state)?;
+    let empty = Map::new();
+    let creates = args.get("create").and_then(|c| c.as_object()).unwrap_or(&empty);
+    let updates = args.get("update").and_then(|u| u.as_object()).unwrap_or(&empty);
+    let destroys: Vec<&str> = match args.get("destroy").and_then(|d| d.as_array()) {
+        Some(ids) => ids.iter().filter_map(|id| id.as_str()).collect(),
+        None => Vec::new(),
+    };
+    if creates.len() + updates.len() + destroys.len() > MAX_OBJECTS_IN_SET {
+        return Err(MethodError::new("requestTooLarge"));
+    }
+
+    let mut results = SetResults::default();
+    for (creation_id, object) in creates {
+        match create(store, object) {
+            Ok(object) => {
+                if let Some(id) = object.get("id").and_then(|i| i.as_str()) {
+                    store.created_ids.insert(creation_id.clone(), id.to_string());
+                }
+                results.created.insert(creation_id.clone(), object);
+            }
+            Err(error) => {
+                results.not_created.insert(creation_id.clone(), error);
+            }
+        }
+    }
+    for (id, patch) in updates {
+        let id = store.resolve_id(id);
+        match update(store, &id, patch) {
+            Ok(()) => {
+                results.updated.insert(id, Value::Null);
+            }
+            Err(error) => {
+                results.not_updated.insert(id, error);
+            }
+        }
+    }
+    for id in destroys {
+        let id = store.resolve_id(id);
+        match destroy(store, &id) {
+            Ok(()) => results.destroyed.push(id),
+            Err(error) => {
+                results.not_destroyed.insert(id, error);
+            }
+        }
+    }
+    Ok(results.into_json(store.account_id(), old_state, store.email_state()))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn message(raw: &str) -> MIME_Message {
+        MIME_Message::from_bytes(raw.as_bytes()).unwrap()
+    }
+
+    fn part_ids(parts: &[PartRef]) -> Vec<String> {
+        parts.iter().map(|(id, _)| id.clone()).collect()
+    }
+
+    #[test]
+    fn test_bodies() {
+        let raw = "Content-Type: multipart/mixed; boundary=\"m\"\n\n--m\nContent-Type: multipart/alternative; boundary=\"a\"\n\n--a\nContent-Type: text/plain\n\nplain\n--a\nContent-Type: text/html\n\n<p>html</p>\n--a--\n--m\nContent-Type: application/pdf; name=\"doc.pdf\"\nContent-Disposition: attachment; filename=\"doc.pdf\"\nContent-Transfer-Encoding: base64\n\nYWJj\n--m--\n";
+        let message = message(raw);
+        let bodies = bodies(&message);
+        assert_eq!(part_ids(&bodies.text), vec!["1.1"]);
+        assert_eq!(part_ids(&bodies.html), vec!["1.2"]);
+        assert_eq!(part_ids(&bodies.attachments), vec!["2"]);
+        assert_eq!(preview(&bodies), "plain");
+        let attachment = find_part(message.get_body_part(), "2").unwrap();
+        assert_eq!(part_name(attachment), Some("doc.pdf".to_string()));
+        assert_eq!(attachment.decoded_bytes(), b"abc");
+
+        let message = self::message("Subject: x\n\nJust text\n");
+        let bodies = super::bodies(&message);
+        assert_eq!(part_ids(&bodies.text), vec!["1"]);
+        assert_eq!(part_ids(&bodies.html), vec!["1"]);
+        assert!(bodies.attachments.is_empty());
+        assert!(find_part(message.get_body_part(), "1").is_some());
+        assert!(find_part(message.get_body_part(), "2").is_none());
+    }
+
+    #[test]
+    fn test_keywords() {
+        let flags: HashSet<Flag> = vec![Flag::Seen, Flag::Deleted].into_iter().collect();
+        assert_eq!(keywords_json(&flags), json!({"$seen": true}));
+        assert_eq!(flag_names(&flags), vec!["\\Seen"]);
+        assert_eq!(keyword_flag("$Flagged"), Some(Flag::Flagged));
+        assert_eq!(keyword_flag("$junk"), Some(Flag::Keyword("$junk".to_string())));
+        assert_eq!(keyword_flag("\\Deleted"), None);
+        let flags: HashSet<Flag> = vec![Flag::Forwarded, Flag::Keyword("$Junk".to_string())].into_iter().collect();
+        assert_eq!(keywords_json(&flags), json!({"$forwarded": true, "$junk": true}));
+        assert_eq!(flag_names(&flags), vec!["$Forwarded", "$Junk"]);
+    }
+
+    #[test]
+    fn test_format_addresses() {
+        let value = json!([{"name": "Doe, Jane", "email": "jane@example.com"}, {"email": "bob@example.org"}, {"name": "René", "email": "r@example.fr"}]);
+        assert_eq!(
+            format_addresses(&value).unwrap(),
+            format!("\"Doe, Jane\" <jane@example.com>, <bob@example.org>, {} <r@example.fr>", encode_header("René"))
+        );
+        assert!(format_addresses(&json!([{"email": "a>b"}])).is_err());
+        let parsed = parse_address_list(&format_addresses(&value).unwrap());
+        assert_eq!(parsed[0].name.as_deref(), Some("Doe, Jane"));
+        assert_eq!(parsed[2].name.as_deref(), Some("René"));
+    }
+}
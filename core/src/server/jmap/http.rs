@@ -0,0 +1,214 @@
+//! Just enough of HTTP/1.1 (RFC 7230) to serve JMAP: one request per
+//! connection, bodies sized by Content-Length.
+
+use std::io::{self, BufRead, Read, Write};
+
+// Limits on the request line and the header, in bytes
+const MAX_LINE: usize = 8192;
+const MAX_HEADER_FIELDS: usize = 100;
+
+/// An HTTP request. Header field names are lower-cased.
+pub struct Request {
+    pub method: String,
+    pub path: String,
+    pub query: String,
+    headers: Vec<(String, String)>,
+    pub body: Vec<u8>,
+}
+
+impl Request {
+    pub fn header(&self, name: &str) -> Option<&str> {
+        self.headers.iter().find(|(n, _)| n == name).map(|(_, v)| &v[..])
+    }
+
+    /// The value of a parameter in the query string, decoded.
+    pub fn query_param(&self, name: &str) -> Option<String> {
+        self.query.split('&').find_map(|param| {
+            let param = param.replace('+', " ");
+            let mut split = param.splitn(2, '=');
+            if percent_decode(split.next()?) == name {
+                Some(percent_decode(split.next().unwrap_or("")))
+            } else {
+                None
+            }
+        })
+    }
+}
+
+/// Why a request could not be read; each maps onto an error status.
+#[derive(Debug, PartialEq)]
+pub enum RequestError {
+    BadRequest,
+    TooLarge,
+    LengthRequired,
+}
+
+impl RequestError {
+    pub fn status(&self) -> u16 {
+        match *self {
+            RequestError::BadRequest => 400,
+            RequestError::TooLarge => 413,
+            RequestError::LengthRequired => 411,
+        }
+    }
+}
+
+fn read_line<S: BufRead>(stream: &mut S) -> Result<Option<String>, RequestError> {
+    let mut line = Vec::new();
+    match stream.by_ref().take(MAX_LINE as u64).read_until(b'\n', &mut line) {
+        Ok(0) => return Ok(None),
+        Ok(_) => {}
+        Err(_) => return Ok(None),
+    }
+    if !line.ends_with(b"\n") {
+        return Err(RequestError::TooLarge);
+    }
+    let line = String::from_utf8(line).map_err(|_| RequestError::BadRequest)?;
+    Ok(Some(line.trim_end_matches(['\r', '\n']).to_string()))
+}
+
+/// Read a request, with a body of at most `max_body` bytes. Returns
+/// `Ok(None)` if the connection was closed first.
+pub fn read_request<S: BufRead>(stream: &mut S, max_body: usize) -> Result<Option<Request>, RequestError> {
+    let request_line = match read_line(stream)? {
+        Some(line) => line,
+        None => return Ok(None),
+    };
+    let mut split = request_line.split(' ');
+    let method = split.next().unwrap_or("").to_string();
+    let target = split.next().ok_or(RequestError::BadRequest)?;
+    match split.next() {
+        Some(version) if version.starts_with("HTTP/1.") => {}
+        _ => return Err(RequestError::BadRequest),
+    }
+    let (path, query) = match target.find('?') {
+        Some(index) => (&target[..index], &target[index + 1..]),
+        None => (target, ""),
+    };
+
+    let mut headers = Vec::new();
+    loop {
+        let line = read_line(stream)?.ok_or(RequestError::BadRequest)?;
+        if line.is_empty() {
+            break;
+        }
+        if headers.len() >= MAX_HEADER_FIELDS {
+            return Err(RequestError::TooLarge);
+        }
+        let mut split = line.splitn(2, ':');
+        let name = split.next().unwrap_or("").trim().to_ascii_lowercase();
+        let value = split.next().ok_or(RequestError::BadRequest)?.trim().to_string();
+        headers.push((name, value));
+    }
+
+    let mut request = Request {
+        method,
+        path: path.to_string(),
+        query: query.to_string(),
+        headers,
+        body: Vec::new(),
+    };
+    if request.header("transfer-encoding").is_some() {
+        return Err(RequestError::LengthRequired);
+    }
+    let length = match request.header("content-length") {
+        Some(length) => length.parse::<usize>().map_err(|_| RequestError::BadRequest)?,
+        None => 0,
+    };
+    if length > max_body {
+        return Err(RequestError::TooLarge);
+    }
+    request.body = vec![0; length];
+    stream.read_exact(&mut request.body).map_err(|_| RequestError::BadRequest)?;
+    Ok(Some(request))
+}
+
+pub fn reason_phrase(status: u16) -> &'static str {
+    match status {
+        200 => "OK",
+        201 => "Created",
+        204 => "No Content",
+        400 => "Bad Request",
+        401 => "Unauthorized",
+        403 => "Forbidden",
+        404 => "Not Found",
+        405 => "Method Not Allowed",
+        411 => "Length Required",
+        413 => "Payload Too Large",
+        _ => "Internal Server Error",
+    }
+}
+
+/// Send a response and mark the connection as closing.
+pub fn write_response<S: Write>(
+    stream: &mut S,
+    status: u16,
+    headers: &[(&str, String)],
+    body: &[u8],
+) -> io::Result<()> {
+    let mut head = format!("HTTP/1.1 {} {}\r\n", status, reason_phrase(status));
+    for (name, value) in headers {
+        head.push_str(&format!("{}: {}\r\n", name, value));
+    }
+    head.push_str(&format!("Content-Length: {}\r\n", body.len()));
+    // Web clients call the API from other origins
+    head.push_str("Access-Control-Allow-Origin: *\r\n");
+    head.push_str("Connection: close\r\n\r\n");
+    stream.write_all(head.as_bytes())?;
+    stream.write_all(body)?;
+    stream.flush()
+}
+
+/// Decode the %XX escapes of a URL component.
+pub fn percent_decode(value: &str) -> String {
+    let bytes = value.as_bytes();
+    let mut res = Vec::with_capacity(bytes.len());
+    let mut i = 0;
+    while i < bytes.len() {
+        if bytes[i] == b'%'
+            && i + 2 < bytes.len()
+            && bytes[i + 1].is_ascii_hexdigit()
+            && bytes[i + 2].is_ascii_hexdigit()
+        {
+            let hex = [bytes[i + 1], bytes[i + 2]];
+            res.push(u8::from_str_radix(std::str::from_utf8(&hex).unwrap_or("0"), 16).unwrap_or(0));
+            i += 3;
+            continue;
+        }
+        res.push(bytes[i]);
+        i += 1;
+    }
+    String::from_utf8_lossy(&res).into_owned()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::io::Cursor;
+
+    #[test]
+    fn test_read_request() {
+        let mut input = Cursor::new(
+            &b"POST /jmap/upload/a/?x=1&accept=text%2Fplain&name=a+b HTTP/1.1\r\nHost: mail\r\nContent-Length: 4\r\n\r\nbody"[..],
+        );
+        let request = read_request(&mut input, 10).unwrap().unwrap();
+        assert_eq!(request.method, "POST");
+        assert_eq!(request.path, "/jmap/upload/a/");
+        assert_eq!(request.query_param("accept"), Some("text/plain".to_string()));
+        assert_eq!(request.query_param("name"), Some("a b".to_string()));
+        assert_eq!(request.header("host"), Some("mail"));
+        assert_eq!(request.body, b"body");
+
+        let mut input = Cursor::new(&b"POST / HTTP/1.1\r\nContent-Length: 11\r\n\r\nbody"[..]);
+        assert_eq!(read_request(&mut input, 10).err(), Some(RequestError::TooLarge));
+        let mut input = Cursor::new(&b"GET /\r\n\r\n"[..]);
+        assert_eq!(read_request(&mut input, 10).err(), Some(RequestError::BadRequest));
+        assert!(read_request(&mut Cursor::new(&b""[..]), 10).unwrap().is_none());
+    }
+
+    #[test]
+    fn test_percent_decode() {
+        assert_eq!(percent_decode("a%20b+c%zz%4"), "a b+c%zz%4");
+        assert_eq!(percent_decode("caf%C3%A9"), "café");
+    }
+}
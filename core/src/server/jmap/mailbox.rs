@@ -0,0 +1,395 @@
+//! The Mailbox methods of RFC 8621 section 2. Mailboxes are the maildir
+//! folders; INBOX is the maildir itself.
+
+use std::cmp::Ordering;
+use std::fs;
+
+use serde_json::{json, Map, Value};
+
+use super::store::{Mailbox, Store};
+use super::{
+    bool_argument, check_state, ids_argument, properties_argument, query_window, select_properties, set_error,
+    MethodError, MethodResult, SetResults, MAX_OBJECTS_IN_SET,
+};
+
+static PROPERTIES: &[&str] = &[
+    "id",
+    "name",
+    "parentId",
+    "role",
+    "sortOrder",
+    "totalEmails",
+    "unreadEmails",
+    "totalThreads",
+    "unreadThreads",
+    "myRights",
+    "isSubscribed",
+];
+
+fn role(mailbox: &Mailbox) -> Option<&'static str> {
+    if mailbox.relative.is_empty() {
+        Some("inbox")
+    } else {
+        None
+    }
+}
+
+fn to_json(store: &Store, mailbox: &Mailbox) -> Value {
+    let (total, unread, total_threads, unread_threads) = store.counts(&mailbox.id);
+    json!({
+        "id": mailbox.id,
+        "name": mailbox.name,
+        "parentId": mailbox.parent_id,
+        "role": role(mailbox),
+        "sortOrder": 0,
+        "totalEmails": total,
+        "unreadEmails": unread,
+        "totalThreads": total_threads,
+        "unreadThreads": unread_threads,
+        "myRights": {
+            "mayReadItems": true,
+            "mayAddItems": true,
+            "mayRemoveItems": true,
+            "maySetSeen": true,
+            "maySetKeywords": true,
+            "mayCreateChild": true,
+            "mayRename": role(mailbox).is_none(),
+            "mayDelete": role(mailbox).is_none(),
+            "maySubmit": true,
+        },
+        "isSubscribed": true,
+    })
+}
+
+pub fn get(store: &mut Store, args: &Value) -> MethodResult {
+    let properties = properties_argument(args, PROPERTIES, PROPERTIES)?;
+    let ids = match ids_argument(store, args)? {
+        Some(ids) => ids,
+        None => store.mailboxes.iter().map(|m| m.id.clone()).collect(),
+    };
+    let mut list = Vec::new();
+    let mut not_found = Vec::new();
+    for id in ids {
+        match store.mailbox(&id) {
+            Some(mailbox) => list.push(select_properties(to_json(store, mailbox), &properties)),
+            None => not_found.push(id),
+        }
+    }
+    Ok(json!({
+        "accountId": store.account_id(),
+        "state": store.mailbox_state(),
+        "list": list,
+        "notFound": not_found,
+    }))
+}
+
+/// Whether a mailbox matches a filter: a FilterCondition or a
+/// FilterOperator combining other filters.
+fn matches(store: &Store, mailbox: &Mailbox, filter: &Value) -> Result<bool, MethodError> {
+    let filter = match filter.as_object() {
+        Some(filter) => filter,
+        None => return Err(MethodError::invalid_arguments("filter must be an object")),
+    };
+    if let Some(operator) = filter.get("operator") {
+        let conditions = filter.get("conditions").and_then(|c| c.as_array());
+        let conditions = conditions.ok_or_else(|| MethodError::invalid_arguments("conditions must be an array"))?;
+        let mut results = Vec::new();
+        for condition in conditions {
+            results.push(matches(store, mailbox, condition)?);
+        }
+        return match operator.as_str() {
+            Some("AND") => Ok(results.iter().all(|r| *r)),
+            Some("OR") => Ok(results.iter().any(|r| *r)),
+            Some("NOT") => Ok(!results.iter().any(|r| *r)),
+            _ => Err(MethodError::invalid_arguments("Unknown filter operator")),
+        };
+    }
+    for (name, value) in filter {
+        let matched = match &name[..] {
+            "parentId" => match value {
+                Value::Null => mailbox.parent_id.is_none(),
+                Value::String(id) => mailbox.parent_id.as_deref() == Some(&store.resolve_id(id)[..]),
+                _ => return Err(MethodError::invalid_arguments("parentId must be a string or null")),
+            },
+            "name" => match value.as_str() {
+                Some(name) => mailbox.name.to_lowercase().contains(&name.to_lowercase()),
+                None => return Err(MethodError::invalid_arguments("name must be a string")),
+            },
+            "role" => match value {
+                Value::Null => role(mailbox).is_none(),
+                Value::String(r) => role(mailbox) == Some(&r[..]),
+                _ => return Err(MethodError::invalid_arguments("role must be a string or null")),
+            },
+            "hasAnyRole" => Some(role(mailbox).is_some()) == value.as_bool(),
+            // Every mailbox counts as subscribed
+            "isSubscribed" => value.as_bool() == Some(true),
+            _ => return Err(MethodError::new("unsupportedFilter")),
+        };
+        if !matched {
+            return Ok(false);
+        }
+    }
+    Ok(true)
+}
+
+pub fn query(store: &mut Store, args: &Value) -> MethodResult {
+    let mut mailboxes: Vec<&Mailbox> = Vec::new();
+    for mailbox in &store.mailboxes {
+        match args.get("filter") {
+            None | Some(Value::Null) => mailboxes.push(mailbox),
+            Some(filter) => {
+                if matches(store, mailbox, filter)? {
+                    mailboxes.push(mailbox);
+                }
+            }
+        }
+    }
+
+    let mut comparators: Vec<(String, bool)> = Vec::new();
+    if let Some(sort) = args.get("sort").and_then(|s| s.as_array()) {
+        for comparator in sort {
+            let property = comparator.get("property").and_then(|p| p.as_str()).unwrap_or("");
+            if property != "name" && property != "sortOrder" {
+                return Err(MethodError::new("unsupportedSort"));
+            }
+            let ascending = comparator.get("isAscending").and_then(|a| a.as_bool()).unwrap_or(true);
+            comparators.push((property.to_string(), ascending));
+        }
+    }
+    mailboxes.sort_by(|a, b| {
+        for (property, ascending) in &comparators {
+            // Every mailbox has the same sort order
+            let ordering = match &property[..] {
+                "name" => a.name.to_lowercase().cmp(&b.name.to_lowercase()),
+                _ => Ordering::Equal,
+            };
+            let ordering = if *ascending { ordering } else { ordering.reverse() };
+            if ordering != Ordering::Equal {
+                return ordering;
+            }
+        }
+        a.relative.cmp(&b.relative)
+    });
+
+    let ids: Vec<String> = mailboxes.iter().map(|m| m.id.clone()).collect();
+    let total = ids.len();
+    let (position, ids) = query_window(ids, args)?;
+    let mut response = json!({
+        "accountId": store.account_id(),
+        "queryState": store.mailbox_state(),
+        "canCalculateChanges": false,
+        "position": position,
+        "ids": ids,
+    });
+    if bool_argument(args, "calculateTotal")? {
+        response["total"] = json!(total);
+    }
+    Ok(response)
+}
+
+/// Where a mailbox with the given name and parent would be, relative to
+/// the maildir.
+fn relative_path(store: &Store, name: &Value, parent_id: Option<&Value>) -> Result<String, Value> {
+    let name = match name.as_str() {
+        Some(name) if store.layout.valid_level(name) && name.len() <= 255 => name,
+        _ => return Err(set_error("invalidProperties", "Invalid mailbox name")),
+    };
+    match parent_id {
+        None | Some(Value::Null) => Ok(name.to_string()),
+        Some(Value::String(id)) => match store.mailbox(&store.resolve_id(id)) {
+            Some(parent) if parent.relative.is_empty() => Ok(name.to_string()),
+            Some(parent) => Ok(format!("{}/{}", parent.relative, name)),
+            None => Err(set_error("invalidProperties", "No such parent mailbox")),
+        },
+        Some(_) => Err(set_error("invalidProperties", "parentId must be a string or null")),
+    }
+}
+
+/// Check properties which cannot be changed are left alone.
+fn check_fixed_properties(store: &Store, mailbox: Option<&Mailbox>, object: &Map<String, Value>) -> Result<(), Value> {
+    for (name, value) in object {
+        let unchanged = match &name[..] {
+            "name" | "parentId" => true,
+            "role" => value.as_str() == mailbox.and_then(role),
+            "sortOrder" => value.as_u64() == Some(0),
+            "isSubscribed" => value.as_bool() == Some(true),
+            "id" | "totalEmails" | "unreadEmails" | "totalThreads" | "unreadThreads" | "myRights" => {
+                match mailbox {
+                    Some(mailbox) => to_json(store, mailbox).get(name) == Some(value),
+                    None => false,
+                }
+            }
+            _ => false,
+        };
+        if !unchanged {
+            return Err(set_error("invalidProperties", &format!("Cannot set {}", name)));
+        }
+    }
+    Ok(())
+}
+
+fn create(store: &mut Store, object: &Value) -> Result<Value, Value> {
+    let object = object
+        .as_object()
+        .ok_or_else(|| set_error("invalidProperties", "Not an object"))?;
+    check_fixed_properties(store, None, object)?;
+    let name = object.get("name").unwrap_or(&Value::Null);
+    let relative = relative_path(store, name, object.get("parentId"))?;
+    if let Some(existing) = store.mailboxes.iter().find(|m| m.relative == relative) {
+        let mut error = set_error("alreadyExists", "A mailbox with this name exists");
+        error["existingId"] = json!(existing.id);
+        return Err(error);
+    }
+    let levels: Vec<&str> = relative.split('/').collect();
+    let path = store.layout.folder_path(store.maildir(), &levels);
+    if let Err(e) = store.layout.create(&path) {
+        warn!("Error creating folder '{}': {}", path.display(), e);
+        return Err(set_error("serverFail", "Could not create the mailbox"));
+    }
+    store.scan();
+    match store.mailboxes.iter().find(|m| m.relative == relative) {
+        Some(mailbox) => {
+            let mut created = to_json(store, mailbox);
+            if let Value::Object(ref mut map) = created {
+                map.remove("name");
+                map.remove("parentId");
+            }
+            Ok(created)
+        }
+        None => Err(set_error("serverFail", "Could not create the mailbox")),
+    }
+}
+
+fn update(store: &mut Store, id: &str, patch: &Value) -> Result<(), Value> {
+    let patch = patch.as_object().ok_or_else(|| set_error("invalidPatch", "Not an object"))?;
+    let mailbox = store.mailbox(id).ok_or_else(|| set_error("notFound", "No such mailbox"))?;
+    check_fixed_properties(store, Some(mailbox), patch)?;
+    if !patch.contains_key("name") && !patch.contains_key("parentId") {
+        return Ok(());
+    }
+    if role(mailbox).is_some() {
+        return Err(set_error("forbidden", "INBOX cannot be renamed"));
+    }
+    let name = patch.get("name").cloned().unwrap_or_else(|| json!(mailbox.name));
+    let parent_id = match patch.get("parentId") {
+        Some(parent_id) => parent_id.clone(),
+        None => json!(mailbox.parent_id),
+    };
+    let relative = relative_path(store, &name, Some(&parent_id))?;
+    if relative == mailbox.relative {
+        return Ok(());
+    }
+    if relative.starts_with(&format!("{}/", mailbox.relative)) {
+        return Err(set_error("invalidProperties", "A mailbox cannot be moved into itself"));
+    }
+    if store.mailboxes.iter().any(|m| m.relative == relative) {
+        return Err(set_error("alreadyExists", "A mailbox with this name exists"));
+    }
+    let from = mailbox.relative.clone();
+    let from_levels: Vec<&str> = from.split('/').collect();
+    let to_levels: Vec<&str> = relative.split('/').collect();
+    if let Err(e) = store.layout.rename(store.maildir(), &from_levels, &to_levels) {
+        warn!("Error renaming folder '{}': {}", mailbox.path.display(), e);
+        return Err(set_error("serverFail", "Could not rename the mailbox"));
+    }
+    store.move_mailbox(&from, &relative);
+    store.scan();
+    Ok(())
+}
+
+fn destroy(store: &mut Store, id: &str, remove_emails: bool) -> Result<(), Value> {
+    let mailbox = store.mailbox(id).ok_or_else(|| set_error("notFound", "No such mailbox"))?;
+    if role(mailbox).is_some() {
+        return Err(set_error("forbidden", "INBOX cannot be destroyed"));
+    }
+    let prefix = format!("{}/", mailbox.relative);
+    if store.mailboxes.iter().any(|m| m.relative.starts_with(&prefix)) {
+        return Err(set_error("mailboxHasChild", "The mailbox has child mailboxes"));
+    }
+    if !remove_emails && store.counts(id).0 > 0 {
+        return Err(set_error("mailboxHasEmail", "The mailbox is not empty"));
+    }
+    if let Err(e) = fs::remove_dir_all(&mailbox.path) {
+        warn!("Error removing folder '{}': {}", mailbox.path.display(), e);
+        return Err(set_error("serverFail", "Could not destroy the mailbox"));
+    }
+    store.scan();
+    Ok(())
+}
+
+pub fn set(store: &mut Store, args: &Value) -> MethodResult {
+    let old_state = store.mailbox_state();
+    check_state(args, &old_state)?;
+    let empty = Map::new();
+    let creates = args.get("create").and_then(|c| c.as_object()).unwrap_or(&empty);
+    let updates = args.get("update").and_then(|u| u.as_object()).unwrap_or(&empty);
+    let destroys: Vec<&str> = match args.get("destroy").and_then(|d| d.as_array()) {
+        Some(ids) => ids.iter().filter_map(|id| id.as_str()).collect(),
+        None => Vec::new(),
+    };
+    if creates.len() + updates.len() + destroys.len() > MAX_OBJECTS_IN_SET {
+        return Err(MethodError::new("requestTooLarge"));
+    }
+    let remove_emails = bool_argument(args, "onDestroyRemoveEmails")?;
+
+    let mut results = SetResults::default();
+    // A mailbox may be created inside one created later in the same call;
+    // creations waiting on others are retried for as long as some succeed.
+    let mut pending: Vec<(&String, &Value)> = creates.iter().collect();
+    while !pending.is_empty() {
+        let attempted = pending.len();
+        let mut waiting = Vec::new();
+        for (creation_id, object) in pending.drain(..) {
+            let parent = object.get("parentId").and_then(|p| p.as_str()).unwrap_or("");
+            if let Some(parent_creation_id) = parent.strip_prefix('#') {
+                if !store.created_ids.contains_key(parent_creation_id) && creates.contains_key(parent_creation_id) {
+                    waiting.push((creation_id, object));
+                    continue;
+                }
+            }
+            match create(store, object) {
+                Ok(object) => {
+                    if let Some(id) = object.get("id").and_then(|i| i.as_str()) {
+                        store.created_ids.insert(creation_id.clone(), id.to_string());
+                    }
+                    results.created.insert(creation_id.clone(), object);
+                }
+                Err(error) => {
+                    results.not_created.insert(creation_id.clone(), error);
+                }
+            }
+        }
+        if waiting.len() == attempted {
+            for (creation_id, _) in waiting {
+                let error = set_error("invalidProperties", "No such parent mailbox");
+                results.not_created.insert(creation_id.clone(), error);
+            }
+            break;
+        }
+        pending = waiting;
+    }
+
+    for (id, patch) in updates {
+        let id = store.resolve_id(id);
+        match update(store, &id, patch) {
+            Ok(()) => {
+                results.updated.insert(id, Value::Null);
+            }
+            Err(error) => {
+                results.not_updated.insert(id, error);
+            }
+        }
+    }
+
+    for id in destroys {
+        let id = store.resolve_id(id);
+        match destroy(store, &id, remove_emails) {
+            Ok(()) => results.destroyed.push(id),
+            Err(error) => {
+                results.not_destroyed.insert(id, error);
+            }
+        }
+    }
+
+    Ok(results.into_json(store.account_id(), old_state, store.mailbox_state()))
+}
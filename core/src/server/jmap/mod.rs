@@ -0,0 +1,639 @@
+//! A JMAP server (RFC 8620 and RFC 8621) over HTTP, giving access to the
+//! mailboxes and emails in a user's maildir. Requests are authenticated
+//! with HTTP Basic authentication.
+
+use std::sync::{Arc, Mutex};
+
+use bufstream::BufStream;
+use mime::encoding::base64_decode;
+use serde_json::{json, Map, Value};
+
+use self::http::{percent_decode, read_request, write_response, Request};
+use self::store::{account_id, Store};
+pub use self::store::{Cache, JMAP_DIR};
+use crate::layout::Layout;
+use crate::server::user::User;
+use crate::server::{Server, Stream};
+
+mod email;
+mod http;
+mod mailbox;
+mod store;
+
+static CORE_CAPABILITY: &str = "urn:ietf:params:jmap:core";
+static MAIL_CAPABILITY: &str = "urn:ietf:params:jmap:mail";
+
+// The session object never changes while the server runs.
+static SESSION_STATE: &str = "0";
+
+const MAX_SIZE_UPLOAD: usize = 50_000_000;
+const MAX_SIZE_REQUEST: usize = 10_000_000;
+const MAX_CALLS_IN_REQUEST: usize = 32;
+const MAX_OBJECTS_IN_GET: usize = 500;
+const MAX_OBJECTS_IN_SET: usize = 500;
+
+/// A response: status, extra header fields and body.
+type Response = (u16, Vec<(&'static str, String)>, Vec<u8>);
+
+fn json_response(status: u16, value: &Value) -> Response {
+    let body = serde_json::to_vec(value).unwrap_or_default();
+    (status, vec![("Content-Type", "application/json".to_string())], body)
+}
+
+/// A request-level error, as an RFC 7807 problem details object.
+fn problem(status: u16, kind: &str, detail: &str) -> Response {
+    let mut response = json_response(
+        status,
+        &json!({
+            "type": kind,
+            "status": status,
+            "detail": detail,
+        }),
+    );
+    response.1[0].1 = "application/problem+json".to_string();
+    response
+}
+
+/// The error a method call fails with.
+#[derive(Debug, PartialEq)]
+pub struct MethodError {
+    kind: &'static str,
+    description: Option<String>,
+}
+
+impl MethodError {
+    pub fn new(kind: &'static str) -> MethodError {
+        MethodError { kind, description: None }
+    }
+
+    pub fn invalid_arguments(description: &str) -> MethodError {
+        MethodError {
+            kind: "invalidArguments",
+            description: Some(description.to_string()),
+        }
+    }
+
+    fn to_json(&self) -> Value {
+        let mut error = json!({ "type": self.kind });
+        if let Some(ref description) = self.description {
+            error["description"] = json!(description);
+        }
+        error
+    }
+}
+
+pub type MethodResult = Result<Value, MethodError>;
+
+/// An error for one object of a /set call.
+pub fn set_error(kind: &str, description: &str) -> Value {
+    json!({ "type": kind, "description": description })
+}
+
+/// The outcome of a /set call, object by object.
+#[derive(Default)]
+pub struct SetResults {
+    pub created: Map<String, Value>,
+    pub not_created: Map<String, Value>,
+    pub updated: Map<String, Value>,
+    pub not_updated: Map<String, Value>,
+    pub destroyed: Vec<String>,
+    pub not_destroyed: Map<String, Value>,
+}
+
+impl SetResults {
+    pub fn into_json(self, account_id: String, old_state: String, new_state: String) -> Value {
+        let map_or_null = |map: Map<String, Value>| {
+            if map.is_empty() {
+                Value::Null
+            } else {
+                Value::Object(map)
+            }
+        };
+        json!({
+            "accountId": account_id,
+            "oldState": old_state,
+            "newState": new_state,
+            "created": map_or_null(self.created),
+            "updated": map_or_null(self.updated),
+            "destroyed": if self.destroyed.is_empty() { Value::Null } else { json!(self.destroyed) },
+            "notCreated": map_or_null(self.not_created),
+            "notUpdated": map_or_null(self.not_updated),
+            "notDestroyed": map_or_null(self.not_destroyed),
+        })
+    }
+}
+
+/// The ids argument of a /get call; `None` stands for every object.
+pub fn ids_argument(store: &Store, args: &Value) -> Result<Option<Vec<String>>, MethodError> {
+    match args.get("ids") {
+        None | Some(Value::Null) => Ok(None),
+        Some(Value::Array(ids)) => {
+            if ids.len() > MAX_OBJECTS_IN_GET {
+                return Err(MethodError::new("requestTooLarge"));
+            }
+            ids.iter()
+                .map(|id| match id.as_str() {
+                    Some(id) => Ok(store.resolve_id(id)),
+                    None => Err(MethodError::invalid_arguments("ids must be strings")),
+                })
+                .collect::<Result<Vec<String>, MethodError>>()
+                .map(Some)
+        }
+        Some(_) => Err(MethodError::invalid_arguments("ids must be an array")),
+    }
+}
+
+/// The properties argument of a /get call, checked against the properties
+/// the object has. The id is always returned.
+pub fn properties_argument(args: &Value, known: &[&str], default: &[&str]) -> Result<Vec<String>, MethodError> {
+    let mut properties: Vec<String> = match args.get("properties") {
+        None | Some(Value::Null) => default.iter().map(|p| p.to_string()).collect(),
+        Some(Value::Array(properties)) => {
+            let mut res = Vec::new();
+            for property in properties {
+                match property.as_str() {
+                    Some(p) if known.contains(&p) || p.starts_with("header:") => res.push(p.to_string()),
+                    _ => return Err(MethodError::invalid_arguments(&format!("Unknown property {}", property))),
+                }
+            }
+            res
+        }
+        Some(_) => return Err(MethodError::invalid_arguments("properties must be an array")),
+    };
+    if !properties.iter().any(|p| p == "id") {
+        properties.insert(0, "id".to_string());
+    }
+    Ok(properties)
+}
+
+/// An optional unsigned integer argument.
+pub fn uint_argument(args: &Value, name: &str) -> Result<Option<u64>, MethodError> {
+    match args.get(name) {
+        None | Some(Value::Null) => Ok(None),
+        Some(value) => match value.as_u64() {
+            Some(n) => Ok(Some(n)),
+            None => Err(MethodError::invalid_arguments(&format!("{} must be an unsigned integer", name))),
+        },
+    }
+}
+
+/// An optional boolean argument.
+pub fn bool_argument(args: &Value, name: &str) -> Result<bool, MethodError> {
+    match args.get(name) {
+        None | Some(Value::Null) => Ok(false),
+        Some(Value::Bool(b)) => Ok(*b),
+        Some(_) => Err(MethodError::invalid_arguments(&format!("{} must be a boolean", name))),
+    }
+}
+
+/// Fail a /set call if the client expects another state.
+pub fn check_state(args: &Value, state: &str) -> Result<(), MethodError> {
+    match args.get("ifInState") {
+        None | Some(Value::Null) => Ok(()),
+        Some(expected) if expected.as_str() == Some(state) => Ok(()),
+        Some(_) => Err(MethodError::new("stateMismatch")),
+    }
+}
+
+/// The response to a /changes call.
+pub fn changes_response<'a>(
+    store: &Store<'a>,
+    args: &Value,
+    changes: impl Fn(&Store<'a>, &str, Option<usize>) -> Option<store::ChangeSet>,
+) -> MethodResult {
+    let since = match args.get("sinceState").and_then(|s| s.as_str()) {
+        Some(since) => since,
+        None => return Err(MethodError::invalid_arguments("sinceState is required")),
+    };
+    let max_changes = uint_argument(args, "maxChanges")?.map(|n| n as usize);
+    if max_changes == Some(0) {
+        return Err(MethodError::invalid_arguments("maxChanges must be positive"));
+    }
+    let set = changes(store, since, max_changes).ok_or_else(|| MethodError::new("cannotCalculateChanges"))?;
+    Ok(json!({
+        "accountId": store.account_id(),
+        "oldState": since,
+        "newState": set.new_state,
+        "hasMoreChanges": set.has_more,
+        "created": set.created,
+        "updated": set.updated,
+        "destroyed": set.destroyed,
+    }))
+}
+
+/// Keep the requested properties of an object.
+pub fn select_properties(object: Value, properties: &[String]) -> Value {
+    match object {
+        Value::Object(mut map) => {
+            let mut res = Map::new();
+            for property in properties {
+                if let Some(value) = map.remove(property) {
+                    res.insert(property.clone(), value);
+                }
+            }
+            Value::Object(res)
+        }
+        other => other,
+    }
+}
+
+/// Apply the position, anchor and limit arguments of a /query call to the
+/// sorted ids, returning the position of the first id kept.
+pub fn query_window(ids: Vec<String>, args: &Value) -> Result<(usize, Vec<String>), MethodError> {
+    let total = ids.len() as i64;
+    let mut position = match args.get("position") {
+        None | Some(Value::Null) => 0,
+        Some(value) => value
+            .as_i64()
+            .ok_or_else(|| MethodError::invalid_arguments("position must be an integer"))?,
+    };
+    if let Some(anchor) = args.get("anchor").and_then(|a| a.as_str()) {
+        let index = ids
+            .iter()
+            .position(|id| id == anchor)
+            .ok_or_else(|| MethodError::new("anchorNotFound"))?;
+        let offset = args.get("anchorOffset").and_then(|o| o.as_i64()).unwrap_or(0);
+        position = (index as i64 + offset).max(0);
+    } else if position < 0 {
+        position = (total + position).max(0);
+    }
+    let position = position.min(total) as usize;
+    let limit = uint_argument(args, "limit")?.map(|l| l as usize).unwrap_or(usize::MAX);
+    let window = ids.into_iter().skip(position).take(limit).collect();
+    Ok((position, window))
+}
+
+/// Evaluate a JSON pointer with the "*" extension of RFC 8620 section
+/// 3.7, which maps the rest of the pointer over an array.
+fn evaluate_pointer(value: &Value, path: &str) -> Option<Value> {
+    if path.is_empty() {
+        return Some(value.clone());
+    }
+    let path = path.strip_prefix('/')?;
+    let (token, rest) = match path.find('/') {
+        Some(index) => (&path[..index], &path[index..]),
+        None => (path, ""),
+    };
+    let token = token.replace("~1", "/").replace("~0", "~");
+    match value {
+        Value::Array(items) if token == "*" => {
+            let mut res = Vec::new();
+            for item in items {
+                match evaluate_pointer(item, rest)? {
+                    Value::Array(inner) => res.extend(inner),
+                    other => res.push(other),
+                }
+            }
+            Some(Value::Array(res))
+        }
+        Value::Array(items) => evaluate_pointer(items.get(token.parse::<usize>().ok()?)?, rest),
+        Value::Object(map) => evaluate_pointer(map.get(&token)?, rest),
+        _ => None,
+    }
+}
+
+/// Replace the arguments given as references to the results of earlier
+/// method calls, such as `"#ids": {"resultOf": "c1", "name":
+/// "Email/query", "path": "/ids"}`, with those results.
+fn resolve_references(args: &Value, responses: &[Value]) -> MethodResult {
+    let object = args
+        .as_object()
+        .ok_or_else(|| MethodError::invalid_arguments("Arguments must be an object"))?;
+    let mut resolved = Map::new();
+    for (key, value) in object {
+        let name = match key.strip_prefix('#') {
+            Some(name) => name,
+            None => {
+                resolved.insert(key.clone(), value.clone());
+                continue;
+            }
+        };
+        if object.contains_key(name) {
+            return Err(MethodError::invalid_arguments(&format!("{} given twice", name)));
+        }
+        let invalid = || MethodError::new("invalidResultReference");
+        let result_of = value.get("resultOf").and_then(|v| v.as_str()).ok_or_else(invalid)?;
+        let method = value.get("name").and_then(|v| v.as_str()).ok_or_else(invalid)?;
+        let path = value.get("path").and_then(|v| v.as_str()).ok_or_else(invalid)?;
+        let response = responses
+            .iter()
+            .find(|r| r[2].as_str() == Some(result_of) && r[0].as_str() == Some(method))
+            .ok_or_else(invalid)?;
+        resolved.insert(name.to_string(), evaluate_pointer(&response[1], path).ok_or_else(invalid)?);
+    }
+    Ok(Value::Object(resolved))
+}
+
+fn call_method(store: &mut Store, name: &str, args: &Value, using: &[&str]) -> MethodResult {
+    if name == "Core/echo" {
+        return Ok(args.clone());
+    }
+    if !using.contains(&MAIL_CAPABILITY) {
+        return Err(MethodError::new("unknownMethod"));
+    }
+    if args.get("accountId").and_then(|a| a.as_str()) != Some(&store.account_id()) {
+        return Err(MethodError::new("accountNotFound"));
+    }
+    match name {
+        "Mailbox/get" => mailbox::get(store, args),
+        "Mailbox/changes" => changes_response(store, args, Store::mailbox_changes),
+        "Mailbox/query" => mailbox::query(store, args),
+        "Mailbox/set" => mailbox::set(store, args),
+        "Email/get" => email::get(store, args),
+        "Email/changes" => changes_response(store, args, Store::email_changes),
+        "Email/query" => email::query(store, args),
+        "Email/set" => email::set(store, args),
+        "Thread/get" => email::get_threads(store, args),
+        _ => Err(MethodError::new("unknownMethod")),
+    }
+}
+
+/// Handle a JMAP API request: a batch of method calls.
+fn api(user: &User, host: &str, layout: Layout, cache: &Mutex<Cache>, body: &[u8]) -> Response {
+    if body.len() > MAX_SIZE_REQUEST {
+        let mut response = problem(400, "urn:ietf:params:jmap:error:limit", "The request is too large");
+        response.2 = serde_json::to_vec(&json!({
+            "type": "urn:ietf:params:jmap:error:limit",
+            "status": 400,
+            "limit": "maxSizeRequest",
+        }))
+        .unwrap_or_default();
+        return response;
+    }
+    let request: Value = match serde_json::from_slice(body) {
+        Ok(request) => request,
+        Err(_) => return problem(400, "urn:ietf:params:jmap:error:notJSON", "The request is not JSON"),
+    };
+    let not_request = || problem(400, "urn:ietf:params:jmap:error:notRequest", "The request is not a JMAP request");
+    let using: Vec<&str> = match request.get("using").and_then(|u| u.as_array()) {
+        Some(using) => using.iter().filter_map(|c| c.as_str()).collect(),
+        None => return not_request(),
+    };
+    let calls = match request.get("methodCalls").and_then(|c| c.as_array()) {
+        Some(calls) => calls,
+        None => return not_request(),
+    };
+    if let Some(capability) = using.iter().find(|c| **c != CORE_CAPABILITY && **c != MAIL_CAPABILITY) {
+        return problem(
+            400,
+            "urn:ietf:params:jmap:error:unknownCapability",
+            &format!("Unknown capability {}", capability),
+        );
+    }
+    if calls.len() > MAX_CALLS_IN_REQUEST {
+        return problem(400, "urn:ietf:params:jmap:error:limit", "Too many method calls");
+    }
+
+    let mut cache = cache.lock().unwrap_or_else(|e| e.into_inner());
+    let mut store = Store::open(user, host, layout, &mut cache);
+    if let Some(created_ids) = request.get("createdIds").and_then(|c| c.as_object()) {
+        for (creation_id, id) in created_ids {
+            if let Some(id) = id.as_str() {
+                store.created_ids.insert(creation_id.clone(), id.to_string());
+            }
+        }
+    }
+    let mut responses: Vec<Value> = Vec::new();
+    for call in calls {
+        let (name, args, call_id) = match call.as_array().map(|c| &c[..]) {
+            Some([Value::String(name), args, Value::String(call_id)]) => (name, args, call_id),
+            _ => return not_request(),
+        };
+        match resolve_references(args, &responses).and_then(|args| call_method(&mut store, name, &args, &using)) {
+            Ok(result) => responses.push(json!([name, result, call_id])),
+            Err(e) => responses.push(json!(["error", e.to_json(), call_id])),
+        }
+    }
+    if let Err(e) = store.save() {
+        warn!("Error saving JMAP state for {}: {}", user.email, e);
+    }
+
+    let mut response = json!({
+        "methodResponses": responses,
+        "sessionState": SESSION_STATE,
+    });
+    if request.get("createdIds").is_some() {
+        response["createdIds"] = json!(store.created_ids);
+    }
+    json_response(200, &response)
+}
+
+/// The session resource, describing the server and the user's account.
+fn session(user: &User, base_url: &str) -> Response {
+    let account = account_id(user);
+    json_response(
+        200,
+        &json!({
+            "capabilities": {
+                CORE_CAPABILITY: {
+                    "maxSizeUpload": MAX_SIZE_UPLOAD,
+                    "maxConcurrentUpload": 1,
+                    "maxSizeRequest": MAX_SIZE_REQUEST,
+                    "maxConcurrentRequests": 1,
+                    "maxCallsInRequest": MAX_CALLS_IN_REQUEST,
+                    "maxObjectsInGet": MAX_OBJECTS_IN_GET,
+                    "maxObjectsInSet": MAX_OBJECTS_IN_SET,
+                    "collationAlgorithms": ["i;ascii-casemap"],
+                },
+                MAIL_CAPABILITY: {},
+            },
+            "accounts": {
+                account.clone(): {
+                    "name": user.email.to_string(),
+                    "isPersonal": true,
+                    "isReadOnly": false,
+                    "accountCapabilities": {
+                        CORE_CAPABILITY: {},
+                        MAIL_CAPABILITY: {
+                            "maxMailboxesPerEmail": null,
+                            "maxMailboxDepth": null,
+                            "maxSizeMailboxName": 255,
+                            "maxSizeAttachmentsPerEmail": MAX_SIZE_UPLOAD,
+                            "emailQuerySortOptions": email::SORT_PROPERTIES,
+                            "mayCreateTopLevelMailbox": true,
+                        },
+                    },
+                },
+            },
+            "primaryAccounts": {
+                CORE_CAPABILITY: account.clone(),
+                MAIL_CAPABILITY: account,
+            },
+            "username": user.email.to_string(),
+            "apiUrl": format!("{}/jmap/", base_url),
+            "downloadUrl": format!("{}/jmap/download/{{accountId}}/{{blobId}}/{{name}}?accept={{type}}", base_url),
+            "uploadUrl": format!("{}/jmap/upload/{{accountId}}/", base_url),
+            "eventSourceUrl": format!("{}/jmap/eventsource/", base_url),
+            "state": SESSION_STATE,
+        }),
+    )
+}
+
+/// Send a blob. The path is `{accountId}/{blobId}/{name}`.
+fn download(user: &User, host: &str, layout: Layout, cache: &Mutex<Cache>, path: &str, request: &Request) -> Response {
+    let mut split = path.splitn(3, '/');
+    let (account, blob_id, name) = match (split.next(), split.next(), split.next()) {
+        (Some(account), Some(blob_id), Some(name)) => (account, blob_id, percent_decode(name)),
+        _ => return problem(404, "about:blank", "No such blob"),
+    };
+    if account != account_id(user) {
+        return problem(404, "about:blank", "No such account");
+    }
+    let data = {
+        let mut cache = cache.lock().unwrap_or_else(|e| e.into_inner());
+        Store::open(user, host, layout, &mut cache).blob(blob_id)
+    };
+    let data = match data {
+        Some(data) => data,
+        None => return problem(404, "about:blank", "No such blob"),
+    };
+    let content_type = request
+        .query_param("accept")
+        .filter(|t| !t.is_empty() && !t.contains(['\r', '\n']))
+        .unwrap_or_else(|| "application/octet-stream".to_string());
+    let name: String = name.chars().filter(|c| !c.is_control() && *c != '"' && *c != '\\').collect();
+    (
+        200,
+        vec![
+            ("Content-Type", content_type),
+            ("Content-Disposition", format!("attachment; filename=\"{}\"", name)),
+            ("Cache-Control", "private, immutable, max-age=31536000".to_string()),
+        ],
+        data,
+    )
+}
+
+/// Keep an uploaded blob. The path is `{accountId}/`.
+fn upload(user: &User, cache: &Mutex<Cache>, path: &str, request: &Request) -> Response {
+    if path.trim_end_matches('/') != account_id(user) {
+        return problem(404, "about:blank", "No such account");
+    }
+    let result = {
+        let _lock = cache.lock().unwrap_or_else(|e| e.into_inner());
+        store::upload(user, &request.body)
+    };
+    match result {
+        Ok(blob_id) => json_response(
+            201,
+            &json!({
+                "accountId": account_id(user),
+                "blobId": blob_id,
+                "type": request.header("content-type").unwrap_or("application/octet-stream"),
+                "size": request.body.len(),
+            }),
+        ),
+        Err(e) => {
+            warn!("Error storing upload for {}: {}", user.email, e);
+            problem(500, "about:blank", "Error storing the upload")
+        }
+    }
+}
+
+/// Check the credentials of a request, given with Basic authentication.
+fn authenticate<'a>(serv: &'a Server, request: &Request) -> Option<&'a User> {
+    let credentials = request.header("authorization")?;
+    let (scheme, encoded) = credentials.split_once(' ')?;
+    if !scheme.eq_ignore_ascii_case("Basic") {
+        return None;
+    }
+    let decoded = String::from_utf8(base64_decode(encoded.trim().as_bytes())).ok()?;
+    let (email, password) = decoded.split_once(':')?;
+    serv.login(email.to_string(), password.to_string())
+}
+
+fn handle(serv: &Server, request: &Request) -> Response {
+    if request.method == "OPTIONS" {
+        // A CORS preflight request
+        return (
+            204,
+            vec![
+                ("Access-Control-Allow-Methods", "GET, POST, OPTIONS".to_string()),
+                ("Access-Control-Allow-Headers", "Authorization, Content-Type, Accept".to_string()),
+                ("Access-Control-Max-Age", "86400".to_string()),
+            ],
+            Vec::new(),
+        );
+    }
+    let user = match authenticate(serv, request) {
+        Some(user) => user,
+        None => {
+            let mut response = problem(401, "about:blank", "Authentication required");
+            response
+                .1
+                .push(("WWW-Authenticate", "Basic realm=\"JMAP\", charset=\"UTF-8\"".to_string()));
+            return response;
+        }
+    };
+    let host = match request.header("host") {
+        Some(host) => host.to_string(),
+        None => format!("{}:{}", serv.host(), serv.conf.jmap_port.unwrap_or(80)),
+    };
+    let base_url = format!("http://{}", host);
+    let path = &request.path[..];
+    // Requests for the same maildir must not interleave, as they share the
+    // state file and the cache
+    let cache = serv.jmap_cache(user);
+    match &request.method[..] {
+        "GET" if path == "/.well-known/jmap" || path == "/jmap/session" => session(user, &base_url),
+        "POST" if path == "/jmap/" || path == "/jmap" => api(user, serv.host(), serv.layout(user), &cache, &request.body),
+        "GET" if path.starts_with("/jmap/download/") => {
+            download(user, serv.host(), serv.layout(user), &cache, &path["/jmap/download/".len()..], request)
+        }
+        "POST" if path.starts_with("/jmap/upload/") => upload(user, &cache, &path["/jmap/upload/".len()..], request),
+        "GET" | "POST" => problem(404, "about:blank", "Not found"),
+        _ => problem(405, "about:blank", "Method not allowed"),
+    }
+}
+
+pub fn serve(serv: Arc<Server>, mut stream: BufStream<Stream>) {
+    let response = match read_request(&mut stream, MAX_SIZE_UPLOAD) {
+        Ok(Some(request)) => handle(&serv, &request),
+        Ok(None) => return,
+        Err(e) => problem(e.status(), "about:blank", "Invalid request"),
+    };
+    let (status, headers, body) = response;
+    if let Err(e) = write_response(&mut stream, status, &headers, &body) {
+        warn!("Error sending JMAP response: {}", e);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_evaluate_pointer() {
+        let value = json!({"list": [{"id": "a", "ids": ["x", "y"]}, {"id": "b", "ids": ["z"]}], "a/b": 1});
+        assert_eq!(evaluate_pointer(&value, "/list/*/id"), Some(json!(["a", "b"])));
+        assert_eq!(evaluate_pointer(&value, "/list/*/ids"), Some(json!(["x", "y", "z"])));
+        assert_eq!(evaluate_pointer(&value, "/list/1/id"), Some(json!("b")));
+        assert_eq!(evaluate_pointer(&value, "/a~1b"), Some(json!(1)));
+        assert_eq!(evaluate_pointer(&value, "/missing"), None);
+    }
+
+    #[test]
+    fn test_resolve_references() {
+        let responses = vec![json!(["Email/query", {"ids": ["e1", "e2"]}, "c1"])];
+        let args = json!({"accountId": "a", "#ids": {"resultOf": "c1", "name": "Email/query", "path": "/ids"}});
+        assert_eq!(
+            resolve_references(&args, &responses),
+            Ok(json!({"accountId": "a", "ids": ["e1", "e2"]}))
+        );
+        let args = json!({"#ids": {"resultOf": "c1", "name": "Email/get", "path": "/ids"}});
+        assert_eq!(
+            resolve_references(&args, &responses),
+            Err(MethodError::new("invalidResultReference"))
+        );
+    }
+
+    #[test]
+    fn test_query_window() {
+        let ids: Vec<String> = (0..5).map(|i| i.to_string()).collect();
+        let window = |args: Value| query_window(ids.clone(), &args);
+        assert_eq!(window(json!({})).unwrap(), (0, ids.clone()));
+        assert_eq!(window(json!({"position": 1, "limit": 2})).unwrap(), (1, vec!["1".to_string(), "2".to_string()]));
+        assert_eq!(window(json!({"position": -2})).unwrap(), (3, vec!["3".to_string(), "4".to_string()]));
+        assert_eq!(window(json!({"anchor": "2", "anchorOffset": -1, "limit": 1})).unwrap(), (1, vec!["1".to_string()]));
+        assert_eq!(window(json!({"anchor": "x"})).err(), Some(MethodError::new("anchorNotFound")));
+    }
+}
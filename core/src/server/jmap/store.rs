@@ -0,0 +1,689 @@
+//! The view of a user's maildir that JMAP methods work on: mailboxes and
+//! emails with their ids, and the state strings that let clients ask for
+//! what changed since they last looked.
+
+use std::collections::{BTreeSet, HashMap, HashSet};
+use std::fs::{self, File};
+use std::io::{self, Read};
+use std::path::{Path, PathBuf};
+use std::time::{Duration, SystemTime};
+
+use crypto::digest::Digest;
+use crypto::sha1::Sha1;
+use mime::thread::message_ids;
+use mime::Message as MIME_Message;
+
+use crate::folder::Folder;
+use crate::layout::Layout;
+use crate::message::{flag_name, Flag, Message};
+use crate::server::user::User;
+use crate::sieve::write_file;
+
+// The directory in the maildir holding the JMAP state and uploaded blobs.
+pub static JMAP_DIR: &str = ".jmap";
+static STATE_FILE: &str = "state.json";
+static BLOB_DIR: &str = "blobs";
+
+// How many changes are kept for the /changes methods.
+const MAX_LOGGED_CHANGES: usize = 1000;
+
+// The coarsest modification times a file system may keep.
+const TIMESTAMP_GRANULARITY: Duration = Duration::from_secs(2);
+
+// Uploaded blobs are removed after this long, whether used or not.
+const BLOB_LIFETIME: Duration = Duration::from_secs(24 * 60 * 60);
+
+/// The SHA-1 digest of some data, in hex.
+pub fn digest(data: &[u8]) -> String {
+    let mut hasher = Sha1::new();
+    hasher.input(data);
+    hasher.result_str()
+}
+
+/// The thread an email belongs to is named after the first message of the
+/// conversation, as far as the email's references tell.
+fn thread_id(message: &MIME_Message, email_id: &str) -> String {
+    let root = ["References", "In-Reply-To", "Message-ID"]
+        .iter()
+        .filter_map(|name| message.get_header_values(name).first().and_then(|v| message_ids(v).into_iter().next()))
+        .next();
+    match root {
+        Some(root) => format!("t{}", &digest(root.as_bytes())[..24]),
+        None => format!("t{}", &email_id[1..25]),
+    }
+}
+
+/// A maildir folder.
+pub struct Mailbox {
+    pub id: String,
+    pub name: String,
+    pub parent_id: Option<String>,
+    /// The folder's path relative to the maildir, with "/" between the
+    /// levels of the hierarchy. INBOX, the maildir itself, is "".
+    pub relative: String,
+    pub path: PathBuf,
+}
+
+/// One copy of an email, as a file in a mailbox.
+pub struct EmailFile {
+    pub mailbox_id: String,
+    pub message: Message,
+}
+
+/// An email, made up of every file with the same contents. Emails are
+/// identified by a digest of their contents, so their ids stay the same
+/// when they move between mailboxes.
+pub struct Email {
+    pub id: String,
+    pub thread_id: String,
+    pub size: u64,
+    pub files: Vec<EmailFile>,
+}
+
+impl Email {
+    pub fn mime(&self) -> &MIME_Message {
+        self.files[0].message.get_mime_message()
+    }
+
+    pub fn path(&self) -> &Path {
+        self.files[0].message.get_path()
+    }
+
+    /// The id of the blob holding the raw message.
+    pub fn blob_id(&self) -> String {
+        format!("b{}", &self.id[1..])
+    }
+
+    pub fn flags(&self) -> HashSet<Flag> {
+        self.files.iter().flat_map(|f| f.message.get_flags().iter().cloned()).collect()
+    }
+
+    pub fn mailbox_ids(&self) -> BTreeSet<String> {
+        self.files.iter().map(|f| f.mailbox_id.clone()).collect()
+    }
+
+    /// When the email was delivered, as a UNIX timestamp.
+    pub fn received_at(&self) -> usize {
+        self.files.iter().map(|f| f.message.get_uid()).min().unwrap_or(0)
+    }
+}
+
+/// The changes to one type of object between two states.
+pub struct ChangeSet {
+    pub new_state: String,
+    pub has_more: bool,
+    pub created: Vec<String>,
+    pub updated: Vec<String>,
+    pub destroyed: Vec<String>,
+}
+
+#[derive(Clone, Default, Serialize, Deserialize)]
+struct Change {
+    state: u64,
+    created: Vec<String>,
+    updated: Vec<String>,
+    destroyed: Vec<String>,
+}
+
+#[derive(Clone, Copy, PartialEq)]
+enum ChangeKind {
+    Created,
+    Updated,
+    Destroyed,
+}
+
+/// The state of one type of object, a fingerprint of each object at that
+/// state and the recent changes.
+#[derive(Default, Serialize, Deserialize)]
+struct Changes {
+    state: u64,
+    snapshot: HashMap<String, String>,
+    log: Vec<Change>,
+}
+
+impl Changes {
+    /// Compare the objects as they are now to the last snapshot, moving to
+    /// a new state if anything changed.
+    fn record(&mut self, snapshot: HashMap<String, String>) {
+        if self.state == 0 {
+            // Nothing to compare to the first time around
+            self.state = 1;
+            self.snapshot = snapshot;
+            return;
+        }
+        let mut change = Change {
+            state: self.state + 1,
+            ..Change::default()
+        };
+        for (id, fingerprint) in &snapshot {
+            match self.snapshot.get(id) {
+                None => change.created.push(id.clone()),
+                Some(old) if old != fingerprint => change.updated.push(id.clone()),
+                Some(_) => {}
+            }
+        }
+        for id in self.snapshot.keys() {
+            if !snapshot.contains_key(id) {
+                change.destroyed.push(id.clone());
+            }
+        }
+        if change.created.is_empty() && change.updated.is_empty() && change.destroyed.is_empty() {
+            return;
+        }
+        change.created.sort();
+        change.updated.sort();
+        change.destroyed.sort();
+        self.state += 1;
+        self.log.push(change);
+        if self.log.len() > MAX_LOGGED_CHANGES {
+            let excess = self.log.len() - MAX_LOGGED_CHANGES;
+            self.log.drain(..excess);
+        }
+        self.snapshot = snapshot;
+    }
+
+    /// The changes since the given state, stopping early once about
+    /// `max_changes` ids have been collected. `None` if the state is unknown
+    /// or too old.
+    fn since(&self, since: &str, max_changes: Option<usize>) -> Option<ChangeSet> {
+        let since: u64 = since.parse().ok()?;
+        if since == 0 || since > self.state {
+            return None;
+        }
+        if since < self.state && self.log.first()?.state > since + 1 {
+            return None;
+        }
+        let mut kinds: HashMap<String, ChangeKind> = HashMap::new();
+        let mut order: Vec<String> = Vec::new();
+        let mut new_state = since;
+        let mut has_more = false;
+        for change in self.log.iter().filter(|c| c.state > since) {
+            let count = change.created.len() + change.updated.len() + change.destroyed.len();
+            if let Some(max) = max_changes {
+                if new_state > since && kinds.len() + count > max {
+                    has_more = true;
+                    break;
+                }
+            }
+            for (ids, kind) in &[
+                (&change.created, ChangeKind::Created),
+                (&change.updated, ChangeKind::Updated),
+                (&change.destroyed, ChangeKind::Destroyed),
+            ] {
+                for id in ids.iter() {
+                    let previous = kinds.get(id).copied();
+                    let next = match (previous, *kind) {
+                        // Gone again before the client saw it
+                        (Some(ChangeKind::Created), ChangeKind::Destroyed) => {
+                            kinds.remove(id);
+                            continue;
+                        }
+                        (Some(ChangeKind::Created), _) => ChangeKind::Created,
+                        (Some(ChangeKind::Destroyed), ChangeKind::Created) => ChangeKind::Updated,
+                        (_, kind) => kind,
+                    };
+                    if previous.is_none() {
+                        order.push(id.clone());
+                    }
+                    kinds.insert(id.clone(), next);
+                }
+            }
+            new_state = change.state;
+        }
+        let mut set = ChangeSet {
+            new_state: new_state.to_string(),
+            has_more,
+            created: Vec::new(),
+            updated: Vec::new(),
+            destroyed: Vec::new(),
+        };
+        for id in order {
+            match kinds.remove(&id) {
+                Some(ChangeKind::Created) => set.created.push(id),
+                Some(ChangeKind::Updated) => set.updated.push(id),
+                Some(ChangeKind::Destroyed) => set.destroyed.push(id),
+                None => {}
+            }
+        }
+        Some(set)
+    }
+}
+
+/// What is kept between requests, in the JMAP directory of the maildir.
+#[derive(Default, Serialize, Deserialize)]
+struct State {
+    next_mailbox_id: u64,
+    // Mailbox ids by relative path
+    mailbox_ids: HashMap<String, String>,
+    // Email ids by relative path and uid, to avoid reading every file for
+    // every request
+    email_ids: HashMap<String, String>,
+    mailboxes: Changes,
+    emails: Changes,
+}
+
+/// When the cur/ and new/ directories of a folder last changed.
+type Stamp = (Option<SystemTime>, Option<SystemTime>);
+
+/// The messages of each folder of a maildir as last read, by path, along
+/// with when its cur/ and new/ directories last changed and when it was
+/// read. A folder is only read again once either directory has changed.
+#[derive(Default)]
+pub struct Cache {
+    folders: HashMap<PathBuf, (Stamp, SystemTime, Vec<Message>)>,
+}
+
+impl Cache {
+    fn messages(&mut self, path: &Path) -> Option<&[Message]> {
+        let modified = |dir: &str| fs::metadata(path.join(dir)).and_then(|m| m.modified()).ok();
+        let stamp = (modified("cur"), modified("new"));
+        // A change in the same tick of the file system clock as the read
+        // would not show, so a folder read then is read again
+        let fresh = |(cached, read_at, _): &(Stamp, SystemTime, Vec<Message>)| {
+            let settled = |time: Option<SystemTime>| time.is_some_and(|time| time + TIMESTAMP_GRANULARITY < *read_at);
+            *cached == stamp && settled(stamp.0) && settled(stamp.1)
+        };
+        if !self.folders.get(path).is_some_and(fresh) {
+            self.folders.remove(path);
+            let read_at = SystemTime::now();
+            let folder = Folder::new(path.to_path_buf(), true)?;
+            let messages = (0..folder.message_count()).filter_map(|index| folder.message(index).cloned()).collect();
+            self.folders.insert(path.to_path_buf(), (stamp, read_at, messages));
+        }
+        self.folders.get(path).map(|(_, _, messages)| &messages[..])
+    }
+}
+
+/// A user's mailboxes and emails, as of the last scan of the maildir.
+pub struct Store<'a> {
+    pub user: &'a User,
+    /// The server's host name, for the messages it creates
+    pub host: &'a str,
+    maildir: PathBuf,
+    /// How the folders of the maildir are laid out
+    pub layout: Layout,
+    state: State,
+    cache: &'a mut Cache,
+    pub mailboxes: Vec<Mailbox>,
+    pub emails: Vec<Email>,
+    email_index: HashMap<String, usize>,
+    /// The ids of the objects created in the current request, by the
+    /// creation ids the client gave them.
+    pub created_ids: HashMap<String, String>,
+}
+
+impl<'a> Store<'a> {
+    pub fn open(user: &'a User, host: &'a str, layout: Layout, cache: &'a mut Cache) -> Store<'a> {
+        let maildir = PathBuf::from(&user.maildir);
+        let mut contents = String::new();
+        let state_path = maildir.join(JMAP_DIR).join(STATE_FILE);
+        let state = match File::open(&state_path).and_then(|mut f| f.read_to_string(&mut contents)) {
+            Ok(_) => serde_json::from_str(&contents).unwrap_or_else(|e| {
+                warn!("Error parsing JMAP state '{}': {}", state_path.display(), e);
+                State::default()
+            }),
+            Err(_) => State::default(),
+        };
+        let mut store = Store {
+            user,
+            host,
+            maildir,
+            layout,
+            state,
+            cache,
+            mailboxes: Vec::new(),
+            emails: Vec::new(),
+            email_index: HashMap::new(),
+            created_ids: HashMap::new(),
+        };
+        store.scan();
+        store
+    }
+
+    /// Write out the state for the next request.
+    pub fn save(&self) -> io::Result<()> {
+        let encoded = serde_json::to_string(&self.state)?;
+        write_file(&self.maildir.join(JMAP_DIR).join(STATE_FILE), encoded.as_bytes())
+    }
+
+    /// The JMAP account of a user. Account ids may only hold letters,
+    /// digits, "-" and "_", so the address is hex encoded.
+    pub fn account_id(&self) -> String {
+        account_id(self.user)
+    }
+
+    pub fn maildir(&self) -> &Path {
+        &self.maildir
+    }
+
+    /// Read the maildir again, after changes to it.
+    pub fn scan(&mut self) {
+        self.scan_mailboxes();
+        self.scan_emails();
+
+        let mut snapshot = HashMap::new();
+        for email in &self.emails {
+            let mailbox_ids: Vec<String> = email.mailbox_ids().into_iter().collect();
+            let flags = email.flags();
+            let mut flag_names: Vec<&str> = flags.iter().map(flag_name).collect();
+            flag_names.sort();
+            snapshot.insert(email.id.clone(), format!("{} {}", mailbox_ids.join(","), flag_names.join(" ")));
+        }
+        self.state.emails.record(snapshot);
+
+        let mut snapshot = HashMap::new();
+        for mailbox in &self.mailboxes {
+            let counts = self.counts(&mailbox.id);
+            snapshot.insert(
+                mailbox.id.clone(),
+                format!("{}/{}/{}/{:?}", mailbox.name, mailbox.parent_id.as_deref().unwrap_or(""), mailbox.relative, counts),
+            );
+        }
+        self.state.mailboxes.record(snapshot);
+    }
+
+    fn scan_mailboxes(&mut self) {
+        let mut relatives = Vec::new();
+        if self.maildir.join("cur").is_dir() && self.maildir.join("new").is_dir() {
+            relatives.push(String::new());
+        }
+        relatives.extend(self.layout.folders(&self.maildir));
+
+        let mut mailbox_ids = HashMap::new();
+        for relative in &relatives {
+            let id = match self.state.mailbox_ids.get(relative) {
+                Some(id) => id.clone(),
+                None => {
+                    self.state.next_mailbox_id += 1;
+                    format!("m{}", self.state.next_mailbox_id)
+                }
+            };
+            mailbox_ids.insert(relative.clone(), id);
+        }
+        self.mailboxes = relatives
+            .iter()
+            .map(|relative| {
+                let (parent, name) = match relative.rfind('/') {
+                    Some(index) => (&relative[..index], &relative[index + 1..]),
+                    None if relative.is_empty() => ("", "Inbox"),
+                    None => ("", &relative[..]),
+                };
+                let levels: Vec<&str> = relative.split('/').filter(|level| !level.is_empty()).collect();
+                // Top-level folders are siblings of INBOX, as in IMAP
+                let parent_id = if parent.is_empty() {
+                    None
+                } else {
+                    mailbox_ids.get(parent).cloned()
+                };
+                Mailbox {
+                    id: mailbox_ids[relative].clone(),
+                    name: name.to_string(),
+                    parent_id,
+                    relative: relative.clone(),
+                    path: self.layout.folder_path(&self.maildir, &levels),
+                }
+            })
+            .collect();
+        self.state.mailbox_ids = mailbox_ids;
+    }
+
+    fn scan_emails(&mut self) {
+        let mut email_ids = HashMap::new();
+        let mut emails: Vec<Email> = Vec::new();
+        let mut email_index: HashMap<String, usize> = HashMap::new();
+        let paths: HashSet<&PathBuf> = self.mailboxes.iter().map(|mailbox| &mailbox.path).collect();
+        self.cache.folders.retain(|path, _| paths.contains(path));
+        for mailbox in &self.mailboxes {
+            let messages = match self.cache.messages(&mailbox.path) {
+                Some(messages) => messages,
+                None => continue,
+            };
+            for message in messages {
+                let message = message.clone();
+                let key = format!("{}/{}", mailbox.relative, message.get_uid());
+                let id = match self.state.email_ids.get(&key) {
+                    Some(id) => id.clone(),
+                    None => {
+                        let mut data = Vec::new();
+                        if let Err(e) = File::open(message.get_path()).and_then(|mut f| f.read_to_end(&mut data)) {
+                            warn!("Error reading '{}': {}", message.get_path().display(), e);
+                            continue;
+                        }
+                        format!("e{}", digest(&data))
+                    }
+                };
+                email_ids.insert(key, id.clone());
+                let file = EmailFile {
+                    mailbox_id: mailbox.id.clone(),
+                    message,
+                };
+                match email_index.get(&id) {
+                    Some(&i) => emails[i].files.push(file),
+                    None => {
+                        let size = fs::metadata(file.message.get_path()).map(|m| m.len()).unwrap_or(0);
+                        let thread_id = thread_id(file.message.get_mime_message(), &id);
+                        email_index.insert(id.clone(), emails.len());
+                        emails.push(Email {
+                            id,
+                            thread_id,
+                            size,
+                            files: vec![file],
+                        });
+                    }
+                }
+            }
+        }
+        self.state.email_ids = email_ids;
+        self.emails = emails;
+        self.email_index = email_index;
+    }
+
+    /// The number of emails and threads in a mailbox, and how many of them
+    /// are unread.
+    pub fn counts(&self, mailbox_id: &str) -> (usize, usize, usize, usize) {
+        let mut total = 0;
+        let mut unread = 0;
+        let mut threads = HashSet::new();
+        let mut unread_threads = HashSet::new();
+        for email in &self.emails {
+            if !email.files.iter().any(|f| f.mailbox_id == mailbox_id) {
+                continue;
+            }
+            total += 1;
+            threads.insert(&email.thread_id);
+            if !email.flags().contains(&Flag::Seen) {
+                unread += 1;
+                unread_threads.insert(&email.thread_id);
+            }
+        }
+        (total, unread, threads.len(), unread_threads.len())
+    }
+
+    pub fn mailbox(&self, id: &str) -> Option<&Mailbox> {
+        self.mailboxes.iter().find(|m| m.id == id)
+    }
+
+    pub fn email(&self, id: &str) -> Option<&Email> {
+        self.email_index.get(id).map(|&i| &self.emails[i])
+    }
+
+    pub fn mailbox_state(&self) -> String {
+        self.state.mailboxes.state.to_string()
+    }
+
+    pub fn email_state(&self) -> String {
+        self.state.emails.state.to_string()
+    }
+
+    pub fn mailbox_changes(&self, since: &str, max_changes: Option<usize>) -> Option<ChangeSet> {
+        self.state.mailboxes.since(since, max_changes)
+    }
+
+    pub fn email_changes(&self, since: &str, max_changes: Option<usize>) -> Option<ChangeSet> {
+        self.state.emails.since(since, max_changes)
+    }
+
+    /// Keep the id of a mailbox, and those of the mailboxes below it, when
+    /// it is moved to a new path.
+    pub fn move_mailbox(&mut self, from: &str, to: &str) {
+        let prefix = format!("{}/", from);
+        let moved: Vec<(String, String)> = self
+            .state
+            .mailbox_ids
+            .iter()
+            .filter(|(relative, _)| *relative == from || relative.starts_with(&prefix))
+            .map(|(relative, id)| (relative.clone(), id.clone()))
+            .collect();
+        for (relative, id) in moved {
+            self.state.mailbox_ids.remove(&relative);
+            self.state.mailbox_ids.insert(format!("{}{}", to, &relative[from.len()..]), id);
+        }
+    }
+
+    /// The contents of a blob: an uploaded file, a whole email or, for ids
+    /// with a part id after a "-", a body part of an email decoded. Blob ids
+    /// may not hold dots, so those of the part id are written as "_".
+    pub fn blob(&self, id: &str) -> Option<Vec<u8>> {
+        if let Some(digest) = id.strip_prefix('u') {
+            if !digest.chars().all(|c| c.is_ascii_hexdigit()) {
+                return None;
+            }
+            let mut data = Vec::new();
+            File::open(blob_dir(self.user).join(digest)).and_then(|mut f| f.read_to_end(&mut data)).ok()?;
+            return Some(data);
+        }
+        let digest = id.strip_prefix('b')?;
+        let (digest, part_id) = match digest.find('-') {
+            Some(index) => (&digest[..index], Some(digest[index + 1..].replace('_', "."))),
+            None => (digest, None),
+        };
+        let email = self.email(&format!("e{}", digest))?;
+        match part_id {
+            Some(part_id) => super::email::find_part(email.mime().get_body_part(), &part_id).map(|p| p.decoded_bytes()),
+            None => {
+                let mut data = Vec::new();
+                File::open(email.path()).and_then(|mut f| f.read_to_end(&mut data)).ok()?;
+                Some(data)
+            }
+        }
+    }
+
+    /// The id of an object, which may be the creation id of an object
+    /// created earlier in the request, prefixed with "#".
+    pub fn resolve_id(&self, id: &str) -> String {
+        match id.strip_prefix('#') {
+            Some(creation_id) => self.created_ids.get(creation_id).cloned().unwrap_or_else(|| id.to_string()),
+            None => id.to_string(),
+        }
+    }
+}
+
+fn blob_dir(user: &User) -> PathBuf {
+    Path::new(&user.maildir).join(JMAP_DIR).join(BLOB_DIR)
+}
+
+/// Keep an uploaded blob, returning its id. Blobs uploaded long ago are
+/// cleaned up at the same time.
+pub fn upload(user: &User, data: &[u8]) -> io::Result<String> {
+    let dir = blob_dir(user);
+    if let Ok(entries) = fs::read_dir(&dir) {
+        for entry in entries.flatten() {
+            let expired = match entry.metadata().and_then(|m| m.modified()) {
+                Ok(modified) => modified.elapsed().map(|age| age > BLOB_LIFETIME).unwrap_or(false),
+                Err(_) => false,
+            };
+            if expired {
+                let _ = fs::remove_file(entry.path());
+            }
+        }
+    }
+    let digest = digest(data);
+    let path = dir.join(&digest);
+    if path.exists() {
+        // Uploading the same data again extends its life
+        let _ = File::options().append(true).open(&path).and_then(|f| f.set_modified(SystemTime::now()));
+    } else {
+        write_file(&path, data)?;
+    }
+    Ok(format!("u{}", digest))
+}
+
+/// The JMAP account id of a user.
+pub fn account_id(user: &User) -> String {
+    user.email.to_string().bytes().map(|b| format!("{:02x}", b)).collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testing::TempDir;
+
+    fn snapshot(entries: &[(&str, &str)]) -> HashMap<String, String> {
+        entries.iter().map(|(k, v)| (k.to_string(), v.to_string())).collect()
+    }
+
+    #[test]
+    fn test_changes() {
+        let mut changes = Changes::default();
+        changes.record(snapshot(&[("a", "1"), ("b", "1")]));
+        assert_eq!(changes.state, 1);
+        changes.record(snapshot(&[("a", "1"), ("b", "1")]));
+        assert_eq!(changes.state, 1);
+        // b updated, c created
+        changes.record(snapshot(&[("a", "1"), ("b", "2"), ("c", "1")]));
+        // a destroyed, c updated, d created
+        changes.record(snapshot(&[("b", "2"), ("c", "2"), ("d", "1")]));
+        // d destroyed again
+        changes.record(snapshot(&[("b", "2"), ("c", "2")]));
+        assert_eq!(changes.state, 4);
+
+        let set = changes.since("1", None).unwrap();
+        assert_eq!(set.new_state, "4");
+        assert!(!set.has_more);
+        assert_eq!(set.created, vec!["c"]);
+        assert_eq!(set.updated, vec!["b"]);
+        assert_eq!(set.destroyed, vec!["a"]);
+
+        let set = changes.since("2", None).unwrap();
+        assert_eq!(set.created, Vec::<String>::new());
+        assert_eq!(set.updated, vec!["c"]);
+        assert_eq!(set.destroyed, vec!["a"]);
+
+        let set = changes.since("1", Some(2)).unwrap();
+        assert_eq!(set.new_state, "2");
+        assert!(set.has_more);
+
+        assert!(changes.since("4", None).unwrap().created.is_empty());
+        assert!(changes.since("5", None).is_none());
+        assert!(changes.since("0", None).is_none());
+        assert!(changes.since("x", None).is_none());
+    }
+
+    #[test]
+    fn test_cache() {
+        let path = TempDir::new("jmap-cache");
+        fs::create_dir_all(path.join("cur")).unwrap();
+        fs::create_dir_all(path.join("new")).unwrap();
+        fs::write(path.join("cur").join("1000:2,S"), "Subject: a\n\n").unwrap();
+        let past = SystemTime::now() - Duration::from_secs(60);
+        let settle = || {
+            for dir in &["cur", "new"] {
+                File::open(path.join(dir)).unwrap().set_modified(past).unwrap();
+            }
+        };
+        settle();
+        let mut cache = Cache::default();
+        let uids = |cache: &mut Cache| -> Vec<usize> {
+            cache.messages(&path).unwrap().iter().map(|message| message.get_uid()).collect()
+        };
+        assert_eq!(uids(&mut cache), vec![1000]);
+
+        // Unchanged directories are not read again
+        fs::remove_file(path.join("cur").join("1000:2,S")).unwrap();
+        settle();
+        assert_eq!(uids(&mut cache), vec![1000]);
+
+        fs::write(path.join("new").join("1001"), "Subject: b\n\n").unwrap();
+        assert_eq!(uids(&mut cache), vec![1001]);
+    }
+}
@@ -1,15 +1,29 @@
-use std::fs::File;
+use std::collections::HashSet;
+use std::fs::{self, OpenOptions};
 use std::io::ErrorKind::AlreadyExists;
-use std::io::{BufRead, Write};
-use std::net::TcpStream;
-use std::path::Path;
+use std::io::{self, BufRead, Read, Write};
+use std::os::unix::fs::PermissionsExt;
+use std::path::{Path, PathBuf};
+use std::str::Split;
 use std::sync::Arc;
 use std::time::{SystemTime, UNIX_EPOCH};
 
 use bufstream::BufStream;
+use chrono::Utc;
+use mime::Message as MimeMessage;
 
+use crate::folder;
+use crate::fts;
+use crate::keywords::Keywords;
+use crate::quota::Exceeded;
+use crate::message::{maildir_info, parse_flag, Flag};
+use crate::server::config::DetailMailbox;
 use crate::server::user::{Email, User};
-use crate::server::Server;
+use crate::server::{input_pending, relay, sasl, Server, Stream};
+use crate::sieve::{self, vacation, Action, Context};
+use crate::spool::{enqueue, SpooledMessage};
+use crate::layout::Layout;
+use crate::util::mailbox_path;
 
 // Just bail if there is some error.
 // Used when performing operations on a TCP Stream generally
@@ -22,13 +36,6 @@ macro_rules! return_on_err(
     }
 );
 
-macro_rules! delivery_ioerror(
-    ($res:ident) => ({
-        $res.push_str("451 Error in processing.\r\n");
-        break;
-    })
-);
-
 macro_rules! grab_email_token(
     ($arg:expr) => {
         match $arg {
@@ -38,58 +45,465 @@ macro_rules! grab_email_token(
     }
 );
 
+/// The protocols spoken over the command loop.
+#[derive(Clone, Copy, PartialEq)]
+enum Protocol {
+    Lmtp,
+    // Mail submission (RFC 6409) by authenticated users, to be relayed
+    Submission,
+}
+
+/// A recipient accepted with RCPT
+struct Recipient<'a> {
+    user: &'a User,
+    // The address as given in RCPT, including any detail
+    address: Email,
+    // The detail part of the address, e.g. "lists" in user+lists@domain
+    detail: Option<String>,
+}
+
 struct Lmtp<'a> {
+    protocol: Protocol,
+    // The domain given by the client in LHLO, or EHLO for submission
+    lhlo_domain: Option<String>,
+    // The address of the client, for the Received header
+    peer: String,
+    // A description of the TLS session, if the connection is encrypted
+    tls: Option<String>,
+    // The user authenticated with AUTH, for submission
+    user: Option<&'a User>,
     rev_path: Option<Email>,
-    to_path: Vec<&'a User>,
-    data: String,
+    to_path: Vec<Recipient<'a>>,
+    // The recipients of a submitted message, which may be anywhere
+    forward_path: Vec<Email>,
+    data: Vec<u8>,
+    // Whether the message is being transferred with BDAT rather than DATA
+    chunking: bool,
+    // Whether the message grew beyond the configured maximum size
+    oversized: bool,
     quit: bool,
 }
 
-static OK: &'static str = "250 OK\r\n";
+static OK: &str = "250 2.0.0 OK\r\n";
+static INVALID: &str = "500 5.5.1 Invalid command\r\n";
+static SYNTAX: &str = "501 5.5.4 Syntax error in parameters\r\n";
+static BAD_SEQUENCE: &str = "503 5.5.1 Bad sequence of commands\r\n";
+static TOO_BIG: &str = "552 5.3.4 Message size exceeds fixed maximum message size\r\n";
+static TLS_REQUIRED: &str = "530 5.7.0 Must issue a STARTTLS command first\r\n";
+static MAILBOX_FULL: &str = "452 4.2.2 Mailbox full\r\n";
+static OVER_QUOTA: &str = "552 5.2.2 Message exceeds mailbox quota\r\n";
+
+// The folder in which copies of submitted messages are kept
+static SENT_FOLDER: &str = "Sent";
+// The most recipients a submitted message may have
+const MAX_RECIPIENTS: usize = 100;
 
 impl<'a> Lmtp<'a> {
-    fn deliver(&self) -> String {
+    /// Clear the envelope and any message data, as required after RSET, LHLO
+    /// and at the end of each mail transaction.
+    fn reset(&mut self) {
+        self.rev_path = None;
+        self.to_path = Vec::new();
+        self.forward_path = Vec::new();
+        self.data = Vec::new();
+        self.chunking = false;
+        self.oversized = false;
+    }
+
+    /// Add a piece of message data, unless doing so would exceed `max_size`.
+    fn append(&mut self, bytes: &[u8], max_size: Option<usize>) {
+        if let Some(max) = max_size {
+            if self.oversized || self.data.len() + bytes.len() > max {
+                self.oversized = true;
+                return;
+            }
+        }
+        self.data.extend_from_slice(bytes);
+    }
+
+    fn has_recipients(&self) -> bool {
+        !self.to_path.is_empty() || !self.forward_path.is_empty()
+    }
+
+    /// Build a Received header per RFC 5321 section 4.4, naming the protocol
+    /// as RFC 3848 does. The recipient is only given for single deliveries.
+    fn received_header(&self, host: &str, rcpt: Option<&Email>) -> String {
+        let protocol = match (self.protocol, self.tls.is_some()) {
+            (Protocol::Lmtp, false) => "LMTP",
+            (Protocol::Lmtp, true) => "LMTPS",
+            (Protocol::Submission, false) => "ESMTPA",
+            (Protocol::Submission, true) => "ESMTPSA",
+        };
+        let tls = match self.tls {
+            Some(ref tls) => format!("\t(using {})\n", tls),
+            None => String::new(),
+        };
+        let rcpt = match rcpt {
+            Some(rcpt) => format!("\n\tfor <{}>", rcpt),
+            None => String::new(),
+        };
+        format!(
+            "Received: from {} ({})\n{}\tby {} with {}{}; {}\n",
+            self.lhlo_domain.as_deref().unwrap_or("unknown"),
+            self.peer,
+            tls,
+            host,
+            protocol,
+            rcpt,
+            Utc::now().to_rfc2822()
+        )
+    }
+
+    /// Build the trace headers prepended to the message delivered to `rcpt`:
+    /// Return-Path, Delivered-To and Received.
+    fn trace_headers(&self, rcpt: &Recipient, host: &str) -> String {
+        let rev_path = match self.rev_path {
+            Some(ref email) => email.to_string(),
+            None => String::new(),
+        };
+        format!(
+            "Return-Path: <{}>\nDelivered-To: {}\n{}",
+            rev_path,
+            rcpt.address,
+            self.received_header(host, Some(&rcpt.address))
+        )
+    }
+
+    /// Finish a mail transaction once the message data is in.
+    fn finish(&self, serv: &Arc<Server>) -> String {
+        match self.protocol {
+            Protocol::Lmtp => self.deliver(serv),
+            Protocol::Submission => self.submit(serv),
+        }
+    }
+
+    /// Queue a submitted message to be relayed, and keep a copy in the
+    /// sender's Sent folder. Unlike LMTP, SMTP has a single response for
+    /// all recipients.
+    fn submit(&self, serv: &Arc<Server>) -> String {
+        let (user, rev_path) = match (self.user, &self.rev_path) {
+            (Some(user), Some(rev_path)) if !self.forward_path.is_empty() => (user, rev_path),
+            _ => return BAD_SEQUENCE.to_string(),
+        };
+        if self.oversized {
+            return TOO_BIG.to_string();
+        }
+        let mut data = self.received_header(serv.host(), None).into_bytes();
+        data.extend_from_slice(&self.data);
+        let message = SpooledMessage {
+            from: rev_path.to_string(),
+            to: self.forward_path.iter().map(|email| email.to_string()).collect(),
+            data,
+        };
+        if let Err(e) = spool(serv, &message) {
+            warn!("Cannot queue message from {}: {}", user.email, e);
+            return "451 4.3.0 Error queueing message\r\n".to_string();
+        }
+        relay::wake(serv);
+
+        let layout = serv.layout(user);
+        let saved = match mailbox_path(Path::new(&user.maildir), SENT_FOLDER, layout) {
+            Some(sent) => {
+                let created = if sent.join("cur").is_dir() {
+                    Ok(())
+                } else {
+                    layout.create(&sent)
+                };
+                let flags = ["\\Seen".to_string()];
+                created.and_then(|_| store_indexed(&sent, &self.data, &flags, serv.full_text_index(user)))
+            }
+            None => Err(io::Error::other("invalid Sent folder")),
+        };
+        if let Err(e) = saved.and_then(|_| serv.quota(user).add(self.data.len() as u64, 1)) {
+            warn!("Cannot save sent message for {}: {}", user.email, e);
+        }
+        "250 2.0.0 Message queued\r\n".to_string()
+    }
+
+    /// Deliver the message to every recipient. LMTP requires one response
+    /// per successful RCPT.
+    fn deliver(&self, serv: &Server) -> String {
         if self.to_path.is_empty() {
-            return "503 Bad sequence - no recipients".to_string();
+            return BAD_SEQUENCE.to_string();
         }
         let mut res = String::new();
         for rcpt in &self.to_path {
-            let mut timestamp = match SystemTime::now().duration_since(UNIX_EPOCH) {
-                Ok(x) => x.as_secs(),
-                Err(_) => {
-                    res.push_str("555 UNIX time error\r\n");
-                    break;
-                }
-            };
-            let maildir = rcpt.maildir.clone();
-            let newdir_path = Path::new(&maildir[..]).join("new");
-            loop {
-                let file_path = &newdir_path.join(timestamp.to_string());
-                match File::create(&file_path) {
+            if self.oversized {
+                res.push_str(TOO_BIG);
+                continue;
+            }
+            res.push_str(&self.deliver_to(serv, rcpt));
+        }
+        res
+    }
+
+    /// Deliver the message to a single recipient, filtering it through
+    /// their active Sieve script if they have one.
+    fn deliver_to(&self, serv: &Server, rcpt: &Recipient) -> String {
+        let mut data = self.trace_headers(rcpt, serv.host()).into_bytes();
+        data.extend_from_slice(&self.data);
+        let maildir = Path::new(&rcpt.user.maildir);
+        let layout = serv.layout(rcpt.user);
+        let index = serv.full_text_index(rcpt.user);
+        let rev_path = match self.rev_path {
+            Some(ref email) => email.to_string(),
+            None => String::new(),
+        };
+
+        // A full maildir may take the message later; one which could never
+        // hold it will not
+        let mut quota = serv.quota(rcpt.user);
+        match quota.check(data.len() as u64, 1, serv.conf.quota_grace) {
+            Ok(()) => {}
+            Err(Exceeded::Full) => return MAILBOX_FULL.to_string(),
+            Err(Exceeded::TooBig) => return OVER_QUOTA.to_string(),
+        }
+
+        let script = sieve::load_active(maildir);
+        let message = match script {
+            Some(_) => MimeMessage::from_bytes(&data).ok(),
+            None => None,
+        };
+        let keep = vec![Action::Keep { flags: Vec::new() }];
+        let actions = match (&script, &message) {
+            (Some(script), Some(message)) => {
+                let context = Context {
+                    message,
+                    size: data.len(),
+                    envelope_from: rev_path.clone(),
+                    envelope_to: rcpt.address.to_string(),
+                };
+                match sieve::execute(script, &context) {
+                    Ok(actions) => actions,
                     Err(e) => {
-                        if e.kind() == AlreadyExists {
-                            timestamp += 1;
-                        } else {
-                            warn!("Error creating file '{}': {}", file_path.to_str().unwrap_or_default(), e);
-                            delivery_ioerror!(res);
-                        }
+                        warn!("Error running Sieve script for {}: {}", rcpt.user.email, e);
+                        keep
                     }
-                    Ok(mut file) => {
-                        if file.write(self.data.as_bytes()).is_err() {
-                            warn!("Error creating file '{}': cannot write file", file_path.to_str().unwrap_or_default());
-                            delivery_ioerror!(res);
+                }
+            }
+            _ => keep,
+        };
+
+        let mut failed = false;
+        // How many copies of the message were kept
+        let mut stored = 0;
+        for action in actions {
+            let result = match action {
+                Action::Keep { flags } => {
+                    let folder = delivery_folder(rcpt, serv.conf.detail_mailbox, layout);
+                    store_indexed(&folder, &data, &flags, index).map(|_| stored += 1)
+                }
+                Action::FileInto { mailbox, flags } => {
+                    let folder = match fileinto_folder(maildir, &mailbox, layout) {
+                        Some(folder) => folder,
+                        // RFC 5228 says to keep the message if it cannot be filed
+                        None => {
+                            warn!("Cannot file message for {} into '{}'", rcpt.user.email, mailbox);
+                            delivery_folder(rcpt, serv.conf.detail_mailbox, layout)
                         }
-                        if file.flush().is_err() {
-                            warn!("Error creating file '{}': cannot flush", file_path.to_str().unwrap_or_default());
-                            delivery_ioerror!(res);
+                    };
+                    store_indexed(&folder, &data, &flags, index).map(|_| stored += 1)
+                }
+                Action::Redirect(address) => {
+                    let message = SpooledMessage {
+                        from: rev_path.clone(),
+                        to: vec![address],
+                        data: data.clone(),
+                    };
+                    spool(serv, &message).or_else(|e| {
+                        warn!("Cannot redirect message for {}, keeping it: {}", rcpt.user.email, e);
+                        let folder = delivery_folder(rcpt, serv.conf.detail_mailbox, layout);
+                        store_indexed(&folder, &data, &[], index).map(|_| stored += 1)
+                    })
+                }
+                Action::Reject(reason) => {
+                    let reason = reason.split_whitespace().collect::<Vec<_>>().join(" ");
+                    return format!("550 5.7.1 {}\r\n", reason);
+                }
+                Action::Vacation(vacation) => {
+                    let reply = message.as_ref().and_then(|message| {
+                        vacation::respond(
+                            &vacation,
+                            message,
+                            &rev_path,
+                            &rcpt.address.to_string(),
+                            &[rcpt.user.email.to_string()],
+                            &sieve::script_dir(maildir),
+                            serv.host(),
+                        )
+                    });
+                    if let Some(reply) = reply {
+                        let message = SpooledMessage {
+                            from: String::new(),
+                            to: vec![reply.to],
+                            data: reply.data.into_bytes(),
+                        };
+                        if let Err(e) = spool(serv, &message) {
+                            warn!("Cannot send vacation reply for {}: {}", rcpt.user.email, e);
                         }
-                        res.push_str("250 OK\r\n");
-                        break;
                     }
+                    Ok(())
+                }
+            };
+            if let Err(e) = result {
+                warn!("Error delivering message for {}: {}", rcpt.user.email, e);
+                failed = true;
+            }
+        }
+        if stored > 0 {
+            if let Err(e) = quota.add(data.len() as u64 * stored, stored) {
+                warn!("Error updating the quota of {}: {}", rcpt.user.email, e);
+            }
+        }
+        if failed {
+            "451 4.3.0 Error in processing.\r\n".to_string()
+        } else {
+            OK.to_string()
+        }
+    }
+}
+
+/// Store a message in a maildir folder. Messages without flags are left in
+/// new; messages with flags go straight to cur with the flags in their
+/// filename.
+pub fn store(folder: &Path, data: &[u8], flags: &[String]) -> io::Result<()> {
+    store_with_uid(folder, data, flags).map(|_| ())
+}
+
+/// Store a message like `store`, adding it to the folder's full-text index
+/// too if `index` is set.
+fn store_indexed(folder: &Path, data: &[u8], flags: &[String], index: bool) -> io::Result<()> {
+    let (uid, _) = store_with_uid(folder, data, flags)?;
+    if index {
+        fts::add_message(folder, uid, data);
+    }
+    Ok(())
+}
+
+/// Store a message like `store`, returning the UID it was given and the
+/// file it was written to.
+pub fn store_with_uid(folder: &Path, data: &[u8], flags: &[String]) -> io::Result<(usize, PathBuf)> {
+    let flags: HashSet<Flag> = flags.iter().filter_map(|f| parse_flag(f)).collect();
+    let now = match SystemTime::now().duration_since(UNIX_EPOCH) {
+        Ok(x) => x.as_secs() as usize,
+        Err(_) => return Err(io::Error::other("UNIX time error")),
+    };
+    // UIDs go up strictly and are never reused (RFC 3501 section 2.3.1.1),
+    // so the new one is past every UID in use or expunged, and is taken
+    // before anyone else looks for the highest
+    let _lock = folder::lock_allocations(folder)?;
+    let mut timestamp = now.max(folder::highest_uid(folder) + 1);
+    loop {
+        let file_path = folder.join("new").join(timestamp.to_string());
+        match OpenOptions::new().write(true).create_new(true).open(&file_path) {
+            Err(ref e) if e.kind() == AlreadyExists => timestamp += 1,
+            Err(e) => return Err(e),
+            Ok(mut file) => {
+                if let Err(e) = file.write_all(data).and_then(|_| file.flush()) {
+                    let _ = fs::remove_file(&file_path);
+                    return Err(e);
+                }
+                if flags.is_empty() {
+                    return Ok((timestamp, file_path));
+                }
+                let info = maildir_info(&flags, "", &mut Keywords::load(folder));
+                let cur_path = folder.join("cur").join(format!("{}{}", timestamp, info));
+                fs::rename(&file_path, &cur_path)?;
+                return Ok((timestamp, cur_path));
+            }
+        }
+    }
+}
+
+/// Add a message to the outgoing spool.
+fn spool(serv: &Server, message: &SpooledMessage) -> io::Result<()> {
+    match serv.conf.spool_dir {
+        Some(ref dir) => enqueue(Path::new(dir), message).map(|_| ()),
+        None => Err(io::Error::other("no spool directory configured")),
+    }
+}
+
+/// Find the folder a Sieve script files a message into. Names are relative
+/// to the user's maildir, with or without a leading INBOX, and "/"
+/// separates the levels of the hierarchy. Folders are not created.
+fn fileinto_folder(maildir: &Path, mailbox: &str, layout: Layout) -> Option<PathBuf> {
+    let folder = mailbox_path(maildir, mailbox, layout)?;
+    if folder.join("cur").is_dir() && folder.join("new").is_dir() {
+        Some(folder)
+    } else {
+        None
+    }
+}
+
+/// Split the detail off the local part of a recipient address, at the first
+/// of the delimiter characters. `user+lists@domain` becomes `user@domain`
+/// with the detail `lists`.
+fn split_detail(email: &Email, delimiter: &str) -> Option<(Email, String)> {
+    let index = email.local_part.find(|c| delimiter.contains(c))?;
+    let (local_part, detail) = email.local_part.split_at(index);
+    let detail = &detail[detail.chars().next()?.len_utf8()..];
+    Some((
+        Email::new(local_part.to_string(), email.domain_part.clone()),
+        detail.to_string(),
+    ))
+}
+
+/// Look up the user a recipient address belongs to, ignoring any detail part.
+fn find_recipient<'a>(serv: &'a Server, email: Email) -> Option<Recipient<'a>> {
+    if let Some(user) = serv.users.get(&email) {
+        return Some(Recipient {
+            user,
+            address: email,
+            detail: None,
+        });
+    }
+    let delimiter = serv.conf.recipient_delimiter.as_ref()?;
+    let (base, detail) = split_detail(&email, delimiter)?;
+    let user = serv.users.get(&base)?;
+    Some(Recipient {
+        user,
+        address: email,
+        detail: Some(detail),
+    })
+}
+
+/// Create the directories making up a maildir folder.
+pub fn create_folder(path: &Path) -> io::Result<()> {
+    for dir in &["new", "cur", "tmp"] {
+        let dir_path = path.join(dir);
+        fs::create_dir_all(&dir_path)?;
+        fs::set_permissions(&dir_path, fs::Permissions::from_mode(0o755))?;
+    }
+    Ok(())
+}
+
+/// Choose the folder a message to the recipient is delivered into. This is
+/// INBOX unless the address has a detail naming a folder, depending on the
+/// configuration.
+fn delivery_folder(rcpt: &Recipient, mode: DetailMailbox, layout: Layout) -> PathBuf {
+    let inbox = PathBuf::from(&rcpt.user.maildir);
+    let detail = match rcpt.detail {
+        Some(ref detail) if layout.valid_level(detail) => detail,
+        _ => return inbox,
+    };
+    let folder = layout.folder_path(&inbox, &[detail]);
+    let exists = folder.join("cur").is_dir() && folder.join("new").is_dir();
+    match mode {
+        DetailMailbox::Inbox => inbox,
+        DetailMailbox::Existing if exists => folder,
+        DetailMailbox::Existing => inbox,
+        DetailMailbox::Create => {
+            if exists {
+                return folder;
+            }
+            match layout.create(&folder) {
+                Ok(()) => folder,
+                Err(e) => {
+                    warn!("Error creating folder '{}': {}", folder.display(), e);
+                    inbox
                 }
             }
         }
-        res
     }
 }
 
@@ -131,14 +545,216 @@ fn grab_email(arg: Option<&str>) -> Option<Email> {
     Some(Email::new(local_part, domain_part))
 }
 
-pub fn serve(serv: Arc<Server>, mut stream: BufStream<TcpStream>) {
+/// Check the ESMTP parameters which follow the reverse path of MAIL FROM.
+/// Returns the error response to send back if any parameter is unacceptable.
+fn check_mail_params(params: Split<char>, max_size: Option<usize>) -> Result<(), &'static str> {
+    for param in params {
+        let mut parts = param.splitn(2, '=');
+        let keyword = parts.next().unwrap_or("").to_ascii_uppercase();
+        match (&keyword[..], parts.next()) {
+            // Extra whitespace between parameters
+            ("", None) => {}
+            // RFC 1870: the declared size of the message
+            ("SIZE", Some(value)) => match value.parse::<usize>() {
+                Ok(size) => {
+                    if let Some(max) = max_size {
+                        if size > max {
+                            return Err(TOO_BIG);
+                        }
+                    }
+                }
+                Err(_) => return Err(SYNTAX),
+            },
+            // RFC 6152: 8-bit content is stored as is
+            ("BODY", Some(value)) => match &value.to_ascii_uppercase()[..] {
+                "7BIT" | "8BITMIME" => {}
+                _ => return Err(SYNTAX),
+            },
+            _ => return Err("555 5.5.4 Unsupported parameter\r\n"),
+        }
+    }
+    Ok(())
+}
+
+/// Strip the line ending from a line of input.
+fn strip_eol(line: &[u8]) -> &[u8] {
+    let line = line.strip_suffix(b"\n").unwrap_or(line);
+    line.strip_suffix(b"\r").unwrap_or(line)
+}
+
+/// Convert CRLF line endings to the LF line endings used in the maildir.
+fn crlf_to_lf(data: &[u8]) -> Vec<u8> {
+    let mut res = Vec::with_capacity(data.len());
+    for (i, byte) in data.iter().enumerate() {
+        if *byte == b'\r' && data.get(i + 1) == Some(&b'\n') {
+            continue;
+        }
+        res.push(*byte);
+    }
+    res
+}
+
+/// Read the message following DATA up to the terminating "." line, undoing
+/// the dot-stuffing. Returns false if the connection was lost.
+fn read_data<S: BufRead>(stream: &mut S, l: &mut Lmtp, max_size: Option<usize>) -> bool {
+    loop {
+        let mut line = Vec::new();
+        match stream.read_until(b'\n', &mut line) {
+            Ok(0) | Err(_) => return false,
+            Ok(_) => {}
+        }
+        let line = strip_eol(&line);
+        if line == b"." {
+            return true;
+        }
+        let line = line.strip_prefix(b".").unwrap_or(line);
+        l.append(line, max_size);
+        l.append(b"\n", max_size);
+    }
+}
+
+/// Read a BDAT chunk of exactly `size` octets. The chunk must be consumed
+/// even when it is going to be rejected. Returns false if the connection was
+/// lost.
+fn read_chunk<S: Read>(
+    stream: &mut S,
+    l: &mut Lmtp,
+    size: usize,
+    max_size: Option<usize>,
+    keep: bool,
+) -> bool {
+    let mut chunk = stream.take(size as u64);
+    let too_big = match max_size {
+        Some(max) => l.data.len() + size > max,
+        None => false,
+    };
+    if !keep || l.oversized || too_big {
+        l.oversized = l.oversized || (keep && too_big);
+        return match io::copy(&mut chunk, &mut io::sink()) {
+            Ok(n) => n == size as u64,
+            Err(_) => false,
+        };
+    }
+    match chunk.read_to_end(&mut l.data) {
+        Ok(n) => n == size,
+        Err(_) => false,
+    }
+}
+
+/// The LHLO or EHLO response, advertising the supported extensions.
+/// Submission offers STARTTLS until the connection is encrypted, and AUTH
+/// only once it is.
+fn lhlo_response(serv: &Server, l: &Lmtp) -> String {
+    let mut extensions = vec![
+        "PIPELINING".to_string(),
+        "8BITMIME".to_string(),
+        format!("SIZE {}", serv.conf.lmtp_max_size.unwrap_or(0)),
+        "ENHANCEDSTATUSCODES".to_string(),
+        "CHUNKING".to_string(),
+    ];
+    if l.protocol == Protocol::Submission {
+        if l.tls.is_none() && serv.can_starttls() {
+            extensions.push("STARTTLS".to_string());
+        }
+        if l.tls.is_some() && l.user.is_none() {
+            extensions.push(format!("AUTH {}", sasl::MECHANISMS));
+        }
+    }
+    let mut res = format!("250-{}\r\n", *serv.host());
+    for (i, extension) in extensions.iter().enumerate() {
+        let separator = if i + 1 == extensions.len() { ' ' } else { '-' };
+        res.push_str(&format!("250{}{}\r\n", separator, extension));
+    }
+    res
+}
+
+/// Whether a user may send mail from an address: their own or an alias.
+fn owns_address(user: &User, email: &Email) -> bool {
+    let address = email.to_string().to_lowercase();
+    user.email.to_string().to_lowercase() == address
+        || user.aliases.iter().any(|alias| alias.to_string().to_lowercase() == address)
+}
+
+/// Handle AUTH (RFC 4954) for submission, which is only allowed over TLS
+/// and outside of a mail transaction.
+fn authenticate<'a>(
+    serv: &'a Server,
+    stream: &mut BufStream<Stream>,
+    l: &mut Lmtp<'a>,
+    mut args: Split<char>,
+) -> io::Result<String> {
+    if l.tls.is_none() {
+        return Ok(TLS_REQUIRED.to_string());
+    }
+    if l.user.is_some() || l.lhlo_domain.is_none() || l.rev_path.is_some() {
+        return Ok(BAD_SEQUENCE.to_string());
+    }
+    let mechanism = match args.next() {
+        Some(mechanism) => mechanism,
+        None => return Ok(SYNTAX.to_string()),
+    };
+    if !sasl::MECHANISMS.split(' ').any(|m| m.eq_ignore_ascii_case(mechanism)) {
+        return Ok("504 5.5.4 Unrecognized authentication type\r\n".to_string());
+    }
+    let response = match args.next() {
+        // An empty initial response is sent as "="
+        Some("=") => String::new(),
+        Some(response) => response.to_string(),
+        None => {
+            stream.write_all(b"334 \r\n")?;
+            stream.flush()?;
+            let mut line = String::new();
+            if stream.read_line(&mut line)? == 0 {
+                return Err(io::ErrorKind::UnexpectedEof.into());
+            }
+            line.trim_end().to_string()
+        }
+    };
+    if response == "*" {
+        return Ok("501 5.0.0 Authentication cancelled\r\n".to_string());
+    }
+    Ok(match sasl::login(serv, mechanism, &response) {
+        Some(user) => {
+            l.user = Some(user);
+            "235 2.7.0 Authentication successful\r\n".to_string()
+        }
+        None => "535 5.7.8 Authentication credentials invalid\r\n".to_string(),
+    })
+}
+
+pub fn serve(serv: Arc<Server>, stream: BufStream<Stream>) {
+    serve_protocol(serv, stream, Protocol::Lmtp)
+}
+
+pub fn serve_submission(serv: Arc<Server>, stream: BufStream<Stream>) {
+    serve_protocol(serv, stream, Protocol::Submission)
+}
+
+fn serve_protocol(serv: Arc<Server>, mut stream: BufStream<Stream>, protocol: Protocol) {
+    let peer = match stream.get_ref().peer_addr() {
+        Ok(addr) => addr.ip().to_string(),
+        Err(_) => "unknown".to_string(),
+    };
     let mut l = Lmtp {
+        protocol,
+        lhlo_domain: None,
+        peer,
+        tls: stream.get_ref().tls_description(),
+        user: None,
         rev_path: None,
         to_path: Vec::new(),
-        data: String::new(),
+        forward_path: Vec::new(),
+        data: Vec::new(),
+        chunking: false,
+        oversized: false,
         quit: false,
     };
-    return_on_err!(stream.write(format!("220 {} LMTP server ready\r\n", *serv.host()).as_bytes()));
+    let max_size = serv.conf.lmtp_max_size;
+    let greeting = match protocol {
+        Protocol::Lmtp => "LMTP",
+        Protocol::Submission => "ESMTP",
+    };
+    return_on_err!(stream.write(format!("220 {} {} server ready\r\n", *serv.host(), greeting).as_bytes()));
     return_on_err!(stream.flush());
     loop {
         let mut command = String::new();
@@ -149,84 +765,172 @@ pub fn serve(serv: Arc<Server>, mut stream: BufStream<TcpStream>) {
                 }
                 let trimmed_command = (&command[..]).trim();
                 let mut args = trimmed_command.split(' ');
-                let invalid = "500 Invalid command\r\n".to_string();
                 let data_res = b"354 Start mail input; end with <CRLF>.<CRLF>\r\n";
-                let ok_res = OK.to_string();
+                let submission = l.protocol == Protocol::Submission;
+                let mut starttls = false;
                 let res = match args.next() {
                     Some(cmd) => {
-                        warn!("LMTP Cmd: {}", trimmed_command);
+                        if cmd.eq_ignore_ascii_case("auth") {
+                            warn!("LMTP Cmd: AUTH");
+                        } else {
+                            warn!("LMTP Cmd: {}", trimmed_command);
+                        }
                         match &cmd.to_ascii_lowercase()[..] {
-                            "lhlo" => match args.next() {
+                            "lhlo" if !submission => match args.next() {
+                                Some(domain) => {
+                                    l.reset();
+                                    l.lhlo_domain = Some(domain.to_string());
+                                    lhlo_response(&serv, &l)
+                                }
+                                _ => SYNTAX.to_string(),
+                            },
+                            "ehlo" | "helo" if submission => match args.next() {
                                 Some(domain) => {
-                                    format!("250 {}\r\n", domain)
+                                    l.reset();
+                                    l.lhlo_domain = Some(domain.to_string());
+                                    if cmd.eq_ignore_ascii_case("ehlo") {
+                                        lhlo_response(&serv, &l)
+                                    } else {
+                                        format!("250 {}\r\n", *serv.host())
+                                    }
                                 }
-                                _ => invalid,
+                                _ => SYNTAX.to_string(),
+                            },
+                            // RFC 3207
+                            "starttls" if submission => {
+                                if l.tls.is_some() {
+                                    BAD_SEQUENCE.to_string()
+                                } else if !serv.can_starttls() {
+                                    "454 4.7.0 TLS not available\r\n".to_string()
+                                } else {
+                                    starttls = true;
+                                    "220 2.0.0 Ready to start TLS\r\n".to_string()
+                                }
+                            }
+                            "auth" if submission => match authenticate(&serv, &mut stream, &mut l, args) {
+                                Ok(res) => res,
+                                Err(_) => return,
                             },
                             "rset" => {
-                                l.rev_path = None;
-                                l.to_path = Vec::new();
-                                ok_res
+                                l.reset();
+                                OK.to_string()
                             }
-                            "noop" => ok_res,
+                            "noop" => OK.to_string(),
                             "quit" => {
                                 l.quit = true;
-                                format!("221 {} Closing connection\r\n", *serv.host())
+                                format!("221 2.0.0 {} Closing connection\r\n", *serv.host())
                             }
-                            "vrfy" => invalid,
-                            "mail" => match grab_email(args.next()) {
-                                None => invalid,
-                                s => {
-                                    l.rev_path = s;
-                                    ok_res
+                            "vrfy" => INVALID.to_string(),
+                            "mail" if submission && l.tls.is_none() => TLS_REQUIRED.to_string(),
+                            "mail" if submission && l.user.is_none() => {
+                                "530 5.7.0 Authentication required\r\n".to_string()
+                            }
+                            "mail" => match l.rev_path {
+                                Some(_) => BAD_SEQUENCE.to_string(),
+                                None => match grab_email(args.next()) {
+                                    None => SYNTAX.to_string(),
+                                    Some(ref email) if submission && !l.user.is_some_and(|u| owns_address(u, email)) => {
+                                        format!("553 5.7.1 Sender address {} not owned by user\r\n", email)
+                                    }
+                                    s => match check_mail_params(args, max_size) {
+                                        Err(e) => e.to_string(),
+                                        Ok(()) => {
+                                            l.rev_path = s;
+                                            "250 2.1.0 OK\r\n".to_string()
+                                        }
+                                    },
+                                },
+                            },
+                            "rcpt" if submission => match (&l.rev_path, grab_email(args.next())) {
+                                (None, _) => BAD_SEQUENCE.to_string(),
+                                (_, None) => SYNTAX.to_string(),
+                                _ if l.forward_path.len() >= MAX_RECIPIENTS => {
+                                    "452 4.5.3 Too many recipients\r\n".to_string()
+                                }
+                                (_, Some(email)) => {
+                                    l.forward_path.push(email);
+                                    "250 2.1.5 OK\r\n".to_string()
                                 }
                             },
                             "rcpt" => match l.rev_path {
-                                None => invalid,
+                                None => BAD_SEQUENCE.to_string(),
                                 _ => match grab_email(args.next()) {
-                                    None => invalid,
-                                    Some(email) => match serv.users.get(&email) {
-                                        None => format!("550 No such user {}\r\n", email),
-                                        Some(user) => {
-                                            l.to_path.push(user);
-                                            ok_res
+                                    None => SYNTAX.to_string(),
+                                    Some(email) => match find_recipient(&serv, email.clone()) {
+                                        None => format!("550 5.1.1 No such user {}\r\n", email),
+                                        Some(rcpt) => {
+                                            l.to_path.push(rcpt);
+                                            "250 2.1.5 OK\r\n".to_string()
                                         }
                                     },
                                 },
                             },
                             "data" => {
-                                return_on_err!(stream.write(data_res));
-                                return_on_err!(stream.flush());
-                                let mut loop_res = invalid;
-                                loop {
-                                    let mut data_command = String::new();
-                                    match stream.read_line(&mut data_command) {
-                                        Ok(_) => {
-                                            if data_command.is_empty() {
-                                                break;
-                                            }
-                                            let data_cmd = (&data_command[..]).trim();
-                                            if data_cmd == "." {
-                                                loop_res = l.deliver();
-                                                l.data = String::new();
-                                                break;
-                                            }
-                                            l.data.push_str(data_cmd);
-                                            l.data.push('\n');
+                                if !l.has_recipients() || l.chunking {
+                                    BAD_SEQUENCE.to_string()
+                                } else {
+                                    return_on_err!(stream.write(data_res));
+                                    return_on_err!(stream.flush());
+                                    if !read_data(&mut stream, &mut l, max_size) {
+                                        return;
+                                    }
+                                    let res = l.finish(&serv);
+                                    l.reset();
+                                    res
+                                }
+                            }
+                            // RFC 3030: the message is sent in chunks of
+                            // known size, with the last one marked LAST.
+                            "bdat" => {
+                                let size = args.next().and_then(|size| size.parse::<usize>().ok());
+                                let last = match args.next() {
+                                    None => Some(false),
+                                    Some(last) if last.eq_ignore_ascii_case("last") => Some(true),
+                                    _ => None,
+                                };
+                                match (size, last) {
+                                    (Some(size), Some(last)) => {
+                                        let keep = l.has_recipients();
+                                        if !read_chunk(&mut stream, &mut l, size, max_size, keep) {
+                                            return;
                                         }
-                                        _ => {
-                                            break;
+                                        if !keep {
+                                            BAD_SEQUENCE.to_string()
+                                        } else if last {
+                                            l.data = crlf_to_lf(&l.data);
+                                            let res = l.finish(&serv);
+                                            l.reset();
+                                            res
+                                        } else {
+                                            l.chunking = true;
+                                            format!("250 2.0.0 {} octets received\r\n", size)
                                         }
                                     }
+                                    _ => SYNTAX.to_string(),
                                 }
-                                loop_res
                             }
-                            _ => invalid,
+                            _ => INVALID.to_string(),
                         }
                     }
-                    None => invalid,
+                    None => INVALID.to_string(),
                 };
                 return_on_err!(stream.write(res.as_bytes()));
-                return_on_err!(stream.flush());
+                // Hold back the responses to pipelined commands until every
+                // command the client has sent so far has been handled.
+                if l.quit || starttls || !input_pending(&mut stream) {
+                    return_on_err!(stream.flush());
+                }
+                // Anything pipelined after STARTTLS is discarded with the
+                // plaintext stream, and the session starts over (RFC 3207)
+                if starttls {
+                    match serv.starttls(stream.into_inner()) {
+                        Some(ssl_stream) => stream = BufStream::new(Stream::Ssl(ssl_stream)),
+                        None => return,
+                    }
+                    l.reset();
+                    l.lhlo_domain = None;
+                    l.tls = stream.get_ref().tls_description();
+                }
                 if l.quit {
                     return;
                 }
@@ -241,6 +945,7 @@ pub fn serve(serv: Arc<Server>, mut stream: BufStream<TcpStream>) {
 #[cfg(test)]
 mod tests {
     use super::*;
+    use crate::testing::TempDir;
 
     #[test]
     fn test_grab_email_to() {
@@ -268,4 +973,176 @@ mod tests {
         let parsed_email = grab_email(email);
         assert!(parsed_email.is_none());
     }
-}
\ No newline at end of file
+
+    #[test]
+    fn test_check_mail_params() {
+        assert!(check_mail_params("".split(' '), None).is_ok());
+        assert!(check_mail_params("SIZE=1000 BODY=8BITMIME".split(' '), Some(1000)).is_ok());
+        assert!(check_mail_params("body=7bit".split(' '), None).is_ok());
+        assert_eq!(check_mail_params("SIZE=1001".split(' '), Some(1000)), Err(TOO_BIG));
+        assert_eq!(check_mail_params("SIZE=abc".split(' '), None), Err(SYNTAX));
+        assert_eq!(check_mail_params("BODY=BINARYMIME".split(' '), None), Err(SYNTAX));
+        assert!(check_mail_params("AUTH=<>".split(' '), None).is_err());
+    }
+
+    fn new_lmtp<'a>() -> Lmtp<'a> {
+        Lmtp {
+            protocol: Protocol::Lmtp,
+            lhlo_domain: None,
+            peer: "127.0.0.1".to_string(),
+            tls: None,
+            user: None,
+            rev_path: None,
+            to_path: Vec::new(),
+            forward_path: Vec::new(),
+            data: Vec::new(),
+            chunking: false,
+            oversized: false,
+            quit: false,
+        }
+    }
+
+    #[test]
+    fn test_read_data() {
+        let mut l = new_lmtp();
+        let mut input = &b"Subject: hi\r\n\r\n..leading dot\r\nbody\r\n.\r\nNOOP\r\n"[..];
+        assert!(read_data(&mut input, &mut l, None));
+        assert_eq!(l.data, b"Subject: hi\n\n.leading dot\nbody\n".to_vec());
+        assert_eq!(input, b"NOOP\r\n");
+
+        let mut l = new_lmtp();
+        let mut input = &b"truncated\r\n"[..];
+        assert!(!read_data(&mut input, &mut l, None));
+
+        let mut l = new_lmtp();
+        let mut input = &b"0123456789\r\n.\r\n"[..];
+        assert!(read_data(&mut input, &mut l, Some(5)));
+        assert!(l.oversized);
+    }
+
+    #[test]
+    fn test_read_chunk() {
+        let mut l = new_lmtp();
+        let mut input = &b"ab\r\ncdNOOP"[..];
+        assert!(read_chunk(&mut input, &mut l, 6, None, true));
+        assert_eq!(l.data, b"ab\r\ncd".to_vec());
+        assert_eq!(input, b"NOOP");
+        assert_eq!(crlf_to_lf(&l.data), b"ab\ncd".to_vec());
+
+        let mut l = new_lmtp();
+        let mut input = &b"abcdef"[..];
+        assert!(read_chunk(&mut input, &mut l, 6, Some(4), true));
+        assert!(l.oversized);
+        assert!(l.data.is_empty());
+        assert!(input.is_empty());
+
+        let mut l = new_lmtp();
+        let mut input = &b"abc"[..];
+        assert!(!read_chunk(&mut input, &mut l, 6, None, true));
+    }
+
+    #[test]
+    fn test_split_detail() {
+        let email = Email::new("user+lists".to_string(), "example.com".to_string());
+        let (base, detail) = split_detail(&email, "+").unwrap();
+        assert_eq!(base, Email::new("user".to_string(), "example.com".to_string()));
+        assert_eq!(detail, "lists");
+
+        let email = Email::new("user-a+b".to_string(), "example.com".to_string());
+        let (base, detail) = split_detail(&email, "+-").unwrap();
+        assert_eq!(base.local_part, "user");
+        assert_eq!(detail, "a+b");
+
+        let email = Email::new("user".to_string(), "example.com".to_string());
+        assert!(split_detail(&email, "+").is_none());
+    }
+
+    #[test]
+    fn test_fileinto_folder() {
+        let maildir = TempDir::new("fileinto");
+        create_folder(&maildir).unwrap();
+        create_folder(&maildir.join("lists")).unwrap();
+        assert_eq!(fileinto_folder(&maildir, "INBOX", Layout::Nested), Some(maildir.to_path_buf()));
+        assert_eq!(fileinto_folder(&maildir, "lists", Layout::Nested), Some(maildir.join("lists")));
+        assert_eq!(fileinto_folder(&maildir, "inbox/lists", Layout::Nested), Some(maildir.join("lists")));
+        assert_eq!(fileinto_folder(&maildir, "missing", Layout::Nested), None);
+        assert_eq!(fileinto_folder(&maildir, "../lists", Layout::Nested), None);
+        assert_eq!(fileinto_folder(&maildir, "lists/cur", Layout::Nested), None);
+    }
+
+    #[test]
+    fn test_store_with_uid() {
+        let folder = TempDir::new("store");
+        create_folder(&folder).unwrap();
+        let (seen, seen_path) = store_with_uid(&folder, b"a\n", &["\\Seen".to_string()]).unwrap();
+        let (unseen, unseen_path) = store_with_uid(&folder, b"b\n", &[]).unwrap();
+        assert!(unseen > seen);
+        assert_eq!(seen_path, folder.join("cur").join(format!("{}:2,S", seen)));
+        assert!(seen_path.is_file());
+        assert_eq!(unseen_path, folder.join("new").join(unseen.to_string()));
+        assert_eq!(fs::read(&unseen_path).unwrap(), b"b\n");
+
+        // UIDs keep going up past those in use or expunged, whatever the time
+        fs::write(folder.join("cur").join("9000000000:2,S"), "c\n").unwrap();
+        assert_eq!(store_with_uid(&folder, b"d\n", &[]).unwrap().0, 9000000001);
+        fs::write(folder.join(".modseq"), "highestmodseq 3\nexpunged 9000000005 3\n").unwrap();
+        assert_eq!(store_with_uid(&folder, b"e\n", &[]).unwrap().0, 9000000006);
+    }
+
+    #[test]
+    fn test_trace_headers() {
+        let user = User::new(
+            Email::new("user".to_string(), "example.com".to_string()),
+            "12345".to_string(),
+            "./maildir".to_string(),
+        );
+        let rcpt = Recipient {
+            user: &user,
+            address: user.email.clone(),
+            detail: None,
+        };
+        let mut l = new_lmtp();
+        l.lhlo_domain = Some("mx.example.org".to_string());
+        l.rev_path = Some(Email::new("sender".to_string(), "example.org".to_string()));
+        let headers = l.trace_headers(&rcpt, "imap.example.com");
+        let mut lines = headers.lines();
+        assert_eq!(lines.next(), Some("Return-Path: <sender@example.org>"));
+        assert_eq!(lines.next(), Some("Delivered-To: user@example.com"));
+        assert_eq!(lines.next(), Some("Received: from mx.example.org (127.0.0.1)"));
+        assert_eq!(lines.next(), Some("\tby imap.example.com with LMTP"));
+        assert!(lines.next().unwrap().starts_with("\tfor <user@example.com>; "));
+        assert_eq!(lines.next(), None);
+
+        l.tls = Some("TLSv1.3 with cipher TLS_AES_256_GCM_SHA384".to_string());
+        let headers = l.trace_headers(&rcpt, "imap.example.com");
+        assert!(headers.contains("\t(using TLSv1.3 with cipher TLS_AES_256_GCM_SHA384)\n"));
+        assert!(headers.contains("\tby imap.example.com with LMTPS\n"));
+    }
+
+    #[test]
+    fn test_submission() {
+        let mut user = User::new(
+            Email::new("user".to_string(), "example.com".to_string()),
+            "12345".to_string(),
+            "./maildir".to_string(),
+        );
+        user.aliases.push(Email::new("postmaster".to_string(), "example.com".to_string()));
+        assert!(owns_address(&user, &Email::new("User".to_string(), "Example.com".to_string())));
+        assert!(owns_address(&user, &Email::new("postmaster".to_string(), "example.com".to_string())));
+        assert!(!owns_address(&user, &Email::new("other".to_string(), "example.com".to_string())));
+
+        let mut l = new_lmtp();
+        l.protocol = Protocol::Submission;
+        l.lhlo_domain = Some("laptop".to_string());
+        l.tls = Some("TLSv1.3".to_string());
+        let received = l.received_header("mail.example.com", None);
+        assert!(received.starts_with("Received: from laptop (127.0.0.1)\n\t(using TLSv1.3)\n"));
+        assert!(received.contains("\tby mail.example.com with ESMTPSA; "));
+        assert!(!received.contains("for <"));
+        assert!(!l.has_recipients());
+        l.forward_path.push(Email::new("a".to_string(), "b.c".to_string()));
+        assert!(l.has_recipients());
+        l.reset();
+        assert!(!l.has_recipients());
+    }
+}
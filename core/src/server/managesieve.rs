@@ -0,0 +1,435 @@
+//! ManageSieve (RFC 5804): lets users upload and manage the Sieve scripts
+//! run over their incoming mail.
+
+use std::fs::{self, File};
+use std::io::{self, BufRead, Read, Write};
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+
+use bufstream::BufStream;
+
+use crate::server::{sasl, Server, Stream};
+use crate::sieve;
+
+// The largest script, or other literal, accepted from a client.
+const MAX_SCRIPT_SIZE: usize = 1 << 20;
+
+/// A command argument: an atom, or a string sent either quoted or as a
+/// literal.
+#[derive(Debug, PartialEq)]
+enum Token {
+    Atom(String),
+    String(String),
+}
+
+impl Token {
+    fn into_string(self) -> String {
+        match self {
+            Token::Atom(s) | Token::String(s) => s,
+        }
+    }
+}
+
+/// Read a command line, including the literals it holds. Returns `Ok(None)`
+/// when the client has gone away, and the response to send if the command
+/// is malformed.
+fn read_command<S: BufRead>(stream: &mut S) -> io::Result<Option<Result<Vec<Token>, String>>> {
+    let mut tokens = Vec::new();
+    let mut error = None;
+    loop {
+        let mut line = Vec::new();
+        if stream.read_until(b'\n', &mut line)? == 0 {
+            return Ok(None);
+        }
+        let line = line.strip_suffix(b"\n").unwrap_or(&line);
+        let line = line.strip_suffix(b"\r").unwrap_or(line);
+        let mut pos = 0;
+        let mut literal = None;
+        while pos < line.len() && error.is_none() {
+            match line[pos] {
+                b' ' => pos += 1,
+                b'"' => {
+                    let mut value = Vec::new();
+                    pos += 1;
+                    loop {
+                        match line.get(pos) {
+                            None => {
+                                error = Some(no(None, "Unterminated string"));
+                                break;
+                            }
+                            Some(b'"') => {
+                                pos += 1;
+                                break;
+                            }
+                            Some(b'\\') if pos + 1 < line.len() => {
+                                value.push(line[pos + 1]);
+                                pos += 2;
+                            }
+                            Some(chr) => {
+                                value.push(*chr);
+                                pos += 1;
+                            }
+                        }
+                    }
+                    match String::from_utf8(value) {
+                        Ok(value) => tokens.push(Token::String(value)),
+                        Err(_) => error = Some(no(None, "Invalid UTF-8 in string")),
+                    }
+                }
+                b'{' => {
+                    // A literal must end the line: {size+} or {size}
+                    let spec = String::from_utf8_lossy(&line[pos + 1..]).into_owned();
+                    match spec.trim_end_matches('}').trim_end_matches('+').parse::<usize>() {
+                        Ok(size) if spec.ends_with('}') => literal = Some(size),
+                        _ => error = Some(no(None, "Invalid literal")),
+                    }
+                    break;
+                }
+                _ => {
+                    let start = pos;
+                    while pos < line.len() && line[pos] != b' ' {
+                        pos += 1;
+                    }
+                    tokens.push(Token::Atom(String::from_utf8_lossy(&line[start..pos]).into_owned()));
+                }
+            }
+        }
+        let size = match literal {
+            Some(size) => size,
+            None => {
+                return Ok(Some(match error {
+                    Some(error) => Err(error),
+                    None => Ok(tokens),
+                }))
+            }
+        };
+        // The literal has to be read even if it is going to be refused.
+        let mut data = Vec::new();
+        if size > MAX_SCRIPT_SIZE || error.is_some() {
+            io::copy(&mut stream.take(size as u64), &mut io::sink())?;
+            if error.is_none() {
+                error = Some(no(Some("QUOTA/MAXSIZE"), "Script too large"));
+            }
+        } else if stream.take(size as u64).read_to_end(&mut data)? < size {
+            return Ok(None);
+        }
+        match String::from_utf8(data) {
+            Ok(value) => tokens.push(Token::String(value)),
+            Err(_) => error = Some(no(None, "Invalid UTF-8 in literal")),
+        }
+    }
+}
+
+/// Encode a string for a response: quoted if possible, or else as a
+/// literal.
+fn string(s: &str) -> String {
+    if s.len() > 1024 || s.contains(['\r', '\n', '\0']) {
+        format!("{{{}}}\r\n{}", s.len(), s)
+    } else {
+        format!("\"{}\"", s.replace('\\', "\\\\").replace('"', "\\\""))
+    }
+}
+
+fn ok(message: &str) -> String {
+    format!("OK {}\r\n", string(message))
+}
+
+/// A NO response with an optional response code.
+fn no(code: Option<&str>, message: &str) -> String {
+    match code {
+        Some(code) => format!("NO ({}) {}\r\n", code, string(message)),
+        None => format!("NO {}\r\n", string(message)),
+    }
+}
+
+/// The capabilities, sent in the greeting, after STARTTLS and in answer to
+/// CAPABILITY.
+fn capabilities(serv: &Server, tls: bool) -> String {
+    let mut res = format!(
+        "\"IMPLEMENTATION\" \"segimap\"\r\n\"SASL\" \"{}\"\r\n\"SIEVE\" \"{}\"\r\n",
+        sasl::MECHANISMS,
+        sieve::CAPABILITIES.join(" ")
+    );
+    if !tls && serv.can_starttls() {
+        res.push_str("\"STARTTLS\"\r\n");
+    }
+    res.push_str("\"VERSION\" \"1.0\"\r\n");
+    res
+}
+
+struct Session {
+    serv: Arc<Server>,
+    /// The logged in user's maildir, where their scripts are kept.
+    maildir: Option<PathBuf>,
+    logout: bool,
+}
+
+impl Session {
+    /// Check a script's syntax, giving the error response if it is invalid.
+    fn check(script: &str) -> Option<String> {
+        match sieve::parse(script) {
+            Ok(_) => None,
+            Err(e) => Some(no(None, &e.to_string())),
+        }
+    }
+
+    /// Interpret a command which needs the user to be logged in.
+    fn interpret(&mut self, maildir: &Path, command: &str, args: Vec<String>) -> io::Result<String> {
+        let arg = |i: usize| args.get(i).map(|s| &s[..]);
+        let exists = |name: &str| sieve::script_path(maildir, name).is_file();
+        Ok(match (command, args.len()) {
+            ("HAVESPACE", 2) => match arg(1).unwrap_or_default().parse::<usize>() {
+                Ok(size) if size <= MAX_SCRIPT_SIZE => ok("Putscript would succeed"),
+                Ok(_) => no(Some("QUOTA/MAXSIZE"), "Script too large"),
+                Err(_) => no(None, "Invalid size"),
+            },
+            ("PUTSCRIPT", 2) => {
+                let name = arg(0).unwrap_or_default();
+                if !sieve::valid_script_name(name) {
+                    return Ok(no(None, "Invalid script name"));
+                }
+                if let Some(error) = Session::check(&args[1]) {
+                    return Ok(error);
+                }
+                sieve::write_file(&sieve::script_path(maildir, name), args[1].as_bytes())?;
+                ok("Script stored")
+            }
+            ("CHECKSCRIPT", 1) => match Session::check(&args[0]) {
+                Some(error) => error,
+                None => ok("Script is valid"),
+            },
+            ("LISTSCRIPTS", 0) => {
+                let active = sieve::active_script_name(maildir);
+                let mut res = String::new();
+                for name in sieve::list_scripts(maildir)? {
+                    res.push_str(&string(&name));
+                    if active.as_ref() == Some(&name) {
+                        res.push_str(" ACTIVE");
+                    }
+                    res.push_str("\r\n");
+                }
+                res.push_str(&ok("Listscripts completed"));
+                res
+            }
+            ("GETSCRIPT", 1) => {
+                let mut script = String::new();
+                let name = arg(0).unwrap_or_default();
+                if !sieve::valid_script_name(name) || !exists(name) {
+                    return Ok(no(Some("NONEXISTENT"), "No such script"));
+                }
+                File::open(sieve::script_path(maildir, name))?.read_to_string(&mut script)?;
+                format!("{{{}}}\r\n{}\r\n{}", script.len(), script, ok("Getscript completed"))
+            }
+            ("SETACTIVE", 1) => {
+                let name = arg(0).unwrap_or_default();
+                if name.is_empty() {
+                    sieve::set_active_script(maildir, None)?;
+                    return Ok(ok("No script is active"));
+                }
+                if !sieve::valid_script_name(name) || !exists(name) {
+                    return Ok(no(Some("NONEXISTENT"), "No such script"));
+                }
+                sieve::set_active_script(maildir, Some(name))?;
+                ok("Script activated")
+            }
+            ("DELETESCRIPT", 1) => {
+                let name = arg(0).unwrap_or_default();
+                if !sieve::valid_script_name(name) || !exists(name) {
+                    return Ok(no(Some("NONEXISTENT"), "No such script"));
+                }
+                if sieve::active_script_name(maildir).as_deref() == Some(name) {
+                    return Ok(no(Some("ACTIVE"), "Cannot delete the active script"));
+                }
+                fs::remove_file(sieve::script_path(maildir, name))?;
+                ok("Script deleted")
+            }
+            ("RENAMESCRIPT", 2) => {
+                let (old, new) = (arg(0).unwrap_or_default(), arg(1).unwrap_or_default());
+                if !sieve::valid_script_name(old) || !exists(old) {
+                    return Ok(no(Some("NONEXISTENT"), "No such script"));
+                }
+                if !sieve::valid_script_name(new) {
+                    return Ok(no(None, "Invalid script name"));
+                }
+                if exists(new) {
+                    return Ok(no(Some("ALREADYEXISTS"), "A script with that name already exists"));
+                }
+                fs::rename(sieve::script_path(maildir, old), sieve::script_path(maildir, new))?;
+                if sieve::active_script_name(maildir).as_deref() == Some(old) {
+                    sieve::set_active_script(maildir, Some(new))?;
+                }
+                ok("Script renamed")
+            }
+            ("UNAUTHENTICATE", 0) => {
+                self.maildir = None;
+                ok("Logged out")
+            }
+            ("HAVESPACE", _)
+            | ("PUTSCRIPT", _)
+            | ("CHECKSCRIPT", _)
+            | ("LISTSCRIPTS", _)
+            | ("GETSCRIPT", _)
+            | ("SETACTIVE", _)
+            | ("DELETESCRIPT", _)
+            | ("RENAMESCRIPT", _)
+            | ("UNAUTHENTICATE", _) => no(None, "Wrong number of arguments"),
+            _ => no(None, "Invalid command"),
+        })
+    }
+
+    /// Run the SASL exchange of AUTHENTICATE. The client response comes with
+    /// the command or else after an empty challenge.
+    fn authenticate(&mut self, stream: &mut BufStream<Stream>, args: Vec<String>) -> io::Result<Option<String>> {
+        if self.maildir.is_some() {
+            return Ok(Some(no(None, "Already authenticated")));
+        }
+        let mechanism = match args.first() {
+            Some(mechanism) if args.len() <= 2 => mechanism.clone(),
+            _ => return Ok(Some(no(None, "Wrong number of arguments"))),
+        };
+        let response = match args.get(1) {
+            Some(response) => response.clone(),
+            None => {
+                stream.write_all(b"\"\"\r\n")?;
+                stream.flush()?;
+                match read_command(stream)? {
+                    None => return Ok(None),
+                    Some(Ok(tokens)) if tokens.len() == 1 => match tokens.into_iter().next() {
+                        Some(Token::String(response)) => response,
+                        _ => return Ok(Some(no(None, "Authentication aborted"))),
+                    },
+                    Some(_) => return Ok(Some(no(None, "Authentication aborted"))),
+                }
+            }
+        };
+        Ok(Some(match sasl::login(&self.serv, &mechanism, &response) {
+            Some(user) => {
+                self.maildir = Some(PathBuf::from(&user.maildir));
+                ok("Logged in")
+            }
+            None => no(None, "Authentication failed"),
+        }))
+    }
+}
+
+pub fn serve(serv: Arc<Server>, mut stream: BufStream<Stream>) {
+    let mut session = Session {
+        serv: serv.clone(),
+        maildir: None,
+        logout: false,
+    };
+    let greeting = format!("{}{}", capabilities(&serv, false), ok("ManageSieve server ready"));
+    return_on_err!(stream.write_all(greeting.as_bytes()));
+    return_on_err!(stream.flush());
+
+    loop {
+        let tokens = match read_command(&mut stream) {
+            Ok(Some(Ok(tokens))) => tokens,
+            Ok(Some(Err(res))) => {
+                return_on_err!(stream.write_all(res.as_bytes()));
+                return_on_err!(stream.flush());
+                continue;
+            }
+            Ok(None) | Err(_) => return,
+        };
+        let mut tokens = tokens.into_iter();
+        let command = match tokens.next() {
+            Some(Token::Atom(command)) => command.to_ascii_uppercase(),
+            None => continue,
+            Some(Token::String(_)) => String::new(),
+        };
+        let args: Vec<String> = tokens.map(Token::into_string).collect();
+        let tls = matches!(stream.get_ref(), Stream::Ssl(_));
+
+        let mut starttls = false;
+        let res = match &command[..] {
+            "CAPABILITY" => format!("{}{}", capabilities(&serv, tls), ok("Capability completed")),
+            "NOOP" => match args.first() {
+                Some(tag) => format!("OK (TAG {}) \"Done\"\r\n", string(tag)),
+                None => ok("Done"),
+            },
+            "LOGOUT" => {
+                session.logout = true;
+                ok("Logout completed")
+            }
+            "STARTTLS" => {
+                if tls || !serv.can_starttls() {
+                    no(None, "TLS is not available")
+                } else {
+                    starttls = true;
+                    ok("Begin TLS negotiation now")
+                }
+            }
+            "AUTHENTICATE" => match session.authenticate(&mut stream, args) {
+                Ok(Some(res)) => res,
+                Ok(None) | Err(_) => return,
+            },
+            _ => match session.maildir.clone() {
+                Some(maildir) => match session.interpret(&maildir, &command, args) {
+                    Ok(res) => res,
+                    Err(e) => {
+                        warn!("ManageSieve error for '{}': {}", maildir.display(), e);
+                        no(Some("TRYLATER"), "Error accessing scripts")
+                    }
+                },
+                None => no(None, "Authenticate first"),
+            },
+        };
+        return_on_err!(stream.write_all(res.as_bytes()));
+        return_on_err!(stream.flush());
+
+        if starttls {
+            match serv.starttls(stream.into_inner()) {
+                Some(ssl_stream) => stream = BufStream::new(Stream::Ssl(ssl_stream)),
+                None => return,
+            }
+            // The capabilities are sent again once TLS is established
+            let res = format!("{}{}", capabilities(&serv, true), ok("TLS negotiation successful"));
+            return_on_err!(stream.write_all(res.as_bytes()));
+            return_on_err!(stream.flush());
+        }
+        if session.logout {
+            return;
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::io::Cursor;
+
+    fn command(input: &str) -> Result<Vec<Token>, String> {
+        read_command(&mut Cursor::new(input.as_bytes())).unwrap().unwrap()
+    }
+
+    #[test]
+    fn test_read_command() {
+        assert_eq!(
+            command("PUTSCRIPT \"my \\\"script\\\"\" {6+}\r\nkeep;\n\r\n"),
+            Ok(vec![
+                Token::Atom("PUTSCRIPT".to_string()),
+                Token::String("my \"script\"".to_string()),
+                Token::String("keep;\n".to_string()),
+            ])
+        );
+        assert_eq!(
+            command("AUTHENTICATE \"PLAIN\" {4}\r\nAGEA\r\n"),
+            Ok(vec![
+                Token::Atom("AUTHENTICATE".to_string()),
+                Token::String("PLAIN".to_string()),
+                Token::String("AGEA".to_string()),
+            ])
+        );
+        assert!(command("GETSCRIPT \"open\r\n").is_err());
+        assert!(command("CHECKSCRIPT {x+}\r\n").is_err());
+        assert!(read_command(&mut Cursor::new(b"")).unwrap().is_none());
+    }
+
+    #[test]
+    fn test_string() {
+        assert_eq!(string("a \"b\" \\"), "\"a \\\"b\\\" \\\\\"");
+        assert_eq!(string("line 1\nline 2"), "{13}\r\nline 1\nline 2");
+        assert_eq!(no(Some("NONEXISTENT"), "x"), "NO (NONEXISTENT) \"x\"\r\n");
+    }
+}
@@ -1,21 +1,35 @@
-use std::collections::HashMap;
-use std::io::{Read, Result, Write};
-use std::net::{Shutdown, TcpListener, TcpStream};
+use std::collections::{BTreeMap, HashMap};
+use std::io::{BufRead, Read, Result, Write};
+use std::net::{Shutdown, SocketAddr, TcpListener, TcpStream};
+use std::path::{Path, PathBuf};
 use std::result::Result as StdResult;
-use std::sync::Arc;
+use std::sync::{Arc, Mutex};
 
 use bufstream::{BufStream, IntoInnerError};
+use crypto::digest::Digest;
+use crypto::md5::Md5;
 use openssl::ssl::{SslAcceptor, SslStream};
 
 use self::config::Config;
 use self::imap::ImapSession;
 use self::user::{load_users, Email, LoginData, User};
 use crate::error::ImapResult;
+use crate::layout::Layout;
+use crate::namespace::Root;
+use crate::quota::Quota;
+use crate::special_use::SpecialUse;
+
+pub use self::jmap::JMAP_DIR;
 
 mod config;
 #[macro_use]
 pub mod lmtp;
 mod imap;
+mod jmap;
+mod managesieve;
+mod pop3;
+mod relay;
+mod sasl;
 mod user;
 
 pub enum Stream {
@@ -23,6 +37,38 @@ pub enum Stream {
     Tcp(TcpStream),
 }
 
+impl Stream {
+    fn tcp_stream(&self) -> &TcpStream {
+        match *self {
+            Stream::Ssl(ref s) => s.get_ref(),
+            Stream::Tcp(ref s) => s,
+        }
+    }
+
+    pub fn set_nonblocking(&self, nonblocking: bool) -> Result<()> {
+        self.tcp_stream().set_nonblocking(nonblocking)
+    }
+
+    pub fn peer_addr(&self) -> Result<SocketAddr> {
+        self.tcp_stream().peer_addr()
+    }
+
+    /// Describe the TLS session, if there is one, for use in trace headers.
+    pub fn tls_description(&self) -> Option<String> {
+        match *self {
+            Stream::Ssl(ref s) => {
+                let ssl = s.ssl();
+                let cipher = match ssl.current_cipher() {
+                    Some(cipher) => cipher.name(),
+                    None => "unknown",
+                };
+                Some(format!("{} with cipher {}", ssl.version_str(), cipher))
+            }
+            Stream::Tcp(_) => None,
+        }
+    }
+}
+
 impl Write for Stream {
     fn write(&mut self, buf: &[u8]) -> Result<usize> {
         match *self {
@@ -53,6 +99,9 @@ pub struct Server {
     conf: Config,
     users: HashMap<Email, User>,
     ssl_acceptor: Option<SslAcceptor>,
+    /// What JMAP keeps of each maildir between requests, locked by the
+    /// request using it
+    jmap_caches: Mutex<HashMap<PathBuf, Arc<Mutex<jmap::Cache>>>>,
 }
 
 impl Server {
@@ -70,6 +119,7 @@ impl Server {
             conf: conf,
             users: users,
             ssl_acceptor: ssl_acceptor,
+            jmap_caches: Mutex::new(HashMap::new()),
         })
     }
 
@@ -102,9 +152,40 @@ impl Server {
         self.generic_listener(self.conf.lmtp_ssl_port)
     }
 
-    pub fn imap_ssl(&self, stream: TcpStream) -> Stream {
+    /// Create a TCP listener on the server host and ManageSieve port
+    pub fn managesieve_listener(&self) -> Option<Result<TcpListener>> {
+        self.generic_listener(self.conf.managesieve_port)
+    }
+
+    /// Create a TCP listener on the server host and JMAP port
+    pub fn jmap_listener(&self) -> Option<Result<TcpListener>> {
+        self.generic_listener(self.conf.jmap_port)
+    }
+
+    /// Create a TCP listener on the server host and submission port
+    pub fn submission_listener(&self) -> Option<Result<TcpListener>> {
+        self.generic_listener(self.conf.submission_port)
+    }
+
+    /// Whether outgoing mail is relayed anywhere
+    pub fn can_relay(&self) -> bool {
+        self.conf.spool_dir.is_some() && self.conf.smarthost.is_some()
+    }
+
+    /// Create a TCP listener on the server host and pop3 port
+    pub fn pop3_listener(&self) -> Option<Result<TcpListener>> {
+        self.generic_listener(self.conf.pop3_port)
+    }
+
+    /// Create a TCP listener on the server host and pop3 ssl port
+    pub fn pop3_ssl_listener(&self) -> Option<Result<TcpListener>> {
+        self.generic_listener(self.conf.pop3_ssl_port)
+    }
+
+    /// Wrap the stream in SSL if it was accepted on the given SSL port.
+    fn ssl_stream(&self, stream: TcpStream, ssl_port: Option<u16>) -> Stream {
         if let Ok(addr) = stream.local_addr() {
-            if Some(addr.port()) == self.conf.imap_ssl_port {
+            if Some(addr.port()) == ssl_port {
                 if let Some(ref ssl_acceptor) = self.ssl_acceptor {
                     return Stream::Ssl(ssl_acceptor.accept(stream).unwrap());
                 }
@@ -115,6 +196,14 @@ impl Server {
         Stream::Tcp(stream)
     }
 
+    pub fn imap_ssl(&self, stream: TcpStream) -> Stream {
+        self.ssl_stream(stream, self.conf.imap_ssl_port)
+    }
+
+    pub fn pop3_ssl(&self, stream: TcpStream) -> Stream {
+        self.ssl_stream(stream, self.conf.pop3_ssl_port)
+    }
+
     pub fn can_starttls(&self) -> bool {
         if let Some(_) = self.ssl_acceptor {
             true
@@ -141,20 +230,159 @@ impl Server {
         &self.conf.host
     }
 
+    /// The mailbox with each special use for a user: the configured ones,
+    /// overridden by the user's own.
+    pub fn special_use(&self, user: &User) -> BTreeMap<String, String> {
+        let mut special_use = self.conf.special_use.clone();
+        special_use.extend(user.special_use.clone());
+        special_use
+    }
+
+    /// The JMAP cache of a user's maildir.
+    fn jmap_cache(&self, user: &User) -> Arc<Mutex<jmap::Cache>> {
+        let mut caches = self.jmap_caches.lock().unwrap_or_else(|e| e.into_inner());
+        caches.entry(PathBuf::from(&user.maildir)).or_default().clone()
+    }
+
+    /// How the folders of a user's maildir are laid out.
+    pub fn layout(&self, user: &User) -> Layout {
+        user.layout.unwrap_or(self.conf.layout)
+    }
+
+    /// The roots of the mailboxes a user sees: their own maildir first,
+    /// then, with the namespaces configured, the other users' maildirs and
+    /// the shared root.
+    pub fn roots(&self, user: &User) -> Vec<Root> {
+        let mut roots = vec![Root {
+            prefix: String::new(),
+            path: PathBuf::from(&user.maildir),
+            layout: self.layout(user),
+            owner: Some(user.email.to_string()),
+        }];
+        if let Some(ref prefix) = self.conf.other_users_namespace {
+            let mut others: Vec<&User> = self.users.values().filter(|other| other.email != user.email).collect();
+            others.sort_by_key(|other| other.email.to_string());
+            for other in others {
+                let address = other.email.to_string();
+                roots.push(Root {
+                    prefix: format!("{}/{}", prefix, self.conf.delimiter.stored_name(&address)),
+                    path: PathBuf::from(&other.maildir),
+                    layout: self.layout(other),
+                    owner: Some(address),
+                });
+            }
+        }
+        if let (Some(prefix), Some(path)) = (&self.conf.shared_namespace, &self.conf.shared_root) {
+            roots.push(Root {
+                prefix: prefix.clone(),
+                path: PathBuf::from(path),
+                layout: self.conf.layout,
+                owner: None,
+            });
+        }
+        roots
+    }
+
+    /// The quota on a user's maildir.
+    pub fn quota(&self, user: &User) -> Quota {
+        Quota::load(Path::new(&user.maildir), self.layout(user), user.quota)
+    }
+
+    /// Whether the folders of a user's maildir keep a full-text index.
+    pub fn full_text_index(&self, user: &User) -> bool {
+        user.full_text_index.unwrap_or(self.conf.full_text_index)
+    }
+
+    /// The user with an address, as quota roots and the owners of roots
+    /// name them.
+    pub fn user_by_address(&self, address: &str) -> Option<&User> {
+        self.users.values().find(|user| user.email.to_string() == address)
+    }
+
+    /// The prefixes of the other users' and shared namespaces, if they are
+    /// configured.
+    pub fn namespaces(&self) -> (Option<&str>, Option<&str>) {
+        let shared = self.conf.shared_root.as_ref().and(self.conf.shared_namespace.as_deref());
+        (self.conf.other_users_namespace.as_deref(), shared)
+    }
+
     pub fn login(&self, email: String, password: String) -> Option<&User> {
         if let Some(login_data) = LoginData::new(email, password) {
             if let Some(user) = self.users.get(&login_data.email) {
                 if user.auth_data.verify_auth(login_data.password) {
-                    return Some(user);
+                    return Some(self.logged_in(user));
                 }
             }
         }
         None
     }
+
+    /// Authenticate a user with APOP (RFC 1939 section 7), whose digest is
+    /// the MD5 of the timestamp in the greeting followed by the user's APOP
+    /// secret.
+    pub fn apop_login(&self, email: String, timestamp: &str, digest: &str) -> Option<&User> {
+        let login_data = LoginData::new(email, String::new())?;
+        let user = self.users.get(&login_data.email)?;
+        let mut md5 = Md5::new();
+        md5.input_str(timestamp);
+        md5.input_str(user.apop_secret.as_ref()?);
+        if md5.result_str().eq_ignore_ascii_case(digest) {
+            Some(self.logged_in(user))
+        } else {
+            None
+        }
+    }
+
+    // Set up the maildir of a user who has just logged in
+    fn logged_in<'a>(&self, user: &'a User) -> &'a User {
+        if self.conf.create_special_use {
+            let maildir = Path::new(&user.maildir);
+            if let Err(e) = SpecialUse::provision(maildir, self.layout(user), &self.special_use(user)) {
+                warn!("Error creating special-use folders in '{}': {}", user.maildir, e);
+            }
+        }
+        user
+    }
+}
+
+/// Check whether the client has already sent more input than we have
+/// processed, without blocking. Used to batch up responses to pipelined
+/// commands.
+pub fn input_pending(stream: &mut BufStream<Stream>) -> bool {
+    if stream.get_ref().set_nonblocking(true).is_err() {
+        return false;
+    }
+    let pending = match stream.fill_buf() {
+        Ok(buf) => !buf.is_empty(),
+        Err(_) => false,
+    };
+    let _ = stream.get_ref().set_nonblocking(false);
+    pending
 }
 
 pub fn lmtp_serve(serv: Arc<Server>, stream: TcpStream) {
-    lmtp::serve(serv, BufStream::new(stream))
+    lmtp::serve(serv, BufStream::new(Stream::Tcp(stream)))
+}
+
+pub fn submission_serve(serv: Arc<Server>, stream: TcpStream) {
+    lmtp::serve_submission(serv, BufStream::new(Stream::Tcp(stream)))
+}
+
+pub fn relay_spool(serv: Arc<Server>) {
+    relay::run(serv)
+}
+
+pub fn managesieve_serve(serv: Arc<Server>, stream: TcpStream) {
+    managesieve::serve(serv, BufStream::new(Stream::Tcp(stream)))
+}
+
+pub fn jmap_serve(serv: Arc<Server>, stream: TcpStream) {
+    jmap::serve(serv, BufStream::new(Stream::Tcp(stream)))
+}
+
+pub fn pop3_serve(serv: Arc<Server>, stream: TcpStream) {
+    let stream = serv.pop3_ssl(stream);
+    pop3::serve(serv, BufStream::new(stream))
 }
 
 pub fn imap_serve(serv: Arc<Server>, stream: TcpStream) {
@@ -0,0 +1,487 @@
+//! POP3 (RFC 1939) access to the INBOX of the maildir, with the CAPA
+//! (RFC 2449), STLS (RFC 2595) and AUTH (RFC 5034) extensions.
+
+use std::fs::File;
+use std::io::{self, BufRead, Read, Write};
+use std::path::PathBuf;
+use std::process;
+use std::sync::Arc;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use bufstream::BufStream;
+
+use crate::folder::Folder;
+use crate::message::Flag;
+use crate::server::{sasl, Server, Stream};
+
+static NO_SUCH_MESSAGE: &str = "-ERR No such message\r\n";
+static INVALID: &str = "-ERR Invalid command\r\n";
+
+/// Representation of a POP3 session
+struct Pop3 {
+    /// The name given with USER, waiting for PASS
+    user: Option<String>,
+    /// The timestamp in the greeting, unique to the session, which APOP
+    /// digests
+    timestamp: String,
+    /// The maildrop, once logged in: the INBOX of the user's maildir
+    folder: Option<Folder>,
+    /// The size of each message as sent, with CRLF line endings
+    sizes: Vec<usize>,
+    /// Which messages have been marked as deleted with DELE
+    deleted: Vec<bool>,
+    quit: bool,
+}
+
+/// Read a message file. Maildir messages are stored with LF line endings.
+fn read_message(path: &std::path::Path) -> io::Result<Vec<u8>> {
+    let mut data = Vec::new();
+    File::open(path)?.read_to_end(&mut data)?;
+    Ok(data)
+}
+
+/// The size of a message once its line endings are CRLF.
+fn crlf_size(data: &[u8]) -> usize {
+    let bare_lfs = data
+        .iter()
+        .enumerate()
+        .filter(|&(i, byte)| *byte == b'\n' && (i == 0 || data[i - 1] != b'\r'))
+        .count();
+    data.len() + bare_lfs
+}
+
+/// Turn message data into a multi-line response body: CRLF line endings,
+/// byte-stuffed, and terminated by a line holding a single ".". With
+/// `body_lines` set, only the header and that many lines of the body are
+/// included, as TOP requires.
+fn multiline(data: &[u8], body_lines: Option<usize>) -> Vec<u8> {
+    let mut res = Vec::with_capacity(data.len() + data.len() / 32 + 3);
+    let mut in_body = false;
+    let mut body_count = 0;
+    for line in data.split_inclusive(|b| *b == b'\n') {
+        if in_body {
+            if let Some(max) = body_lines {
+                if body_count >= max {
+                    break;
+                }
+            }
+            body_count += 1;
+        }
+        let line = line.strip_suffix(b"\n").unwrap_or(line);
+        let line = line.strip_suffix(b"\r").unwrap_or(line);
+        if line.is_empty() {
+            in_body = true;
+        }
+        if line.starts_with(b".") {
+            res.push(b'.');
+        }
+        res.extend_from_slice(line);
+        res.extend_from_slice(b"\r\n");
+    }
+    res.extend_from_slice(b".\r\n");
+    res
+}
+
+fn capabilities(serv: &Server, tls: bool, logged_in: bool) -> String {
+    let mut res = "+OK Capability list follows\r\nTOP\r\nUIDL\r\nRESP-CODES\r\nAUTH-RESP-CODE\r\nIMPLEMENTATION segimap\r\n".to_string();
+    if !logged_in {
+        res.push_str(&format!("USER\r\nSASL {}\r\n", sasl::MECHANISMS));
+        if !tls && serv.can_starttls() {
+            res.push_str("STLS\r\n");
+        }
+    }
+    res.push_str(".\r\n");
+    res
+}
+
+impl Pop3 {
+    /// Open the user's maildrop. Only one session may hold it at a time.
+    fn open(&mut self, maildir: &str) -> String {
+        let folder = match Folder::new(PathBuf::from(maildir), false) {
+            Some(folder) => folder,
+            None => return "-ERR [SYS/TEMP] Cannot open maildrop\r\n".to_string(),
+        };
+        if folder.is_readonly() {
+            return "-ERR [IN-USE] Maildrop is already locked\r\n".to_string();
+        }
+        self.sizes = Vec::new();
+        for index in 0..folder.message_count() {
+            let size = folder
+                .message(index)
+                .and_then(|message| read_message(message.get_path()).ok())
+                .map(|data| crlf_size(&data))
+                .unwrap_or(0);
+            self.sizes.push(size);
+        }
+        self.deleted = vec![false; self.sizes.len()];
+        let res = format!("+OK Maildrop has {} messages\r\n", self.sizes.len());
+        self.folder = Some(folder);
+        res
+    }
+
+    /// Turn a message number argument into an index, if it names a message
+    /// which has not been deleted.
+    fn index(&self, arg: Option<&str>) -> Option<usize> {
+        let number: usize = arg?.parse().ok()?;
+        if number == 0 || number > self.sizes.len() || self.deleted[number - 1] {
+            return None;
+        }
+        Some(number - 1)
+    }
+
+    /// Send the data of a message for RETR or TOP.
+    fn retrieve(&mut self, index: usize, body_lines: Option<usize>) -> Vec<u8> {
+        let folder = match self.folder {
+            Some(ref mut folder) => folder,
+            None => return INVALID.as_bytes().to_vec(),
+        };
+        let data = match folder.message(index).map(|message| read_message(message.get_path())) {
+            Some(Ok(data)) => data,
+            _ => return b"-ERR [SYS/TEMP] Cannot read message\r\n".to_vec(),
+        };
+        let mut res = format!("+OK {} octets\r\n", self.sizes[index]).into_bytes();
+        res.extend(multiline(&data, body_lines));
+        if body_lines.is_none() {
+            folder.set_flag(index, Flag::Seen, true);
+        }
+        res
+    }
+
+    /// Interprets a command in the TRANSACTION state
+    fn transaction(&mut self, cmd: &str, args: &[&str]) -> Vec<u8> {
+        let arg = args.first().copied();
+        let res = match cmd {
+            "stat" => {
+                let (count, size) = self
+                    .sizes
+                    .iter()
+                    .zip(&self.deleted)
+                    .filter(|&(_, deleted)| !deleted)
+                    .fold((0, 0), |(count, total), (size, _)| (count + 1, total + size));
+                format!("+OK {} {}\r\n", count, size)
+            }
+            "list" | "uidl" => {
+                let folder = match self.folder {
+                    Some(ref folder) => folder,
+                    None => return INVALID.as_bytes().to_vec(),
+                };
+                let value = |index: usize| {
+                    if cmd == "list" {
+                        self.sizes[index].to_string()
+                    } else {
+                        folder.message(index).map(|m| m.get_uid()).unwrap_or(0).to_string()
+                    }
+                };
+                match arg {
+                    Some(_) => match self.index(arg) {
+                        Some(index) => format!("+OK {} {}\r\n", index + 1, value(index)),
+                        None => NO_SUCH_MESSAGE.to_string(),
+                    },
+                    None => {
+                        let mut res = "+OK\r\n".to_string();
+                        for index in 0..self.sizes.len() {
+                            if !self.deleted[index] {
+                                res.push_str(&format!("{} {}\r\n", index + 1, value(index)));
+                            }
+                        }
+                        res.push_str(".\r\n");
+                        res
+                    }
+                }
+            }
+            "retr" => {
+                return match self.index(arg) {
+                    Some(index) => self.retrieve(index, None),
+                    None => NO_SUCH_MESSAGE.as_bytes().to_vec(),
+                };
+            }
+            "top" => {
+                let lines = args.get(1).and_then(|n| n.parse().ok());
+                return match (self.index(arg), lines) {
+                    (Some(index), Some(lines)) => self.retrieve(index, Some(lines)),
+                    (None, Some(_)) => NO_SUCH_MESSAGE.as_bytes().to_vec(),
+                    _ => INVALID.as_bytes().to_vec(),
+                };
+            }
+            "dele" => match self.index(arg) {
+                Some(index) => {
+                    self.deleted[index] = true;
+                    if let Some(ref mut folder) = self.folder {
+                        folder.set_flag(index, Flag::Deleted, true);
+                    }
+                    format!("+OK Message {} deleted\r\n", index + 1)
+                }
+                None => NO_SUCH_MESSAGE.to_string(),
+            },
+            "rset" => {
+                if let Some(ref mut folder) = self.folder {
+                    for (index, deleted) in self.deleted.iter_mut().enumerate() {
+                        if *deleted {
+                            folder.set_flag(index, Flag::Deleted, false);
+                            *deleted = false;
+                        }
+                    }
+                }
+                format!("+OK Maildrop has {} messages\r\n", self.sizes.len())
+            }
+            "noop" => "+OK\r\n".to_string(),
+            "quit" => {
+                // The UPDATE state: remove the messages deleted with DELE,
+                // but not others flagged \Deleted over IMAP
+                self.quit = true;
+                if let Some(ref mut folder) = self.folder {
+                    let uids: Vec<usize> = self
+                        .deleted
+                        .iter()
+                        .enumerate()
+                        .filter(|&(_, deleted)| *deleted)
+                        .filter_map(|(index, _)| folder.message(index).map(|message| message.get_uid()))
+                        .collect();
+                    folder.check();
+                    folder.expunge(Some(&uids));
+                    folder.unlock();
+                }
+                self.folder = None;
+                "+OK Bye\r\n".to_string()
+            }
+            _ => INVALID.to_string(),
+        };
+        res.into_bytes()
+    }
+
+    /// Interprets a command in the AUTHORIZATION state, other than the
+    /// commands which need access to the stream.
+    fn authorization(&mut self, serv: &Server, cmd: &str, args: &[&str]) -> String {
+        match cmd {
+            "user" => match args.first() {
+                Some(name) if args.len() == 1 => {
+                    self.user = Some(name.to_string());
+                    "+OK\r\n".to_string()
+                }
+                _ => INVALID.to_string(),
+            },
+            "pass" => {
+                let user = match self.user.take() {
+                    Some(user) => user,
+                    None => return "-ERR USER first\r\n".to_string(),
+                };
+                // The password may contain spaces
+                match serv.login(user, args.join(" ")) {
+                    Some(user) => self.open(&user.maildir),
+                    None => "-ERR [AUTH] Invalid username or password\r\n".to_string(),
+                }
+            }
+            "apop" => match args[..] {
+                [name, digest] => match serv.apop_login(name.to_string(), &self.timestamp, digest) {
+                    Some(user) => self.open(&user.maildir),
+                    None => "-ERR [AUTH] Invalid username or digest\r\n".to_string(),
+                },
+                _ => INVALID.to_string(),
+            },
+            "quit" => {
+                self.quit = true;
+                "+OK Bye\r\n".to_string()
+            }
+            "noop" => "+OK\r\n".to_string(),
+            _ => INVALID.to_string(),
+        }
+    }
+}
+
+/// Run the SASL exchange of AUTH. The client response comes with the
+/// command or else after an empty challenge.
+fn authenticate(serv: &Server, stream: &mut BufStream<Stream>, s: &mut Pop3, args: &[&str]) -> io::Result<String> {
+    let mechanism = match args.first() {
+        Some(mechanism) => mechanism,
+        // AUTH alone lists the mechanisms
+        None => return Ok(format!("+OK\r\n{}\r\n.\r\n", sasl::MECHANISMS.replace(' ', "\r\n"))),
+    };
+    let response = match args.get(1) {
+        // An empty initial response is sent as "="
+        Some(&"=") => String::new(),
+        Some(response) => response.to_string(),
+        None => {
+            stream.write_all(b"+ \r\n")?;
+            stream.flush()?;
+            let mut line = String::new();
+            if stream.read_line(&mut line)? == 0 {
+                return Err(io::ErrorKind::UnexpectedEof.into());
+            }
+            line.trim_end().to_string()
+        }
+    };
+    if response == "*" {
+        return Ok("-ERR Authentication cancelled\r\n".to_string());
+    }
+    Ok(match sasl::login(serv, mechanism, &response) {
+        Some(user) => s.open(&user.maildir),
+        None => "-ERR [AUTH] Authentication failed\r\n".to_string(),
+    })
+}
+
+/// A timestamp for the greeting which no other session gets, in the form
+/// RFC 1939 suggests: <process-ID.clock@hostname>.
+fn greeting_timestamp(host: &str) -> String {
+    let clock = SystemTime::now().duration_since(UNIX_EPOCH).map_or(0, |time| time.as_nanos());
+    format!("<{}.{}@{}>", process::id(), clock, host)
+}
+
+pub fn serve(serv: Arc<Server>, mut stream: BufStream<Stream>) {
+    let mut s = Pop3 {
+        user: None,
+        timestamp: greeting_timestamp(&serv.conf.host),
+        folder: None,
+        sizes: Vec::new(),
+        deleted: Vec::new(),
+        quit: false,
+    };
+    let greeting = format!("+OK POP3 server ready {}\r\n", s.timestamp);
+    return_on_err!(stream.write_all(greeting.as_bytes()));
+    return_on_err!(stream.flush());
+
+    let mut command = String::new();
+    loop {
+        command.clear();
+        match stream.read_line(&mut command) {
+            Ok(0) | Err(_) => break,
+            Ok(_) => {}
+        }
+        let mut split = command.trim_end_matches(['\r', '\n']).split(' ');
+        let cmd = split.next().unwrap_or("").to_ascii_lowercase();
+        let args: Vec<&str> = split.filter(|arg| !arg.is_empty()).collect();
+        let tls = matches!(stream.get_ref(), Stream::Ssl(_));
+
+        let mut starttls = false;
+        let res = match &cmd[..] {
+            "capa" => capabilities(&serv, tls, s.folder.is_some()).into_bytes(),
+            _ if s.folder.is_some() => s.transaction(&cmd, &args),
+            "stls" => {
+                if tls || !serv.can_starttls() {
+                    b"-ERR TLS is not available\r\n".to_vec()
+                } else {
+                    starttls = true;
+                    b"+OK Begin TLS negotiation now\r\n".to_vec()
+                }
+            }
+            "auth" => match authenticate(&serv, &mut stream, &mut s, &args) {
+                Ok(res) => res.into_bytes(),
+                Err(_) => break,
+            },
+            _ => s.authorization(&serv, &cmd, &args).into_bytes(),
+        };
+        if stream.write_all(&res).and_then(|_| stream.flush()).is_err() {
+            break;
+        }
+
+        if starttls {
+            match serv.starttls(stream.into_inner()) {
+                Some(ssl_stream) => stream = BufStream::new(Stream::Ssl(ssl_stream)),
+                None => return,
+            }
+        }
+        if s.quit {
+            return;
+        }
+    }
+
+    // The connection was lost: nothing is deleted, but the maildrop has to
+    // be released
+    if let Some(ref folder) = s.folder {
+        folder.unlock();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::server::user::{Email, User};
+    use crate::testing::TempDir;
+    use std::collections::HashMap;
+    use std::fs;
+    use std::sync::Mutex;
+
+    #[test]
+    fn test_crlf_size() {
+        assert_eq!(crlf_size(b"a\nb\r\n"), 6);
+        assert_eq!(crlf_size(b"\n"), 2);
+        assert_eq!(crlf_size(b""), 0);
+    }
+
+    #[test]
+    fn test_multiline() {
+        let data = b"Subject: a\n\n.line 1\nline 2\nline 3\n";
+        assert_eq!(
+            multiline(data, None),
+            b"Subject: a\r\n\r\n..line 1\r\nline 2\r\nline 3\r\n.\r\n".to_vec()
+        );
+        assert_eq!(multiline(data, Some(1)), b"Subject: a\r\n\r\n..line 1\r\n.\r\n".to_vec());
+        assert_eq!(multiline(data, Some(0)), b"Subject: a\r\n\r\n.\r\n".to_vec());
+        assert_eq!(multiline(b"", None), b".\r\n".to_vec());
+    }
+
+    #[test]
+    fn test_quit_expunges_only_dele() {
+        let path = TempDir::new("pop3");
+        fs::create_dir_all(path.join("cur")).unwrap();
+        fs::create_dir_all(path.join("new")).unwrap();
+        fs::write(path.join("cur").join("1000:2,T"), "Subject: a\n\nkept\n").unwrap();
+        fs::write(path.join("cur").join("1001:2,S"), "Subject: b\n\ngone\n").unwrap();
+        let mut s = Pop3 {
+            user: None,
+            timestamp: String::new(),
+            folder: None,
+            sizes: Vec::new(),
+            deleted: Vec::new(),
+            quit: false,
+        };
+        assert_eq!(s.open(path.to_str().unwrap()), "+OK Maildrop has 2 messages\r\n");
+        let index = (0..2).find(|&i| s.folder.as_ref().unwrap().message(i).unwrap().get_uid() == 1001);
+        let dele = format!("{}", index.unwrap() + 1);
+        assert!(s.transaction("dele", &[&dele]).starts_with(b"+OK"));
+        assert_eq!(s.transaction("quit", &[]), b"+OK Bye\r\n".to_vec());
+
+        // The message flagged \Deleted before the session is still there
+        let names: Vec<String> = fs::read_dir(path.join("cur"))
+            .unwrap()
+            .map(|entry| entry.unwrap().file_name().into_string().unwrap())
+            .collect();
+        assert_eq!(names, vec!["1000:2,T".to_string()]);
+    }
+
+    #[test]
+    fn test_apop() {
+        let maildir = TempDir::new("pop3-apop");
+        fs::create_dir_all(maildir.join("cur")).unwrap();
+        fs::create_dir_all(maildir.join("new")).unwrap();
+        let email = Email::new("mrose".to_string(), "dbc.mtview.ca.us".to_string());
+        let mut user = User::new(email.clone(), "password".to_string(), maildir.to_str().unwrap().to_string());
+        user.apop_secret = Some("tanstaaf".to_string());
+        let mut users = HashMap::new();
+        users.insert(email, user);
+        let serv = Server {
+            conf: toml::from_str("host = \"127.0.0.1\"\nusers = \"\"\npkcs_file = \"\"\npkcs_pass = \"\"\n").unwrap(),
+            users,
+            ssl_acceptor: None,
+            jmap_caches: Mutex::new(HashMap::new()),
+        };
+        let mut s = Pop3 {
+            user: None,
+            timestamp: "<1896.697170952@dbc.mtview.ca.us>".to_string(),
+            folder: None,
+            sizes: Vec::new(),
+            deleted: Vec::new(),
+            quit: false,
+        };
+        assert!(greeting_timestamp("xqz.ca").ends_with("@xqz.ca>"));
+        assert_ne!(greeting_timestamp("xqz.ca"), greeting_timestamp("xqz.ca"));
+
+        // The example of RFC 1939 section 7
+        let name = "mrose@dbc.mtview.ca.us";
+        let wrong = s.authorization(&serv, "apop", &[name, "00000000000000000000000000000000"]);
+        assert_eq!(wrong, "-ERR [AUTH] Invalid username or digest\r\n");
+        assert_eq!(s.authorization(&serv, "apop", &[name]), INVALID);
+        let res = s.authorization(&serv, "apop", &[name, "c4c9334bac560ecc979e58001b3e22fb"]);
+        assert_eq!(res, "+OK Maildrop has 0 messages\r\n");
+        s.folder.unwrap().unlock();
+    }
+}
@@ -0,0 +1,321 @@
+//! Relays the outgoing mail spool to a smarthost over SMTP (RFC 5321).
+//! Messages are removed from the spool once the smarthost has accepted
+//! them or refused them for good; on temporary failures they are retried.
+
+use std::fs;
+use std::io::{self, BufRead, Write};
+use std::net::TcpStream;
+use std::path::Path;
+use std::sync::{Arc, Mutex};
+use std::thread::{sleep, spawn};
+use std::time::Duration;
+
+use bufstream::BufStream;
+use thiserror::Error;
+
+use crate::server::Server;
+use crate::spool::{self, SpooledMessage};
+
+// How often the spool is drained when nothing else triggers it
+const RELAY_INTERVAL: Duration = Duration::from_secs(60);
+// How long to wait for the smarthost to reply
+const REPLY_TIMEOUT: Duration = Duration::from_secs(300);
+
+// Only one thread drains the spool at a time, so no message is sent twice.
+static DRAIN_LOCK: Mutex<()> = Mutex::new(());
+
+/// Why a message could not be relayed.
+#[derive(Debug, Error)]
+pub enum RelayError {
+    #[error(transparent)]
+    Io(#[from] io::Error),
+    /// A 4xx reply: the message stays in the spool.
+    #[error("temporary failure: {0}")]
+    Temporary(String),
+    /// A 5xx reply: the message will never be accepted.
+    #[error("permanent failure: {0}")]
+    Permanent(String),
+}
+
+/// Turn spooled message data into the contents of SMTP DATA: CRLF line
+/// endings, dot-stuffed, and terminated by a line holding a single ".".
+fn wire_data(data: &[u8]) -> Vec<u8> {
+    let mut res = Vec::with_capacity(data.len() + data.len() / 32 + 3);
+    for line in data.split_inclusive(|b| *b == b'\n') {
+        let line = line.strip_suffix(b"\n").unwrap_or(line);
+        let line = line.strip_suffix(b"\r").unwrap_or(line);
+        if line.starts_with(b".") {
+            res.push(b'.');
+        }
+        res.extend_from_slice(line);
+        res.extend_from_slice(b"\r\n");
+    }
+    res.extend_from_slice(b".\r\n");
+    res
+}
+
+/// Read a reply, joining the lines of multi-line replies.
+fn read_reply<S: BufRead>(stream: &mut S) -> Result<(u16, String), RelayError> {
+    let mut text = String::new();
+    loop {
+        let mut line = String::new();
+        if stream.read_line(&mut line)? == 0 {
+            return Err(io::Error::from(io::ErrorKind::UnexpectedEof).into());
+        }
+        let line = line.trim_end();
+        let code = match line.get(..3).and_then(|code| code.parse::<u16>().ok()) {
+            Some(code) => code,
+            None => return Err(RelayError::Temporary(format!("invalid reply '{}'", line))),
+        };
+        text.push_str(line);
+        if line.as_bytes().get(3) != Some(&b'-') {
+            return Ok((code, text));
+        }
+        text.push(' ');
+    }
+}
+
+/// Check that a reply is of the expected class, 2 or 3.
+fn expect(reply: (u16, String), class: u16) -> Result<String, RelayError> {
+    match reply.0 / 100 {
+        c if c == class => Ok(reply.1),
+        5 => Err(RelayError::Permanent(reply.1)),
+        _ => Err(RelayError::Temporary(reply.1)),
+    }
+}
+
+fn command<S: BufRead + Write>(stream: &mut S, line: &str) -> Result<(u16, String), RelayError> {
+    stream.write_all(line.as_bytes())?;
+    stream.write_all(b"\r\n")?;
+    stream.flush()?;
+    read_reply(stream)
+}
+
+/// Run an SMTP transaction for one message. Recipients the smarthost
+/// refuses for good are dropped as long as it accepts any of them.
+fn transact<S: BufRead + Write>(stream: &mut S, helo: &str, message: &SpooledMessage) -> Result<(), RelayError> {
+    expect(read_reply(stream)?, 2)?;
+    let ehlo = command(stream, &format!("EHLO {}", helo))?;
+    if ehlo.0 / 100 != 2 {
+        expect(command(stream, &format!("HELO {}", helo))?, 2)?;
+    }
+    // 8-bit data is declared to smarthosts which take it (RFC 6152)
+    let body = if !message.data.is_ascii() && ehlo.0 / 100 == 2 && ehlo.1.contains("8BITMIME") {
+        " BODY=8BITMIME"
+    } else {
+        ""
+    };
+    expect(command(stream, &format!("MAIL FROM:<{}>{}", message.from, body))?, 2)?;
+    let mut accepted = 0;
+    for rcpt in &message.to {
+        match expect(command(stream, &format!("RCPT TO:<{}>", rcpt))?, 2) {
+            Ok(_) => accepted += 1,
+            Err(RelayError::Permanent(reason)) => warn!("Smarthost refused recipient {}: {}", rcpt, reason),
+            Err(e) => return Err(e),
+        }
+    }
+    if accepted == 0 {
+        let _ = command(stream, "RSET");
+        return Err(RelayError::Permanent("no recipient was accepted".to_string()));
+    }
+    expect(command(stream, "DATA")?, 3)?;
+    stream.write_all(&wire_data(&message.data))?;
+    stream.flush()?;
+    expect(read_reply(stream)?, 2)?;
+    let _ = command(stream, "QUIT");
+    Ok(())
+}
+
+/// Send one message through the smarthost, given as host:port.
+pub fn send(smarthost: &str, helo: &str, message: &SpooledMessage) -> Result<(), RelayError> {
+    let stream = TcpStream::connect(smarthost)?;
+    stream.set_read_timeout(Some(REPLY_TIMEOUT))?;
+    transact(&mut BufStream::new(stream), helo, message)
+}
+
+/// Try to relay every message in the spool once. Draining stops at the
+/// first temporary failure, as the smarthost is likely unavailable.
+pub fn drain(dir: &Path, smarthost: &str, helo: &str) {
+    let _guard = DRAIN_LOCK.lock().unwrap_or_else(|e| e.into_inner());
+    let paths = match spool::pending(dir) {
+        Ok(paths) => paths,
+        Err(ref e) if e.kind() == io::ErrorKind::NotFound => return,
+        Err(e) => {
+            warn!("Error reading spool '{}': {}", dir.display(), e);
+            return;
+        }
+    };
+    for path in paths {
+        let message = match spool::load(&path) {
+            Ok(message) => message,
+            Err(e) => {
+                warn!("Error reading spooled message '{}': {}", path.display(), e);
+                continue;
+            }
+        };
+        match send(smarthost, helo, &message) {
+            Ok(()) => {}
+            Err(RelayError::Permanent(reason)) => {
+                warn!("Dropping message from <{}> to {:?}: {}", message.from, message.to, reason);
+            }
+            Err(e) => {
+                warn!("Error relaying to {}, will retry: {}", smarthost, e);
+                return;
+            }
+        }
+        if let Err(e) = fs::remove_file(&path) {
+            warn!("Error removing spooled message '{}': {}", path.display(), e);
+        }
+    }
+}
+
+/// Drain the spool in the background now, rather than at the next interval.
+pub fn wake(serv: &Arc<Server>) {
+    if let (Some(dir), Some(smarthost)) = (serv.conf.spool_dir.clone(), serv.conf.smarthost.clone()) {
+        let host = serv.host().clone();
+        spawn(move || drain(Path::new(&dir), &smarthost, &host));
+    }
+}
+
+/// Drain the spool periodically, forever.
+pub fn run(serv: Arc<Server>) {
+    let (dir, smarthost) = match (&serv.conf.spool_dir, &serv.conf.smarthost) {
+        (Some(dir), Some(smarthost)) => (dir.clone(), smarthost.clone()),
+        _ => return,
+    };
+    loop {
+        drain(Path::new(&dir), &smarthost, serv.host());
+        sleep(RELAY_INTERVAL);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::spool::enqueue;
+    use crate::testing::TempDir;
+    use std::io::BufReader;
+    use std::net::TcpListener;
+    use std::thread::JoinHandle;
+
+    // The commands and message data a sink received
+    type Received = (Vec<String>, Vec<u8>);
+
+    /// A stand-in SMTP server which accepts one connection, refuses the
+    /// recipients in `refused`, and returns what it received.
+    fn sink(refused: &'static [&'static str]) -> (String, JoinHandle<Received>) {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let addr = listener.local_addr().unwrap().to_string();
+        let handle = spawn(move || {
+            let (stream, _) = listener.accept().unwrap();
+            let mut writer = stream.try_clone().unwrap();
+            let mut reader = BufReader::new(stream);
+            let mut commands = Vec::new();
+            let mut data = Vec::new();
+            writer.write_all(b"220 sink ready\r\n").unwrap();
+            loop {
+                let mut line = String::new();
+                if reader.read_line(&mut line).unwrap() == 0 {
+                    break;
+                }
+                let line = line.trim_end().to_string();
+                commands.push(line.clone());
+                let reply: &[u8] = if line.starts_with("EHLO") {
+                    b"250-sink\r\n250 8BITMIME\r\n"
+                } else if refused.iter().any(|r| line.contains(r)) {
+                    b"550 5.1.1 No such user\r\n"
+                } else if line == "DATA" {
+                    writer.write_all(b"354 Go ahead\r\n").unwrap();
+                    loop {
+                        let mut chunk = Vec::new();
+                        reader.read_until(b'\n', &mut chunk).unwrap();
+                        data.extend_from_slice(&chunk);
+                        if chunk == b".\r\n" {
+                            break;
+                        }
+                    }
+                    b"250 2.0.0 Queued\r\n"
+                } else if line == "QUIT" {
+                    writer.write_all(b"221 Bye\r\n").unwrap();
+                    break;
+                } else {
+                    b"250 OK\r\n"
+                };
+                writer.write_all(reply).unwrap();
+            }
+            (commands, data)
+        });
+        (addr, handle)
+    }
+
+    #[test]
+    fn test_wire_data() {
+        assert_eq!(wire_data(b"Subject: a\n\n.hi\nbye\n"), b"Subject: a\r\n\r\n..hi\r\nbye\r\n.\r\n".to_vec());
+        assert_eq!(wire_data(b"a\r\nb"), b"a\r\nb\r\n.\r\n".to_vec());
+        assert_eq!(wire_data(b"caf\xe9\n"), b"caf\xe9\r\n.\r\n".to_vec());
+        assert_eq!(wire_data(b""), b".\r\n".to_vec());
+    }
+
+    #[test]
+    fn test_read_reply() {
+        let mut input = &b"250-first\r\n250 last\r\n354 next\r\n"[..];
+        assert_eq!(read_reply(&mut input).unwrap(), (250, "250-first 250 last".to_string()));
+        assert_eq!(read_reply(&mut input).unwrap().0, 354);
+        let mut input = &b"hello\r\n"[..];
+        assert!(matches!(read_reply(&mut input), Err(RelayError::Temporary(_))));
+    }
+
+    #[test]
+    fn test_drain() {
+        let (addr, handle) = sink(&["nobody@"]);
+        let dir = TempDir::new("relay-drain");
+        let message = SpooledMessage {
+            from: "will@xqz.ca".to_string(),
+            to: vec!["a@example.com".to_string(), "nobody@example.com".to_string()],
+            data: b"Subject: hi\n\n.leading dot\ncaf\xe9\n".to_vec(),
+        };
+        enqueue(&dir, &message).unwrap();
+        drain(&dir, &addr, "mail.xqz.ca");
+        let (commands, data) = handle.join().unwrap();
+        assert_eq!(
+            commands,
+            vec![
+                "EHLO mail.xqz.ca",
+                "MAIL FROM:<will@xqz.ca> BODY=8BITMIME",
+                "RCPT TO:<a@example.com>",
+                "RCPT TO:<nobody@example.com>",
+                "DATA",
+                "QUIT"
+            ]
+        );
+        assert_eq!(data, b"Subject: hi\r\n\r\n..leading dot\r\ncaf\xe9\r\n.\r\n".to_vec());
+        assert!(spool::pending(&dir).unwrap().is_empty());
+    }
+
+    #[test]
+    fn test_drain_failures() {
+        // A message no recipient of which is accepted is dropped
+        let (addr, handle) = sink(&["nobody@"]);
+        let dir = TempDir::new("relay-refused");
+        let message = SpooledMessage {
+            from: String::new(),
+            to: vec!["nobody@example.com".to_string()],
+            data: b"body\n".to_vec(),
+        };
+        enqueue(&dir, &message).unwrap();
+        drain(&dir, &addr, "mail.xqz.ca");
+        let (commands, _) = handle.join().unwrap();
+        assert!(!commands.iter().any(|c| c == "DATA"));
+        assert!(spool::pending(&dir).unwrap().is_empty());
+
+        // An unreachable smarthost leaves it in the spool
+        enqueue(&dir, &message).unwrap();
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let addr = listener.local_addr().unwrap().to_string();
+        drop(listener);
+        drain(&dir, &addr, "mail.xqz.ca");
+        let pending = spool::pending(&dir).unwrap();
+        assert_eq!(pending.len(), 1);
+        assert_eq!(spool::load(&pending[0]).unwrap(), message);
+    }
+}
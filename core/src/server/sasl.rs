@@ -0,0 +1,57 @@
+//! SASL authentication (RFC 4422) for the protocols which use it.
+
+use mime::encoding::base64_decode;
+
+use crate::server::user::User;
+use crate::server::Server;
+
+/// The mechanisms offered to clients, separated by spaces.
+pub static MECHANISMS: &str = "PLAIN";
+
+/// Split up the client response of the PLAIN mechanism (RFC 4616): the
+/// authorization identity, the authentication identity and the password,
+/// separated by NULs.
+fn decode_plain(response: &str) -> Option<(String, String, String)> {
+    let decoded = String::from_utf8(base64_decode(response.trim().as_bytes())).ok()?;
+    let mut parts = decoded.split('\0');
+    let authzid = parts.next()?.to_string();
+    let authcid = parts.next()?.to_string();
+    let password = parts.next()?.to_string();
+    if parts.next().is_some() || authcid.is_empty() {
+        return None;
+    }
+    Some((authzid, authcid, password))
+}
+
+/// Authenticate a user with the given mechanism and base64-encoded client
+/// response. Acting as another user is not supported, so any authorization
+/// identity must be that of the user logging in.
+pub fn login<'a>(serv: &'a Server, mechanism: &str, response: &str) -> Option<&'a User> {
+    if !mechanism.eq_ignore_ascii_case("PLAIN") {
+        return None;
+    }
+    let (authzid, authcid, password) = decode_plain(response)?;
+    if !authzid.is_empty() && authzid != authcid {
+        return None;
+    }
+    serv.login(authcid, password)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::decode_plain;
+    use mime::encoding::base64_encode;
+
+    #[test]
+    fn test_decode_plain() {
+        let response = base64_encode(b"\0will@xqz.ca\x0054321");
+        assert_eq!(
+            decode_plain(&response),
+            Some((String::new(), "will@xqz.ca".to_string(), "54321".to_string()))
+        );
+        let response = base64_encode(b"will@xqz.ca\0will@xqz.ca\0pw");
+        assert_eq!(decode_plain(&response).unwrap().0, "will@xqz.ca");
+        assert_eq!(decode_plain(&base64_encode(b"\0\0pw")), None);
+        assert_eq!(decode_plain(&base64_encode(b"no separators")), None);
+    }
+}
@@ -1,7 +1,9 @@
 use self::auth::AuthData;
 use crate::error::ImapResult;
+use crate::layout::Layout;
+use crate::quota::Limits;
 use serde_json;
-use std::collections::HashMap;
+use std::collections::{BTreeMap, HashMap};
 use std::fs::File;
 use std::io::{Read, Write};
 use std::path::Path;
@@ -23,6 +25,30 @@ pub struct User {
     pub auth_data: AuthData,
     /// The root directory in which the user's mail is stored.
     pub maildir: String,
+    /// Other addresses the user may send mail from.
+    #[serde(default)]
+    pub aliases: Vec<Email>,
+    /// The mailbox with each special use, overriding the configuration.
+    #[serde(default)]
+    pub special_use: BTreeMap<String, String>,
+    /// How the folders of the maildir are laid out, overriding the
+    /// configuration.
+    #[serde(default)]
+    pub layout: Option<Layout>,
+    /// The most storage, in bytes, and messages the maildir may hold.
+    #[serde(default)]
+    pub quota: Limits,
+    /// Whether the user's folders keep a full-text index, overriding the
+    /// configuration.
+    #[serde(default)]
+    pub full_text_index: Option<bool>,
+    /// Whether the user may see and set the quotas of every user.
+    #[serde(default)]
+    pub admin: bool,
+    /// The secret shared with POP3 clients which log in with APOP. It is
+    /// kept in the clear, as APOP digests it; without one APOP is refused.
+    #[serde(default)]
+    pub apop_secret: Option<String>,
 }
 
 impl User {
@@ -33,6 +59,13 @@ impl User {
             email: email,
             auth_data: AuthData::new(password),
             maildir: maildir,
+            aliases: Vec::new(),
+            special_use: BTreeMap::new(),
+            layout: None,
+            quota: Limits::default(),
+            full_text_index: None,
+            admin: false,
+            apop_secret: None,
         }
     }
 }
@@ -0,0 +1,20 @@
+use std::result::Result as StdResult;
+use thiserror::Error;
+
+/// A convenient alias type for results for `sieve`.
+pub type Result<T> = StdResult<T, Error>;
+
+/// Represents errors found while parsing or validating a Sieve script.
+#[derive(Debug, PartialEq, Error)]
+#[error("line {line}: {message}")]
+pub struct Error {
+    /// The line of the script on which the error was found.
+    pub line: usize,
+    pub message: String,
+}
+
+impl Error {
+    pub fn new(line: usize, message: String) -> Error {
+        Error { line, message }
+    }
+}
@@ -0,0 +1,944 @@
+use std::collections::HashSet;
+
+use super::error::{Error, Result};
+use super::lexer::{tokenize, Token};
+
+/// The extensions which may be named in `require`.
+pub static CAPABILITIES: &[&str] = &[
+    "fileinto",
+    "envelope",
+    "imap4flags",
+    "body",
+    "variables",
+    "reject",
+    "vacation",
+    "comparator-i;octet",
+    "comparator-i;ascii-casemap",
+];
+
+/* Generic syntax, RFC 5228 section 8.2 */
+
+#[derive(Clone, Debug, PartialEq)]
+enum Argument {
+    Tag(String),
+    Number(u64),
+    String(String),
+    StringList(Vec<String>),
+}
+
+#[derive(Debug)]
+struct GenericTest {
+    name: String,
+    arguments: Vec<Argument>,
+    tests: Vec<GenericTest>,
+    line: usize,
+}
+
+#[derive(Debug)]
+struct GenericCommand {
+    name: String,
+    arguments: Vec<Argument>,
+    tests: Vec<GenericTest>,
+    block: Option<Vec<GenericCommand>>,
+    line: usize,
+}
+
+struct Parser {
+    tokens: Vec<(Token, usize)>,
+    pos: usize,
+    depth: usize,
+}
+
+// Blocks and tests nested deeper than this are refused.
+const MAX_NESTING: usize = 32;
+
+impl Parser {
+    fn peek(&self) -> Option<&Token> {
+        self.tokens.get(self.pos).map(|(token, _)| token)
+    }
+
+    fn line(&self) -> usize {
+        match self.tokens.get(self.pos) {
+            Some((_, line)) => *line,
+            None => self.tokens.last().map(|(_, line)| *line).unwrap_or(1),
+        }
+    }
+
+    fn next(&mut self) -> Option<Token> {
+        let token = self.tokens.get(self.pos).map(|(token, _)| token.clone());
+        self.pos += 1;
+        token
+    }
+
+    fn error<T>(&self, message: &str) -> Result<T> {
+        Err(Error::new(self.line(), message.to_string()))
+    }
+
+    fn expect(&mut self, expected: Token, message: &str) -> Result<()> {
+        if self.peek() == Some(&expected) {
+            self.pos += 1;
+            Ok(())
+        } else {
+            self.error(message)
+        }
+    }
+
+    fn commands(&mut self) -> Result<Vec<GenericCommand>> {
+        let mut commands = Vec::new();
+        while let Some(token) = self.peek() {
+            if *token == Token::RightBrace {
+                break;
+            }
+            commands.push(self.command()?);
+        }
+        Ok(commands)
+    }
+
+    fn command(&mut self) -> Result<GenericCommand> {
+        let line = self.line();
+        let name = match self.next() {
+            Some(Token::Identifier(name)) => name,
+            _ => return Err(Error::new(line, "expected a command".to_string())),
+        };
+        let (arguments, tests) = self.arguments()?;
+        let block = match self.next() {
+            Some(Token::Semicolon) => None,
+            Some(Token::LeftBrace) => {
+                self.depth += 1;
+                if self.depth > MAX_NESTING {
+                    return self.error("blocks nested too deeply");
+                }
+                let block = self.commands()?;
+                self.depth -= 1;
+                self.expect(Token::RightBrace, "expected '}'")?;
+                Some(block)
+            }
+            _ => {
+                self.pos -= 1;
+                return self.error("expected ';' or a block");
+            }
+        };
+        Ok(GenericCommand {
+            name,
+            arguments,
+            tests,
+            block,
+            line,
+        })
+    }
+
+    fn arguments(&mut self) -> Result<(Vec<Argument>, Vec<GenericTest>)> {
+        let mut arguments = Vec::new();
+        loop {
+            match self.peek() {
+                Some(Token::Tag(_)) | Some(Token::Number(_)) | Some(Token::String(_)) => {
+                    arguments.push(match self.next() {
+                        Some(Token::Tag(tag)) => Argument::Tag(tag),
+                        Some(Token::Number(num)) => Argument::Number(num),
+                        Some(Token::String(s)) => Argument::String(s),
+                        _ => unreachable!(),
+                    });
+                }
+                Some(Token::LeftBracket) => {
+                    self.pos += 1;
+                    arguments.push(Argument::StringList(self.string_list()?));
+                }
+                _ => break,
+            }
+        }
+        let tests = match self.peek() {
+            Some(Token::Identifier(_)) => vec![self.test()?],
+            Some(Token::LeftParen) => {
+                self.pos += 1;
+                let mut tests = vec![self.test()?];
+                while self.peek() == Some(&Token::Comma) {
+                    self.pos += 1;
+                    tests.push(self.test()?);
+                }
+                self.expect(Token::RightParen, "expected ')'")?;
+                tests
+            }
+            _ => Vec::new(),
+        };
+        Ok((arguments, tests))
+    }
+
+    fn string_list(&mut self) -> Result<Vec<String>> {
+        let mut strings = Vec::new();
+        loop {
+            match self.next() {
+                Some(Token::String(s)) => strings.push(s),
+                _ => {
+                    self.pos -= 1;
+                    return self.error("expected a string");
+                }
+            }
+            match self.next() {
+                Some(Token::Comma) => continue,
+                Some(Token::RightBracket) => return Ok(strings),
+                _ => {
+                    self.pos -= 1;
+                    return self.error("expected ',' or ']'");
+                }
+            }
+        }
+    }
+
+    fn test(&mut self) -> Result<GenericTest> {
+        let line = self.line();
+        let name = match self.next() {
+            Some(Token::Identifier(name)) => name,
+            _ => return Err(Error::new(line, "expected a test".to_string())),
+        };
+        self.depth += 1;
+        if self.depth > MAX_NESTING {
+            return self.error("tests nested too deeply");
+        }
+        let (arguments, tests) = self.arguments()?;
+        self.depth -= 1;
+        Ok(GenericTest {
+            name,
+            arguments,
+            tests,
+            line,
+        })
+    }
+}
+
+/* Validated syntax tree */
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Comparator {
+    Octet,
+    AsciiCasemap,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum MatchType {
+    Is,
+    Contains,
+    Matches,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct Matcher {
+    pub comparator: Comparator,
+    pub match_type: MatchType,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum AddressPart {
+    All,
+    LocalPart,
+    Domain,
+}
+
+#[derive(Clone, Debug, PartialEq)]
+pub enum BodyTransform {
+    Raw,
+    Text,
+    Content(Vec<String>),
+}
+
+/// Modifiers of the `set` command, RFC 5229 section 4.1.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Modifier {
+    Lower,
+    Upper,
+    LowerFirst,
+    UpperFirst,
+    QuoteWildcard,
+    Length,
+}
+
+impl Modifier {
+    /// Modifiers are applied from the highest precedence to the lowest.
+    pub fn precedence(self) -> u8 {
+        match self {
+            Modifier::Lower | Modifier::Upper => 40,
+            Modifier::LowerFirst | Modifier::UpperFirst => 30,
+            Modifier::QuoteWildcard => 20,
+            Modifier::Length => 10,
+        }
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum FlagAction {
+    Set,
+    Add,
+    Remove,
+}
+
+#[derive(Clone, Debug, PartialEq)]
+pub struct Vacation {
+    pub days: Option<u64>,
+    pub subject: Option<String>,
+    pub from: Option<String>,
+    pub addresses: Vec<String>,
+    pub mime: bool,
+    pub handle: Option<String>,
+    pub reason: String,
+}
+
+#[derive(Clone, Debug, PartialEq)]
+pub enum Test {
+    True,
+    False,
+    Not(Box<Test>),
+    AllOf(Vec<Test>),
+    AnyOf(Vec<Test>),
+    Exists(Vec<String>),
+    Size { over: bool, limit: u64 },
+    Header { matcher: Matcher, names: Vec<String>, keys: Vec<String> },
+    Address { matcher: Matcher, part: AddressPart, headers: Vec<String>, keys: Vec<String> },
+    Envelope { matcher: Matcher, part: AddressPart, parts: Vec<String>, keys: Vec<String> },
+    Body { matcher: Matcher, transform: BodyTransform, keys: Vec<String> },
+    String { matcher: Matcher, sources: Vec<String>, keys: Vec<String> },
+    HasFlag { matcher: Matcher, variables: Vec<String>, flags: Vec<String> },
+}
+
+#[derive(Clone, Debug, PartialEq)]
+pub enum Command {
+    /// An if/elsif chain with an optional else block.
+    If(Vec<(Test, Vec<Command>)>, Option<Vec<Command>>),
+    Stop,
+    Keep { flags: Option<Vec<String>> },
+    Discard,
+    FileInto { mailbox: String, flags: Option<Vec<String>> },
+    Redirect(String),
+    Reject(String),
+    Flag { action: FlagAction, variable: Option<String>, flags: Vec<String> },
+    Set { modifiers: Vec<Modifier>, name: String, value: String },
+    Vacation(Vacation),
+}
+
+/// A parsed and validated Sieve script.
+#[derive(Clone, Debug, PartialEq)]
+pub struct Script {
+    pub capabilities: HashSet<String>,
+    pub commands: Vec<Command>,
+}
+
+/// The arguments of a command or test, split into tagged arguments (with
+/// their values) and positional arguments.
+struct Arguments {
+    tags: Vec<(String, Option<Argument>)>,
+    positional: Vec<Argument>,
+    line: usize,
+}
+
+impl Arguments {
+    /// Split up `arguments`; the tags listed in `valued` take the argument
+    /// that follows them as their value.
+    fn new(arguments: Vec<Argument>, valued: &[&str], line: usize) -> Result<Arguments> {
+        let mut tags = Vec::new();
+        let mut positional = Vec::new();
+        let mut iter = arguments.into_iter();
+        while let Some(argument) = iter.next() {
+            match argument {
+                Argument::Tag(tag) => {
+                    if !positional.is_empty() {
+                        return Err(Error::new(line, format!("tag :{} after positional arguments", tag)));
+                    }
+                    let value = if valued.contains(&&tag[..]) {
+                        match iter.next() {
+                            Some(value) => Some(value),
+                            None => return Err(Error::new(line, format!(":{} needs a value", tag))),
+                        }
+                    } else {
+                        None
+                    };
+                    tags.push((tag, value));
+                }
+                argument => positional.push(argument),
+            }
+        }
+        Ok(Arguments { tags, positional, line })
+    }
+
+    fn error<T>(&self, message: String) -> Result<T> {
+        Err(Error::new(self.line, message))
+    }
+
+    /// Take the tag, if it was given, with its value.
+    fn take_tag(&mut self, name: &str) -> Option<Option<Argument>> {
+        let index = self.tags.iter().position(|(tag, _)| tag == name)?;
+        Some(self.tags.remove(index).1)
+    }
+
+    fn take_string_tag(&mut self, name: &str) -> Result<Option<String>> {
+        match self.take_tag(name) {
+            None => Ok(None),
+            Some(Some(Argument::String(s))) => Ok(Some(s)),
+            Some(_) => self.error(format!(":{} needs a string", name)),
+        }
+    }
+
+    fn take_list_tag(&mut self, name: &str) -> Result<Option<Vec<String>>> {
+        match self.take_tag(name) {
+            None => Ok(None),
+            Some(Some(Argument::String(s))) => Ok(Some(vec![s])),
+            Some(Some(Argument::StringList(list))) => Ok(Some(list)),
+            Some(_) => self.error(format!(":{} needs a string list", name)),
+        }
+    }
+
+    fn take_number_tag(&mut self, name: &str) -> Result<Option<u64>> {
+        match self.take_tag(name) {
+            None => Ok(None),
+            Some(Some(Argument::Number(num))) => Ok(Some(num)),
+            Some(_) => self.error(format!(":{} needs a number", name)),
+        }
+    }
+
+    /// Take whichever of the mutually exclusive tags was given.
+    fn take_one_of(&mut self, names: &[&str]) -> Result<Option<String>> {
+        let mut found = None;
+        for name in names {
+            if self.take_tag(name).is_some() {
+                if found.is_some() {
+                    return self.error(format!("conflicting tag :{}", name));
+                }
+                found = Some(name.to_string());
+            }
+        }
+        Ok(found)
+    }
+
+    fn matcher(&mut self) -> Result<Matcher> {
+        let comparator = match self.take_string_tag("comparator")? {
+            None => Comparator::AsciiCasemap,
+            Some(name) => match &name.to_ascii_lowercase()[..] {
+                "i;ascii-casemap" => Comparator::AsciiCasemap,
+                "i;octet" => Comparator::Octet,
+                _ => return self.error(format!("unsupported comparator \"{}\"", name)),
+            },
+        };
+        let match_type = match self.take_one_of(&["is", "contains", "matches"])? {
+            Some(ref name) if name == "contains" => MatchType::Contains,
+            Some(ref name) if name == "matches" => MatchType::Matches,
+            _ => MatchType::Is,
+        };
+        Ok(Matcher { comparator, match_type })
+    }
+
+    fn address_part(&mut self) -> Result<AddressPart> {
+        Ok(match self.take_one_of(&["all", "localpart", "domain"])? {
+            Some(ref name) if name == "localpart" => AddressPart::LocalPart,
+            Some(ref name) if name == "domain" => AddressPart::Domain,
+            _ => AddressPart::All,
+        })
+    }
+
+    /// Take the next positional argument as a string list.
+    fn string_list(&mut self) -> Result<Vec<String>> {
+        if self.positional.is_empty() {
+            return self.error("missing argument".to_string());
+        }
+        match self.positional.remove(0) {
+            Argument::String(s) => Ok(vec![s]),
+            Argument::StringList(list) => Ok(list),
+            _ => self.error("expected a string list".to_string()),
+        }
+    }
+
+    /// Take the next positional argument as a single string.
+    fn string(&mut self) -> Result<String> {
+        if self.positional.is_empty() {
+            return self.error("missing argument".to_string());
+        }
+        match self.positional.remove(0) {
+            Argument::String(s) => Ok(s),
+            _ => self.error("expected a string".to_string()),
+        }
+    }
+
+    /// Make sure every argument has been used up.
+    fn finish(self) -> Result<()> {
+        if let Some((tag, _)) = self.tags.first() {
+            return Err(Error::new(self.line, format!("unexpected tag :{}", tag)));
+        }
+        if !self.positional.is_empty() {
+            return Err(Error::new(self.line, "too many arguments".to_string()));
+        }
+        Ok(())
+    }
+}
+
+/// Turns the generic syntax tree into the validated one, checking that
+/// every extension used has been required.
+struct Validator {
+    capabilities: HashSet<String>,
+}
+
+impl Validator {
+    fn require(&self, capability: &str, line: usize) -> Result<()> {
+        if self.capabilities.contains(capability) {
+            Ok(())
+        } else {
+            Err(Error::new(line, format!("missing require \"{}\"", capability)))
+        }
+    }
+
+    fn commands(&self, commands: Vec<GenericCommand>) -> Result<Vec<Command>> {
+        let mut res = Vec::new();
+        let mut iter = commands.into_iter().peekable();
+        while let Some(command) = iter.next() {
+            match &command.name[..] {
+                "require" => {
+                    return Err(Error::new(command.line, "require must come before other commands".to_string()))
+                }
+                "if" => {
+                    let mut branches = vec![self.branch(command)?];
+                    let mut otherwise = None;
+                    while let Some(next) = iter.peek() {
+                        match &next.name[..] {
+                            "elsif" => {
+                                let next = iter.next().unwrap();
+                                branches.push(self.branch(next)?);
+                            }
+                            "else" => {
+                                let next = iter.next().unwrap();
+                                if !next.arguments.is_empty() || !next.tests.is_empty() {
+                                    return Err(Error::new(next.line, "else takes no arguments".to_string()));
+                                }
+                                match next.block {
+                                    Some(block) => otherwise = Some(self.commands(block)?),
+                                    None => return Err(Error::new(next.line, "else needs a block".to_string())),
+                                }
+                                break;
+                            }
+                            _ => break,
+                        }
+                    }
+                    res.push(Command::If(branches, otherwise));
+                }
+                "elsif" | "else" => {
+                    return Err(Error::new(command.line, format!("{} without if", command.name)))
+                }
+                _ => res.push(self.command(command)?),
+            }
+        }
+        Ok(res)
+    }
+
+    fn branch(&self, command: GenericCommand) -> Result<(Test, Vec<Command>)> {
+        let line = command.line;
+        if !command.arguments.is_empty() || command.tests.len() != 1 {
+            return Err(Error::new(line, format!("{} needs a single test", command.name)));
+        }
+        let test = self.test(command.tests.into_iter().next().unwrap())?;
+        match command.block {
+            Some(block) => Ok((test, self.commands(block)?)),
+            None => Err(Error::new(line, format!("{} needs a block", command.name))),
+        }
+    }
+
+    fn command(&self, command: GenericCommand) -> Result<Command> {
+        let line = command.line;
+        if command.block.is_some() {
+            return Err(Error::new(line, format!("{} does not take a block", command.name)));
+        }
+        if !command.tests.is_empty() {
+            return Err(Error::new(line, format!("{} does not take a test", command.name)));
+        }
+        let valued: &[&str] = &["flags", "days", "subject", "from", "addresses", "handle"];
+        let mut args = Arguments::new(command.arguments, valued, line)?;
+        let res = match &command.name[..] {
+            "stop" => Command::Stop,
+            "discard" => Command::Discard,
+            "keep" => {
+                let flags = args.take_list_tag("flags")?;
+                if flags.is_some() {
+                    self.require("imap4flags", line)?;
+                }
+                Command::Keep { flags }
+            }
+            "fileinto" => {
+                self.require("fileinto", line)?;
+                let flags = args.take_list_tag("flags")?;
+                if flags.is_some() {
+                    self.require("imap4flags", line)?;
+                }
+                Command::FileInto {
+                    mailbox: args.string()?,
+                    flags,
+                }
+            }
+            "redirect" => Command::Redirect(args.string()?),
+            "reject" => {
+                self.require("reject", line)?;
+                Command::Reject(args.string()?)
+            }
+            "setflag" | "addflag" | "removeflag" => {
+                self.require("imap4flags", line)?;
+                let action = match &command.name[..] {
+                    "setflag" => FlagAction::Set,
+                    "addflag" => FlagAction::Add,
+                    _ => FlagAction::Remove,
+                };
+                let first = args.string_list()?;
+                let (variable, flags) = if args.positional.is_empty() {
+                    (None, first)
+                } else {
+                    if first.len() != 1 {
+                        return Err(Error::new(line, "expected a variable name".to_string()));
+                    }
+                    self.require("variables", line)?;
+                    (first.into_iter().next(), args.string_list()?)
+                };
+                Command::Flag {
+                    action,
+                    variable: variable.map(|v| v.to_ascii_lowercase()),
+                    flags,
+                }
+            }
+            "set" => {
+                self.require("variables", line)?;
+                let mut modifiers = Vec::new();
+                for (name, modifier) in &[
+                    ("lower", Modifier::Lower),
+                    ("upper", Modifier::Upper),
+                    ("lowerfirst", Modifier::LowerFirst),
+                    ("upperfirst", Modifier::UpperFirst),
+                    ("quotewildcard", Modifier::QuoteWildcard),
+                    ("length", Modifier::Length),
+                ] {
+                    if args.take_tag(name).is_some() {
+                        if modifiers.iter().any(|m: &Modifier| m.precedence() == modifier.precedence()) {
+                            return Err(Error::new(line, format!("conflicting modifier :{}", name)));
+                        }
+                        modifiers.push(*modifier);
+                    }
+                }
+                modifiers.sort_by_key(|m| std::cmp::Reverse(m.precedence()));
+                let name = args.string()?.to_ascii_lowercase();
+                if !valid_variable_name(&name) {
+                    return Err(Error::new(line, format!("invalid variable name \"{}\"", name)));
+                }
+                Command::Set {
+                    modifiers,
+                    name,
+                    value: args.string()?,
+                }
+            }
+            "vacation" => {
+                self.require("vacation", line)?;
+                Command::Vacation(Vacation {
+                    days: args.take_number_tag("days")?,
+                    subject: args.take_string_tag("subject")?,
+                    from: args.take_string_tag("from")?,
+                    addresses: args.take_list_tag("addresses")?.unwrap_or_default(),
+                    mime: args.take_tag("mime").is_some(),
+                    handle: args.take_string_tag("handle")?,
+                    reason: args.string()?,
+                })
+            }
+            name => return Err(Error::new(line, format!("unknown command \"{}\"", name))),
+        };
+        args.finish()?;
+        Ok(res)
+    }
+
+    fn test(&self, test: GenericTest) -> Result<Test> {
+        let line = test.line;
+        match &test.name[..] {
+            "not" | "allof" | "anyof" => {
+                if !test.arguments.is_empty() || test.tests.is_empty() {
+                    return Err(Error::new(line, format!("{} needs tests", test.name)));
+                }
+                let name = test.name;
+                let mut tests = Vec::new();
+                for sub in test.tests {
+                    tests.push(self.test(sub)?);
+                }
+                return Ok(match &name[..] {
+                    "not" => {
+                        if tests.len() != 1 {
+                            return Err(Error::new(line, "not needs a single test".to_string()));
+                        }
+                        Test::Not(Box::new(tests.remove(0)))
+                    }
+                    "allof" => Test::AllOf(tests),
+                    _ => Test::AnyOf(tests),
+                });
+            }
+            _ => {
+                if !test.tests.is_empty() {
+                    return Err(Error::new(line, format!("{} does not take tests", test.name)));
+                }
+            }
+        }
+        let valued: &[&str] = &["comparator", "over", "under", "content"];
+        let mut args = Arguments::new(test.arguments, valued, line)?;
+        let res = match &test.name[..] {
+            "true" => Test::True,
+            "false" => Test::False,
+            "exists" => Test::Exists(args.string_list()?),
+            "size" => {
+                let over = args.take_number_tag("over")?;
+                let under = args.take_number_tag("under")?;
+                match (over, under) {
+                    (Some(limit), None) => Test::Size { over: true, limit },
+                    (None, Some(limit)) => Test::Size { over: false, limit },
+                    _ => return Err(Error::new(line, "size needs one of :over or :under".to_string())),
+                }
+            }
+            "header" => Test::Header {
+                matcher: args.matcher()?,
+                names: args.string_list()?,
+                keys: args.string_list()?,
+            },
+            "address" => Test::Address {
+                matcher: args.matcher()?,
+                part: args.address_part()?,
+                headers: args.string_list()?,
+                keys: args.string_list()?,
+            },
+            "envelope" => {
+                self.require("envelope", line)?;
+                let matcher = args.matcher()?;
+                let part = args.address_part()?;
+                let parts: Vec<String> = args.string_list()?.iter().map(|p| p.to_ascii_lowercase()).collect();
+                for part in &parts {
+                    if part != "from" && part != "to" {
+                        return Err(Error::new(line, format!("unsupported envelope part \"{}\"", part)));
+                    }
+                }
+                Test::Envelope {
+                    matcher,
+                    part,
+                    parts,
+                    keys: args.string_list()?,
+                }
+            }
+            "body" => {
+                self.require("body", line)?;
+                let matcher = args.matcher()?;
+                let content = args.take_list_tag("content")?;
+                let transform = match (args.take_one_of(&["raw", "text"])?, content) {
+                    (None, Some(types)) => BodyTransform::Content(types),
+                    (Some(ref t), None) if t == "raw" => BodyTransform::Raw,
+                    (_, None) => BodyTransform::Text,
+                    (Some(_), Some(_)) => {
+                        return Err(Error::new(line, "conflicting body transforms".to_string()))
+                    }
+                };
+                Test::Body {
+                    matcher,
+                    transform,
+                    keys: args.string_list()?,
+                }
+            }
+            "string" => {
+                self.require("variables", line)?;
+                Test::String {
+                    matcher: args.matcher()?,
+                    sources: args.string_list()?,
+                    keys: args.string_list()?,
+                }
+            }
+            "hasflag" => {
+                self.require("imap4flags", line)?;
+                let matcher = args.matcher()?;
+                let first = args.string_list()?;
+                let (variables, flags) = if args.positional.is_empty() {
+                    (Vec::new(), first)
+                } else {
+                    self.require("variables", line)?;
+                    (first, args.string_list()?)
+                };
+                Test::HasFlag {
+                    matcher,
+                    variables: variables.iter().map(|v| v.to_ascii_lowercase()).collect(),
+                    flags,
+                }
+            }
+            name => return Err(Error::new(line, format!("unknown test \"{}\"", name))),
+        };
+        args.finish()?;
+        Ok(res)
+    }
+}
+
+/// Whether `name` may be assigned with `set`: identifiers, optionally
+/// dotted, as RFC 5229 section 3 describes.
+pub fn valid_variable_name(name: &str) -> bool {
+    !name.is_empty()
+        && name.split('.').all(|part| {
+            let mut chars = part.chars();
+            match chars.next() {
+                Some(c) if c.is_ascii_alphabetic() || c == '_' => {}
+                _ => return false,
+            }
+            chars.all(|c| c.is_ascii_alphanumeric() || c == '_')
+        })
+}
+
+/// Parse and validate a Sieve script.
+pub fn parse(source: &str) -> Result<Script> {
+    let mut parser = Parser {
+        tokens: tokenize(source)?,
+        pos: 0,
+        depth: 0,
+    };
+    let mut commands = parser.commands()?;
+    if parser.pos < parser.tokens.len() {
+        return parser.error("unexpected '}'");
+    }
+
+    // The require commands must come first.
+    let mut capabilities = HashSet::new();
+    while !commands.is_empty() && commands[0].name == "require" {
+        let command = commands.remove(0);
+        let line = command.line;
+        if command.block.is_some() || !command.tests.is_empty() {
+            return Err(Error::new(line, "invalid require".to_string()));
+        }
+        let mut args = Arguments::new(command.arguments, &[], line)?;
+        for capability in args.string_list()? {
+            let capability = capability.to_ascii_lowercase();
+            if !CAPABILITIES.contains(&&capability[..]) {
+                return Err(Error::new(line, format!("unsupported extension \"{}\"", capability)));
+            }
+            capabilities.insert(capability);
+        }
+        args.finish()?;
+    }
+
+    let validator = Validator { capabilities };
+    let commands = validator.commands(commands)?;
+    Ok(Script {
+        capabilities: validator.capabilities,
+        commands,
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse() {
+        let script = parse(
+            "require [\"fileinto\", \"imap4flags\"];\n\
+             if header :contains \"subject\" \"sale\" {\n\
+                 fileinto :flags \"\\\\Seen\" \"Junk\";\n\
+             } elsif not exists \"date\" {\n\
+                 discard; stop;\n\
+             } else {\n\
+                 keep;\n\
+             }",
+        )
+        .unwrap();
+        assert!(script.capabilities.contains("fileinto"));
+        assert_eq!(
+            script.commands,
+            vec![Command::If(
+                vec![
+                    (
+                        Test::Header {
+                            matcher: Matcher {
+                                comparator: Comparator::AsciiCasemap,
+                                match_type: MatchType::Contains
+                            },
+                            names: vec!["subject".to_string()],
+                            keys: vec!["sale".to_string()],
+                        },
+                        vec![Command::FileInto {
+                            mailbox: "Junk".to_string(),
+                            flags: Some(vec!["\\Seen".to_string()]),
+                        }]
+                    ),
+                    (
+                        Test::Not(Box::new(Test::Exists(vec!["date".to_string()]))),
+                        vec![Command::Discard, Command::Stop]
+                    ),
+                ],
+                Some(vec![Command::Keep { flags: None }])
+            )]
+        );
+    }
+
+    #[test]
+    fn test_parse_tests() {
+        let script = parse(
+            "require [\"envelope\", \"body\", \"variables\"];\n\
+             if allof (size :over 1M, address :domain :is [\"from\", \"sender\"] \"example.com\",\n\
+                       envelope :localpart :matches \"to\" \"*+*\", body :raw :contains \"x\",\n\
+                       string :comparator \"i;octet\" \"${1}\" \"a\") { keep; }",
+        )
+        .unwrap();
+        match script.commands[0] {
+            Command::If(ref branches, None) => match branches[0].0 {
+                Test::AllOf(ref tests) => {
+                    assert_eq!(tests[0], Test::Size { over: true, limit: 1 << 20 });
+                    assert_eq!(
+                        tests[1],
+                        Test::Address {
+                            matcher: Matcher {
+                                comparator: Comparator::AsciiCasemap,
+                                match_type: MatchType::Is
+                            },
+                            part: AddressPart::Domain,
+                            headers: vec!["from".to_string(), "sender".to_string()],
+                            keys: vec!["example.com".to_string()],
+                        }
+                    );
+                    assert_eq!(tests.len(), 5);
+                }
+                _ => panic!("expected allof"),
+            },
+            _ => panic!("expected if"),
+        }
+    }
+
+    #[test]
+    fn test_parse_set_and_vacation() {
+        let script = parse(
+            "require [\"variables\", \"vacation\"];\n\
+             set :upperfirst :lower \"name\" \"VALUE\";\n\
+             vacation :days 3 :subject \"Away\" :addresses [\"a@b.c\"] :mime \"reason\";",
+        )
+        .unwrap();
+        assert_eq!(
+            script.commands[0],
+            Command::Set {
+                modifiers: vec![Modifier::Lower, Modifier::UpperFirst],
+                name: "name".to_string(),
+                value: "VALUE".to_string(),
+            }
+        );
+        assert_eq!(
+            script.commands[1],
+            Command::Vacation(Vacation {
+                days: Some(3),
+                subject: Some("Away".to_string()),
+                from: None,
+                addresses: vec!["a@b.c".to_string()],
+                mime: true,
+                handle: None,
+                reason: "reason".to_string(),
+            })
+        );
+    }
+
+    #[test]
+    fn test_parse_errors() {
+        assert_eq!(parse("fileinto \"x\";").unwrap_err().message, "missing require \"fileinto\"");
+        assert_eq!(parse("keep;\nrequire \"fileinto\";").unwrap_err().line, 2);
+        assert!(parse("require \"unknown\";").is_err());
+        assert!(parse("if true keep;").is_err());
+        assert!(parse("elsif true { keep; }").is_err());
+        assert!(parse("keep").is_err());
+        assert!(parse("keep; }").is_err());
+        assert!(parse("bogus;").is_err());
+        assert!(parse("if header :is :contains \"a\" \"b\" { keep; }").is_err());
+        assert!(parse("if size :over \"a\" { keep; }").is_err());
+        assert!(parse("redirect;").is_err());
+        assert!(parse("redirect \"a@b.c\" \"d\";").is_err());
+        assert!(parse(&"if not ".repeat(100)).is_err());
+        assert!(parse("").unwrap().commands.is_empty());
+    }
+}
@@ -0,0 +1,701 @@
+use std::collections::{HashMap, HashSet};
+
+use mime::address::parse_address_list;
+use mime::Message;
+
+use super::grammar::{
+    AddressPart, BodyTransform, Command, Comparator, FlagAction, MatchType, Matcher, Modifier, Script, Test,
+    Vacation,
+};
+
+// The number of redirects a single script may perform.
+const MAX_REDIRECTS: usize = 4;
+
+/// What is to be done with a message after running a script over it.
+#[derive(Clone, Debug, PartialEq)]
+pub enum Action {
+    /// Store the message where it would have gone without a script.
+    Keep { flags: Vec<String> },
+    /// Store the message in the named mailbox.
+    FileInto { mailbox: String, flags: Vec<String> },
+    /// Send the message on to another address.
+    Redirect(String),
+    /// Refuse the message, giving the reason to the sender.
+    Reject(String),
+    /// Answer the sender, subject to the checks of RFC 5230.
+    Vacation(Vacation),
+}
+
+/// The message a script is run over and its envelope.
+pub struct Context<'a> {
+    pub message: &'a Message,
+    pub size: usize,
+    /// The reverse path, empty for the null sender.
+    pub envelope_from: String,
+    pub envelope_to: String,
+}
+
+struct Interpreter<'a> {
+    context: &'a Context<'a>,
+    variables_enabled: bool,
+    variables: HashMap<String, String>,
+    // ${0} to ${9}, set by the last successful :matches test
+    match_variables: Vec<String>,
+    // The flags of the imap4flags internal variable
+    flags: Vec<String>,
+    actions: Vec<Action>,
+    implicit_keep: bool,
+}
+
+/// The result of running a block of commands.
+enum Flow {
+    Continue,
+    Stop,
+}
+
+/// Add the flags in `new` to `flags`, splitting on spaces and ignoring case
+/// when looking for duplicates.
+fn add_flags(flags: &mut Vec<String>, new: &[String]) {
+    for flag in new.iter().flat_map(|f| f.split_whitespace()) {
+        if !flags.iter().any(|f| f.eq_ignore_ascii_case(flag)) {
+            flags.push(flag.to_string());
+        }
+    }
+}
+
+fn remove_flags(flags: &mut Vec<String>, old: &[String]) {
+    for flag in old.iter().flat_map(|f| f.split_whitespace()) {
+        flags.retain(|f| !f.eq_ignore_ascii_case(flag));
+    }
+}
+
+/// A piece of a :matches pattern.
+#[derive(Clone, Copy, PartialEq)]
+enum Glob {
+    Char(char),
+    Any,
+    Star,
+}
+
+fn compile_glob(pattern: &str) -> Vec<Glob> {
+    let mut res = Vec::new();
+    let mut chars = pattern.chars();
+    while let Some(chr) = chars.next() {
+        res.push(match chr {
+            '*' => Glob::Star,
+            '?' => Glob::Any,
+            '\\' => Glob::Char(chars.next().unwrap_or('\\')),
+            chr => Glob::Char(chr),
+        });
+    }
+    res
+}
+
+/// Match `text` against a compiled pattern, returning what each wildcard
+/// matched. Wildcards match as little as possible, as RFC 5229 requires.
+/// Failed positions are remembered so that patterns with many stars cannot
+/// take exponential time.
+fn glob_match(pattern: &[Glob], text: &[char], casemap: bool) -> Option<Vec<String>> {
+    fn step(
+        pattern: &[Glob],
+        text: &[char],
+        pi: usize,
+        ti: usize,
+        casemap: bool,
+        failed: &mut HashSet<(usize, usize)>,
+        captures: &mut Vec<String>,
+    ) -> bool {
+        if failed.contains(&(pi, ti)) {
+            return false;
+        }
+        let matched = match pattern.get(pi) {
+            None => ti == text.len(),
+            Some(Glob::Char(chr)) => {
+                ti < text.len()
+                    && (text[ti] == *chr || (casemap && text[ti].eq_ignore_ascii_case(chr)))
+                    && step(pattern, text, pi + 1, ti + 1, casemap, failed, captures)
+            }
+            Some(Glob::Any) => {
+                ti < text.len() && {
+                    captures.push(text[ti].to_string());
+                    step(pattern, text, pi + 1, ti + 1, casemap, failed, captures) || {
+                        captures.pop();
+                        false
+                    }
+                }
+            }
+            Some(Glob::Star) => {
+                let mut found = false;
+                for end in ti..=text.len() {
+                    captures.push(text[ti..end].iter().collect());
+                    if step(pattern, text, pi + 1, end, casemap, failed, captures) {
+                        found = true;
+                        break;
+                    }
+                    captures.pop();
+                }
+                found
+            }
+        };
+        if !matched {
+            failed.insert((pi, ti));
+        }
+        matched
+    }
+
+    let mut captures = Vec::new();
+    let mut failed = HashSet::new();
+    if step(pattern, text, 0, 0, casemap, &mut failed, &mut captures) {
+        Some(captures)
+    } else {
+        None
+    }
+}
+
+/// Compare a value against a key. For :matches, returns the match
+/// variables on success.
+fn compare(matcher: &Matcher, value: &str, key: &str) -> Option<Vec<String>> {
+    let casemap = matcher.comparator == Comparator::AsciiCasemap;
+    let matched = match matcher.match_type {
+        MatchType::Is => {
+            if casemap {
+                value.eq_ignore_ascii_case(key)
+            } else {
+                value == key
+            }
+        }
+        MatchType::Contains => {
+            if casemap {
+                value.to_ascii_lowercase().contains(&key.to_ascii_lowercase())
+            } else {
+                value.contains(key)
+            }
+        }
+        MatchType::Matches => {
+            let text: Vec<char> = value.chars().collect();
+            return glob_match(&compile_glob(key), &text, casemap).map(|mut captures| {
+                captures.insert(0, value.to_string());
+                captures
+            });
+        }
+    };
+    if matched {
+        Some(Vec::new())
+    } else {
+        None
+    }
+}
+
+/// Split a header field value into the addresses it holds, without any
+/// display names, comments or group names.
+pub fn parse_addresses(value: &str) -> Vec<String> {
+    parse_address_list(value).into_iter().map(|a| a.email).collect()
+}
+
+/// Pick the part of an address a test is about.
+fn address_part(address: &str, part: AddressPart) -> String {
+    let (local_part, domain) = match address.rfind('@') {
+        Some(index) => (&address[..index], &address[index + 1..]),
+        None => (address, ""),
+    };
+    match part {
+        AddressPart::All => address.to_string(),
+        AddressPart::LocalPart => local_part.to_string(),
+        AddressPart::Domain => domain.to_string(),
+    }
+}
+
+/// Apply the modifiers of a `set` command, which are sorted from the
+/// highest precedence to the lowest.
+fn apply_modifiers(modifiers: &[Modifier], value: String) -> String {
+    let mut value = value;
+    for modifier in modifiers {
+        value = match modifier {
+            Modifier::Lower => value.to_lowercase(),
+            Modifier::Upper => value.to_uppercase(),
+            Modifier::LowerFirst | Modifier::UpperFirst => {
+                let mut chars = value.chars();
+                match chars.next() {
+                    Some(first) => {
+                        let first: String = if *modifier == Modifier::LowerFirst {
+                            first.to_lowercase().collect()
+                        } else {
+                            first.to_uppercase().collect()
+                        };
+                        first + chars.as_str()
+                    }
+                    None => value,
+                }
+            }
+            Modifier::QuoteWildcard => {
+                let mut res = String::new();
+                for chr in value.chars() {
+                    if chr == '*' || chr == '?' || chr == '\\' {
+                        res.push('\\');
+                    }
+                    res.push(chr);
+                }
+                res
+            }
+            Modifier::Length => value.chars().count().to_string(),
+        };
+    }
+    value
+}
+
+impl<'a> Interpreter<'a> {
+    /// Substitute the variables referenced in a string, as described in
+    /// RFC 5229 section 3. Unknown variables expand to nothing; anything
+    /// which is not a valid reference is left alone.
+    fn expand(&self, s: &str) -> String {
+        if !self.variables_enabled || !s.contains("${") {
+            return s.to_string();
+        }
+        let mut res = String::new();
+        let mut rest = s;
+        while let Some(start) = rest.find("${") {
+            res.push_str(&rest[..start]);
+            let after = &rest[start + 2..];
+            let value = after.find('}').and_then(|end| {
+                let name = after[..end].to_ascii_lowercase();
+                let value = if !name.is_empty() && name.chars().all(|c| c.is_ascii_digit()) {
+                    let index: usize = name.parse().unwrap_or(usize::MAX);
+                    self.match_variables.get(index).cloned().unwrap_or_default()
+                } else if super::grammar::valid_variable_name(&name) {
+                    self.variables.get(&name).cloned().unwrap_or_default()
+                } else {
+                    return None;
+                };
+                Some((value, end))
+            });
+            match value {
+                Some((value, end)) => {
+                    res.push_str(&value);
+                    rest = &after[end + 1..];
+                }
+                None => {
+                    res.push_str("${");
+                    rest = after;
+                }
+            }
+        }
+        res.push_str(rest);
+        res
+    }
+
+    fn expand_list(&self, list: &[String]) -> Vec<String> {
+        list.iter().map(|s| self.expand(s)).collect()
+    }
+
+    /// Compare every value against every key, remembering the match
+    /// variables of the first success.
+    fn any_match(&mut self, matcher: &Matcher, values: &[String], keys: &[String]) -> bool {
+        let keys = self.expand_list(keys);
+        for value in values {
+            for key in &keys {
+                if let Some(captures) = compare(matcher, value, key) {
+                    if matcher.match_type == MatchType::Matches && self.variables_enabled {
+                        self.match_variables = captures;
+                    }
+                    return true;
+                }
+            }
+        }
+        false
+    }
+
+    fn header_addresses(&self, headers: &[String], part: AddressPart) -> Vec<String> {
+        let mut res = Vec::new();
+        for header in headers {
+            for value in self.context.message.get_header_values(&self.expand(header)) {
+                for address in parse_addresses(&value) {
+                    res.push(address_part(&address, part));
+                }
+            }
+        }
+        res
+    }
+
+    fn test(&mut self, test: &Test) -> bool {
+        match test {
+            Test::True => true,
+            Test::False => false,
+            Test::Not(test) => !self.test(test),
+            Test::AllOf(tests) => tests.iter().all(|t| self.test(t)),
+            Test::AnyOf(tests) => tests.iter().any(|t| self.test(t)),
+            Test::Exists(names) => names
+                .iter()
+                .all(|name| !self.context.message.get_header_values(&self.expand(name)).is_empty()),
+            Test::Size { over, limit } => {
+                let size = self.context.size as u64;
+                if *over {
+                    size > *limit
+                } else {
+                    size < *limit
+                }
+            }
+            Test::Header { matcher, names, keys } => {
+                let mut values = Vec::new();
+                for name in names {
+                    values.extend(self.context.message.get_header_values(&self.expand(name)));
+                }
+                self.any_match(matcher, &values, keys)
+            }
+            Test::Address {
+                matcher,
+                part,
+                headers,
+                keys,
+            } => {
+                let values = self.header_addresses(headers, *part);
+                self.any_match(matcher, &values, keys)
+            }
+            Test::Envelope {
+                matcher,
+                part,
+                parts,
+                keys,
+            } => {
+                let values: Vec<String> = parts
+                    .iter()
+                    .map(|p| {
+                        let address = if p == "from" {
+                            &self.context.envelope_from
+                        } else {
+                            &self.context.envelope_to
+                        };
+                        address_part(address, *part)
+                    })
+                    .collect();
+                self.any_match(matcher, &values, keys)
+            }
+            Test::Body {
+                matcher,
+                transform,
+                keys,
+            } => {
+                let values = match transform {
+                    BodyTransform::Raw => vec![self.context.message.get_raw_body().to_string()],
+                    BodyTransform::Text => self.context.message.get_body_text(&["text".to_string()]),
+                    BodyTransform::Content(types) => {
+                        let types = self.expand_list(types);
+                        // An empty type selects every body part
+                        if types.iter().any(|t| t.is_empty()) {
+                            self.context.message.get_body_text(&[])
+                        } else {
+                            self.context.message.get_body_text(&types)
+                        }
+                    }
+                };
+                self.any_match(matcher, &values, keys)
+            }
+            Test::String { matcher, sources, keys } => {
+                let values = self.expand_list(sources);
+                self.any_match(matcher, &values, keys)
+            }
+            Test::HasFlag {
+                matcher,
+                variables,
+                flags,
+            } => {
+                let mut current = Vec::new();
+                if variables.is_empty() {
+                    current = self.flags.clone();
+                } else {
+                    for variable in variables {
+                        let value = self.variables.get(variable).cloned().unwrap_or_default();
+                        add_flags(&mut current, &[value]);
+                    }
+                }
+                let mut keys = Vec::new();
+                add_flags(&mut keys, &self.expand_list(flags));
+                self.any_match(matcher, &current, &keys)
+            }
+        }
+    }
+
+    /// The flags a message is stored with: those given with :flags, or the
+    /// internal variable's.
+    fn store_flags(&self, flags: &Option<Vec<String>>) -> Vec<String> {
+        match flags {
+            Some(flags) => {
+                let mut res = Vec::new();
+                add_flags(&mut res, &self.expand_list(flags));
+                res
+            }
+            None => self.flags.clone(),
+        }
+    }
+
+    fn push_action(&mut self, action: Action) -> Result<(), String> {
+        let rejecting = self.actions.iter().any(|a| matches!(a, Action::Reject(_)));
+        match action {
+            Action::Reject(_) if !self.actions.is_empty() => {
+                return Err("reject cannot be combined with other actions".to_string())
+            }
+            _ if rejecting => return Err("reject cannot be combined with other actions".to_string()),
+            Action::Vacation(_) if self.actions.iter().any(|a| matches!(a, Action::Vacation(_))) => {
+                return Err("vacation used more than once".to_string())
+            }
+            Action::Redirect(_)
+                if self.actions.iter().filter(|a| matches!(a, Action::Redirect(_))).count() >= MAX_REDIRECTS =>
+            {
+                return Err("too many redirects".to_string())
+            }
+            _ => {}
+        }
+        // Storing a message twice in the same place stores it once
+        let duplicate = self.actions.iter().any(|a| match (a, &action) {
+            (Action::Keep { .. }, Action::Keep { .. }) => true,
+            (Action::FileInto { mailbox: a, .. }, Action::FileInto { mailbox: b, .. }) => a == b,
+            (Action::Redirect(a), Action::Redirect(b)) => a.eq_ignore_ascii_case(b),
+            _ => false,
+        });
+        if !duplicate {
+            self.actions.push(action);
+        }
+        Ok(())
+    }
+
+    fn run(&mut self, commands: &[Command]) -> Result<Flow, String> {
+        for command in commands {
+            match command {
+                Command::If(branches, otherwise) => {
+                    let mut block = otherwise.as_ref();
+                    for (test, commands) in branches {
+                        if self.test(test) {
+                            block = Some(commands);
+                            break;
+                        }
+                    }
+                    if let Some(commands) = block {
+                        if let Flow::Stop = self.run(commands)? {
+                            return Ok(Flow::Stop);
+                        }
+                    }
+                }
+                Command::Stop => return Ok(Flow::Stop),
+                Command::Keep { flags } => {
+                    let flags = self.store_flags(flags);
+                    self.push_action(Action::Keep { flags })?;
+                    self.implicit_keep = false;
+                }
+                Command::Discard => self.implicit_keep = false,
+                Command::FileInto { mailbox, flags } => {
+                    let flags = self.store_flags(flags);
+                    let mailbox = self.expand(mailbox);
+                    self.push_action(Action::FileInto { mailbox, flags })?;
+                    self.implicit_keep = false;
+                }
+                Command::Redirect(address) => {
+                    let address = self.expand(address);
+                    let address = parse_addresses(&address).into_iter().next().unwrap_or_default();
+                    if !address.contains('@') || address.contains(char::is_whitespace) {
+                        return Err(format!("invalid redirect address \"{}\"", address));
+                    }
+                    self.push_action(Action::Redirect(address))?;
+                    self.implicit_keep = false;
+                }
+                Command::Reject(reason) => {
+                    let reason = self.expand(reason);
+                    self.push_action(Action::Reject(reason))?;
+                    self.implicit_keep = false;
+                }
+                Command::Flag {
+                    action,
+                    variable,
+                    flags,
+                } => {
+                    let flags = self.expand_list(flags);
+                    let mut current = match variable {
+                        Some(name) => {
+                            let mut current = Vec::new();
+                            add_flags(&mut current, &[self.variables.get(name).cloned().unwrap_or_default()]);
+                            current
+                        }
+                        None => self.flags.clone(),
+                    };
+                    match action {
+                        FlagAction::Set => {
+                            current.clear();
+                            add_flags(&mut current, &flags);
+                        }
+                        FlagAction::Add => add_flags(&mut current, &flags),
+                        FlagAction::Remove => remove_flags(&mut current, &flags),
+                    }
+                    match variable {
+                        Some(name) => {
+                            self.variables.insert(name.clone(), current.join(" "));
+                        }
+                        None => self.flags = current,
+                    }
+                }
+                Command::Set { modifiers, name, value } => {
+                    let value = apply_modifiers(modifiers, self.expand(value));
+                    self.variables.insert(name.clone(), value);
+                }
+                Command::Vacation(vacation) => {
+                    let vacation = Vacation {
+                        days: vacation.days,
+                        subject: vacation.subject.as_ref().map(|s| self.expand(s)),
+                        from: vacation.from.as_ref().map(|s| self.expand(s)),
+                        addresses: self.expand_list(&vacation.addresses),
+                        mime: vacation.mime,
+                        handle: vacation.handle.as_ref().map(|s| self.expand(s)),
+                        reason: self.expand(&vacation.reason),
+                    };
+                    self.push_action(Action::Vacation(vacation))?;
+                }
+            }
+        }
+        Ok(Flow::Continue)
+    }
+}
+
+/// Run a script over a message, returning the actions to take. The
+/// implicit keep is included unless the script cancelled it. A runtime
+/// error gives the error message; the message should then be kept.
+pub fn execute(script: &Script, context: &Context) -> Result<Vec<Action>, String> {
+    let mut interpreter = Interpreter {
+        context,
+        variables_enabled: script.capabilities.contains("variables"),
+        variables: HashMap::new(),
+        match_variables: Vec::new(),
+        flags: Vec::new(),
+        actions: Vec::new(),
+        implicit_keep: true,
+    };
+    interpreter.run(&script.commands)?;
+    let mut actions = interpreter.actions;
+    if interpreter.implicit_keep {
+        let flags = interpreter.flags;
+        if !actions.iter().any(|a| matches!(a, Action::Keep { .. })) {
+            actions.push(Action::Keep { flags });
+        }
+    }
+    Ok(actions)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::sieve::grammar::parse;
+
+    static MESSAGE: &str = "From: \"Doe, Jane\" <jane@example.com>\n\
+                            To: will@xqz.ca, (comment) other@example.org\n\
+                            Subject: [acme-users] [fwd] version 1.0 is out\n\
+                            Content-Type: text/plain\n\
+                            \n\
+                            Hello there\n";
+
+    fn run(script: &str) -> Result<Vec<Action>, String> {
+        let message = Message::from_bytes(MESSAGE.as_bytes()).unwrap();
+        let context = Context {
+            message: &message,
+            size: MESSAGE.len(),
+            envelope_from: "jane@example.com".to_string(),
+            envelope_to: "will+lists@xqz.ca".to_string(),
+        };
+        execute(&parse(script).unwrap(), &context)
+    }
+
+    fn fileinto(mailbox: &str, flags: &[&str]) -> Action {
+        Action::FileInto {
+            mailbox: mailbox.to_string(),
+            flags: flags.iter().map(|f| f.to_string()).collect(),
+        }
+    }
+
+    #[test]
+    fn test_glob_match() {
+        let text: Vec<char> = "[acme-users] [fwd] out".chars().collect();
+        assert_eq!(
+            glob_match(&compile_glob("[*] *"), &text, false),
+            Some(vec!["acme-users".to_string(), "[fwd] out".to_string()])
+        );
+        let text: Vec<char> = "abc".chars().collect();
+        assert_eq!(
+            glob_match(&compile_glob("A?*"), &text, true),
+            Some(vec!["b".to_string(), "c".to_string()])
+        );
+        assert_eq!(glob_match(&compile_glob("a\\*"), &text, false), None);
+        let text: Vec<char> = "a".repeat(200).chars().collect();
+        assert_eq!(glob_match(&compile_glob(&"*a".repeat(30).replace("a*", "b*")), &text, false), None);
+    }
+
+    #[test]
+    fn test_parse_addresses() {
+        assert_eq!(
+            parse_addresses("\"Doe, Jane\" <jane@example.com>, bob@example.org (Bob)"),
+            vec!["jane@example.com".to_string(), "bob@example.org".to_string()]
+        );
+        assert_eq!(
+            parse_addresses("friends: a@b.c, d@e.f;, <@route:g@h.i>"),
+            vec!["a@b.c".to_string(), "d@e.f".to_string(), "g@h.i".to_string()]
+        );
+    }
+
+    #[test]
+    fn test_implicit_keep() {
+        assert_eq!(run("").unwrap(), vec![Action::Keep { flags: vec![] }]);
+        assert_eq!(run("discard;").unwrap(), vec![]);
+        assert_eq!(
+            run("require \"imap4flags\"; addflag [\"\\\\Seen\", \"a b\"]; removeflag \"A\";").unwrap(),
+            vec![Action::Keep {
+                flags: vec!["\\Seen".to_string(), "b".to_string()]
+            }]
+        );
+    }
+
+    #[test]
+    fn test_tests() {
+        let script = "require [\"fileinto\", \"envelope\", \"body\"];\n\
+                      if address :domain \"from\" \"EXAMPLE.com\" { fileinto \"a\"; }\n\
+                      if address :localpart :is \"to\" \"other\" { fileinto \"b\"; }\n\
+                      if envelope :localpart :matches \"to\" \"*+lists\" { fileinto \"c\"; }\n\
+                      if body :text :contains \"hello\" { fileinto \"d\"; }\n\
+                      if header :comparator \"i;octet\" :contains \"subject\" \"ACME\" { fileinto \"e\"; }\n\
+                      if size :under 10 { fileinto \"f\"; }\n\
+                      if anyof (not exists \"x-spam\", false) { fileinto \"g\"; }";
+        assert_eq!(
+            run(script).unwrap(),
+            vec![
+                fileinto("a", &[]),
+                fileinto("b", &[]),
+                fileinto("c", &[]),
+                fileinto("d", &[]),
+                fileinto("g", &[]),
+            ]
+        );
+    }
+
+    #[test]
+    fn test_variables() {
+        let script = "require [\"fileinto\", \"variables\", \"imap4flags\"];\n\
+                      if header :matches \"subject\" \"[*] *\" {\n\
+                          set :upperfirst \"list\" \"${1}\";\n\
+                          set \"flag\" \"\\\\Flagged\";\n\
+                          fileinto :flags \"${flag}\" \"lists/${list}${unknown}\";\n\
+                      }\n\
+                      if string :is \"${list}\" \"Acme-users\" { stop; }\n\
+                      keep;";
+        assert_eq!(run(script).unwrap(), vec![fileinto("lists/Acme-users", &["\\Flagged"])]);
+        assert_eq!(apply_modifiers(&[Modifier::Upper, Modifier::QuoteWildcard], "a*".to_string()), "A\\*");
+        assert_eq!(apply_modifiers(&[Modifier::Length], "héllo".to_string()), "5");
+    }
+
+    #[test]
+    fn test_runtime_errors() {
+        assert!(run("require [\"reject\", \"fileinto\"]; fileinto \"a\"; reject \"no\";").is_err());
+        assert!(run("require \"vacation\"; vacation \"a\"; vacation \"b\";").is_err());
+        assert!(run("redirect \"not an address\";").is_err());
+        assert_eq!(
+            run("require \"reject\"; reject \"go away\";").unwrap(),
+            vec![Action::Reject("go away".to_string())]
+        );
+        assert_eq!(
+            run("redirect \"a@b.c\"; redirect \"A@b.c\";").unwrap(),
+            vec![Action::Redirect("a@b.c".to_string())]
+        );
+    }
+}
@@ -0,0 +1,293 @@
+use super::error::{Error, Result};
+
+/// The lexical tokens of a Sieve script, RFC 5228 section 8.1.
+#[derive(Clone, Debug, PartialEq)]
+pub enum Token {
+    /// An identifier, lower-cased as identifiers are case-insensitive.
+    Identifier(String),
+    /// A tagged argument such as `:is`, lower-cased and without the colon.
+    Tag(String),
+    Number(u64),
+    String(String),
+    LeftBracket,
+    RightBracket,
+    LeftParen,
+    RightParen,
+    LeftBrace,
+    RightBrace,
+    Comma,
+    Semicolon,
+}
+
+struct Lexer<'a> {
+    chars: &'a [u8],
+    pos: usize,
+    line: usize,
+}
+
+impl<'a> Lexer<'a> {
+    fn peek(&self) -> Option<u8> {
+        self.chars.get(self.pos).copied()
+    }
+
+    fn starts_with(&self, s: &[u8]) -> bool {
+        self.chars[self.pos..].starts_with(s)
+    }
+
+    fn bump(&mut self) -> Option<u8> {
+        let chr = self.peek()?;
+        self.pos += 1;
+        if chr == b'\n' {
+            self.line += 1;
+        }
+        Some(chr)
+    }
+
+    fn error<T>(&self, message: &str) -> Result<T> {
+        Err(Error::new(self.line, message.to_string()))
+    }
+
+    /// Skip whitespace and comments.
+    fn skip_space(&mut self) -> Result<()> {
+        loop {
+            match self.peek() {
+                Some(b' ') | Some(b'\t') | Some(b'\r') | Some(b'\n') => {
+                    self.bump();
+                }
+                Some(b'#') => {
+                    while let Some(chr) = self.bump() {
+                        if chr == b'\n' {
+                            break;
+                        }
+                    }
+                }
+                Some(b'/') if self.starts_with(b"/*") => {
+                    self.pos += 2;
+                    loop {
+                        if self.starts_with(b"*/") {
+                            self.pos += 2;
+                            break;
+                        }
+                        if self.bump().is_none() {
+                            return self.error("unterminated comment");
+                        }
+                    }
+                }
+                _ => return Ok(()),
+            }
+        }
+    }
+
+    fn word(&mut self) -> String {
+        let start = self.pos;
+        while let Some(chr) = self.peek() {
+            if chr.is_ascii_alphanumeric() || chr == b'_' {
+                self.pos += 1;
+            } else {
+                break;
+            }
+        }
+        String::from_utf8_lossy(&self.chars[start..self.pos]).to_ascii_lowercase()
+    }
+
+    fn number(&mut self) -> Result<u64> {
+        let start = self.pos;
+        while let Some(b'0'..=b'9') = self.peek() {
+            self.pos += 1;
+        }
+        let digits = String::from_utf8_lossy(&self.chars[start..self.pos]).into_owned();
+        let value: u64 = match digits.parse() {
+            Ok(value) => value,
+            Err(_) => return self.error("number too large"),
+        };
+        let multiplier = match self.peek() {
+            Some(b'K') | Some(b'k') => 1 << 10,
+            Some(b'M') | Some(b'm') => 1 << 20,
+            Some(b'G') | Some(b'g') => 1 << 30,
+            _ => 1,
+        };
+        if multiplier > 1 {
+            self.pos += 1;
+        }
+        match value.checked_mul(multiplier) {
+            Some(value) => Ok(value),
+            None => self.error("number too large"),
+        }
+    }
+
+    fn quoted_string(&mut self) -> Result<String> {
+        let mut res = Vec::new();
+        loop {
+            match self.bump() {
+                None => return self.error("unterminated string"),
+                Some(b'"') => break,
+                // A backslash quotes the next character
+                Some(b'\\') => match self.bump() {
+                    Some(chr) => res.push(chr),
+                    None => return self.error("unterminated string"),
+                },
+                Some(chr) => res.push(chr),
+            }
+        }
+        Ok(String::from_utf8_lossy(&res).replace("\r\n", "\n"))
+    }
+
+    /// A multi-line string: "text:" up to a line holding a single ".",
+    /// with dot-stuffing removed.
+    fn multiline_string(&mut self) -> Result<String> {
+        // Only whitespace or a comment may follow "text:" on its line
+        loop {
+            match self.peek() {
+                Some(b' ') | Some(b'\t') | Some(b'\r') => {
+                    self.bump();
+                }
+                Some(b'#') | Some(b'\n') => {
+                    while let Some(chr) = self.bump() {
+                        if chr == b'\n' {
+                            break;
+                        }
+                    }
+                    break;
+                }
+                _ => return self.error("expected a line break after text:"),
+            }
+        }
+        let mut res = String::new();
+        loop {
+            let start = self.pos;
+            while let Some(chr) = self.bump() {
+                if chr == b'\n' {
+                    break;
+                }
+            }
+            if start == self.pos {
+                return self.error("unterminated multi-line string");
+            }
+            let line = String::from_utf8_lossy(&self.chars[start..self.pos]).into_owned();
+            let line = line.trim_end_matches('\n').trim_end_matches('\r');
+            if line == "." {
+                return Ok(res);
+            }
+            res.push_str(line.strip_prefix('.').unwrap_or(line));
+            res.push('\n');
+        }
+    }
+}
+
+/// Split a script into tokens, each with the line it starts on.
+pub fn tokenize(source: &str) -> Result<Vec<(Token, usize)>> {
+    let mut lexer = Lexer {
+        chars: source.as_bytes(),
+        pos: 0,
+        line: 1,
+    };
+    let mut tokens = Vec::new();
+    loop {
+        lexer.skip_space()?;
+        let line = lexer.line;
+        let chr = match lexer.peek() {
+            Some(chr) => chr,
+            None => return Ok(tokens),
+        };
+        let token = match chr {
+            b'[' => Token::LeftBracket,
+            b']' => Token::RightBracket,
+            b'(' => Token::LeftParen,
+            b')' => Token::RightParen,
+            b'{' => Token::LeftBrace,
+            b'}' => Token::RightBrace,
+            b',' => Token::Comma,
+            b';' => Token::Semicolon,
+            b'"' => {
+                lexer.bump();
+                tokens.push((Token::String(lexer.quoted_string()?), line));
+                continue;
+            }
+            b':' => {
+                lexer.bump();
+                let tag = lexer.word();
+                if tag.is_empty() {
+                    return lexer.error("expected a tag after ':'");
+                }
+                tokens.push((Token::Tag(tag), line));
+                continue;
+            }
+            b'0'..=b'9' => {
+                tokens.push((Token::Number(lexer.number()?), line));
+                continue;
+            }
+            chr if chr.is_ascii_alphabetic() || chr == b'_' => {
+                let word = lexer.word();
+                if word == "text" && lexer.peek() == Some(b':') {
+                    lexer.bump();
+                    tokens.push((Token::String(lexer.multiline_string()?), line));
+                } else {
+                    tokens.push((Token::Identifier(word), line));
+                }
+                continue;
+            }
+            _ => return lexer.error("unexpected character"),
+        };
+        lexer.bump();
+        tokens.push((token, line));
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::Token::*;
+    use super::*;
+
+    fn tokens(source: &str) -> Vec<Token> {
+        tokenize(source).unwrap().into_iter().map(|(t, _)| t).collect()
+    }
+
+    #[test]
+    fn test_tokenize() {
+        assert_eq!(
+            tokens("if size :over 100K { discard; } # comment\n/* block\ncomment */"),
+            vec![
+                Identifier("if".to_string()),
+                Identifier("size".to_string()),
+                Tag("over".to_string()),
+                Number(102400),
+                LeftBrace,
+                Identifier("discard".to_string()),
+                Semicolon,
+                RightBrace,
+            ]
+        );
+        assert_eq!(
+            tokens("Header :IS [\"a\\\"b\", \"c\"]"),
+            vec![
+                Identifier("header".to_string()),
+                Tag("is".to_string()),
+                LeftBracket,
+                String("a\"b".to_string()),
+                Comma,
+                String("c".to_string()),
+                RightBracket,
+            ]
+        );
+    }
+
+    #[test]
+    fn test_multiline_string() {
+        assert_eq!(
+            tokens("vacation text: # reason\r\nline one\r\n..dotted\r\n.\r\n;"),
+            vec![
+                Identifier("vacation".to_string()),
+                String("line one\n.dotted\n".to_string()),
+                Semicolon,
+            ]
+        );
+    }
+
+    #[test]
+    fn test_tokenize_errors() {
+        assert_eq!(tokenize("\"open").unwrap_err().line, 1);
+        assert_eq!(tokenize("keep;\n/* open").unwrap_err().line, 2);
+        assert!(tokenize("keep @").is_err());
+        assert!(tokenize("size :over 99999999999999999999").is_err());
+    }
+}
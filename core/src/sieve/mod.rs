@@ -0,0 +1,152 @@
+//! Sieve mail filtering (RFC 5228) with the fileinto, envelope, imap4flags
+//! (RFC 5232), body (RFC 5173), variables (RFC 5229), reject (RFC 5429) and
+//! vacation (RFC 5230) extensions. Scripts are run over each message as it is
+//! delivered over LMTP.
+
+use std::fs::{self, File};
+use std::io::{self, Read, Write};
+use std::path::{Path, PathBuf};
+
+pub use self::grammar::{parse, Script, CAPABILITIES};
+pub use self::interpreter::{execute, Action, Context};
+
+mod error;
+mod grammar;
+mod interpreter;
+mod lexer;
+pub mod vacation;
+
+// The file in the script directory naming the active script.
+static ACTIVE_FILE: &str = ".active";
+
+/// The directory in the root of a maildir holding the user's scripts
+pub static SCRIPT_DIR: &str = ".sieve";
+
+/// The directory holding a user's scripts, inside the root of their
+/// maildir.
+pub fn script_dir(maildir: &Path) -> PathBuf {
+    maildir.join(SCRIPT_DIR)
+}
+
+/// Whether a script name is acceptable, per RFC 5804 section 1.6. Names
+/// are also used as file names, so they may not hold "/" or begin with ".".
+pub fn valid_script_name(name: &str) -> bool {
+    !name.is_empty()
+        && name.chars().count() <= 128
+        && !name.starts_with('.')
+        && !name.contains('/')
+        && !name.chars().any(|c| c.is_control() || c == '\u{2028}' || c == '\u{2029}')
+}
+
+/// The file holding the script with the given name.
+pub fn script_path(maildir: &Path, name: &str) -> PathBuf {
+    script_dir(maildir).join(format!("{}.sieve", name))
+}
+
+/// The names of the user's scripts, sorted.
+pub fn list_scripts(maildir: &Path) -> io::Result<Vec<String>> {
+    let mut names = Vec::new();
+    let entries = match fs::read_dir(script_dir(maildir)) {
+        Ok(entries) => entries,
+        Err(ref e) if e.kind() == io::ErrorKind::NotFound => return Ok(names),
+        Err(e) => return Err(e),
+    };
+    for entry in entries {
+        let file_name = entry?.file_name().to_string_lossy().into_owned();
+        if let Some(name) = file_name.strip_suffix(".sieve") {
+            if valid_script_name(name) {
+                names.push(name.to_string());
+            }
+        }
+    }
+    names.sort();
+    Ok(names)
+}
+
+/// The name of the active script, if there is one.
+pub fn active_script_name(maildir: &Path) -> Option<String> {
+    let mut name = String::new();
+    File::open(script_dir(maildir).join(ACTIVE_FILE))
+        .ok()?
+        .read_to_string(&mut name)
+        .ok()?;
+    let name = name.trim();
+    if valid_script_name(name) {
+        Some(name.to_string())
+    } else {
+        None
+    }
+}
+
+/// Make the named script the active one, or deactivate scripts altogether.
+pub fn set_active_script(maildir: &Path, name: Option<&str>) -> io::Result<()> {
+    let path = script_dir(maildir).join(ACTIVE_FILE);
+    match name {
+        Some(name) => write_file(&path, name.as_bytes()),
+        None => match fs::remove_file(&path) {
+            Err(ref e) if e.kind() == io::ErrorKind::NotFound => Ok(()),
+            res => res,
+        },
+    }
+}
+
+/// Write a file in the script directory, replacing it atomically.
+pub fn write_file(path: &Path, contents: &[u8]) -> io::Result<()> {
+    if let Some(dir) = path.parent() {
+        fs::create_dir_all(dir)?;
+    }
+    let tmp_path = path.with_extension("tmp");
+    let mut file = File::create(&tmp_path)?;
+    file.write_all(contents)?;
+    file.sync_all()?;
+    fs::rename(&tmp_path, path)
+}
+
+/// Load and parse the user's active script, if there is one. A script which
+/// fails to parse is logged and ignored, so that mail is still delivered.
+pub fn load_active(maildir: &Path) -> Option<Script> {
+    let name = active_script_name(maildir)?;
+    let path = script_path(maildir, &name);
+    let mut source = String::new();
+    if let Err(e) = File::open(&path).and_then(|mut f| f.read_to_string(&mut source)) {
+        warn!("Error reading Sieve script '{}': {}", path.display(), e);
+        return None;
+    }
+    match parse(&source) {
+        Ok(script) => Some(script),
+        Err(e) => {
+            warn!("Error in Sieve script '{}': {}", path.display(), e);
+            None
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testing::TempDir;
+
+    #[test]
+    fn test_scripts() {
+        let maildir = TempDir::new("sieve");
+        assert_eq!(list_scripts(&maildir).unwrap(), Vec::<String>::new());
+        write_file(&script_path(&maildir, "b"), b"keep;").unwrap();
+        write_file(&script_path(&maildir, "a"), b"discard;").unwrap();
+        assert_eq!(list_scripts(&maildir).unwrap(), vec!["a".to_string(), "b".to_string()]);
+        assert_eq!(active_script_name(&maildir), None);
+        set_active_script(&maildir, Some("a")).unwrap();
+        assert_eq!(active_script_name(&maildir), Some("a".to_string()));
+        assert!(load_active(&maildir).is_some());
+        set_active_script(&maildir, None).unwrap();
+        assert!(load_active(&maildir).is_none());
+    }
+
+    #[test]
+    fn test_valid_script_name() {
+        assert!(valid_script_name("my script"));
+        assert!(!valid_script_name(""));
+        assert!(!valid_script_name(".active"));
+        assert!(!valid_script_name("../x"));
+        assert!(!valid_script_name("a\nb"));
+    }
+}
@@ -0,0 +1,251 @@
+use std::collections::HashMap;
+use std::fs::File;
+use std::io::{self, Read};
+use std::path::Path;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use chrono::Utc;
+use crypto::digest::Digest;
+use crypto::sha1::Sha1;
+use mime::encoding::encode_header;
+use mime::Message;
+
+use super::grammar::Vacation;
+use super::interpreter::parse_addresses;
+use super::write_file;
+
+// The interval between replies to the same sender, in days, and its limits.
+const DEFAULT_DAYS: u64 = 7;
+const MAX_DAYS: u64 = 30;
+
+// The file, in the script directory, recording who has been answered when.
+static TRACKING_FILE: &str = ".vacation.json";
+
+/// An automatic reply ready to be sent.
+#[derive(Debug, PartialEq)]
+pub struct Reply {
+    /// The address the reply goes to; it is sent from the null sender.
+    pub to: String,
+    pub data: String,
+}
+
+/// Whether the message comes from a sender which must never be answered
+/// automatically, according to RFC 5230 section 4.
+fn is_automated(message: &Message, sender: &str) -> bool {
+    let local_part = sender.rsplit_once('@').map(|(l, _)| l).unwrap_or(sender).to_ascii_lowercase();
+    if local_part.starts_with("owner-") || local_part.ends_with("-request") || local_part == "mailer-daemon" {
+        return true;
+    }
+    if message
+        .get_header_values("Auto-Submitted")
+        .iter()
+        .any(|v| !v.trim().eq_ignore_ascii_case("no"))
+    {
+        return true;
+    }
+    for list_header in &["List-Id", "List-Help", "List-Subscribe", "List-Unsubscribe", "List-Post", "List-Owner", "List-Archive"] {
+        if !message.get_header_values(list_header).is_empty() {
+            return true;
+        }
+    }
+    message.get_header_values("Precedence").iter().any(|v| {
+        let v = v.trim().to_ascii_lowercase();
+        v == "bulk" || v == "list" || v == "junk"
+    })
+}
+
+/// Whether one of the user's addresses is among the message's recipients.
+fn addressed_to_user(message: &Message, addresses: &[String]) -> bool {
+    for header in &["To", "Cc", "Bcc", "Resent-To", "Resent-Cc", "Resent-Bcc"] {
+        for value in message.get_header_values(header) {
+            for address in parse_addresses(&value) {
+                if addresses.iter().any(|a| a.eq_ignore_ascii_case(&address)) {
+                    return true;
+                }
+            }
+        }
+    }
+    false
+}
+
+/// The key under which replies are tracked: the handle, derived from the
+/// arguments when not given, and the sender.
+fn tracking_key(vacation: &Vacation, sender: &str) -> String {
+    let mut hasher = Sha1::new();
+    match vacation.handle {
+        Some(ref handle) => hasher.input_str(handle),
+        None => {
+            hasher.input_str(vacation.subject.as_deref().unwrap_or(""));
+            hasher.input_str("\0");
+            hasher.input_str(vacation.from.as_deref().unwrap_or(""));
+            hasher.input_str("\0");
+            hasher.input_str(&vacation.reason);
+        }
+    }
+    hasher.input_str("\0");
+    hasher.input_str(&sender.to_ascii_lowercase());
+    hasher.result_str()
+}
+
+fn load_tracking(path: &Path) -> HashMap<String, u64> {
+    let mut contents = String::new();
+    match File::open(path).and_then(|mut f| f.read_to_string(&mut contents)) {
+        Ok(_) => serde_json::from_str(&contents).unwrap_or_default(),
+        Err(_) => HashMap::new(),
+    }
+}
+
+fn save_tracking(path: &Path, tracking: &HashMap<String, u64>) -> io::Result<()> {
+    let encoded = serde_json::to_string(tracking)?;
+    write_file(path, encoded.as_bytes())
+}
+
+/// Build the reply itself.
+fn build_reply(vacation: &Vacation, message: &Message, sender: &str, from: &str, host: &str) -> String {
+    let subject = match vacation.subject {
+        Some(ref subject) => subject.clone(),
+        None => match message.get_header_values("Subject").first() {
+            Some(subject) if !subject.trim().is_empty() => format!("Auto: {}", subject.trim()),
+            _ => "Automated reply".to_string(),
+        },
+    };
+    let now = Utc::now();
+    let mut data = format!(
+        "From: {}\nTo: <{}>\nSubject: {}\nDate: {}\nMessage-ID: <{}.vacation@{}>\nAuto-Submitted: auto-replied\n",
+        from,
+        sender,
+        encode_header(&subject),
+        now.to_rfc2822(),
+        now.timestamp_nanos(),
+        host
+    );
+    if let Some(message_id) = message.get_header_values("Message-ID").first() {
+        let message_id = message_id.trim();
+        data.push_str(&format!("In-Reply-To: {}\n", message_id));
+        let references = match message.get_header_values("References").first() {
+            Some(references) => format!("{} {}", references.trim(), message_id),
+            None => message_id.to_string(),
+        };
+        data.push_str(&format!("References: {}\n", references));
+    }
+    data.push_str("MIME-Version: 1.0\n");
+    if vacation.mime {
+        // The reason is a complete MIME entity, header included
+        data.push_str(vacation.reason.trim_start_matches('\n'));
+    } else {
+        data.push_str("Content-Type: text/plain; charset=utf-8\nContent-Transfer-Encoding: 8bit\n\n");
+        data.push_str(&vacation.reason);
+    }
+    if !data.ends_with('\n') {
+        data.push('\n');
+    }
+    data
+}
+
+/// Decide whether to answer a message with a vacation reply, and build the
+/// reply if so. `recipient` is the address the message was delivered to and
+/// `user_addresses` holds the other addresses of its owner. Replies sent are
+/// recorded in `script_dir` so that each sender is answered once every
+/// `:days` days.
+pub fn respond(
+    vacation: &Vacation,
+    message: &Message,
+    sender: &str,
+    recipient: &str,
+    user_addresses: &[String],
+    script_dir: &Path,
+    host: &str,
+) -> Option<Reply> {
+    if sender.is_empty() || is_automated(message, sender) {
+        return None;
+    }
+    let mut addresses: Vec<String> = user_addresses.to_vec();
+    addresses.push(recipient.to_string());
+    addresses.extend(vacation.addresses.iter().cloned());
+    if addresses.iter().any(|a| a.eq_ignore_ascii_case(sender)) || !addressed_to_user(message, &addresses) {
+        return None;
+    }
+
+    let now = SystemTime::now().duration_since(UNIX_EPOCH).ok()?.as_secs();
+    let days = vacation.days.unwrap_or(DEFAULT_DAYS).clamp(1, MAX_DAYS);
+    let tracking_path = script_dir.join(TRACKING_FILE);
+    let mut tracking = load_tracking(&tracking_path);
+    let key = tracking_key(vacation, sender);
+    if let Some(sent) = tracking.get(&key) {
+        if now < sent + days * 24 * 60 * 60 {
+            return None;
+        }
+    }
+    // Forget replies old enough not to matter any more
+    tracking.retain(|_, sent| now < *sent + MAX_DAYS * 24 * 60 * 60);
+    tracking.insert(key, now);
+    if let Err(e) = save_tracking(&tracking_path, &tracking) {
+        warn!("Error saving vacation replies to '{}': {}", tracking_path.display(), e);
+    }
+
+    let from = match vacation.from {
+        Some(ref from) => from.clone(),
+        None => format!("<{}>", recipient),
+    };
+    Some(Reply {
+        to: sender.to_string(),
+        data: build_reply(vacation, message, sender, &from, host),
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testing::TempDir;
+    use mime::encoding::base64_encode;
+
+    fn vacation() -> Vacation {
+        Vacation {
+            days: None,
+            subject: None,
+            from: None,
+            addresses: vec![],
+            mime: false,
+            handle: None,
+            reason: "I am away.".to_string(),
+        }
+    }
+
+    fn message(extra: &str) -> Message {
+        let raw = format!(
+            "From: jane@example.com\nTo: Will <will@xqz.ca>\nSubject: café\nMessage-ID: <1@example.com>\n{}\nHi\n",
+            extra
+        );
+        Message::from_bytes(raw.as_bytes()).unwrap()
+    }
+
+    #[test]
+    fn test_respond() {
+        let dir = TempDir::new("vacation-respond");
+        let reply = respond(&vacation(), &message(""), "jane@example.com", "will@xqz.ca", &[], &dir, "host").unwrap();
+        assert_eq!(reply.to, "jane@example.com");
+        assert!(reply.data.starts_with("From: <will@xqz.ca>\nTo: <jane@example.com>\n"));
+        assert!(reply.data.contains(&format!("Subject: =?UTF-8?B?{}?=\n", base64_encode("Auto: café".as_bytes()))));
+        assert!(reply.data.contains("In-Reply-To: <1@example.com>\nReferences: <1@example.com>\n"));
+        assert!(reply.data.ends_with("\n\nI am away.\n"));
+        // The same sender is only answered once
+        assert!(respond(&vacation(), &message(""), "jane@example.com", "will@xqz.ca", &[], &dir, "host").is_none());
+    }
+
+    #[test]
+    fn test_no_response() {
+        let dir = TempDir::new("vacation-none");
+        let respond_to = |extra: &str, sender: &str, recipient: &str| {
+            respond(&vacation(), &message(extra), sender, recipient, &[], &dir, "host")
+        };
+        assert!(respond_to("", "", "will@xqz.ca").is_none());
+        assert!(respond_to("", "owner-list@example.com", "will@xqz.ca").is_none());
+        assert!(respond_to("", "MAILER-DAEMON@example.com", "will@xqz.ca").is_none());
+        assert!(respond_to("Auto-Submitted: auto-generated\n", "a@example.com", "will@xqz.ca").is_none());
+        assert!(respond_to("List-Id: <list.example.com>\n", "b@example.com", "will@xqz.ca").is_none());
+        assert!(respond_to("Precedence: bulk\n", "c@example.com", "will@xqz.ca").is_none());
+        assert!(respond_to("", "d@example.com", "someone@xqz.ca").is_none());
+        assert!(respond_to("", "will@xqz.ca", "will@xqz.ca").is_none());
+        assert!(respond_to("Auto-Submitted: no\n", "e@example.com", "will@xqz.ca").is_some());
+    }
+}
@@ -0,0 +1,213 @@
+//! The special uses of mailboxes (RFC 6154), such as the one sent mail is
+//! saved in. The configuration gives the default mailbox for each use, which
+//! a user's own settings override. Uses clients set with CREATE override
+//! both; they are kept in the maildir's special-use file as one
+//! "<attribute> <mailbox>" line per use.
+
+use std::collections::BTreeMap;
+use std::fs;
+use std::io;
+use std::path::{Path, PathBuf};
+
+use crate::layout::Layout;
+use crate::util::mailbox_path;
+
+pub static SPECIAL_USE_FILE: &str = ".special-use";
+
+/// Every special-use attribute of RFC 6154.
+pub static ATTRIBUTES: &[&str] = &[
+    "\\All",
+    "\\Archive",
+    "\\Drafts",
+    "\\Flagged",
+    "\\Junk",
+    "\\Sent",
+    "\\Trash",
+];
+
+/// The attribute for a use, given with or without its backslash and in any
+/// case.
+pub fn attribute(name: &str) -> Option<&'static str> {
+    let name = name.strip_prefix('\\').unwrap_or(name);
+    ATTRIBUTES
+        .iter()
+        .find(|attribute| attribute[1..].eq_ignore_ascii_case(name))
+        .copied()
+}
+
+/// The attributes of a CREATE parameter list such as "(USE (\Sent \Junk))",
+/// as given. None if the parameters are anything else.
+pub fn parse_create_params(params: &str) -> Option<Vec<&str>> {
+    let params = params.trim().strip_prefix('(')?.strip_suffix(')')?.trim();
+    let (keyword, list) = params.split_at(params.find(' ')?);
+    if !keyword.eq_ignore_ascii_case("USE") {
+        return None;
+    }
+    let list = list.trim().strip_prefix('(')?.strip_suffix(')')?;
+    Some(list.split_whitespace().collect())
+}
+
+#[derive(Debug, Default, PartialEq)]
+pub struct SpecialUse {
+    // Where the uses set by clients are saved
+    path: Option<PathBuf>,
+    // The mailbox of each use by attribute, from the configuration and the
+    // user's settings
+    defaults: BTreeMap<&'static str, String>,
+    // The mailbox of each use set by clients
+    uses: BTreeMap<&'static str, String>,
+}
+
+impl SpecialUse {
+    /// Read the special uses of a maildir. `defaults` maps uses, with or
+    /// without the backslash, onto mailbox names.
+    pub fn load(maildir: &Path, defaults: &BTreeMap<String, String>) -> SpecialUse {
+        let contents = fs::read_to_string(maildir.join(SPECIAL_USE_FILE)).unwrap_or_default();
+        let mut special_use = SpecialUse::parse(&contents);
+        special_use.path = Some(maildir.join(SPECIAL_USE_FILE));
+        for (name, mailbox) in defaults {
+            match attribute(name) {
+                Some(attribute) => {
+                    special_use.defaults.insert(attribute, mailbox.clone());
+                }
+                None => warn!("Unknown special use {}", name),
+            }
+        }
+        special_use
+    }
+
+    fn parse(contents: &str) -> SpecialUse {
+        let mut uses = BTreeMap::new();
+        for line in contents.lines() {
+            let mut fields = line.splitn(2, ' ');
+            match (fields.next().and_then(attribute), fields.next()) {
+                (Some(attribute), Some(mailbox)) if !mailbox.is_empty() => {
+                    uses.insert(attribute, mailbox.to_string());
+                }
+                _ => warn!("Invalid line in {}: {}", SPECIAL_USE_FILE, line),
+            }
+        }
+        SpecialUse {
+            path: None,
+            defaults: BTreeMap::new(),
+            uses,
+        }
+    }
+
+    fn save(&self) -> io::Result<()> {
+        let path = match self.path {
+            Some(ref path) => path,
+            None => return Ok(()),
+        };
+        let mut contents = String::new();
+        for (attribute, mailbox) in &self.uses {
+            contents.push_str(&format!("{} {}\n", attribute, mailbox));
+        }
+        let tmp_path = path.with_extension("tmp");
+        fs::write(&tmp_path, contents).and_then(|_| fs::rename(&tmp_path, path))
+    }
+
+    /// The mailbox of each use.
+    fn mailboxes(&self) -> BTreeMap<&'static str, &str> {
+        let mut mailboxes: BTreeMap<&'static str, &str> = self
+            .defaults
+            .iter()
+            .map(|(&attribute, mailbox)| (attribute, &mailbox[..]))
+            .collect();
+        for (&attribute, mailbox) in &self.uses {
+            mailboxes.insert(attribute, mailbox);
+        }
+        mailboxes
+    }
+
+    /// The special-use attributes of a mailbox.
+    pub fn attributes(&self, mailbox: &str) -> Vec<&'static str> {
+        self.mailboxes()
+            .into_iter()
+            .filter(|&(_, name)| name == mailbox)
+            .map(|(attribute, _)| attribute)
+            .collect()
+    }
+
+    /// Make a mailbox the one with the given uses, as CREATE does.
+    pub fn set(&mut self, mailbox: &str, attributes: &[&'static str]) -> io::Result<()> {
+        for attribute in attributes {
+            self.uses.insert(attribute, mailbox.to_string());
+        }
+        self.save()
+    }
+
+    /// On a user's first login, which is when the maildir has no special-use
+    /// file yet, create the mailboxes of the default uses. Mailboxes which
+    /// exist already are left alone.
+    pub fn provision(maildir: &Path, layout: Layout, defaults: &BTreeMap<String, String>) -> io::Result<()> {
+        let special_use = SpecialUse::load(maildir, defaults);
+        if maildir.join(SPECIAL_USE_FILE).exists() {
+            return Ok(());
+        }
+        for mailbox in special_use.mailboxes().values() {
+            let path = match mailbox_path(maildir, mailbox, layout) {
+                Some(path) => path,
+                None => {
+                    warn!("Invalid special-use mailbox {}", mailbox);
+                    continue;
+                }
+            };
+            if !path.join("cur").is_dir() {
+                layout.create(&path)?;
+            }
+        }
+        special_use.save()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testing::TempDir;
+
+    #[test]
+    fn test_create_params() {
+        assert_eq!(attribute("sent"), Some("\\Sent"));
+        assert_eq!(attribute("\\JUNK"), Some("\\Junk"));
+        assert_eq!(attribute("\\Inbox"), None);
+        assert_eq!(
+            parse_create_params("(USE (\\Sent \\Junk))"),
+            Some(vec!["\\Sent", "\\Junk"])
+        );
+        assert_eq!(parse_create_params("(use ())"), Some(vec![]));
+        assert_eq!(parse_create_params("(FOO (\\Sent))"), None);
+        assert_eq!(parse_create_params("USE (\\Sent)"), None);
+    }
+
+    #[test]
+    fn test_special_use() {
+        let maildir = TempDir::new("special-use");
+        fs::create_dir_all(&maildir).unwrap();
+        let mut defaults = BTreeMap::new();
+        defaults.insert("Sent".to_string(), "Sent".to_string());
+        defaults.insert("\\trash".to_string(), "Deleted".to_string());
+        defaults.insert("Bogus".to_string(), "Bogus".to_string());
+
+        SpecialUse::provision(&maildir, Layout::Nested, &defaults).unwrap();
+        assert!(maildir.join("Sent").join("cur").is_dir());
+        assert!(maildir.join("Deleted").join("new").is_dir());
+        assert!(!maildir.join("Bogus").exists());
+
+        // Provisioning only happens once
+        fs::remove_dir_all(maildir.join("Sent")).unwrap();
+        SpecialUse::provision(&maildir, Layout::Nested, &defaults).unwrap();
+        assert!(!maildir.join("Sent").exists());
+
+        let mut special_use = SpecialUse::load(&maildir, &defaults);
+        assert_eq!(special_use.attributes("Deleted"), vec!["\\Trash"]);
+        special_use.set("Sent Items", &["\\Sent", "\\Archive"]).unwrap();
+        assert_eq!(
+            fs::read_to_string(maildir.join(SPECIAL_USE_FILE)).unwrap(),
+            "\\Archive Sent Items\n\\Sent Sent Items\n"
+        );
+        let special_use = SpecialUse::load(&maildir, &defaults);
+        assert_eq!(special_use.attributes("Sent Items"), vec!["\\Archive", "\\Sent"]);
+        assert!(special_use.attributes("Sent").is_empty());
+    }
+}
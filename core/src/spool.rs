@@ -0,0 +1,108 @@
+//! The outgoing mail spool: messages generated by the server, such as Sieve
+//! redirects and vacation replies, wait here to be relayed. Each message is
+//! a JSON file in the spool directory.
+
+use std::fs::{self, File};
+use std::io::{self, Read, Write};
+use std::path::{Path, PathBuf};
+use std::process;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::time::{SystemTime, UNIX_EPOCH};
+
+// Distinguishes messages spooled within the same nanosecond.
+static COUNTER: AtomicUsize = AtomicUsize::new(0);
+
+/// A message waiting to be sent.
+#[derive(Debug, PartialEq, Serialize, Deserialize)]
+pub struct SpooledMessage {
+    /// The reverse path; empty for the null sender.
+    pub from: String,
+    pub to: Vec<String>,
+    /// The message itself, with LF line endings. It may hold 8-bit data
+    /// in any charset, so it is kept as base64 in the spool file.
+    #[serde(with = "base64_data")]
+    pub data: Vec<u8>,
+}
+
+mod base64_data {
+    use mime::encoding::{base64_decode, base64_encode};
+    use serde::{Deserialize, Deserializer, Serializer};
+
+    pub fn serialize<S: Serializer>(data: &[u8], serializer: S) -> Result<S::Ok, S::Error> {
+        serializer.serialize_str(&base64_encode(data))
+    }
+
+    pub fn deserialize<'de, D: Deserializer<'de>>(deserializer: D) -> Result<Vec<u8>, D::Error> {
+        let encoded = String::deserialize(deserializer)?;
+        Ok(base64_decode(encoded.as_bytes()))
+    }
+}
+
+/// Add a message to the spool. It is written under a temporary name and
+/// then renamed, so that a message is never seen half-written.
+pub fn enqueue(dir: &Path, message: &SpooledMessage) -> io::Result<PathBuf> {
+    fs::create_dir_all(dir)?;
+    let nanos = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|d| d.as_nanos())
+        .unwrap_or_default();
+    let id = format!("{}.{}.{}", nanos, process::id(), COUNTER.fetch_add(1, Ordering::SeqCst));
+    let tmp_path = dir.join(format!(".{}.tmp", id));
+    let path = dir.join(format!("{}.json", id));
+    let encoded = serde_json::to_string(message)?;
+    let mut file = File::create(&tmp_path)?;
+    file.write_all(encoded.as_bytes())?;
+    file.sync_all()?;
+    fs::rename(&tmp_path, &path)?;
+    Ok(path)
+}
+
+/// The messages waiting in the spool, oldest first. Messages still being
+/// written are skipped.
+pub fn pending(dir: &Path) -> io::Result<Vec<PathBuf>> {
+    let mut paths = Vec::new();
+    for entry in fs::read_dir(dir)? {
+        let path = entry?.path();
+        if path.extension().and_then(|e| e.to_str()) == Some("json") {
+            paths.push(path);
+        }
+    }
+    // The names start with the time of spooling; compare them as numbers
+    paths.sort_by_key(|path| {
+        let name = path.file_name().and_then(|n| n.to_str()).unwrap_or("");
+        let nanos = name.split('.').next().and_then(|n| n.parse::<u128>().ok());
+        (nanos.unwrap_or(0), name.to_string())
+    });
+    Ok(paths)
+}
+
+/// Read a spooled message back.
+pub fn load(path: &Path) -> io::Result<SpooledMessage> {
+    let mut contents = String::new();
+    File::open(path)?.read_to_string(&mut contents)?;
+    Ok(serde_json::from_str(&contents)?)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testing::TempDir;
+
+    #[test]
+    fn test_enqueue() {
+        let dir = TempDir::new("spool");
+        let message = SpooledMessage {
+            from: String::new(),
+            to: vec!["a@b.c".to_string()],
+            data: b"Subject: hi\n\nbody \xe9t\xe9\n".to_vec(),
+        };
+        let path = enqueue(&dir, &message).unwrap();
+        let mut contents = String::new();
+        File::open(&path).unwrap().read_to_string(&mut contents).unwrap();
+        assert_eq!(serde_json::from_str::<SpooledMessage>(&contents).unwrap(), message);
+        let second = enqueue(&dir, &message).unwrap();
+        File::create(dir.join(".partial.tmp")).unwrap();
+        assert_eq!(pending(&dir).unwrap(), vec![path.clone(), second]);
+        assert_eq!(load(&path).unwrap(), message);
+    }
+}
@@ -0,0 +1,110 @@
+//! The mailboxes a user is subscribed to, kept like Dovecot does in the
+//! maildir's subscriptions file as one mailbox name per line. Mailboxes need
+//! not exist to be subscribed to.
+
+use std::collections::BTreeSet;
+use std::fs;
+use std::io;
+use std::path::{Path, PathBuf};
+
+static SUBSCRIPTIONS_FILE: &str = "subscriptions";
+
+#[derive(Debug, Default, PartialEq)]
+pub struct Subscriptions {
+    // Where the subscriptions are saved; None to keep them in memory only
+    path: Option<PathBuf>,
+    names: BTreeSet<String>,
+}
+
+/// INBOX is case-insensitive; every other name is kept as given.
+fn canonical(name: &str) -> String {
+    if name.eq_ignore_ascii_case("INBOX") {
+        "INBOX".to_string()
+    } else {
+        name.to_string()
+    }
+}
+
+impl Subscriptions {
+    /// Read the subscriptions of a maildir. Having none is fine.
+    pub fn load(maildir: &Path) -> Subscriptions {
+        let contents = fs::read_to_string(maildir.join(SUBSCRIPTIONS_FILE)).unwrap_or_default();
+        let mut subscriptions = Subscriptions::parse(&contents);
+        subscriptions.path = Some(maildir.join(SUBSCRIPTIONS_FILE));
+        subscriptions
+    }
+
+    fn parse(contents: &str) -> Subscriptions {
+        Subscriptions {
+            path: None,
+            names: contents
+                .lines()
+                .filter(|name| !name.is_empty())
+                .map(canonical)
+                .collect(),
+        }
+    }
+
+    fn save(&self) -> io::Result<()> {
+        let path = match self.path {
+            Some(ref path) => path,
+            None => return Ok(()),
+        };
+        let mut contents = String::new();
+        for name in &self.names {
+            contents.push_str(name);
+            contents.push('\n');
+        }
+        let tmp_path = path.with_extension("tmp");
+        fs::write(&tmp_path, contents).and_then(|_| fs::rename(&tmp_path, path))
+    }
+
+    pub fn contains(&self, name: &str) -> bool {
+        self.names.contains(&canonical(name))
+    }
+
+    /// Every mailbox subscribed to, in order.
+    pub fn names(&self) -> impl Iterator<Item = &str> {
+        self.names.iter().map(|name| &name[..])
+    }
+
+    pub fn subscribe(&mut self, name: &str) -> io::Result<()> {
+        if self.names.insert(canonical(name)) {
+            self.save()?;
+        }
+        Ok(())
+    }
+
+    /// Unsubscribe from a mailbox. Returns whether it was subscribed to.
+    pub fn unsubscribe(&mut self, name: &str) -> io::Result<bool> {
+        if !self.names.remove(&canonical(name)) {
+            return Ok(false);
+        }
+        self.save()?;
+        Ok(true)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testing::TempDir;
+
+    #[test]
+    fn test_subscriptions() {
+        let maildir = TempDir::new("subscriptions");
+        fs::create_dir_all(&maildir).unwrap();
+        let mut subscriptions = Subscriptions::load(&maildir);
+        subscriptions.subscribe("inbox").unwrap();
+        subscriptions.subscribe("Lists/rust").unwrap();
+        assert!(subscriptions.contains("INBOX"));
+        assert_eq!(
+            fs::read_to_string(maildir.join(SUBSCRIPTIONS_FILE)).unwrap(),
+            "INBOX\nLists/rust\n"
+        );
+        assert!(!subscriptions.unsubscribe("Lists").unwrap());
+        assert!(subscriptions.unsubscribe("Lists/rust").unwrap());
+        let subscriptions = Subscriptions::load(&maildir);
+        assert_eq!(subscriptions.names().collect::<Vec<_>>(), vec!["INBOX"]);
+    }
+}
@@ -0,0 +1,126 @@
+//! The modified UTF-7 of RFC 3501 section 5.1.3, in which mailbox names go
+//! over the wire unless the client has enabled UTF8=ACCEPT. Printable ASCII
+//! stands for itself, except "&" which is sent as "&-", and anything else
+//! is UTF-16 in a variant of base64 between "&" and "-".
+
+// Base64 with "," in place of "/"
+static ALPHABET: &[u8] = b"ABCDEFGHIJKLMNOPQRSTUVWXYZabcdefghijklmnopqrstuvwxyz0123456789+,";
+
+fn is_printable(c: char) -> bool {
+    (' '..='~').contains(&c)
+}
+
+fn flush(utf16: &mut Vec<u16>, res: &mut String) {
+    if utf16.is_empty() {
+        return;
+    }
+    let bytes: Vec<u8> = utf16.drain(..).flat_map(|unit| unit.to_be_bytes()).collect();
+    res.push('&');
+    for chunk in bytes.chunks(3) {
+        let bits = chunk.iter().fold(0u32, |bits, &byte| (bits << 8) | u32::from(byte)) << (8 * (3 - chunk.len()));
+        for i in 0..=chunk.len() {
+            res.push(ALPHABET[((bits >> (18 - 6 * i)) & 0x3f) as usize] as char);
+        }
+    }
+    res.push('-');
+}
+
+/// Encode a mailbox name for the wire.
+pub fn encode(name: &str) -> String {
+    let mut res = String::with_capacity(name.len());
+    let mut utf16 = Vec::new();
+    for c in name.chars() {
+        if is_printable(c) {
+            flush(&mut utf16, &mut res);
+            res.push(c);
+            if c == '&' {
+                res.push('-');
+            }
+        } else {
+            let mut units = [0; 2];
+            utf16.extend_from_slice(c.encode_utf16(&mut units));
+        }
+    }
+    flush(&mut utf16, &mut res);
+    res
+}
+
+/// Decode a mailbox name from the wire. None if it is not valid modified
+/// UTF-7, which includes encoding what could have been sent as is.
+pub fn decode(name: &str) -> Option<String> {
+    let mut res = String::with_capacity(name.len());
+    let mut chars = name.chars();
+    while let Some(c) = chars.next() {
+        if !is_printable(c) {
+            return None;
+        }
+        if c != '&' {
+            res.push(c);
+            continue;
+        }
+        let mut bits = 0u32;
+        let mut nbits = 0;
+        let mut bytes = Vec::new();
+        loop {
+            let c = chars.next()?;
+            if c == '-' {
+                break;
+            }
+            let value = ALPHABET.iter().position(|&a| a as char == c)? as u32;
+            bits = (bits << 6) | value;
+            nbits += 6;
+            if nbits >= 8 {
+                nbits -= 8;
+                bytes.push((bits >> nbits) as u8);
+                bits &= (1 << nbits) - 1;
+            }
+        }
+        if bytes.is_empty() {
+            if nbits != 0 {
+                return None;
+            }
+            res.push('&');
+            continue;
+        }
+        // Leftover bits must be padding, and whole UTF-16 units
+        if nbits >= 6 || bits != 0 || bytes.len() % 2 != 0 {
+            return None;
+        }
+        let units = bytes.chunks(2).map(|pair| u16::from_be_bytes([pair[0], pair[1]]));
+        for c in char::decode_utf16(units) {
+            match c {
+                Ok(c) if !is_printable(c) => res.push(c),
+                _ => return None,
+            }
+        }
+    }
+    Some(res)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_utf7() {
+        let names = [
+            ("INBOX", "INBOX"),
+            ("Entwürfe", "Entw&APw-rfe"),
+            ("Tom & Jerry", "Tom &- Jerry"),
+            ("台北/日本語", "&U,BTFw-/&ZeVnLIqe-"),
+            ("~peter/mail/日本語/台北", "~peter/mail/&ZeVnLIqe-/&U,BTFw-"),
+            ("😀", "&2D3eAA-"),
+        ];
+        for &(name, wire) in &names {
+            assert_eq!(encode(name), wire);
+            assert_eq!(decode(wire).as_deref(), Some(name));
+        }
+        assert_eq!(decode("&Jjo"), None);
+        assert_eq!(decode("&Jjo!-"), None);
+        assert_eq!(decode("&AGE-"), None);
+        assert_eq!(decode("&APw"), None);
+        assert_eq!(decode("&APx-"), None);
+        assert_eq!(decode("Entwürfe"), None);
+        assert_eq!(decode("&2D0-"), None);
+    }
+}
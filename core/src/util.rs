@@ -3,14 +3,14 @@
 // on the session (or take what they do need as arguments) and/or they are
 // called by the session in multiple places.
 
-use regex::Regex;
-use std::env::current_dir;
-use std::fs;
 use std::path::Path;
 use std::path::PathBuf;
-use walkdir::WalkDir;
 
-use crate::folder::Folder;
+use crate::command::sequence_set::{self, SequenceItem};
+use crate::folder::{Folder, UIDVALIDITY};
+use crate::layout::Layout;
+use crate::namespace::Delimiter;
+use crate::utf7;
 
 #[macro_export]
 macro_rules! path_filename_to_str(
@@ -20,154 +20,213 @@ macro_rules! path_filename_to_str(
     });
 );
 
-fn make_absolute(dir: &Path) -> String {
-    match current_dir() {
-        Err(_) => dir.display().to_string(),
-        Ok(absp) => {
-            let mut abs_path = absp.clone();
-            abs_path.push(dir);
-            abs_path.display().to_string()
-        }
+/// Whether a name, such as the detail of an address, can be used as the
+/// name of a folder. It must not be able to escape the user's maildir or
+/// name a maildir subdirectory.
+pub fn valid_folder_name(name: &str) -> bool {
+    !name.is_empty()
+        && !name.starts_with('.')
+        && !name.contains('/')
+        && !name.contains('\0')
+        && name != "cur"
+        && name != "new"
+        && name != "tmp"
+}
+
+/// The levels of the hierarchy in a mailbox name, without a leading INBOX.
+pub fn mailbox_levels(name: &str) -> Vec<&str> {
+    let mut levels: Vec<&str> = name.split('/').collect();
+    if levels[0].eq_ignore_ascii_case("INBOX") {
+        levels.remove(0);
     }
+    levels
 }
 
-pub fn perform_select(
-    maildir: &str,
-    select_args: &[&str],
-    examine: bool,
-    tag: &str,
-) -> (Option<Folder>, String) {
-    let err_res = (None, "".to_string());
-    if select_args.len() < 1 {
-        return err_res;
+/// The path of a mailbox in the user's maildir. "/" separates the levels of
+/// the hierarchy, and a leading INBOX, in any case, is the maildir itself.
+/// None if the name could lead outside the maildir, onto one of a folder's
+/// own subdirectories or cannot be kept in the layout; every command
+/// resolves names here.
+pub fn mailbox_path(maildir: &Path, name: &str, layout: Layout) -> Option<PathBuf> {
+    let levels = mailbox_levels(name);
+    if !levels.iter().all(|level| layout.valid_level(level)) {
+        return None;
     }
-    let mbox_name = select_args[0].trim_matches('"').replace("INBOX", ".");
-    let mut maildir_path = PathBuf::new();
-    maildir_path.push(maildir);
-    maildir_path.push(mbox_name);
-    let folder = match Folder::new(maildir_path, examine) {
-        None => {
-            return err_res;
+    let path = layout.folder_path(maildir, &levels);
+    // A symbolic link in the maildir could still point elsewhere
+    if let (Ok(real), Ok(root)) = (path.canonicalize(), maildir.canonicalize()) {
+        if !real.starts_with(root) {
+            return None;
         }
-        Some(folder) => folder.clone(),
-    };
+    }
+    Some(path)
+}
 
-    let ok_res = folder.select_response(tag);
-    (Some(folder), ok_res)
+/// A mailbox name as an IMAP quoted string.
+pub fn quote(name: &str) -> String {
+    let mut quoted = String::with_capacity(name.len() + 2);
+    quoted.push('"');
+    for c in name.chars() {
+        if c == '"' || c == '\\' {
+            quoted.push('\\');
+        }
+        quoted.push(c);
+    }
+    quoted.push('"');
+    quoted
 }
 
-/// For the given dir, make sure it is a valid mail folder and, if it is,
-/// generate the LIST response for it.
-fn list_dir(dir: &Path, regex: &Regex, maildir_path: &Path) -> Option<String> {
-    let dir_string = dir.display().to_string();
-    let dir_name = path_filename_to_str!(dir);
+/// A mailbox name as it goes over the wire: with the delimiter clients
+/// see, and as UTF-8 once the client has enabled UTF8=ACCEPT or in modified
+/// UTF-7 otherwise.
+pub fn wire_name(name: &str, delimiter: Delimiter, utf8: bool) -> String {
+    let name = delimiter.wire_name(name);
+    if utf8 {
+        quote(&name)
+    } else {
+        quote(&utf7::encode(&name))
+    }
+}
 
-    // These folder names are used to hold mail. Every other folder is
-    // valid.
-    if dir_name == "cur" || dir_name == "new" || dir_name == "tmp" {
+/// The parameter which may follow the folder name in SELECT and EXAMINE
+/// (RFC 7162)
+#[derive(Debug, PartialEq)]
+pub enum SelectParam {
+    None,
+    CondStore,
+    // The client last saw the folder with this UIDVALIDITY at this
+    // mod-sequence, and perhaps knew of these UIDs
+    QResync {
+        uidvalidity: usize,
+        modseq: usize,
+        known_uids: Option<Vec<SequenceItem>>,
+    },
+}
+
+/// Parse "(CONDSTORE)" or "(QRESYNC (uidvalidity modseq [known-uids]))".
+/// The sequence match data QRESYNC may end with is ignored, as every
+/// UID expunged since the mod-sequence is sent anyway.
+pub fn parse_select_param(param: &str) -> Option<SelectParam> {
+    if param.is_empty() {
+        return Some(SelectParam::None);
+    }
+    let inner = param.strip_prefix('(')?.strip_suffix(')')?;
+    if inner.eq_ignore_ascii_case("condstore") {
+        return Some(SelectParam::CondStore);
+    }
+    let (name, rest) = inner.split_once(' ')?;
+    if !name.eq_ignore_ascii_case("qresync") {
         return None;
     }
-
-    let abs_dir = make_absolute(dir);
-
-    // If it doesn't have any mail, then it isn't selectable as a mail
-    // folder but it may contain subfolders which hold mail.
-    let mut flags = match fs::read_dir(&dir.join("cur")) {
-        Err(_) => "\\Noselect".to_string(),
-        _ => {
-            match fs::read_dir(&dir.join("new")) {
-                Err(_) => "\\Noselect".to_string(),
-                // If there is new mail in the folder, we should inform the
-                // client. We do this only because we have to perform the
-                // check in order to determine selectability. The RFC says
-                // not to perform the check if it would slow down the
-                // response time.
-                Ok(newlisting) => {
-                    if newlisting.count() == 0 {
-                        "\\Unmarked".to_string()
-                    } else {
-                        "\\Marked".to_string()
-                    }
-                }
-            }
-        }
+    let rest = rest.strip_prefix('(')?.strip_suffix(')')?;
+    let rest = rest.split('(').next().unwrap_or("");
+    let mut fields = rest.split_whitespace();
+    let uidvalidity = fields.next()?.parse().ok()?;
+    let modseq = fields.next()?.parse().ok()?;
+    let known_uids = match fields.next() {
+        Some(set) => Some(sequence_set::parse(set)?),
+        None => None,
     };
-
-    // Changing folders in mutt doesn't work properly if we don't indicate
-    // whether or not a given folder has subfolders. Mutt has issues
-    // selecting folders with subfolders for reading mail, unfortunately.
-    match fs::read_dir(&dir) {
-        Err(_) => {
-            return None;
-        }
-        Ok(dir_listing) => {
-            let mut children = false;
-            for subdir_entry in dir_listing {
-                if let Ok(subdir) = subdir_entry {
-                    if *dir == *maildir_path {
-                        break;
-                    }
-                    let subdir_path = subdir.path();
-                    let subdir_str = path_filename_to_str!(subdir_path);
-                    if subdir_str != "cur" && subdir_str != "new" && subdir_str != "tmp" {
-                        if fs::read_dir(&subdir.path().join("cur")).is_err() {
-                            continue;
-                        }
-                        if fs::read_dir(&subdir.path().join("new")).is_err() {
-                            continue;
-                        }
-                        children = true;
-                        break;
-                    }
-                }
-            }
-            if children {
-                flags.push_str(" \\HasChildren");
-            } else {
-                flags.push_str(" \\HasNoChildren");
-            }
-        }
+    if fields.next().is_some() {
+        return None;
     }
+    Some(SelectParam::QResync {
+        uidvalidity,
+        modseq,
+        known_uids,
+    })
+}
 
-    let re_path = make_absolute(maildir_path);
-    match fs::metadata(dir) {
-        Err(_) => return None,
-        Ok(md) => {
-            if !md.is_dir() {
-                return None;
-            }
+pub fn perform_select(path: PathBuf, examine: bool, tag: &str, param: Option<SelectParam>) -> (Option<Folder>, String) {
+    let err_res = (None, "".to_string());
+    let folder = match Folder::new(path, examine) {
+        None => {
+            return err_res;
         }
+        Some(folder) => folder.clone(),
     };
 
-    if !regex.is_match(&dir_string[..]) {
-        return None;
-    }
-    let mut list_str = "* LIST (".to_string();
-    list_str.push_str(&flags[..]);
-    list_str.push_str(") \"/\" ");
-    let list_dir_string = if abs_dir.starts_with(&re_path[..]) {
-        abs_dir.replacen(&re_path[..], "", 1)
-    } else {
-        abs_dir
+    // Only a client which saw the folder with the same UIDs can catch up
+    let resync = match param {
+        Some(SelectParam::QResync {
+            uidvalidity,
+            modseq,
+            ref known_uids,
+        }) if uidvalidity == UIDVALIDITY => folder.qresync_response(modseq, known_uids.as_deref()),
+        _ => String::new(),
     };
-    list_str.push_str(&(list_dir_string.replace("INBOX", ""))[..]);
-    Some(list_str)
+    let ok_res = folder.select_response(tag, &resync);
+    (Some(folder), ok_res)
 }
 
-/// Go through the logged in user's maildir and list every folder matching
-/// the given regular expression. Returns a list of LIST responses.
-pub fn list(maildir: &str, regex: &Regex) -> Vec<String> {
-    let maildir_path = Path::new(maildir);
-    let mut responses = Vec::new();
-    if let Some(list_response) = list_dir(maildir_path, regex, maildir_path) {
-        responses.push(list_response);
+#[cfg(test)]
+mod tests {
+    use std::path::Path;
+
+    use super::{mailbox_path, parse_select_param, quote, valid_folder_name, SelectParam};
+    use crate::layout::Layout;
+    use crate::command::sequence_set::SequenceItem::{Number, Range};
+
+    #[test]
+    fn test_quote() {
+        assert_eq!(quote("INBOX"), "\"INBOX\"");
+        assert_eq!(quote("a \"b\" \\c"), "\"a \\\"b\\\" \\\\c\"");
     }
-    for dir_res in WalkDir::new(&maildir_path) {
-        if let Ok(dir) = dir_res {
-            if let Some(list_response) = list_dir(dir.path(), regex, maildir_path) {
-                responses.push(list_response);
-            }
-        }
+
+    #[test]
+    fn test_parse_select_param() {
+        assert_eq!(parse_select_param(""), Some(SelectParam::None));
+        assert_eq!(parse_select_param("(condstore)"), Some(SelectParam::CondStore));
+        assert_eq!(
+            parse_select_param("(QRESYNC (1 20))"),
+            Some(SelectParam::QResync {
+                uidvalidity: 1,
+                modseq: 20,
+                known_uids: None
+            })
+        );
+        assert_eq!(
+            parse_select_param("(QRESYNC (1 20 3:5 (1:2 3:4)))"),
+            Some(SelectParam::QResync {
+                uidvalidity: 1,
+                modseq: 20,
+                known_uids: Some(vec![Range(Box::new(Number(3)), Box::new(Number(5)))])
+            })
+        );
+        assert_eq!(parse_select_param("(QRESYNC (1))"), None);
+        assert_eq!(parse_select_param("(FOO)"), None);
+        assert_eq!(parse_select_param("CONDSTORE"), None);
+    }
+
+    #[test]
+    fn test_valid_folder_name() {
+        assert!(valid_folder_name("lists"));
+        assert!(!valid_folder_name(""));
+        assert!(!valid_folder_name(".."));
+        assert!(!valid_folder_name("a/b"));
+        assert!(!valid_folder_name("cur"));
+    }
+
+    #[test]
+    fn test_mailbox_path() {
+        let maildir = Path::new("/var/mail/will");
+        assert_eq!(mailbox_path(maildir, "INBOX", Layout::Nested), Some(maildir.to_path_buf()));
+        assert_eq!(mailbox_path(maildir, "inbox", Layout::Nested), Some(maildir.to_path_buf()));
+        assert_eq!(mailbox_path(maildir, "Inbox/lists", Layout::Nested), Some(maildir.join("lists")));
+        assert_eq!(mailbox_path(maildir, "MyINBOXArchive", Layout::Nested), Some(maildir.join("MyINBOXArchive")));
+        assert_eq!(mailbox_path(maildir, "a/INBOX", Layout::Nested), Some(maildir.join("a").join("INBOX")));
+        assert_eq!(mailbox_path(maildir, "", Layout::Nested), None);
+        assert_eq!(mailbox_path(maildir, "/etc", Layout::Nested), None);
+        assert_eq!(mailbox_path(maildir, "../bob", Layout::Nested), None);
+        assert_eq!(mailbox_path(maildir, "a/../../bob", Layout::Nested), None);
+        assert_eq!(mailbox_path(maildir, "a//b", Layout::Nested), None);
+        assert_eq!(mailbox_path(maildir, "a/", Layout::Nested), None);
+        assert_eq!(mailbox_path(maildir, "new", Layout::Nested), None);
+        assert_eq!(mailbox_path(maildir, "lists/tmp", Layout::Nested), None);
+        assert_eq!(
+            mailbox_path(maildir, "INBOX/Archive/2023", Layout::MaildirPlusPlus),
+            Some(maildir.join(".Archive.2023"))
+        );
+        assert_eq!(mailbox_path(maildir, "v1.2", Layout::MaildirPlusPlus), None);
     }
-    responses
 }
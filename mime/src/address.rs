@@ -0,0 +1,137 @@
+//! Parsing of the address lists found in header fields such as From and To
+//! (RFC 5322 section 3.4).
+
+use crate::encoding::decode_header;
+
+/// A mailbox from an address list.
+#[derive(Clone, Debug, PartialEq)]
+pub struct Address {
+    /// The display name, with any encoded words decoded.
+    pub name: Option<String>,
+    pub email: String,
+}
+
+/// Split a header field value into the mailboxes it holds. Group names are
+/// dropped, leaving the members of each group; a comment stands in for the
+/// display name of an address without one, as in `bob@example.org (Bob)`.
+pub fn parse_address_list(value: &str) -> Vec<Address> {
+    let mut res = Vec::new();
+    // The text of the current address outside of quotes, angle brackets
+    // and comments
+    let mut current = String::new();
+    // The display name, built from words and quoted strings
+    let mut phrase = String::new();
+    let mut comment = String::new();
+    let mut angle: Option<String> = None;
+    let mut in_quotes = false;
+    let mut comment_depth = 0;
+    let mut chars = value.chars();
+
+    let finish = |res: &mut Vec<Address>, current: &mut String, phrase: &mut String, comment: &mut String, angle: &mut Option<String>| {
+        let (email, name) = match angle.take() {
+            Some(address) => (address, phrase.trim().to_string()),
+            None => (current.trim().to_string(), comment.trim().to_string()),
+        };
+        // Route addresses in angle brackets may carry a source route
+        let email = match email.rfind(':') {
+            Some(index) if email.starts_with('@') => email[index + 1..].to_string(),
+            _ => email,
+        };
+        let email = email.trim().to_string();
+        if !email.is_empty() {
+            let name = decode_header(&name);
+            res.push(Address {
+                name: if name.is_empty() { None } else { Some(name) },
+                email,
+            });
+        }
+        current.clear();
+        phrase.clear();
+        comment.clear();
+    };
+
+    while let Some(chr) = chars.next() {
+        match chr {
+            '\\' if in_quotes || comment_depth > 0 => {
+                if let Some(next) = chars.next() {
+                    if in_quotes {
+                        phrase.push(next);
+                        current.push(next);
+                    } else {
+                        comment.push(next);
+                    }
+                }
+            }
+            '"' if comment_depth == 0 => in_quotes = !in_quotes,
+            _ if in_quotes => {
+                phrase.push(chr);
+                current.push(chr);
+            }
+            '(' => {
+                if comment_depth > 0 {
+                    comment.push(chr);
+                }
+                comment_depth += 1;
+            }
+            ')' if comment_depth > 0 => {
+                comment_depth -= 1;
+                if comment_depth > 0 {
+                    comment.push(chr);
+                }
+            }
+            _ if comment_depth > 0 => comment.push(chr),
+            '<' => {
+                let mut address = String::new();
+                for chr in chars.by_ref() {
+                    if chr == '>' {
+                        break;
+                    }
+                    address.push(chr);
+                }
+                angle = Some(address);
+            }
+            // The name of a group of addresses
+            ':' => {
+                current.clear();
+                phrase.clear();
+                comment.clear();
+            }
+            ',' | ';' => finish(&mut res, &mut current, &mut phrase, &mut comment, &mut angle),
+            chr => {
+                current.push(chr);
+                phrase.push(chr);
+            }
+        }
+    }
+    finish(&mut res, &mut current, &mut phrase, &mut comment, &mut angle);
+    res
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn address(name: Option<&str>, email: &str) -> Address {
+        Address {
+            name: name.map(|n| n.to_string()),
+            email: email.to_string(),
+        }
+    }
+
+    #[test]
+    fn test_parse_address_list() {
+        assert_eq!(
+            parse_address_list("\"Doe, Jane\" <jane@example.com>, bob@example.org (Bob), =?UTF-8?Q?Ren=C3=A9?= <rene@example.fr>"),
+            vec![
+                address(Some("Doe, Jane"), "jane@example.com"),
+                address(Some("Bob"), "bob@example.org"),
+                address(Some("René"), "rene@example.fr"),
+            ]
+        );
+        assert_eq!(
+            parse_address_list("friends: a@b.c, d@e.f;, <@route:g@h.i>"),
+            vec![address(None, "a@b.c"), address(None, "d@e.f"), address(None, "g@h.i")]
+        );
+        assert_eq!(parse_address_list(""), vec![]);
+    }
+}
@@ -0,0 +1,242 @@
+//! Decoders for the encodings found in MIME messages: content transfer
+//! encodings (RFC 2045), encoded words in header fields (RFC 2047) and the
+//! common character sets.
+
+static BASE64_CHARS: &[u8] = b"ABCDEFGHIJKLMNOPQRSTUVWXYZabcdefghijklmnopqrstuvwxyz0123456789+/";
+
+fn base64_value(chr: u8) -> Option<u32> {
+    match chr {
+        b'A'..=b'Z' => Some((chr - b'A') as u32),
+        b'a'..=b'z' => Some((chr - b'a') as u32 + 26),
+        b'0'..=b'9' => Some((chr - b'0') as u32 + 52),
+        b'+' => Some(62),
+        b'/' => Some(63),
+        _ => None,
+    }
+}
+
+/// Encode data as base64, with padding and without line breaks.
+pub fn base64_encode(input: &[u8]) -> String {
+    let mut res = String::with_capacity(input.len().div_ceil(3) * 4);
+    for chunk in input.chunks(3) {
+        let mut group = 0u32;
+        for (i, byte) in chunk.iter().enumerate() {
+            group |= (*byte as u32) << (16 - 8 * i);
+        }
+        for i in 0..4 {
+            if i <= chunk.len() {
+                let index = (group >> (18 - 6 * i)) & 0x3f;
+                res.push(BASE64_CHARS[index as usize] as char);
+            } else {
+                res.push('=');
+            }
+        }
+    }
+    res
+}
+
+/// Decode base64 data. Characters outside of the base64 alphabet, such as
+/// line breaks, are skipped as RFC 2045 requires.
+pub fn base64_decode(input: &[u8]) -> Vec<u8> {
+    let mut res = Vec::with_capacity(input.len() / 4 * 3);
+    let mut group = 0u32;
+    let mut bits = 0;
+    for chr in input {
+        if *chr == b'=' {
+            break;
+        }
+        if let Some(value) = base64_value(*chr) {
+            group = (group << 6) | value;
+            bits += 6;
+            if bits >= 8 {
+                bits -= 8;
+                res.push((group >> bits) as u8);
+                group &= (1 << bits) - 1;
+            }
+        }
+    }
+    res
+}
+
+fn hex_value(chr: u8) -> Option<u8> {
+    match chr {
+        b'0'..=b'9' => Some(chr - b'0'),
+        b'A'..=b'F' => Some(chr - b'A' + 10),
+        b'a'..=b'f' => Some(chr - b'a' + 10),
+        _ => None,
+    }
+}
+
+/// Decode quoted-printable data. With `header` set, underscores stand for
+/// spaces as in the "Q" encoding of RFC 2047.
+pub fn quoted_printable_decode(input: &[u8], header: bool) -> Vec<u8> {
+    let mut res = Vec::with_capacity(input.len());
+    let mut i = 0;
+    while i < input.len() {
+        match input[i] {
+            b'=' => {
+                let rest = &input[i + 1..];
+                // A soft line break
+                if rest.starts_with(b"\r\n") {
+                    i += 3;
+                    continue;
+                }
+                if rest.starts_with(b"\n") {
+                    i += 2;
+                    continue;
+                }
+                match (rest.first().and_then(|c| hex_value(*c)), rest.get(1).and_then(|c| hex_value(*c))) {
+                    (Some(high), Some(low)) => {
+                        res.push(high << 4 | low);
+                        i += 3;
+                        continue;
+                    }
+                    // Leave malformed escapes as they are
+                    _ => res.push(b'='),
+                }
+            }
+            b'_' if header => res.push(b' '),
+            chr => res.push(chr),
+        }
+        i += 1;
+    }
+    res
+}
+
+/// Decode a body according to its Content-Transfer-Encoding.
+pub fn transfer_decode(body: &str, encoding: &str) -> Vec<u8> {
+    match &encoding.trim().to_ascii_lowercase()[..] {
+        "base64" => base64_decode(body.as_bytes()),
+        "quoted-printable" => quoted_printable_decode(body.as_bytes(), false),
+        _ => body.as_bytes().to_vec(),
+    }
+}
+
+/// Convert text in the given character set to a `String`. UTF-8 and its
+/// subset US-ASCII are decoded leniently; ISO-8859-1 maps directly onto
+/// Unicode. Other character sets are treated as UTF-8.
+pub fn charset_decode(bytes: &[u8], charset: &str) -> String {
+    match &charset.trim().to_ascii_lowercase()[..] {
+        "iso-8859-1" | "latin1" | "l1" | "iso_8859-1" => bytes.iter().map(|b| *b as char).collect(),
+        "windows-1252" | "cp1252" => bytes.iter().map(|b| windows_1252_char(*b)).collect(),
+        _ => String::from_utf8_lossy(bytes).into_owned(),
+    }
+}
+
+fn windows_1252_char(byte: u8) -> char {
+    static HIGH: [char; 32] = [
+        '\u{20ac}', '\u{81}', '\u{201a}', '\u{192}', '\u{201e}', '\u{2026}', '\u{2020}', '\u{2021}',
+        '\u{2c6}', '\u{2030}', '\u{160}', '\u{2039}', '\u{152}', '\u{8d}', '\u{17d}', '\u{8f}',
+        '\u{90}', '\u{2018}', '\u{2019}', '\u{201c}', '\u{201d}', '\u{2022}', '\u{2013}', '\u{2014}',
+        '\u{2dc}', '\u{2122}', '\u{161}', '\u{203a}', '\u{153}', '\u{9d}', '\u{17e}', '\u{178}',
+    ];
+    match byte {
+        0x80..=0x9f => HIGH[(byte - 0x80) as usize],
+        _ => byte as char,
+    }
+}
+
+/// Decode a single RFC 2047 encoded word: =?charset?encoding?text?=
+fn decode_word(word: &str) -> Option<String> {
+    let inner = word.strip_prefix("=?")?.strip_suffix("?=")?;
+    let mut parts = inner.splitn(3, '?');
+    let charset = parts.next()?;
+    let encoding = parts.next()?;
+    let text = parts.next()?;
+    // RFC 2231 allows a language after the charset
+    let charset = charset.split('*').next().unwrap_or(charset);
+    let bytes = match &encoding.to_ascii_uppercase()[..] {
+        "B" => base64_decode(text.as_bytes()),
+        "Q" => quoted_printable_decode(text.as_bytes(), true),
+        _ => return None,
+    };
+    Some(charset_decode(&bytes, charset))
+}
+
+/// Encode a header field value holding non-ASCII text as an RFC 2047
+/// encoded word.
+pub fn encode_header(value: &str) -> String {
+    if value.is_ascii() {
+        value.to_string()
+    } else {
+        format!("=?UTF-8?B?{}?=", base64_encode(value.as_bytes()))
+    }
+}
+
+/// Decode the RFC 2047 encoded words in a header field value. Whitespace
+/// between adjacent encoded words is dropped.
+pub fn decode_header(value: &str) -> String {
+    if !value.contains("=?") {
+        return value.to_string();
+    }
+    let mut res = String::new();
+    let mut pending_space = String::new();
+    let mut last_was_word = false;
+    let mut rest = value;
+    while !rest.is_empty() {
+        let space_len = rest.len() - rest.trim_start().len();
+        if space_len > 0 {
+            pending_space.push_str(&rest[..space_len]);
+            rest = &rest[space_len..];
+            continue;
+        }
+        let token_len = rest.find(char::is_whitespace).unwrap_or(rest.len());
+        let token = &rest[..token_len];
+        rest = &rest[token_len..];
+        match decode_word(token) {
+            Some(decoded) => {
+                if !last_was_word {
+                    res.push_str(&pending_space);
+                }
+                res.push_str(&decoded);
+                last_was_word = true;
+            }
+            None => {
+                res.push_str(&pending_space);
+                res.push_str(token);
+                last_was_word = false;
+            }
+        }
+        pending_space.clear();
+    }
+    res.push_str(&pending_space);
+    res
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_base64() {
+        assert_eq!(base64_encode(b""), "");
+        assert_eq!(base64_encode(b"f"), "Zg==");
+        assert_eq!(base64_encode(b"fo"), "Zm8=");
+        assert_eq!(base64_encode(b"foobar"), "Zm9vYmFy");
+        assert_eq!(base64_decode(b"Zm9v\r\nYmFy"), b"foobar".to_vec());
+        assert_eq!(base64_decode(b"Zm8="), b"fo".to_vec());
+        assert_eq!(base64_decode(base64_encode(b"\x00user\x00pass").as_bytes()), b"\x00user\x00pass".to_vec());
+    }
+
+    #[test]
+    fn test_quoted_printable() {
+        assert_eq!(quoted_printable_decode(b"caf=C3=A9 =\nau lait", false), "café au lait".as_bytes().to_vec());
+        assert_eq!(quoted_printable_decode(b"a_b=3D", true), b"a b=".to_vec());
+        assert_eq!(quoted_printable_decode(b"100=", false), b"100=".to_vec());
+    }
+
+    #[test]
+    fn test_charset_decode() {
+        assert_eq!(charset_decode(b"caf\xe9", "ISO-8859-1"), "café");
+        assert_eq!(charset_decode(b"\x80", "windows-1252"), "€");
+        assert_eq!(charset_decode("café".as_bytes(), "utf-8"), "café");
+    }
+
+    #[test]
+    fn test_decode_header() {
+        assert_eq!(decode_header("plain subject"), "plain subject");
+        assert_eq!(decode_header("=?UTF-8?B?Y2Fmw6k=?="), "café");
+        assert_eq!(decode_header("Re: =?iso-8859-1?Q?caf=E9_au?= =?iso-8859-1?Q?_lait?= now"), "Re: café au lait now");
+        assert_eq!(decode_header("=?bogus?="), "=?bogus?=");
+    }
+}
@@ -15,8 +15,16 @@ use self::command::Msgtext::{
 pub use self::error::Error;
 use self::error::Result as MimeResult;
 
+use self::encoding::decode_header;
+pub use self::part::{get_param, Part};
+use self::thread::{base_subject, message_ids};
+
+pub mod address;
 mod command;
+pub mod encoding;
 mod error;
+mod part;
+pub mod thread;
 
 static RECEIVED: &'static str = "RECEIVED";
 
@@ -25,76 +33,53 @@ pub struct Message {
     // maps header field names to values
     headers: HashMap<String, String>,
 
-    // contains the MIME Parts (if more than one) of the message
-    #[allow(unused)]
-    body: Vec<MIMEPart>,
+    // every header field in order, including repeated fields
+    header_fields: Vec<(String, String)>,
+
+    // the MIME structure of the message
+    body: Part,
 
-    // size stored in case FETCH asks for it
-    size: usize,
+    // the contents of the file representing the message, byte for byte
+    raw_bytes: Vec<u8>,
 
-    // the raw contents of the file representing the message
+    // the contents decoded as UTF-8, for the parts that need text
     raw_contents: String,
 
-    // where in raw_contents the header ends and the body begins
+    // where in raw_bytes the header ends and the body begins
     header_boundary: usize,
 }
 
-/// Representation of a MIME message part
-#[derive(Debug, Clone)]
-struct MIMEPart {
-    #[allow(unused)]
-    mime_header: String,
-    #[allow(unused)]
-    mime_body: String,
-}
-
 impl Message {
     pub fn new(arg_path: &Path) -> MimeResult<Message> {
         // Load the file contents.
         let mut file = File::open(arg_path)?;
-        let mut raw_contents = String::new();
-        file.read_to_string(&mut raw_contents)?;
+        let mut raw_bytes = Vec::new();
+        file.read_to_end(&mut raw_bytes)?;
 
-        // This slice will avoid copying later
-        let size = raw_contents.len();
+        Message::from_bytes(&raw_bytes)
+    }
+
+    /// Parse a message held in memory, such as one being delivered.
+    pub fn from_bytes(raw_bytes: &[u8]) -> MimeResult<Message> {
+        // Messages delivered as 8BITMIME need not be valid UTF-8, so the
+        // raw bytes are kept for the size and FETCH and only parsing works
+        // on the lossily decoded text.
+        let raw_contents = String::from_utf8_lossy(raw_bytes).into_owned();
+        let (header_boundary, _) = part::split_header(raw_bytes);
 
         // Find boundary between header and body.
         // Use it to create &str of the raw header and raw body
-        let header_boundary = match raw_contents.find("\n\n") {
-            None => {
-                return Err(Error::ParseMultipartBoundary);
-            }
-            Some(n) => n + 1,
-        };
-        let raw_header = &raw_contents[..header_boundary];
-        let raw_body = &raw_contents[header_boundary..];
-
-        // Iterate over the lines of the header in reverse.
-        // If a line with leading whitespace is detected, it is merged to the
-        // line before it.
-        // This "unfolds" the header as indicated in RFC 2822 2.2.3
-        let mut iterator = raw_header.lines().rev();
+        let (text_boundary, body_start) = part::split_header(raw_contents.as_bytes());
+        let raw_header = &raw_contents[..text_boundary];
+        let raw_body = &raw_contents[body_start..];
+
+        // Unfold the header fields. Where a field occurs more than once,
+        // the first occurrence is used.
+        let header_fields = part::parse_header(raw_header);
         let mut headers = HashMap::new();
-        while let Some(line) = iterator.next() {
-            if line.starts_with(' ') || line.starts_with('\t') {
-                while let Some(next) = iterator.next() {
-                    let mut trimmed_next = next
-                        .trim_start_matches(' ')
-                        .trim_start_matches('\t')
-                        .to_string();
-
-                    // Add a space between the merged lines.
-                    trimmed_next.push(' ');
-                    trimmed_next.push_str(line.trim_start_matches(' ').trim_start_matches('\t'));
-                    if !next.starts_with(' ') && !next.starts_with('\t') {
-                        let split: Vec<&str> = (&trimmed_next[..]).splitn(2, ':').collect();
-                        headers.insert(split[0].to_ascii_uppercase(), split[1][1..].to_string());
-                        break;
-                    }
-                }
-            } else {
-                let split: Vec<&str> = line.splitn(2, ':').collect();
-                headers.insert(split[0].to_ascii_uppercase(), split[1][1..].to_string());
+        for (name, value) in &header_fields {
+            if !headers.contains_key(name) {
+                headers.insert(name.clone(), value.clone());
             }
         }
 
@@ -104,83 +89,15 @@ impl Message {
             headers.remove(received_key);
         }
 
-        // Determine whether the message is MULTIPART or not.
-        let mut body = Vec::new();
-        match headers.get(&"CONTENT-TYPE".to_string()) {
-            Some(content_type) => {
-                if (&content_type[..]).contains("MULTIPART") {
-                    // We need the boundary to determine where this part ends
-                    let mime_boundary = {
-                        let value: Vec<&str> = (&content_type[..]).split("BOUNDARY=\"").collect();
-                        if value.len() < 2 {
-                            return Err(Error::ParseMultipartBoundary);
-                        }
-                        let value: Vec<&str> = value[1].splitn(2, '"').collect();
-                        if value.len() < 1 {
-                            return Err(Error::ParseMultipartBoundary);
-                        }
-                        format!("--{}--\n", value[0])
-                    };
-
-                    // Grab the content type for this part
-                    let first_content_type_index = match raw_body.find("Content-Type") {
-                        Some(val) => val,
-                        None => return Err(Error::MissingContentType),
-                    };
-                    let mime_boundary_slice = &mime_boundary[..];
-                    let raw_body = &raw_body[first_content_type_index..];
-                    let raw_body: Vec<&str> = raw_body.split(mime_boundary_slice).collect();
-                    let raw_body_end = raw_body.len() - 1;
-                    let raw_body = &raw_body[..raw_body_end];
-
-                    // Throw the parts of the message into a list of MIMEParts
-                    for part in raw_body.iter() {
-                        let header_boundary = match part.find("\n\n") {
-                            None => return Err(Error::ParseMultipartBoundary),
-                            Some(n) => n,
-                        };
-                        let header = &part[..header_boundary];
-                        let mut content_type = String::new();
-                        for line in header.lines() {
-                            let split_line: Vec<&str> = line.splitn(2, ':').collect();
-                            if split_line[0] == "Content-Type" {
-                                let content_type_values: Vec<&str> =
-                                    split_line[1].splitn(2, ';').collect();
-                                content_type = content_type_values[0][1..].to_string();
-                                break;
-                            }
-                        }
-                        let body_part = MIMEPart {
-                            mime_header: content_type.to_string(),
-                            // TODO: double check that this is working as
-                            // intended.
-                            mime_body: part.to_string(),
-                        };
-                        body.push(body_part);
-                    }
-                } else {
-                    // Not a multipart message.
-                    let body_part = MIMEPart {
-                        mime_header: content_type.to_string(),
-                        mime_body: raw_body.to_string(),
-                    };
-                    body.push(body_part);
-                }
-            }
-            // No Content Type header so it is not a MIME message
-            _ => {
-                let non_mime_part = MIMEPart {
-                    mime_header: "text/plain".to_string(),
-                    mime_body: raw_body.to_string(),
-                };
-                body.push(non_mime_part);
-            }
-        }
+        // Split up the MIME structure of the message.
+        let body = Part::new(header_fields.clone(), raw_body, 0);
+
         let message = Message {
             headers: headers,
+            header_fields,
             body: body,
-            size: size,
-            raw_contents: raw_contents.to_string(),
+            raw_bytes: raw_bytes.to_vec(),
+            raw_contents,
             header_boundary: header_boundary,
         };
 
@@ -188,6 +105,61 @@ impl Message {
         Ok(message)
     }
 
+    /// All the values of a header field, in order, with RFC 2047 encoded
+    /// words decoded.
+    pub fn get_header_values(&self, key: &str) -> Vec<String> {
+        let key = key.to_ascii_uppercase();
+        self.header_fields
+            .iter()
+            .filter(|(name, _)| *name == key)
+            .map(|(_, value)| decode_header(value))
+            .collect()
+    }
+
+    /// The id in the Message-ID header field, without angle brackets.
+    pub fn get_message_id(&self) -> Option<String> {
+        let value = self.headers.get("MESSAGE-ID")?;
+        message_ids(value).into_iter().next()
+    }
+
+    /// The ids of the messages this one follows on from, oldest first: those
+    /// in the References header field or, failing that, the first one in
+    /// In-Reply-To.
+    pub fn get_references(&self) -> Vec<String> {
+        let references = self.headers.get("REFERENCES").map(|value| message_ids(value)).unwrap_or_default();
+        if !references.is_empty() {
+            return references;
+        }
+        let in_reply_to = self.headers.get("IN-REPLY-TO").map(|value| message_ids(value)).unwrap_or_default();
+        in_reply_to.into_iter().take(1).collect()
+    }
+
+    /// The base subject of the message, and whether its subject marks it as
+    /// a reply or forward.
+    pub fn get_base_subject(&self) -> (String, bool) {
+        base_subject(self.headers.get("SUBJECT").map_or("", |value| &value[..]))
+    }
+
+    /// The body of the message, undecoded.
+    pub fn get_raw_body(&self) -> &str {
+        let (_, body_start) = part::split_header(self.raw_contents.as_bytes());
+        &self.raw_contents[body_start..]
+    }
+
+    /// The MIME structure of the message, starting from the message itself.
+    pub fn get_body_part(&self) -> &Part {
+        &self.body
+    }
+
+    /// The decoded contents of the body parts with one of the given media
+    /// types, such as "text" or "text/html". An empty list selects every
+    /// body part.
+    pub fn get_body_text(&self, types: &[String]) -> Vec<String> {
+        let mut res = Vec::new();
+        self.body.collect_text(types, &mut res);
+        res
+    }
+
     // Both BodyPeek and BodySection grab parts of the message
     // BodyPeek does not set the Seen flag while BodySection does.
     // Setting the Seen flag is handled in the Session by detecting BodySection
@@ -195,19 +167,18 @@ impl Message {
         &self,
         section: &'a BodySectionType,
         _octets: &Option<(usize, usize)>,
-    ) -> String {
+    ) -> Vec<u8> {
         let empty_string = "".to_string();
         let peek_attr = match *section {
             AllSection => {
-                format!(
-                    "] {{{}}}\r\n{} ",
-                    (&self.raw_contents[..]).len(),
-                    self.raw_contents
-                )
+                let mut res = format!("] {{{}}}\r\n", self.raw_bytes.len()).into_bytes();
+                res.extend_from_slice(&self.raw_bytes);
+                res.push(b' ');
+                res
             }
             MsgtextSection(ref msgtext) => match *msgtext {
                 HeaderMsgtext | HeaderFieldsNotMsgtext(_) | TextMsgtext | MimeMsgtext => {
-                    empty_string
+                    empty_string.into_bytes()
                 }
                 HeaderFieldsMsgtext(ref fields) => {
                     let mut field_keys = String::new();
@@ -237,11 +208,15 @@ impl Message {
                         &field_values[..].len(),
                         field_values
                     )
+                    .into_bytes()
                 }
             },
-            PartSection(_, _) => "?]".to_string(),
+            PartSection(_, _) => b"?]".to_vec(),
         };
-        format!("BODY[{} ", peek_attr)
+        let mut res = b"BODY[".to_vec();
+        res.extend_from_slice(&peek_attr);
+        res.push(b' ');
+        res
     }
 
     /**
@@ -296,8 +271,8 @@ impl Message {
         }
     }
 
-    pub fn get_size(&self) -> String {
-        self.size.to_string()
+    pub fn get_size(&self) -> usize {
+        self.raw_bytes.len()
     }
 
     pub fn get_header_boundary(&self) -> String {
@@ -305,6 +280,31 @@ impl Message {
     }
 
     pub fn get_header(&self) -> &str {
-        &self.raw_contents[..self.header_boundary]
+        let (text_boundary, _) = part::split_header(self.raw_contents.as_bytes());
+        &self.raw_contents[..text_boundary]
+    }
+
+    /// The header of the message as it is stored.
+    pub fn get_raw_header(&self) -> &[u8] {
+        &self.raw_bytes[..self.header_boundary]
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_eight_bit_body() {
+        let raw = b"Subject: caf\xe9\r\n\r\nLunch at the caf\xe9\r\n";
+        let message = Message::from_bytes(raw).unwrap();
+        assert_eq!(message.get_size(), raw.len());
+        assert_eq!(message.get_raw_header(), b"Subject: caf\xe9\r\n");
+        assert_eq!(message.get_header_boundary(), "15");
+        assert_eq!(message.get_raw_body(), "Lunch at the caf\u{fffd}\r\n");
+
+        let mut expected = format!("BODY[] {{{}}}\r\n", raw.len()).into_bytes();
+        expected.extend_from_slice(raw);
+        assert!(message.get_body(&AllSection, &None).starts_with(&expected));
     }
 }
@@ -0,0 +1,271 @@
+use crate::encoding::{charset_decode, transfer_decode};
+
+// Deeply nested messages are not split up any further than this.
+const MAX_DEPTH: usize = 16;
+
+/// Split a raw header into its fields, unfolding continuation lines as
+/// indicated in RFC 2822 2.2.3. Field names are upper-cased; the order and
+/// any repeated fields are preserved.
+pub fn parse_header(raw_header: &str) -> Vec<(String, String)> {
+    let mut fields: Vec<(String, String)> = Vec::new();
+    for line in raw_header.lines() {
+        let line = line.trim_end_matches('\r');
+        if line.starts_with(' ') || line.starts_with('\t') {
+            // Add a space between the merged lines.
+            if let Some(field) = fields.last_mut() {
+                field.1.push(' ');
+                field.1.push_str(line.trim_start_matches(' ').trim_start_matches('\t'));
+            }
+            continue;
+        }
+        let mut split = line.splitn(2, ':');
+        let name = split.next().unwrap_or("");
+        match split.next() {
+            Some(value) if !name.is_empty() => {
+                fields.push((name.trim_end().to_ascii_uppercase(), value.trim_start().to_string()))
+            }
+            // Lines which are not fields are skipped
+            _ => {}
+        }
+    }
+    fields
+}
+
+/// Find the end of the header: returns the index just past the last header
+/// line and the index where the body starts. A message without a blank line
+/// is all header.
+pub fn split_header(raw: &[u8]) -> (usize, usize) {
+    if raw.starts_with(b"\n") {
+        return (0, 1);
+    }
+    if raw.starts_with(b"\r\n") {
+        return (0, 2);
+    }
+    let lf = find(raw, b"\n\n").map(|n| (n + 1, n + 2));
+    let crlf = find(raw, b"\n\r\n").map(|n| (n + 1, n + 3));
+    match (lf, crlf) {
+        (Some(a), Some(b)) => {
+            if a.0 <= b.0 {
+                a
+            } else {
+                b
+            }
+        }
+        (Some(a), None) => a,
+        (None, Some(b)) => b,
+        (None, None) => (raw.len(), raw.len()),
+    }
+}
+
+// The offset of the first occurrence of needle in haystack
+fn find(haystack: &[u8], needle: &[u8]) -> Option<usize> {
+    haystack.windows(needle.len()).position(|window| window == needle)
+}
+
+/// Look up a parameter, such as the boundary or charset, in a structured
+/// header field value like `multipart/mixed; boundary="abc"`.
+pub fn get_param(value: &str, name: &str) -> Option<String> {
+    for param in value.split(';').skip(1) {
+        let mut split = param.splitn(2, '=');
+        let key = split.next().unwrap_or("").trim();
+        if key.eq_ignore_ascii_case(name) {
+            let value = split.next()?.trim();
+            return Some(value.trim_matches('"').to_string());
+        }
+    }
+    None
+}
+
+/// Split a multipart body into its body parts, per RFC 2046 5.1.1.
+fn split_multipart<'a>(body: &'a str, boundary: &str) -> Vec<&'a str> {
+    let delimiter = format!("--{}", boundary);
+    let mut parts = Vec::new();
+    let mut start: Option<usize> = None;
+    let mut offset = 0;
+    for line in body.split_inclusive('\n') {
+        let trimmed = line.trim_end();
+        if trimmed.starts_with(&delimiter[..]) {
+            let rest = &trimmed[delimiter.len()..];
+            if rest.is_empty() || rest == "--" {
+                if let Some(part_start) = start {
+                    // The line break before the delimiter belongs to it
+                    let mut end = offset;
+                    if body[..end].ends_with('\n') {
+                        end -= 1;
+                    }
+                    if body[..end].ends_with('\r') {
+                        end -= 1;
+                    }
+                    parts.push(&body[part_start..end.max(part_start)]);
+                }
+                if rest == "--" {
+                    return parts;
+                }
+                start = Some(offset + line.len());
+            }
+        }
+        offset += line.len();
+    }
+    // A missing close delimiter ends the last part at the end of the body
+    if let Some(part_start) = start {
+        parts.push(&body[part_start..]);
+    }
+    parts
+}
+
+/// Representation of a MIME body part. The body is kept in its content
+/// transfer encoding; multipart and message/rfc822 parts hold their
+/// sub-parts.
+#[derive(Debug, Clone)]
+pub struct Part {
+    header: Vec<(String, String)>,
+    body: String,
+    parts: Vec<Part>,
+}
+
+impl Part {
+    /// Parse a body part from its raw header and body.
+    pub fn new(header: Vec<(String, String)>, body: &str, depth: usize) -> Part {
+        let mut part = Part {
+            header,
+            body: body.to_string(),
+            parts: Vec::new(),
+        };
+        if depth >= MAX_DEPTH {
+            return part;
+        }
+        let (media_type, subtype) = part.content_type();
+        if media_type == "multipart" {
+            if let Some(boundary) = part.get_header("CONTENT-TYPE").and_then(|v| get_param(v, "boundary")) {
+                for raw_part in split_multipart(body, &boundary) {
+                    let (header_end, body_start) = split_header(raw_part.as_bytes());
+                    let header = parse_header(&raw_part[..header_end]);
+                    part.parts.push(Part::new(header, &raw_part[body_start..], depth + 1));
+                }
+            }
+        } else if media_type == "message" && subtype == "rfc822" {
+            let (header_end, body_start) = split_header(body.as_bytes());
+            let header = parse_header(&body[..header_end]);
+            part.parts.push(Part::new(header, &body[body_start..], depth + 1));
+        }
+        part
+    }
+
+    /// The value of a header field of the part, by upper-cased name.
+    pub fn get_header(&self, key: &str) -> Option<&str> {
+        self.header
+            .iter()
+            .find(|(name, _)| name == key)
+            .map(|(_, value)| &value[..])
+    }
+
+    /// The lower-cased media type and subtype of the part. Parts without a
+    /// Content-Type are text/plain as RFC 2045 says.
+    pub fn content_type(&self) -> (String, String) {
+        let value = match self.get_header("CONTENT-TYPE") {
+            Some(value) => value,
+            None => return ("text".to_string(), "plain".to_string()),
+        };
+        let full_type = value.split(';').next().unwrap_or("").trim().to_ascii_lowercase();
+        let mut split = full_type.splitn(2, '/');
+        let media_type = split.next().unwrap_or("").trim().to_string();
+        let subtype = split.next().unwrap_or("").trim().to_string();
+        if media_type.is_empty() {
+            ("text".to_string(), "plain".to_string())
+        } else {
+            (media_type, subtype)
+        }
+    }
+
+    /// The sub-parts of a multipart or message/rfc822 part.
+    pub fn parts(&self) -> &[Part] {
+        &self.parts
+    }
+
+    /// The body of a leaf part with its transfer encoding decoded.
+    pub fn decoded_bytes(&self) -> Vec<u8> {
+        let encoding = self.get_header("CONTENT-TRANSFER-ENCODING").unwrap_or("7bit");
+        transfer_decode(&self.body, encoding)
+    }
+
+    /// The body of a leaf part with its transfer encoding and character set
+    /// decoded.
+    pub fn decoded_body(&self) -> String {
+        let bytes = self.decoded_bytes();
+        let charset = self
+            .get_header("CONTENT-TYPE")
+            .and_then(|v| get_param(v, "charset"))
+            .unwrap_or_else(|| "us-ascii".to_string());
+        charset_decode(&bytes, &charset)
+    }
+
+    /// Collect the decoded bodies of every leaf part whose media type is
+    /// one of `types`, given as "type" or "type/subtype". An empty list
+    /// matches every leaf part.
+    pub fn collect_text(&self, types: &[String], out: &mut Vec<String>) {
+        if !self.parts.is_empty() {
+            for part in &self.parts {
+                part.collect_text(types, out);
+            }
+            return;
+        }
+        let (media_type, subtype) = self.content_type();
+        let full_type = format!("{}/{}", media_type, subtype);
+        let matches = types.is_empty()
+            || types.iter().any(|t| {
+                let t = t.to_ascii_lowercase();
+                t == media_type || t == full_type
+            });
+        if matches {
+            out.push(self.decoded_body());
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse_header() {
+        let header = parse_header("Subject: a\n  folded\nX-Empty:\nReceived: one\nReceived: two\r\nbogus line\n");
+        assert_eq!(
+            header,
+            vec![
+                ("SUBJECT".to_string(), "a folded".to_string()),
+                ("X-EMPTY".to_string(), "".to_string()),
+                ("RECEIVED".to_string(), "one".to_string()),
+                ("RECEIVED".to_string(), "two".to_string()),
+            ]
+        );
+    }
+
+    #[test]
+    fn test_split_header() {
+        assert_eq!(split_header(b"A: b\n\nbody"), (5, 6));
+        assert_eq!(split_header(b"A: b\r\n\r\nbody"), (6, 8));
+        assert_eq!(split_header(b"\nbody"), (0, 1));
+        assert_eq!(split_header(b"A: b\n"), (5, 5));
+    }
+
+    #[test]
+    fn test_get_param() {
+        let value = "multipart/mixed; boundary=\"abc def\"; CHARSET=utf-8";
+        assert_eq!(get_param(value, "boundary"), Some("abc def".to_string()));
+        assert_eq!(get_param(value, "charset"), Some("utf-8".to_string()));
+        assert_eq!(get_param(value, "name"), None);
+    }
+
+    #[test]
+    fn test_multipart() {
+        let header = parse_header("Content-Type: multipart/alternative; boundary=b1\n");
+        let body = "preamble\n--b1\nContent-Type: text/plain\n\nplain text\n--b1\nContent-Type: text/html\nContent-Transfer-Encoding: base64\n\nPGI+aHRtbDwvYj4=\n--b1--\nepilogue\n";
+        let part = Part::new(header, body, 0);
+        let mut text = Vec::new();
+        part.collect_text(&["text/plain".to_string()], &mut text);
+        assert_eq!(text, vec!["plain text".to_string()]);
+        let mut all = Vec::new();
+        part.collect_text(&[], &mut all);
+        assert_eq!(all, vec!["plain text".to_string(), "<b>html</b>".to_string()]);
+    }
+}
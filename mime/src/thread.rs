@@ -0,0 +1,125 @@
+//! What threading messages together needs from their header fields: the
+//! message ids they refer to and their base subjects (RFC 5256 section 2.1).
+
+use crate::encoding::decode_header;
+
+/// The message ids, without angle brackets, in a Message-ID, In-Reply-To
+/// or References header field.
+pub fn message_ids(value: &str) -> Vec<String> {
+    let mut res = Vec::new();
+    let mut rest = value;
+    while let Some(start) = rest.find('<') {
+        match rest[start..].find('>') {
+            Some(end) => {
+                res.push(rest[start + 1..start + end].trim().to_string());
+                rest = &rest[start + end + 1..];
+            }
+            None => break,
+        }
+    }
+    res
+}
+
+fn strip_prefix_ignore_case<'a>(value: &'a str, prefix: &str) -> Option<&'a str> {
+    match value.get(..prefix.len()) {
+        Some(start) if start.eq_ignore_ascii_case(prefix) => Some(&value[prefix.len()..]),
+        _ => None,
+    }
+}
+
+fn strip_suffix_ignore_case<'a>(value: &'a str, suffix: &str) -> Option<&'a str> {
+    let start = value.len().checked_sub(suffix.len())?;
+    match value.get(start..) {
+        Some(end) if end.eq_ignore_ascii_case(suffix) => Some(&value[..start]),
+        _ => None,
+    }
+}
+
+// A subj-blob: text in square brackets, and the whitespace after it
+fn strip_blob(value: &str) -> Option<&str> {
+    let rest = value.strip_prefix('[')?;
+    let end = rest.find(['[', ']'])?;
+    let rest = rest[end..].strip_prefix(']')?;
+    Some(rest.trim_start())
+}
+
+// A subj-refwd after any number of subj-blobs, as in "[list] Re:"
+fn strip_reply(value: &str) -> Option<&str> {
+    let mut rest = value;
+    while let Some(after) = strip_blob(rest) {
+        rest = after;
+    }
+    let rest = strip_prefix_ignore_case(rest, "re")
+        .or_else(|| strip_prefix_ignore_case(rest, "fwd"))
+        .or_else(|| strip_prefix_ignore_case(rest, "fw"))?
+        .trim_start();
+    let rest = strip_blob(rest).unwrap_or(rest);
+    rest.strip_prefix(':')
+}
+
+/// The base subject of a Subject header field value, which messages of one
+/// conversation share, and whether the subject marks the message as a reply
+/// or forward. Encoded words are decoded and runs of whitespace become a
+/// single space; case is kept.
+pub fn base_subject(value: &str) -> (String, bool) {
+    let decoded = decode_header(value);
+    let collapsed = decoded.split_whitespace().collect::<Vec<_>>().join(" ");
+    let mut subject = &collapsed[..];
+    let mut reply = false;
+    loop {
+        // Trailing "(fwd)"s
+        while let Some(rest) = strip_suffix_ignore_case(subject, "(fwd)") {
+            subject = rest.trim_end();
+            reply = true;
+        }
+        // Leading "Re:"s, and blobs as long as something is left after them
+        loop {
+            let before = subject;
+            while let Some(rest) = strip_reply(subject) {
+                subject = rest.trim_start();
+                reply = true;
+            }
+            if let Some(rest) = strip_blob(subject).filter(|rest| !rest.is_empty()) {
+                subject = rest;
+            }
+            if subject == before {
+                break;
+            }
+        }
+        // A forward written as "[Fwd: subject]"
+        match strip_prefix_ignore_case(subject, "[fwd:").and_then(|rest| rest.strip_suffix(']')) {
+            Some(rest) => {
+                subject = rest.trim();
+                reply = true;
+            }
+            None => break,
+        }
+    }
+    (subject.to_string(), reply)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_message_ids() {
+        assert_eq!(message_ids("<a@b> (comment) <c@d>"), vec!["a@b", "c@d"]);
+        assert_eq!(message_ids("none"), Vec::<String>::new());
+    }
+
+    #[test]
+    fn test_base_subject() {
+        let base = |value| base_subject(value).0;
+        assert_eq!(base_subject("Lunch"), ("Lunch".to_string(), false));
+        assert_eq!(base_subject("Re: Lunch"), ("Lunch".to_string(), true));
+        assert_eq!(base("RE:  re:FWD: Lunch (fwd)"), "Lunch");
+        assert_eq!(base("[list] Re: [list] Lunch\t plans"), "Lunch plans");
+        assert_eq!(base("Re [2]: Lunch"), "Lunch");
+        assert_eq!(base("[Fwd: Re: Lunch]"), "Lunch");
+        assert_eq!(base("[only a blob]"), "[only a blob]");
+        assert_eq!(base("=?UTF-8?Q?Re:_caf=C3=A9?="), "café");
+        assert_eq!(base("Regarding lunch"), "Regarding lunch");
+        assert_eq!(base(""), "");
+    }
+}